@@ -0,0 +1,18 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use libcfhdb::{pci::CfhdbPciDevice, usb::CfhdbUsbDevice};
+
+// Runs against the real buses of the machine, so compare numbers from the same
+// box only. A subprocess sneaking back into the per-device path shows up here.
+fn enumeration(c: &mut Criterion) {
+    let mut group = c.benchmark_group("enumeration");
+    group.sample_size(20);
+    group.bench_function("usb_get_devices", |b| b.iter(CfhdbUsbDevice::get_devices));
+    group.bench_function("pci_get_devices", |b| b.iter(CfhdbPciDevice::get_devices));
+    group.bench_function("usb_get_devices_cached", |b| {
+        b.iter(CfhdbUsbDevice::get_devices_cached)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, enumeration);
+criterion_main!(benches);
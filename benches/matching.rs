@@ -0,0 +1,175 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use libcfhdb::{
+    pci::{CfhdbPciDevice, CfhdbPciProfile},
+    usb::{CfhdbUsbDevice, CfhdbUsbProfile},
+};
+use std::sync::Arc;
+
+const DEVICE_COUNT: usize = 48;
+
+fn ids(prefix: &str, index: usize) -> Vec<String> {
+    vec![format!("{}{:04x}", prefix, index % 512)]
+}
+
+fn usb_devices() -> Vec<CfhdbUsbDevice> {
+    (0..DEVICE_COUNT)
+        .map(|i| CfhdbUsbDevice {
+            manufacturer_string_index: format!("Vendor {}", i),
+            product_string_index: format!("Product {}", i),
+            serial_number_string_index: "Unknown".to_owned(),
+            vendor_name: format!("Vendor {}", i),
+            product_name: format!("Product {}", i),
+            protocol_code: "00".to_owned(),
+            class_code: format!("{:02X}", i % 16),
+            class_name: "Unknown".to_owned(),
+            block_devices: vec![],
+            hid_interfaces: vec![],
+            vendor_id: format!("v{:04x}", i),
+            product_id: format!("p{:04x}", i),
+            usb_version: "2.0".to_owned(),
+            bus_number: 1,
+            port_number: i as u8,
+            address: i as u8,
+            sysfs_busid: format!("1-{}", i),
+            stable_id: None,
+            kernel_driver: "Unknown".to_owned(),
+            started: Some(true),
+            enabled: true,
+            authorized: Some(true),
+            power_control: None,
+            autosuspend_delay_ms: None,
+            wakeup: None,
+            speed: "High".to_owned(),
+            max_speed: "2.0".to_owned(),
+            available_profiles: libcfhdb::usb::ProfileWrapper(Arc::default()),
+        })
+        .collect()
+}
+
+fn usb_profiles(count: usize) -> Vec<Arc<CfhdbUsbProfile>> {
+    (0..count)
+        .map(|i| {
+            Arc::new(CfhdbUsbProfile {
+                codename: format!("usb-profile-{}", i),
+                i18n_desc: String::new(),
+                icon_name: "package-x-generic".to_owned(),
+                license: "Unknown".to_owned(),
+                class_codes: vec!["*".to_owned()],
+                vendor_ids: ids("v", i),
+                product_ids: vec!["*".to_owned()],
+                blacklisted_class_codes: vec![],
+                blacklisted_vendor_ids: vec![],
+                blacklisted_product_ids: ids("p", i + 7),
+                packages: None,
+                check_script: "false".to_owned(),
+                install_script: None,
+                remove_script: None,
+                experimental: false,
+                removable: true,
+                veiled: false,
+                unsandboxed_check: false,
+                check_is_pure: true,
+                flatpaks: None,
+                snaps: None,
+                signature: None,
+                superseded_by: None,
+                regenerate_initramfs: false,
+                module_options: vec![],
+                blacklisted_modules: vec![],
+                kernel_parameters: vec![],
+                priority: i as i32,
+            })
+        })
+        .collect()
+}
+
+fn pci_devices() -> Vec<CfhdbPciDevice> {
+    (0..DEVICE_COUNT)
+        .map(|i| CfhdbPciDevice {
+            class_name: format!("Class {}", i % 16),
+            device_name: format!("Device {}", i),
+            vendor_name: format!("Vendor {}", i),
+            class_id: format!("{:04X}", i % 16),
+            vendor_id: format!("v{:04x}", i),
+            device_id: format!("d{:04x}", i),
+            started: Some(true),
+            enabled: true,
+            sysfs_busid: format!("0000:00:{:02x}.0", i),
+            sysfs_id: String::new(),
+            kernel_driver: "Unknown".to_owned(),
+            available_profiles: libcfhdb::pci::ProfileWrapper(Arc::default()),
+        })
+        .collect()
+}
+
+fn pci_profiles(count: usize) -> Vec<Arc<CfhdbPciProfile>> {
+    (0..count)
+        .map(|i| {
+            Arc::new(CfhdbPciProfile {
+                codename: format!("pci-profile-{}", i),
+                i18n_desc: String::new(),
+                icon_name: "package-x-generic".to_owned(),
+                license: "Unknown".to_owned(),
+                class_ids: vec!["*".to_owned()],
+                vendor_ids: ids("v", i),
+                device_ids: vec!["*".to_owned()],
+                blacklisted_class_ids: vec![],
+                blacklisted_vendor_ids: vec![],
+                blacklisted_device_ids: ids("d", i + 7),
+                packages: None,
+                check_script: "false".to_owned(),
+                install_script: None,
+                remove_script: None,
+                experimental: false,
+                removable: true,
+                veiled: false,
+                unsandboxed_check: false,
+                check_is_pure: true,
+                flatpaks: None,
+                snaps: None,
+                signature: None,
+                superseded_by: None,
+                regenerate_initramfs: false,
+                module_options: vec![],
+                blacklisted_modules: vec![],
+                kernel_parameters: vec![],
+                priority: i as i32,
+            })
+        })
+        .collect()
+}
+
+fn matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matching");
+    let usb = usb_devices();
+    let pci = pci_devices();
+    for count in [1000, 5000] {
+        let profiles = usb_profiles(count);
+        group.bench_with_input(BenchmarkId::new("usb", count), &profiles, |b, profiles| {
+            b.iter(|| {
+                for device in &usb {
+                    black_box(CfhdbUsbDevice::get_available_profiles(profiles, device));
+                }
+            })
+        });
+        let profiles = pci_profiles(count);
+        group.bench_with_input(BenchmarkId::new("pci", count), &profiles, |b, profiles| {
+            b.iter(|| {
+                for device in &pci {
+                    black_box(CfhdbPciDevice::get_available_profiles(profiles, device));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn grouping(c: &mut Criterion) {
+    let usb = usb_devices();
+    c.bench_function("usb_create_class_hashmap", |b| {
+        b.iter(|| CfhdbUsbDevice::create_class_hashmap(black_box(usb.clone())))
+    });
+}
+
+criterion_group!(benches, matching, grouping);
+criterion_main!(benches);
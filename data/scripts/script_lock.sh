@@ -0,0 +1,137 @@
+#!/bin/bash
+
+set -e
+
+# Runs a profile script composed by cfhdb. Only private temporary scripts are
+# accepted so this helper can't be pointed at arbitrary files.
+case "$1" in
+    /tmp/cfhdb-*.sh)
+        ;;
+    *)
+        echo "refusing to run $1"
+        exit 1
+        ;;
+esac
+
+if [ -L "$1" ] || [ ! -f "$1" ]
+then
+  echo "$1 is not a regular file"
+  exit 1
+fi
+
+# One cfhdb transaction at a time. The lock sits in a directory only root can
+# enter, an unprivileged process could otherwise take it and keep it. $2 is
+# how many seconds to wait for it, "wait" waits for as long as it takes.
+lock_timeout="${2:-300}"
+if ! [[ "$lock_timeout" =~ ^([0-9]+|wait)$ ]]
+then
+  echo "invalid lock timeout $lock_timeout"
+  exit 1
+fi
+lock_dir="/run/cfhdb"
+lock_file="$lock_dir/script.lock"
+# cfhdb reads this exit status as a busy lock
+lock_busy_exit=75
+mkdir -p -m 700 "$lock_dir"
+if [ -L "$lock_dir" ] || [ -L "$lock_file" ]
+then
+  echo "refusing to use a symlinked lock"
+  exit 1
+fi
+previous_umask="$(umask)"
+umask 077
+exec 9>> "$lock_file"
+umask "$previous_umask"
+chmod 600 "$lock_file"
+
+lock_waited=0
+until flock -n 9
+do
+  holder="another process"
+  holder_pid=""
+  holder_user=""
+  read -r holder_pid holder_user < "$lock_file" || true
+  if [[ "$holder_pid" =~ ^[0-9]+$ ]] && [ -r "/proc/$holder_pid/comm" ]
+  then
+    holder="$(cat "/proc/$holder_pid/comm") run by ${holder_user:-???} (pid $holder_pid)"
+  fi
+  if [ "$lock_timeout" != "wait" ] && [ "$lock_waited" -ge "$lock_timeout" ]
+  then
+    echo "the package lock is held by $holder"
+    exit "$lock_busy_exit"
+  fi
+  if [ $((lock_waited % 10)) -eq 0 ]
+  then
+    echo "waiting for the package lock, it is held by $holder (${lock_waited}s)"
+  fi
+  sleep 1
+  lock_waited=$((lock_waited + 1))
+done
+
+# Who the lock is held for, shown to runs waiting behind this one
+lock_user="$(id -un)"
+if [ -n "$PKEXEC_UID" ]
+then
+  lock_user="$(id -un "$PKEXEC_UID" 2> /dev/null || echo "$PKEXEC_UID")"
+elif [ -n "$SUDO_USER" ]
+then
+  lock_user="$SUDO_USER"
+fi
+: > "$lock_file"
+echo "$$ $lock_user" >&9
+
+transaction_id="$(date +%Y%m%d-%H%M%S)-$$"
+
+# Keep a copy of everything that runs as root for later auditing, and run that
+# copy so the recorded script is exactly the one that ran
+transactions_dir="/var/lib/cfhdb/transactions"
+mkdir -p "$transactions_dir"
+chmod 700 "$transactions_dir"
+transaction_script="$transactions_dir/$transaction_id.sh"
+install -m 700 "$1" "$transaction_script"
+# Files under /var/lib get var_lib_t, which a systemd unit may not execute
+if command -v selinuxenabled > /dev/null && selinuxenabled && command -v chcon > /dev/null
+then
+  chcon -t bin_t "$transaction_script" || echo "could not relabel $transaction_script, SELinux may block it"
+fi
+
+# The combined output of every run is also kept as a log next to the audit log
+log_dir="/var/log/cfhdb/transactions"
+mkdir -p "$log_dir"
+chmod 755 "$log_dir"
+transaction_log="$log_dir/$transaction_id.log"
+echo "# cfhdb transaction $transaction_id started $(date --iso-8601=seconds)" > "$transaction_log"
+chmod 644 "$transaction_log"
+
+# Resource limits for the transient unit, a runaway script gets throttled
+# instead of taking the machine down
+unit_memory_max="4G"
+unit_tasks_max="1024"
+unit_timeout="2h"
+
+if command -v systemd-run > /dev/null && [ -d /run/systemd/system ]
+then
+  unit_name="cfhdb-$transaction_id"
+  echo "Running as $unit_name.service, stop it with: systemctl stop $unit_name"
+  # Output is passed through to cfhdb and copied to the journal, read it back
+  # later with: journalctl -t $unit_name
+  systemd-run \
+    --unit="$unit_name" \
+    --description="cfhdb profile script $transaction_id" \
+    --quiet --wait --pipe --collect \
+    -p MemoryMax="$unit_memory_max" \
+    -p TasksMax="$unit_tasks_max" \
+    -p RuntimeMaxSec="$unit_timeout" \
+    -p CPUWeight=50 \
+    -p IOWeight=50 \
+    /bin/bash -c 'set -o pipefail; "$1" 2>&1 | tee -a "$3" >(systemd-cat -t "$2")' \
+    cfhdb-script "$transaction_script" "$unit_name" "$transaction_log" &
+  runner_pid=$!
+  # Stop the unit too when cfhdb gets cancelled, systemd-run alone would leave it running
+  trap 'systemctl stop "$unit_name" 2> /dev/null; kill "$runner_pid" 2> /dev/null' INT TERM HUP
+  wait "$runner_pid"
+  exit $?
+fi
+
+set -o pipefail
+"$transaction_script" 2>&1 | tee -a "$transaction_log"
@@ -5,6 +5,67 @@ set -e
 pci_blacklist_file_path="/etc/cfhdb/pci_blacklist"
 usb_blacklist_file_path="/etc/cfhdb/usb_blacklist"
 sysfs_remove_history="/tmp/cfhdb_sysfs_remove_history"
+udev_rules_file_path="/etc/udev/rules.d/90-cfhdb.rules"
+
+# Busids end up in sysfs paths and in the blacklists, only well formed ones
+# are let through. The blacklists also take usb stable ids.
+validate_device () {
+  case "$1" in
+    usb)
+      [[ "$2" =~ ^[0-9]+-[0-9]+(\.[0-9]+)*$ ]] || [[ "$3" == "blacklist" && "$2" =~ ^id:[0-9a-f]{16}$ ]]
+      ;;
+    pci)
+      [[ "$2" =~ ^[0-9a-f]{4}:[0-9a-f]{2}:[0-9a-f]{2}\.[0-7]$ ]]
+      ;;
+    *)
+      return 1
+      ;;
+  esac
+}
+
+# What a device id may be for an operation, see validate_device
+id_kind () {
+  case "$1" in
+    enable_device|disable_device)
+      echo "blacklist"
+      ;;
+    *)
+      echo "busid"
+      ;;
+  esac
+}
+
+# Started once per session by cfhdb, so one authentication covers a batch of
+# operations. Reads "operation category busid [module]" per line, runs it and
+# answers with its exit status. Exits on end of input or after $2 idle seconds.
+if [[ "$1" == "serve" ]]
+then
+  idle_timeout="${2:-300}"
+  while read -r -t "$idle_timeout" operation category busid module
+  do
+    case "$operation" in
+      start_device|stop_device|enable_device|disable_device|reset_device|authorize_device|deauthorize_device|set_power_control|set_autosuspend_delay|set_wakeup|persist_disable_device|persist_enable_device)
+        if ! validate_device "$category" "$busid" "$(id_kind "$operation")"
+        then
+          echo "2"
+          continue
+        fi
+        status=0
+        bash "$0" "$operation" "$category" "$busid" "$module" >&2 || status=$?
+        echo "$status"
+        ;;
+      *)
+        echo "2"
+        ;;
+    esac
+  done
+  exit 0
+fi
+
+if ! validate_device "$2" "$3" "$(id_kind "$1")"
+then
+  exit 2
+fi
 
 if [[ "$2" == "pci" ]]
 then
@@ -18,13 +79,14 @@ fi
 start_device () {
   if [ -f "$sysfs_remove_history" ]
   then
-    DRIVER_NAME=$(grep "^$2 " "$sysfs_remove_history" | awk '{print $2}')
+    DRIVER_NAME=$(awk -v id="$2" '$1 == id {print $2; exit}' "$sysfs_remove_history")
     if [ -z "$DRIVER_NAME" ]; then
       echo "$2" > /sys/bus/"$1"/drivers/"$target_arg3"/bind
       exit 1
     else
       echo "$2" > /sys/bus/"$1"/drivers/"$DRIVER_NAME"/bind
-      sed -i "/^$2 /d" "$sysfs_remove_history"
+      awk -v id="$2" '$1 != id' "$sysfs_remove_history" > "$sysfs_remove_history.tmp"
+      mv "$sysfs_remove_history.tmp" "$sysfs_remove_history"
     fi
   else
     exit 1
@@ -53,10 +115,102 @@ stop_device () {
   echo "$2" > /sys/bus/"$1"/devices/"$2"/driver/unbind
 }
 
+# The lists hold plain busids, unbind_blacklist.sh adds the usb interface
 enable_device () {
   if [ -f "$blacklist_file_path" ]
   then
-     sed -i "/^${2}$/d" "$blacklist_file_path"
+    awk -v id="$2" '$0 != id && $0 != id ":1.0"' "$blacklist_file_path" > "$blacklist_file_path.tmp"
+    mv "$blacklist_file_path.tmp" "$blacklist_file_path"
+  fi
+}
+
+# Deauthorizing a usb device disconnects it, authorizing it again makes the
+# kernel enumerate it from scratch as if it had been replugged
+reset_device () {
+  echo 0 > /sys/bus/"$1"/devices/"$2"/authorized
+  echo 1 > /sys/bus/"$1"/devices/"$2"/authorized
+}
+
+# A deauthorized device stays connected but none of its interfaces get a
+# driver until it is authorized again
+authorize_device () {
+  echo 1 > /sys/bus/"$1"/devices/"$2"/authorized
+}
+
+deauthorize_device () {
+  echo 0 > /sys/bus/"$1"/devices/"$2"/authorized
+}
+
+# auto lets the kernel suspend an idle device, on keeps it awake
+set_power_control () {
+  case "$3" in
+    auto|on)
+      echo "$3" > /sys/bus/"$1"/devices/"$2"/power/control
+      ;;
+    *)
+      exit 1
+      ;;
+  esac
+}
+
+set_autosuspend_delay () {
+  if [[ "$3" =~ ^-?[0-9]+$ ]]
+  then
+    echo "$3" > /sys/bus/"$1"/devices/"$2"/power/autosuspend_delay_ms
+  else
+    exit 1
+  fi
+}
+
+# Left empty by the kernel for devices that can't wake the system, writing
+# to it fails for those
+set_wakeup () {
+  case "$3" in
+    enabled|disabled)
+      echo "$3" > /sys/bus/"$1"/devices/"$2"/power/wakeup
+      ;;
+    *)
+      exit 1
+      ;;
+  esac
+}
+
+# The busid ends up in a udev rule, only plain usb busids are let through
+validate_usb_busid () {
+  if [[ "$1" != "usb" ]] || ! validate_device "$1" "$2" busid
+  then
+    exit 1
+  fi
+}
+
+# Deauthorizes the device on every add, a replug or reboot keeps it disabled.
+# Triggering an add applies the rule to the device right away.
+persist_disable_device () {
+  validate_usb_busid "$1" "$2"
+  rule="ACTION==\"add\", SUBSYSTEM==\"usb\", KERNEL==\"$2\", ATTR{authorized}=\"0\""
+  if [ ! -f "$udev_rules_file_path" ]
+  then
+    echo "# Managed by cfhdb, cfhdb usb enable --persistent <busid> removes a rule" > "$udev_rules_file_path"
+  fi
+  if ! grep -Fxq "$rule" "$udev_rules_file_path"
+  then
+    echo "$rule" >> "$udev_rules_file_path"
+  fi
+  udevadm control --reload
+  udevadm trigger --action=add --subsystem-match=usb --sysname-match="$2"
+}
+
+persist_enable_device () {
+  validate_usb_busid "$1" "$2"
+  if [ -f "$udev_rules_file_path" ]
+  then
+    awk -v kernel="KERNEL==\"$2\"" 'index($0, kernel) == 0' "$udev_rules_file_path" > "$udev_rules_file_path.tmp"
+    mv "$udev_rules_file_path.tmp" "$udev_rules_file_path"
+    udevadm control --reload
+  fi
+  if [ -f /sys/bus/usb/devices/"$2"/authorized ]
+  then
+    echo 1 > /sys/bus/usb/devices/"$2"/authorized
   fi
 }
 
@@ -83,9 +237,33 @@ case "$1" in
         stop_device "$2" "$target_arg3"
         ;;
     enable_device)
-        enable_device "$2" "$target_arg3"
+        enable_device "$2" "$3"
         ;;
     disable_device)
-        disable_device "$2" "$target_arg3"
+        disable_device "$2" "$3"
+        ;;
+    reset_device)
+        reset_device "$2" "$3"
+        ;;
+    authorize_device)
+        authorize_device "$2" "$3"
+        ;;
+    deauthorize_device)
+        deauthorize_device "$2" "$3"
+        ;;
+    set_power_control)
+        set_power_control "$2" "$3" "$4"
+        ;;
+    set_autosuspend_delay)
+        set_autosuspend_delay "$2" "$3" "$4"
+        ;;
+    set_wakeup)
+        set_wakeup "$2" "$3" "$4"
+        ;;
+    persist_disable_device)
+        persist_disable_device "$2" "$3"
+        ;;
+    persist_enable_device)
+        persist_enable_device "$2" "$3"
         ;;
 esac
\ No newline at end of file
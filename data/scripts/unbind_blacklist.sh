@@ -14,7 +14,47 @@ do
   /usr/lib/cfhdb/scripts/sysfs_helper.sh stop_device pci $device
 done
 
+# id:<hash> entries follow a device to whichever port it is on, the hash is
+# FNV-1a over vendor:product:serial like UsbStableId in libcfhdb
+usb_stable_id () {
+  local hash=-3750763034362895579
+  for byte in $(printf '%s' "$1" | od -An -v -tu1)
+  do
+    hash=$(( (hash ^ byte) * 1099511628211 ))
+  done
+  printf 'id:%016x' "$hash"
+}
+
+usb_busids_for_stable_id () {
+  for device_path in /sys/bus/usb/devices/*
+  do
+    if [ -f "$device_path/serial" ]
+    then
+      # libcfhdb hashes the serial with surrounding whitespace trimmed
+      serial="$(cat "$device_path/serial")"
+      serial="${serial#"${serial%%[![:space:]]*}"}"
+      serial="${serial%"${serial##*[![:space:]]}"}"
+      device_id=$(usb_stable_id "$(cat "$device_path/idVendor"):$(cat "$device_path/idProduct"):$serial")
+      if [[ "$device_id" == "$1" ]]
+      then
+        basename "$device_path"
+      fi
+    fi
+  done
+}
+
 for device in $(cat $usb_blacklist_file_path)
 do
-  /usr/lib/cfhdb/scripts/sysfs_helper.sh stop_device usb $device
+  # Entries written by older versions carry the interface suffix, the helper
+  # only takes the plain busid
+  device="${device%:1.0}"
+  if [[ "$device" == id:* ]]
+  then
+    for busid in $(usb_busids_for_stable_id "$device")
+    do
+      /usr/lib/cfhdb/scripts/sysfs_helper.sh stop_device usb $busid
+    done
+  else
+    /usr/lib/cfhdb/scripts/sysfs_helper.sh stop_device usb $device
+  fi
 done
@@ -1,34 +1,102 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    ask_yes_no, download_profile_database, get_profile_url_config, install_profile,
+    profile_status_cell, run_device_action, uninstall_profile, ProfileAutoApply, ProfileCleanup,
+    ProfileImport, ProfileUpgrade,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::bt::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path, process::exit};
+use libcfhdb::{
+    audit::AuditAction,
+    bt::{
+        adapter::CfhdbBtAdapter,
+        agent::{self, PairingAgent},
+        *,
+    },
+    cancel::CancellationToken,
+    commands::{self, CommandError},
+    database,
+    error::CfhdbError,
+    ids::BtAddress,
+    profile::CfhdbProfile,
+    status::{self, DeviceStatus},
+};
+#[cfg(feature = "daemon")]
+use libcfhdb::{
+    packages::PackageBackend,
+    profile::{ProfileOperationOptions, ProfileOperationOutcome},
+};
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal, Write},
+    ops::Deref,
+    sync::Arc,
+    time::Duration,
+};
 
 lazy_static! {
     static ref BT_PROFILE_JSON_URL: String = get_profile_url_config().bt_json_url;
+    // Fetched for the first device `cfhdb monitor` sees, not at startup
+    static ref BT_MONITOR_PROFILES: Vec<Arc<CfhdbBtProfile>> =
+        get_bt_profiles_from_url().unwrap_or_default();
 }
 
 fn display_bt_devices_print_json(hashmap: HashMap<String, Vec<CfhdbBtDevice>>) {
     let json_pretty = serde_json::to_string_pretty(&hashmap).unwrap();
     println!("{}", json_pretty);
 }
+
+// Around -80 dBm audio starts to drop out
+const WEAK_RSSI: i16 = -80;
+
+fn bt_signal_cell(dbm: Option<i16>, weak: bool) -> cli_table::CellStruct {
+    match dbm {
+        Some(t) if weak => format!("{} dBm", t)
+            .cell()
+            .foreground_color(Some(Color::Yellow)),
+        Some(t) => format!("{} dBm", t).cell(),
+        None => "-".cell(),
+    }
+}
+
+// bluez keeps the last level of a device after it disconnects, only the one
+// of a connected device is current
+fn bt_battery_cell(device: &CfhdbBtDevice, low: u8) -> cli_table::CellStruct {
+    match device.battery_level {
+        t if !device.connected || t == 0 => "-".cell(),
+        t if t <= low => format!("{}%", t).cell().foreground_color(Some(Color::Red)),
+        t => format!("{}%", t).cell(),
+    }
+}
+
 fn display_bt_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbBtDevice>>) {
+    // Red from the highest threshold `bt monitor --battery` reports on
+    let low_battery = get_profile_url_config()
+        .bt_battery_thresholds
+        .unwrap_or(events::DEFAULT_BATTERY_THRESHOLDS.to_vec())
+        .into_iter()
+        .max()
+        .unwrap_or_default();
     for (class, devices) in hashmap {
         let mut table_struct = vec![];
         for device in devices {
             let cell_table = vec![
                 match device.alias.char_indices().nth(18) {
-                    None => device.alias,
+                    None => device.alias.clone(),
                     Some((idx, _)) => device.alias[..idx].to_string() + "...",
                 }
                 .cell(),
                 match device.name.char_indices().nth(36) {
-                    None => device.name,
+                    None => device.name.clone(),
                     Some((idx, _)) => device.name[..idx].to_string() + "...",
                 }
                 .cell(),
-                device.address.cell(),
+                device.address.as_str().cell(),
+                match &device.minor_class {
+                    Some(minor_class) => t!("bt_minor_class_".to_string() + minor_class),
+                    None => t!("unknown"),
+                }
+                .cell(),
                 if device.paired {
                     t!("enabled_yes")
                         .cell()
@@ -51,12 +119,13 @@ fn display_bt_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbBtDevice
                     t!("enabled_no").cell().foreground_color(Some(Color::Red))
                 },
                 if device.blocked {
-                    t!("enabled_yes")
-                        .cell()
-                        .foreground_color(Some(Color::Red))
+                    t!("enabled_yes").cell().foreground_color(Some(Color::Red))
                 } else {
                     t!("enabled_no").cell().foreground_color(Some(Color::Green))
                 },
+                bt_signal_cell(device.rssi, device.rssi.is_some_and(|x| x < WEAK_RSSI)),
+                bt_signal_cell(device.tx_power, false),
+                bt_battery_cell(&device, low_battery),
             ];
             table_struct.push(cell_table);
         }
@@ -66,10 +135,14 @@ fn display_bt_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbBtDevice
                 t!("bt_table_alias").cell().bold(true),
                 t!("bt_table_name").cell().bold(true),
                 t!("bt_table_address").cell().bold(true),
+                t!("bt_table_type").cell().bold(true),
                 t!("bt_table_paired").cell().bold(true),
                 t!("bt_table_connected").cell().bold(true),
                 t!("bt_table_trusted").cell().bold(true),
                 t!("bt_table_blocked").cell().bold(true),
+                t!("bt_table_rssi").cell().bold(true),
+                t!("bt_table_tx_power").cell().bold(true),
+                t!("bt_table_battery").cell().bold(true),
             ])
             .bold(true);
 
@@ -83,20 +156,8 @@ fn display_bt_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbBtDevice
     }
 }
 
-fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice) {
+fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice, profiles: Vec<Arc<CfhdbBtProfile>>) {
     let mut table_struct = vec![];
-    let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
-        Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_device")
-            );
-            exit(1);
-        }
-    };
-    profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
         let profile = profile.deref().clone();
         let profile_status = profile.get_status();
@@ -114,13 +175,7 @@ fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice) {
             } else {
                 t!("enabled_no").cell().foreground_color(Some(Color::Green))
             },
-            if profile_status {
-                t!("enabled_yes")
-                    .cell()
-                    .foreground_color(Some(Color::Green))
-            } else {
-                t!("enabled_no").cell().foreground_color(Some(Color::Red))
-            },
+            profile_status_cell(profile_status, profile.check_is_pure),
         ];
         table_struct.push(cell_table);
     }
@@ -141,528 +196,493 @@ fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice) {
     println!("{}\n{}", target.address.bright_green(), table_display);
 }
 
-pub fn display_bt_devices(json: bool) {
-    match CfhdbBtDevice::get_devices() {
-        Some(devices) => {
-            let profiles = match get_bt_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-            for i in &devices {
-                CfhdbBtDevice::set_available_profiles(&profiles, &i);
-            }
-            let hashmap = CfhdbBtDevice::create_class_hashmap(devices);
-            if json {
-                display_bt_devices_print_json(hashmap)
-            } else {
-                display_bt_devices_print_cli_table(hashmap)
-            }
-        }
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("failed_to_get_bt_devices")
-            );
-            exit(1);
-        }
+pub fn display_bt_devices(json: bool) -> Result<(), CfhdbError> {
+    let profiles = get_bt_profiles_from_url()?;
+    let hashmap = commands::list_bt_devices(&profiles).map_err(|e| e.into_cfhdb_error("bt", ""))?;
+    if json {
+        display_bt_devices_print_json(hashmap)
+    } else {
+        display_bt_devices_print_cli_table(hashmap)
     }
+    Ok(())
 }
 
-pub fn display_bt_profiles(json: bool, target: &str) {
-    match CfhdbBtDevice::get_device_from_address(target) {
-        Ok(target_device) => {
-            let profiles = match get_bt_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-            CfhdbBtDevice::set_available_profiles(&profiles, &target_device);
-            if json {
-                let mut profile_arc =
-                    match target_device.available_profiles.0.lock().unwrap().clone() {
-                        Some(t) => t,
-                        None => {
-                            eprintln!(
-                                "[{}] {}",
-                                t!("error").red(),
-                                t!("no_profiles_available_for_device")
-                            );
-                            exit(1);
-                        }
-                    };
-                profile_arc.sort_by_key(|k| k.priority);
-                let profiles = profile_arc
-                    .iter()
-                    .map(|s| s.codename.clone())
-                    .collect::<Vec<_>>();
-                let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-                println!("{}", json_pretty);
-            } else {
-                display_bt_profiles_print_cli_table(&target_device);
-            }
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
+// Text output lists devices as they turn up, --json prints them grouped by
+// class like `cfhdb bt` once the scan is over
+pub fn scan_bt_devices(json: bool, timeout: Duration) -> Result<(), CfhdbError> {
+    let profiles = get_bt_profiles_from_url()?;
+    if !json {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("bt_scan_starting", seconds = timeout.as_secs())
+        );
+    }
+    let devices = CfhdbBtDevice::scan_devices(timeout, &CancellationToken::new(), |device| {
+        CfhdbBtDevice::set_available_profiles(&profiles, device);
+        if json {
+            return;
         }
+        let codenames = match device.available_profiles.0.lock().unwrap().as_ref() {
+            Some(t) => t
+                .iter()
+                .map(|x| x.codename.clone())
+                .collect::<Vec<_>>()
+                .join(", "),
+            None => t!("bt_scan_no_profiles").to_string(),
+        };
+        println!(
+            "{} {}",
+            device.address.bright_green(),
+            t!(
+                "bt_scan_found",
+                alias = device.alias,
+                rssi = match device.rssi {
+                    Some(t) => format!("{} dBm", t),
+                    None => t!("unknown").to_string(),
+                },
+                profiles = codenames
+            )
+        );
+    })?;
+    if json {
+        display_bt_devices_print_json(CfhdbBtDevice::create_class_hashmap(devices));
+    } else if devices.is_empty() {
+        println!("[{}] {}", t!("info").bright_green(), t!("bt_scan_none"));
     }
+    Ok(())
 }
 
-pub fn install_bt_profile(profile_codename: &str) {
-    let profiles = match get_bt_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbBtProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
+pub fn collect_bt_status() -> Result<Vec<DeviceStatus>, CommandError> {
+    let profiles = get_bt_profiles_from_url()?;
+    let hashmap = commands::list_bt_devices(&profiles)?;
+    Ok(hashmap
+        .values()
+        .flatten()
+        .map(|x| status::device_status("bt", x))
+        .collect())
+}
+
+// What `cfhdb monitor` prints with an event, None once the device is gone.
+// The codenames of the matching profiles end up in available_profiles.
+pub fn bt_device_snapshot(id: &str) -> Option<serde_json::Value> {
+    let device = CfhdbBtDevice::get_device_from_address(id).ok()?;
+    CfhdbBtDevice::set_available_profiles(&BT_MONITOR_PROFILES, &device);
+    serde_json::to_value(device).ok()
+}
+
+// Devices per class for `cfhdb exporter`, profiles are not needed for that
+pub fn bt_class_counts() -> Result<Vec<(String, usize)>, CommandError> {
+    let hashmap = commands::list_bt_devices(&[])?;
+    Ok(hashmap
+        .iter()
+        .map(|(class, devices)| (class.clone(), devices.len()))
+        .collect())
+}
+
+// Address, alias and percentage of the connected devices that report one
+pub fn bt_battery_levels() -> Result<Vec<(String, String, u8)>, CommandError> {
+    let hashmap = commands::list_bt_devices(&[])?;
+    Ok(hashmap
+        .values()
+        .flatten()
+        .filter(|x| x.connected && x.battery_level > 0)
+        .map(|x| (x.address.clone(), x.alias.clone(), x.battery_level))
+        .collect())
+}
+
+pub fn display_bt_profiles(json: bool, target: &str) -> Result<(), CfhdbError> {
+    let address: BtAddress = target.parse()?;
+    let profiles = get_bt_profiles_from_url()?;
+    let target_device = commands::get_bt_device_with_profiles(&address, &profiles)
+        .map_err(|e| e.into_cfhdb_error("bt", target))?;
+    let matched_profiles = commands::get_sorted_profiles(&target_device)
+        .map_err(|e| e.into_cfhdb_error("bt", target))?;
+    if json {
+        let codenames = matched_profiles
+            .iter()
+            .map(|s| s.codename.clone())
+            .collect::<Vec<_>>();
+        let json_pretty = serde_json::to_string_pretty(&codenames).unwrap();
+        println!("{}", json_pretty);
+    } else {
+        display_bt_profiles_print_cli_table(&target_device, matched_profiles);
     }
+    Ok(())
 }
-pub fn uninstall_bt_profile(profile_codename: &str) {
-    let profiles = match get_bt_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbBtProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
+
+pub fn install_bt_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_bt_profiles_from_url()?;
+    install_profile(CfhdbBtProfile::get_profile_from_codename(
+        profile_codename,
+        &profiles,
+    )?)
+}
+
+pub fn upgrade_bt_profiles(upgrade: &mut ProfileUpgrade) -> Result<(), std::io::Error> {
+    upgrade.apply(&get_bt_profiles_from_url()?);
+    Ok(())
+}
+
+pub fn autoremove_bt_profiles(cleanup: &mut ProfileCleanup) -> Result<(), CommandError> {
+    let profiles = get_bt_profiles_from_url()?;
+    let hashmap = commands::list_bt_devices(&profiles)?;
+    cleanup.apply(&profiles, hashmap.values().flatten());
+    Ok(())
+}
+
+pub fn auto_bt_profiles(auto: &mut ProfileAutoApply) -> Result<(), CommandError> {
+    let profiles = get_bt_profiles_from_url()?;
+    let hashmap = commands::list_bt_devices(&profiles)?;
+    auto.apply("bt", hashmap.values().flatten());
+    Ok(())
+}
+
+pub fn import_bt_profiles(import: &mut ProfileImport) -> Result<(), std::io::Error> {
+    import.apply(&get_bt_profiles_from_url()?);
+    Ok(())
+}
+
+#[cfg(feature = "daemon")]
+pub fn bt_devices_json() -> Result<String, CommandError> {
+    let profiles = get_bt_profiles_from_url()?;
+    let hashmap = commands::list_bt_devices(&profiles)?;
+    Ok(serde_json::to_string(&hashmap).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn bt_profiles_json(target: &str) -> Result<String, CommandError> {
+    let target: BtAddress = target.parse()?;
+    let profiles = get_bt_profiles_from_url()?;
+    let target_device = commands::get_bt_device_with_profiles(&target, &profiles)?;
+    let matched_profiles = commands::get_sorted_profiles(&target_device)?;
+    let matched_profiles: Vec<&CfhdbBtProfile> =
+        matched_profiles.iter().map(|x| x.deref()).collect();
+    Ok(serde_json::to_string(&matched_profiles).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn run_bt_profile_operation(
+    profile_codename: &str,
+    install: bool,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, CommandError> {
+    let profiles = get_bt_profiles_from_url()?;
+    let target = CfhdbBtProfile::get_profile_from_codename(profile_codename, &profiles);
+    if install {
+        commands::install_profile(target, backend, &ProfileOperationOptions::default())
+    } else {
+        commands::uninstall_profile(target, backend)
+    }
+}
+
+pub fn uninstall_bt_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_bt_profiles_from_url()?;
+    uninstall_profile(CfhdbBtProfile::get_profile_from_codename(
+        profile_codename,
+        &profiles,
+    )?)
+}
+
+// Answers bluez on the terminal while `cfhdb bt pair` runs
+struct CliPairingAgent;
+
+fn prompt(question: &str) -> Option<String> {
+    print!("{} ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok()?;
+    Some(answer.trim().to_owned()).filter(|x| !x.is_empty())
+}
+
+impl PairingAgent for CliPairingAgent {
+    fn request_pin_code(&self, device: &str) -> Option<String> {
+        prompt(&t!("bt_agent_request_pin_code", device = device))
+    }
+
+    fn display_pin_code(&self, device: &str, pin_code: &str) {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!(
+                "bt_agent_display_pin_code",
+                device = device,
+                pin_code = pin_code
+            )
+        );
+    }
+
+    fn request_passkey(&self, device: &str) -> Option<u32> {
+        prompt(&t!("bt_agent_request_passkey", device = device)).and_then(|x| x.parse().ok())
+    }
+
+    fn display_passkey(&self, device: &str, passkey: u32, entered: u16) {
+        // Called again for every digit typed, only shown once
+        if entered == 0 {
+            println!(
                 "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
+                t!("info").bright_green(),
+                t!(
+                    "bt_agent_display_passkey",
+                    device = device,
+                    passkey = format!("{:06}", passkey)
+                )
             );
-            exit(1);
         }
     }
-}
 
-pub fn pair_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.pair_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+    fn request_confirmation(&self, device: &str, passkey: u32) -> bool {
+        ask_yes_no(&t!(
+            "bt_agent_request_confirmation",
+            device = device,
+            passkey = format!("{:06}", passkey)
+        ))
     }
-}
-pub fn connect_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.connect_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+
+    fn request_authorization(&self, device: &str) -> bool {
+        ask_yes_no(&t!("bt_agent_request_authorization", device = device))
+    }
+
+    fn authorize_service(&self, device: &str, service: &str) -> bool {
+        ask_yes_no(&t!(
+            "bt_agent_authorize_service",
+            device = device,
+            service = service
+        ))
     }
 }
 
-pub fn disconnect_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.disconnect_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+pub fn pair_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    // Scripted runs keep pairing only what needs no input
+    if io::stdin().is_terminal() {
+        agent::set_pairing_agent(Arc::new(CliPairingAgent));
     }
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Pair,
+        CfhdbBtDevice::pair_device,
+        "bt",
+        target_sysfs_id,
+    )
 }
-pub fn block_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.block_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+pub fn connect_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Connect,
+        CfhdbBtDevice::connect_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+
+pub fn disconnect_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Disconnect,
+        CfhdbBtDevice::disconnect_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+pub fn block_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Block,
+        CfhdbBtDevice::block_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+
+pub fn unblock_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Unblock,
+        CfhdbBtDevice::unblock_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+
+pub fn trust_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Trust,
+        CfhdbBtDevice::trust_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+pub fn untrust_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Untrust,
+        CfhdbBtDevice::untrust_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+
+pub fn display_bt_services(json: bool, target: &str) -> Result<(), CfhdbError> {
+    let device = CfhdbBtDevice::get_device_from_address(target)?;
+    let services: Vec<(&str, Option<&str>)> = device
+        .uuids
+        .iter()
+        .map(|x| (x.as_str(), services::service_name(x)))
+        .collect();
+    if json {
+        let services: Vec<serde_json::Value> = services
+            .iter()
+            .map(|(uuid, name)| serde_json::json!({ "uuid": uuid, "name": name }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&services).unwrap());
+    } else if services.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("bt_services_none", address = device.address)
+        );
+    } else {
+        let table = services
+            .into_iter()
+            .map(|(uuid, name)| {
+                vec![
+                    uuid.cell(),
+                    name.map(|x| x.to_owned())
+                        .unwrap_or(t!("unknown").to_string())
+                        .cell(),
+                ]
+            })
+            .collect::<Vec<_>>()
+            .table()
+            .title(vec![
+                t!("bt_services_table_uuid").cell().bold(true),
+                t!("bt_services_table_name").cell().bold(true),
+            ])
+            .bold(true);
+        println!(
+            "{}\n{}",
+            device.address.bright_green(),
+            table.display().unwrap()
+        );
     }
+    Ok(())
 }
 
-pub fn unblock_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.unblock_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+pub fn remove_bt_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbBtDevice::get_device_from_address(target_sysfs_id),
+        AuditAction::Remove,
+        CfhdbBtDevice::remove_device,
+        "bt",
+        target_sysfs_id,
+    )
+}
+
+fn bt_adapter_flag_cell(value: bool) -> cli_table::CellStruct {
+    if value {
+        t!("enabled_yes")
+            .cell()
+            .foreground_color(Some(Color::Green))
+    } else {
+        t!("enabled_no").cell().foreground_color(Some(Color::Red))
     }
 }
 
-pub fn trust_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.trust_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+pub fn display_bt_adapters(json: bool, adapter: Option<&str>) -> Result<(), CfhdbError> {
+    let adapters = match adapter {
+        Some(_) => vec![CfhdbBtAdapter::get_adapter(adapter)?],
+        None => CfhdbBtAdapter::get_adapters()?,
+    };
+    if adapters.is_empty() {
+        return Err(CfhdbError::NoAdapter);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&adapters).unwrap());
+        return Ok(());
     }
+    let table = adapters
+        .into_iter()
+        .map(|x| {
+            vec![
+                x.name.cell(),
+                x.address.cell(),
+                x.alias.cell(),
+                bt_adapter_flag_cell(x.powered),
+                bt_adapter_flag_cell(x.discoverable),
+                bt_adapter_flag_cell(x.pairable),
+                bt_adapter_flag_cell(x.discovering),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            t!("bt_adapter_table_name").cell().bold(true),
+            t!("bt_table_address").cell().bold(true),
+            t!("bt_table_alias").cell().bold(true),
+            t!("bt_adapter_table_powered").cell().bold(true),
+            t!("bt_adapter_table_discoverable").cell().bold(true),
+            t!("bt_adapter_table_pairable").cell().bold(true),
+            t!("bt_adapter_table_discovering").cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+    Ok(())
 }
-pub fn untrust_bt_device(target_sysfs_id: &str) {
-    match CfhdbBtDevice::get_device_from_address(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.untrust_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+
+// setting is one of power, discoverable or pairable
+pub fn set_bt_adapter_setting(
+    adapter: Option<&str>,
+    setting: &str,
+    enabled: bool,
+) -> Result<(), CfhdbError> {
+    let adapter = CfhdbBtAdapter::get_adapter(adapter)?;
+    match setting {
+        "power" => adapter.set_powered(enabled)?,
+        "discoverable" => adapter.set_discoverable(enabled)?,
+        _ => adapter.set_pairable(enabled)?,
     }
+    println!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!(
+            "bt_adapter_setting_changed",
+            adapter = adapter.name,
+            setting = setting,
+            value = if enabled { "on" } else { "off" }
+        )
+    );
+    Ok(())
 }
 
-fn get_bt_profiles_from_url() -> Result<Vec<CfhdbBtProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/bt.json");
+pub fn set_bt_adapter_alias(adapter: Option<&str>, alias: &str) -> Result<(), CfhdbError> {
+    let adapter = CfhdbBtAdapter::get_adapter(adapter)?;
+    adapter.set_alias(alias)?;
     println!(
         "[{}] {}",
         t!("info").bright_green(),
-        t!("bt_download_starting")
+        t!(
+            "bt_adapter_alias_changed",
+            adapter = adapter.name,
+            alias = alias
+        )
     );
-    let client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(5))
-        .build()
-        .unwrap();
-    let data = match client.get(BT_PROFILE_JSON_URL.clone()).send() {
-        Ok(t) => {
-            println!(
-                "[{}] {}",
-                t!("info").bright_green(),
-                t!("bt_download_successful")
-            );
-            let cache = t.text().unwrap();
-            let _ = fs::File::create(cached_db_path);
-            let _ = fs::write(cached_db_path, &cache);
-            cache
-        }
-        Err(_) => {
-            println!(
-                "[{}] {}",
-                t!("warn").bright_yellow(),
-                t!("bt_download_failed")
-            );
-            if cached_db_path.exists() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("bt_download_cache_found")
-                );
-                fs::read_to_string(cached_db_path).unwrap()
-            } else {
-                eprintln!(
-                    "[{}] {}",
-                    t!("error").red(),
-                    t!("bt_download_cache_not_found")
-                );
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    t!("bt_download_cache_not_found"),
-                ));
+    Ok(())
+}
+
+fn get_bt_profiles_from_url() -> Result<Vec<Arc<CfhdbBtProfile>>, std::io::Error> {
+    let data = download_profile_database("bt", &BT_PROFILE_JSON_URL)?;
+    let profiles: Vec<CfhdbBtProfile> =
+        database::parse_profile_database(&data, &rust_i18n::locale())?;
+    let mut profiles_array: Vec<Arc<CfhdbBtProfile>> = profiles
+        .into_iter()
+        .map(|mut profile| {
+            if profile.license.is_empty() {
+                profile.license = t!("unknown").to_string();
             }
-        }
-    };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-
-            let class_ids: Vec<String> = match profile["class_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let bt_names: Vec<String> = match profile["bt_names"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let modalias_vendor_ids: Vec<String> = match profile["modalias_vendor_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let modalias_device_ids: Vec<String> = match profile["modalias_device_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let modalias_product_ids: Vec<String> = match profile["modalias_product_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_class_ids: Vec<String> =
-                match profile["blacklisted_class_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-            };
-            let blacklisted_bt_names: Vec<String> = match profile["blacklisted_bt_names"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_modalias_vendor_ids: Vec<String> =
-                match profile["blacklisted_modalias_vendor_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_modalias_device_ids: Vec<String> =
-                match profile["blacklisted_modalias_device_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_modalias_product_ids: Vec<String> =
-                match profile["blacklisted_modalias_product_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_bt_profile_json")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbBtProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                class_ids,
-                bt_names,
-                modalias_vendor_ids,
-                modalias_device_ids,
-                modalias_product_ids,
-                blacklisted_class_ids,
-                blacklisted_bt_names,
-                blacklisted_modalias_vendor_ids,
-                blacklisted_modalias_device_ids,
-                blacklisted_modalias_product_ids,
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
-    }
+            Arc::new(profile)
+        })
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }
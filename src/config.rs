@@ -1,7 +1,7 @@
-pub fn distro_packages_installer(package_list: &str) -> String {
-    format!("pikman install {}", package_list)
-}
+use libcfhdb::packages::{PackageBackend, PikmanBackend};
 
-pub fn distro_packages_uninstaller(package_list: &str) -> String {
-    format!("pikman purge {}", package_list)
+// Distributions pin their backend here, see detect_package_backend for one
+// that follows whichever package manager is installed
+pub fn distro_package_backend() -> Box<dyn PackageBackend> {
+    Box::new(PikmanBackend)
 }
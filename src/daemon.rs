@@ -0,0 +1,268 @@
+#[cfg(feature = "bt")]
+use crate::bt_func;
+#[cfg(feature = "dmi")]
+use crate::dmi_func;
+#[cfg(feature = "pci")]
+use crate::pci_func;
+#[cfg(feature = "usb")]
+use crate::usb_func;
+use crate::{apply_profile_config, collect_device_status, config, init_tracing, monitor, CliError};
+use dbus::{
+    arg::{PropMap, Variant},
+    blocking::Connection,
+    channel::MatchingReceiver,
+    message::MatchRule,
+    MethodErr,
+};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder};
+use libcfhdb::{commands::CommandError, profile::ProfileOperationOutcome};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    io::{self, ErrorKind},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+pub const BUS_NAME: &str = "com.github.cosmicfusion.cfhdb";
+pub const OBJECT_PATH: &str = "/com/github/cosmicfusion/cfhdb";
+pub const INTERFACE: &str = "com.github.cosmicfusion.cfhdb.Manager";
+// Installing and removing profiles, queries are open to every local user
+const MANAGE_ACTION: &str = "com.github.cosmicfusion.cfhdb.manage";
+// Polkit may be waiting for the user to type a password
+const POLKIT_TIMEOUT: Duration = Duration::from_secs(300);
+const POLKIT_ALLOW_USER_INTERACTION: u32 = 1;
+// How often the serving thread sends the replies of finished profile operations
+const PROCESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// Profile operations that got past polkit queue up here, one runs at a time
+static PROFILE_OPERATION: Mutex<()> = Mutex::new(());
+
+fn unknown_category(category: &str) -> CommandError {
+    CommandError::Io(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("unknown or unsupported category {}", category),
+    ))
+}
+
+fn command_error(e: CommandError) -> MethodErr {
+    match e {
+        CommandError::Io(e) if e.kind() == ErrorKind::InvalidInput => {
+            MethodErr::from(("org.freedesktop.DBus.Error.InvalidArgs", e.to_string()))
+        }
+        e => MethodErr::failed(&e),
+    }
+}
+
+// The caller is asked for the admin password by its own polkit agent, the
+// daemon itself already runs as root
+fn authorize(ctx: &Context) -> Result<(), MethodErr> {
+    let sender = ctx
+        .message()
+        .sender()
+        .ok_or_else(|| MethodErr::failed(&"message has no sender"))?;
+    let connection = Connection::new_system().map_err(|e| MethodErr::failed(&e))?;
+    let proxy = connection.with_proxy(
+        "org.freedesktop.PolicyKit1",
+        "/org/freedesktop/PolicyKit1/Authority",
+        POLKIT_TIMEOUT,
+    );
+    let mut subject = PropMap::new();
+    subject.insert("name".to_owned(), Variant(Box::new(sender.to_string())));
+    let ((authorized, _, _),): ((bool, bool, HashMap<String, String>),) = proxy
+        .method_call(
+            "org.freedesktop.PolicyKit1.Authority",
+            "CheckAuthorization",
+            (
+                ("system-bus-name", subject),
+                MANAGE_ACTION,
+                HashMap::<String, String>::new(),
+                POLKIT_ALLOW_USER_INTERACTION,
+                "",
+            ),
+        )
+        .map_err(|e| MethodErr::failed(&e))?;
+    if !authorized {
+        warn!(sender = %sender, "profile operation not authorized");
+        return Err(MethodErr::from((
+            "org.freedesktop.DBus.Error.AccessDenied",
+            "not authorized to manage cfhdb profiles",
+        )));
+    }
+    Ok(())
+}
+
+pub fn list_devices(category: &str) -> Result<String, CommandError> {
+    match category {
+        #[cfg(feature = "dmi")]
+        "dmi" => dmi_func::dmi_info_json(),
+        #[cfg(feature = "pci")]
+        "pci" => pci_func::pci_devices_json(),
+        #[cfg(feature = "usb")]
+        "usb" => usb_func::usb_devices_json(),
+        #[cfg(feature = "bt")]
+        "bt" => bt_func::bt_devices_json(),
+        _ => Err(unknown_category(category)),
+    }
+}
+
+// The device is ignored for dmi, which only has the one machine
+pub fn list_profiles(category: &str, device: &str) -> Result<String, CommandError> {
+    match category {
+        #[cfg(feature = "dmi")]
+        "dmi" => dmi_func::dmi_profiles_json(),
+        #[cfg(feature = "pci")]
+        "pci" => pci_func::pci_profiles_json(device),
+        #[cfg(feature = "usb")]
+        "usb" => usb_func::usb_profiles_json(device),
+        #[cfg(feature = "bt")]
+        "bt" => bt_func::bt_profiles_json(device),
+        _ => Err(unknown_category(category)),
+    }
+}
+
+pub fn run_profile_operation(
+    category: &str,
+    codename: &str,
+    install: bool,
+) -> Result<String, CommandError> {
+    let backend = config::distro_package_backend();
+    let result = match category {
+        #[cfg(feature = "dmi")]
+        "dmi" => dmi_func::run_dmi_profile_operation(codename, install, &*backend),
+        #[cfg(feature = "pci")]
+        "pci" => pci_func::run_pci_profile_operation(codename, install, &*backend),
+        #[cfg(feature = "usb")]
+        "usb" => usb_func::run_usb_profile_operation(codename, install, &*backend),
+        #[cfg(feature = "bt")]
+        "bt" => bt_func::run_bt_profile_operation(codename, install, &*backend),
+        _ => return Err(unknown_category(category)),
+    };
+    Ok(match result? {
+        ProfileOperationOutcome::Completed => "completed",
+        ProfileOperationOutcome::AlreadyInstalled => "already_installed",
+        ProfileOperationOutcome::NotInstalled => "not_installed",
+        ProfileOperationOutcome::NothingToDo => "nothing_to_do",
+    }
+    .to_owned())
+}
+
+// Profile operations wait on polkit and then on a whole package install, so
+// each gets a worker thread and the bus keeps answering everyone else. Only
+// the thread owning the connection sends on it, the worker hands the call back
+// with its reply filled in.
+fn profile_method(
+    b: &mut IfaceBuilder<()>,
+    name: &'static str,
+    install: bool,
+    replies: mpsc::Sender<Context>,
+) {
+    b.method_with_cr_custom::<(String, String), (String,), _, _>(
+        name,
+        ("category", "codename"),
+        ("outcome",),
+        move |mut ctx, _, (category, codename)| {
+            let replies = replies.clone();
+            thread::spawn(move || {
+                let result = authorize(&ctx).and_then(|_| {
+                    let _queued = PROFILE_OPERATION.lock().unwrap_or_else(|e| e.into_inner());
+                    if install {
+                        info!(category = %category, codename = %codename, "installing profile over D-Bus");
+                    } else {
+                        info!(category = %category, codename = %codename, "uninstalling profile over D-Bus");
+                    }
+                    run_profile_operation(&category, &codename, install).map_err(command_error)
+                });
+                ctx.reply(result.map(|x| (x,)));
+                let _ = replies.send(ctx);
+            });
+            None
+        },
+    );
+}
+
+// Called by every loop serving the connection
+pub fn send_replies(replies: &mpsc::Receiver<Context>, connection: &Connection) {
+    while let Ok(mut ctx) = replies.try_recv() {
+        if ctx.flush_messages(connection).is_err() {
+            warn!("could not send a D-Bus reply");
+        }
+    }
+}
+
+// Shared by the D-Bus service and the JSON-RPC socket
+pub fn init_service() -> Result<(), CliError> {
+    // Services start without LANG, messages then stay in English
+    let locale = env::var("LANG").unwrap_or_default();
+    match locale.strip_suffix(".UTF-8") {
+        Some(t) if !t.is_empty() => rust_i18n::set_locale(t),
+        _ => rust_i18n::set_locale("en_US"),
+    }
+    init_tracing(false);
+    apply_profile_config()
+}
+
+// Queries answer with the same JSON `cfhdb --json` prints. Profile operations
+// reply once the lock script finished, queued behind any other running one,
+// so clients should call them with a timeout long enough for a polkit prompt
+// and a package download. With `monitor` the daemon also follows hotplug
+// events, see monitor.rs.
+pub fn run(monitor: bool) -> Result<(), Box<dyn Error>> {
+    init_service()?;
+    let connection = Connection::new_system()?;
+    connection.request_name(BUS_NAME, false, true, false)?;
+    let (reply_sender, replies) = mpsc::channel();
+    let mut crossroads = Crossroads::new();
+    let token = crossroads.register(INTERFACE, |b: &mut IfaceBuilder<()>| {
+        b.method(
+            "ListDevices",
+            ("category",),
+            ("devices",),
+            |_, _, (category,): (String,)| Ok((list_devices(&category).map_err(command_error)?,)),
+        );
+        b.method(
+            "ListProfiles",
+            ("category", "device"),
+            ("profiles",),
+            |_, _, (category, device): (String, String)| {
+                Ok((list_profiles(&category, &device).map_err(command_error)?,))
+            },
+        );
+        b.method("Status", (), ("devices",), |_, _, _: ()| {
+            Ok((serde_json::to_string(&collect_device_status()).unwrap(),))
+        });
+        profile_method(b, "InstallProfile", true, reply_sender.clone());
+        profile_method(b, "UninstallProfile", false, reply_sender);
+        // Only emitted by `cfhdbd --monitor`
+        b.signal::<(String, String, String), _>(
+            "ProfileAvailable",
+            ("category", "device", "codename"),
+        );
+        b.signal::<(String, String, String, bool), _>(
+            "ProfileApplied",
+            ("category", "device", "codename", "success"),
+        );
+    });
+    crossroads.insert(OBJECT_PATH, &[token], ());
+    info!(name = BUS_NAME, "cfhdbd ready");
+    if monitor {
+        return monitor::run(crossroads, &connection, replies);
+    }
+    let mut crossroads = crossroads;
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, connection| {
+            if crossroads.handle_message(message, connection).is_err() {
+                warn!("could not handle a D-Bus method call");
+            }
+            true
+        }),
+    );
+    loop {
+        connection.process(PROCESS_INTERVAL)?;
+        send_replies(&replies, &connection);
+    }
+}
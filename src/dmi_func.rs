@@ -1,9 +1,24 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    download_profile_database, get_profile_url_config, install_profile, profile_status_cell,
+    uninstall_profile, ProfileAutoApply, ProfileCleanup, ProfileImport, ProfileUpgrade,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::dmi::*;
-use std::{fs, ops::Deref, path::Path, process::exit, sync::Arc};
+use libcfhdb::{
+    commands::{self, CommandError},
+    database,
+    dmi::*,
+    error::CfhdbError,
+    profile::CfhdbProfile,
+    status::{self, DeviceStatus},
+};
+#[cfg(feature = "daemon")]
+use libcfhdb::{
+    packages::PackageBackend,
+    profile::{ProfileOperationOptions, ProfileOperationOutcome},
+};
+use std::{ops::Deref, sync::Arc};
 
 lazy_static! {
     static ref DMI_PROFILE_JSON_URL: String = get_profile_url_config().dmi_json_url;
@@ -59,20 +74,8 @@ fn display_dmi_info_print_cli_table(dmi: &CfhdbDmiInfo) {
     );
 }
 
-fn display_dmi_profiles_print_cli_table(target: &CfhdbDmiInfo) {
+fn display_dmi_profiles_print_cli_table(profiles: Vec<Arc<CfhdbDmiProfile>>) {
     let mut table_struct = vec![];
-    let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
-        Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_info")
-            );
-            exit(1);
-        }
-    };
-    profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
         let profile = profile.deref().clone();
         let profile_status = profile.get_status();
@@ -90,13 +93,7 @@ fn display_dmi_profiles_print_cli_table(target: &CfhdbDmiInfo) {
             } else {
                 t!("enabled_no").cell().foreground_color(Some(Color::Green))
             },
-            if profile_status {
-                t!("enabled_yes")
-                    .cell()
-                    .foreground_color(Some(Color::Green))
-            } else {
-                t!("enabled_no").cell().foreground_color(Some(Color::Red))
-            },
+            profile_status_cell(profile_status, profile.check_is_pure),
         ];
         table_struct.push(cell_table);
     }
@@ -117,342 +114,126 @@ fn display_dmi_profiles_print_cli_table(target: &CfhdbDmiInfo) {
     println!("{}", table_display);
 }
 
-pub fn display_dmi_info(json: bool) {
-    let dmi = CfhdbDmiInfo::get_dmi();
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    CfhdbDmiInfo::set_available_profiles(&profiles, &dmi);
+pub fn display_dmi_info(json: bool) -> Result<(), CfhdbError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi = commands::get_dmi_info_with_profiles(&profiles);
     if json {
         display_dmi_info_print_json(&dmi)
     } else {
         display_dmi_info_print_cli_table(&dmi)
     }
+    Ok(())
+}
+
+pub fn collect_dmi_status() -> Result<Vec<DeviceStatus>, std::io::Error> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi = commands::get_dmi_info_with_profiles(&profiles);
+    Ok(vec![status::device_status("dmi", &dmi)])
 }
 
-pub fn display_dmi_profiles(json: bool) {
-    let dmi_info = CfhdbDmiInfo::get_dmi();
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    CfhdbDmiInfo::set_available_profiles(&profiles, &dmi_info);
+pub fn display_dmi_profiles(json: bool) -> Result<(), CfhdbError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi_info = commands::get_dmi_info_with_profiles(&profiles);
+    let matched_profiles =
+        commands::get_sorted_profiles(&dmi_info).map_err(|e| e.into_cfhdb_error("dmi", ""))?;
     if json {
-        let mut profile_arc = match dmi_info.available_profiles.0.lock().unwrap().clone() {
-            Some(t) => t,
-            None => {
-                eprintln!(
-                    "[{}] {}",
-                    t!("error").red(),
-                    t!("no_profiles_available_for_info")
-                );
-                exit(1);
-            }
-        };
-        profile_arc.sort_by_key(|k| k.priority);
-        let profiles = profile_arc
+        let codenames = matched_profiles
             .iter()
             .map(|s| s.codename.clone())
             .collect::<Vec<_>>();
-        let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
+        let json_pretty = serde_json::to_string_pretty(&codenames).unwrap();
         println!("{}", json_pretty);
     } else {
-        display_dmi_profiles_print_cli_table(&dmi_info);
+        display_dmi_profiles_print_cli_table(matched_profiles);
     }
+    Ok(())
 }
 
-pub fn install_dmi_profile(profile_codename: &str) {
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbDmiProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+pub fn install_dmi_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    install_profile(CfhdbDmiProfile::get_profile_from_codename(
+        profile_codename,
+        &profiles,
+    )?)
 }
-pub fn uninstall_dmi_profile(profile_codename: &str) {
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbDmiProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
+
+pub fn upgrade_dmi_profiles(upgrade: &mut ProfileUpgrade) -> Result<(), std::io::Error> {
+    upgrade.apply(&get_dmi_profiles_from_url()?);
+    Ok(())
+}
+
+pub fn autoremove_dmi_profiles(cleanup: &mut ProfileCleanup) -> Result<(), CommandError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi = commands::get_dmi_info_with_profiles(&profiles);
+    cleanup.apply(&profiles, std::iter::once(&dmi));
+    Ok(())
+}
+
+pub fn auto_dmi_profiles(auto: &mut ProfileAutoApply) -> Result<(), CommandError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi = commands::get_dmi_info_with_profiles(&profiles);
+    auto.apply("dmi", std::iter::once(&dmi));
+    Ok(())
+}
+
+pub fn import_dmi_profiles(import: &mut ProfileImport) -> Result<(), std::io::Error> {
+    import.apply(&get_dmi_profiles_from_url()?);
+    Ok(())
+}
+
+#[cfg(feature = "daemon")]
+pub fn dmi_info_json() -> Result<String, CommandError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi = commands::get_dmi_info_with_profiles(&profiles);
+    Ok(serde_json::to_string(&dmi).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn dmi_profiles_json() -> Result<String, CommandError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let dmi_info = commands::get_dmi_info_with_profiles(&profiles);
+    let matched_profiles = commands::get_sorted_profiles(&dmi_info)?;
+    let matched_profiles: Vec<&CfhdbDmiProfile> =
+        matched_profiles.iter().map(|x| x.deref()).collect();
+    Ok(serde_json::to_string(&matched_profiles).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn run_dmi_profile_operation(
+    profile_codename: &str,
+    install: bool,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, CommandError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    let target = CfhdbDmiProfile::get_profile_from_codename(profile_codename, &profiles);
+    if install {
+        commands::install_profile(target, backend, &ProfileOperationOptions::default())
+    } else {
+        commands::uninstall_profile(target, backend)
     }
 }
 
-fn get_dmi_profiles_from_url() -> Result<Vec<CfhdbDmiProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/dmi.json");
-    println!(
-        "[{}] {}",
-        t!("info").bright_green(),
-        t!("dmi_download_starting")
-    );
-    let client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(5))
-        .build()
-        .unwrap();
-    let data = match client.get(DMI_PROFILE_JSON_URL.clone()).send() {
-        Ok(t) => {
-            println!(
-                "[{}] {}",
-                t!("info").bright_green(),
-                t!("dmi_download_successful")
-            );
-            let cache = t.text().unwrap();
-            let _ = fs::File::create(cached_db_path);
-            let _ = fs::write(cached_db_path, &cache);
-            cache
-        }
-        Err(_) => {
-            println!(
-                "[{}] {}",
-                t!("warn").bright_yellow(),
-                t!("dmi_download_failed")
-            );
-            if cached_db_path.exists() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("dmi_download_cache_found")
-                );
-                fs::read_to_string(cached_db_path).unwrap()
-            } else {
-                eprintln!(
-                    "[{}] {}",
-                    t!("error").red(),
-                    t!("dmi_download_cache_not_found")
-                );
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    t!("dmi_download_cache_not_found"),
-                ));
-            }
-        }
-    };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-            let mut dmi_strings_vec = Vec::new();
-            for dmi_string in [
-                "bios_vendors",
-                "board_asset_tags",
-                "board_names",
-                "board_vendors",
-                "product_families",
-                "product_names",
-                "product_skus",
-                "sys_vendors",
-                "blacklisted_bios_vendors",
-                "blacklisted_board_asset_tags",
-                "blacklisted_board_names",
-                "blacklisted_board_vendors",
-                "blacklisted_product_families",
-                "blacklisted_product_names",
-                "blacklisted_product_skus",
-                "blacklisted_sys_vendors",
-            ] {
-                let final_map: Vec<String> = match profile[dmi_string].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-                dmi_strings_vec.push(Arc::new(final_map));
+pub fn uninstall_dmi_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_dmi_profiles_from_url()?;
+    uninstall_profile(CfhdbDmiProfile::get_profile_from_codename(
+        profile_codename,
+        &profiles,
+    )?)
+}
+
+fn get_dmi_profiles_from_url() -> Result<Vec<Arc<CfhdbDmiProfile>>, std::io::Error> {
+    let data = download_profile_database("dmi", &DMI_PROFILE_JSON_URL)?;
+    let profiles: Vec<CfhdbDmiProfile> =
+        database::parse_profile_database(&data, &rust_i18n::locale())?;
+    let mut profiles_array: Vec<Arc<CfhdbDmiProfile>> = profiles
+        .into_iter()
+        .map(|mut profile| {
+            if profile.license.is_empty() {
+                profile.license = t!("unknown").to_string();
             }
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_dmi_profile_json")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbDmiProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                bios_vendors: dmi_strings_vec[0].to_vec(),
-                board_asset_tags: dmi_strings_vec[1].to_vec(),
-                board_names: dmi_strings_vec[2].to_vec(),
-                board_vendors: dmi_strings_vec[3].to_vec(),
-                product_families: dmi_strings_vec[4].to_vec(),
-                product_names: dmi_strings_vec[5].to_vec(),
-                product_skus: dmi_strings_vec[6].to_vec(),
-                sys_vendors: dmi_strings_vec[7].to_vec(),
-                blacklisted_bios_vendors: dmi_strings_vec[8].to_vec(),
-                blacklisted_board_asset_tags: dmi_strings_vec[9].to_vec(),
-                blacklisted_board_names: dmi_strings_vec[10].to_vec(),
-                blacklisted_board_vendors: dmi_strings_vec[11].to_vec(),
-                blacklisted_product_families: dmi_strings_vec[12].to_vec(),
-                blacklisted_product_names: dmi_strings_vec[13].to_vec(),
-                blacklisted_product_skus: dmi_strings_vec[14].to_vec(),
-                blacklisted_sys_vendors: dmi_strings_vec[15].to_vec(),
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
-    }
+            Arc::new(profile)
+        })
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }
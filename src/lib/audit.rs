@@ -0,0 +1,206 @@
+use crate::paths::{in_target_root, user_state_dir};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+use users::get_current_username;
+
+pub const SYSTEM_AUDIT_LOG_PATH: &str = "/var/log/cfhdb/audit.log";
+
+lazy_static! {
+    static ref AUDIT_JOURNAL: Mutex<bool> = Mutex::new(false);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Install,
+    Uninstall,
+    Enable,
+    Disable,
+    Start,
+    Stop,
+    Reset,
+    Authorize,
+    Deauthorize,
+    Power,
+    Pair,
+    Connect,
+    Disconnect,
+    Block,
+    Unblock,
+    Trust,
+    Untrust,
+    Remove,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Install => "install",
+            AuditAction::Uninstall => "uninstall",
+            AuditAction::Enable => "enable",
+            AuditAction::Disable => "disable",
+            AuditAction::Start => "start",
+            AuditAction::Stop => "stop",
+            AuditAction::Reset => "reset",
+            AuditAction::Authorize => "authorize",
+            AuditAction::Deauthorize => "deauthorize",
+            AuditAction::Power => "power",
+            AuditAction::Pair => "pair",
+            AuditAction::Connect => "connect",
+            AuditAction::Disconnect => "disconnect",
+            AuditAction::Block => "block",
+            AuditAction::Unblock => "unblock",
+            AuditAction::Trust => "trust",
+            AuditAction::Untrust => "untrust",
+            AuditAction::Remove => "remove",
+        }
+    }
+}
+
+// One line of the audit log, stored as JSON so `cfhdb history` and external
+// tools can read it back without a custom parser.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    pub user: String,
+    pub action: AuditAction,
+    pub device: Option<String>,
+    pub profile: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+    // The scripts a profile operation ran, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scripts: Vec<String>,
+}
+
+impl AuditRecord {
+    pub fn new<T>(
+        action: AuditAction,
+        device: Option<&str>,
+        profile: Option<&str>,
+        result: &Result<T, io::Error>,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            user: get_current_username()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or("???".to_owned()),
+            action,
+            device: device.map(|x| x.to_owned()),
+            profile: profile.map(|x| x.to_owned()),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            scripts: vec![],
+        }
+    }
+}
+
+// Root appends to the system log, other users can't write there and get a log
+// under their own state directory instead. The journal stamps the real uid on
+// every entry, which makes it the tamper resistant option when enabled.
+fn user_audit_log_path() -> Option<PathBuf> {
+    Some(user_state_dir()?.join("audit.log"))
+}
+
+fn append_line(path: &Path, line: &str) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o644)
+        .open(path)?;
+    writeln!(file, "{}", line)
+}
+
+pub fn set_audit_journal(enabled: bool) {
+    *AUDIT_JOURNAL.lock().unwrap() = enabled;
+}
+
+// Auditing never fails the operation it describes, problems are only logged
+pub fn record(entry: &AuditRecord) {
+    let line = match serde_json::to_string(entry) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(error = %e, "could not serialize audit record");
+            return;
+        }
+    };
+    let written = append_line(&in_target_root(SYSTEM_AUDIT_LOG_PATH), &line).or_else(|_| {
+        match user_audit_log_path() {
+            Some(path) => append_line(&path, &line),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no writable audit log location",
+            )),
+        }
+    });
+    if let Err(e) = written {
+        warn!(error = %e, "could not write audit record");
+    }
+    if *AUDIT_JOURNAL.lock().unwrap() {
+        if let Err(e) = duct::cmd!("logger", "-t", "cfhdb", "-p", "auth.notice", &line).run() {
+            warn!(error = %e, "could not send audit record to the journal");
+        }
+    }
+}
+
+fn read_records(path: &Path) -> Vec<AuditRecord> {
+    let file = match fs::File::open(path) {
+        Ok(t) => t,
+        Err(_) => return vec![],
+    };
+    io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+// System and user records merged, oldest first
+pub fn get_history() -> Vec<AuditRecord> {
+    let mut records = read_records(&in_target_root(SYSTEM_AUDIT_LOG_PATH));
+    if let Some(path) = user_audit_log_path() {
+        records.extend(read_records(&path));
+    }
+    records.sort_by_key(|x| x.timestamp);
+    records
+}
+
+// UTC "YYYY-MM-DD HH:MM:SS", days to civil date after Howard Hinnant
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
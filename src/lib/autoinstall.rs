@@ -0,0 +1,59 @@
+use crate::profile::CfhdbProfile;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Opt-in rules for usb devices plugged in while `cfhdbd --monitor` runs, on
+// top of hotplug_policy. A device only qualifies when exactly one profile
+// could be picked for it, anything less clear cut is left to the user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoInstallMode {
+    // hotplug_policy alone decides
+    #[default]
+    Off,
+    // Offer the profile, the notification agent asks the user
+    Notify,
+    // Install the profile right away
+    Install,
+}
+
+impl AutoInstallMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AutoInstallMode::Off => "off",
+            AutoInstallMode::Notify => "notify",
+            AutoInstallMode::Install => "install",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AutoInstallRules {
+    pub mode: AutoInstallMode,
+    // Codenames never picked, they keep going through the user
+    pub exclude: Vec<String>,
+}
+
+impl AutoInstallRules {
+    pub fn enabled(&self) -> bool {
+        self.mode != AutoInstallMode::Off
+    }
+
+    // Of the profiles matching a device, the one to install. None unless the
+    // device has nothing installed yet and exactly one non-experimental
+    // profile is left.
+    pub fn pick<'a, P: CfhdbProfile>(&self, profiles: &'a [Arc<P>]) -> Option<&'a Arc<P>> {
+        if !self.enabled() || profiles.iter().any(|x| x.get_status()) {
+            return None;
+        }
+        let mut candidates = profiles
+            .iter()
+            .filter(|x| !x.is_veiled() && !x.is_experimental() && x.get_superseded_by().is_none());
+        let candidate = candidates.next()?;
+        if candidates.next().is_some() || self.exclude.iter().any(|x| x == candidate.get_codename())
+        {
+            return None;
+        }
+        Some(candidate)
+    }
+}
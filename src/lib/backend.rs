@@ -0,0 +1,331 @@
+#[cfg(feature = "bt")]
+use crate::bt::CfhdbBtDevice;
+use crate::device::CfhdbDevice;
+#[cfg(feature = "dmi")]
+use crate::dmi::CfhdbDmiInfo;
+#[cfg(feature = "pci")]
+use crate::pci::CfhdbPciDevice;
+#[cfg(feature = "usb")]
+use crate::usb::CfhdbUsbDevice;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Seams between libcfhdb and the hardware. The System* implementations talk to
+// sysfs, rusb, libpci and bluez, the Fake* ones serve canned data so matching,
+// class grouping and rendering can run in CI without real devices or root.
+// Fake devices are easiest to build from JSON snapshots of real machines.
+
+pub trait SysfsProvider: Send + Sync {
+    fn read_to_string(&self, path: &Path) -> Result<String, io::Error>;
+    fn write(&self, path: &Path, contents: &str) -> Result<(), io::Error>;
+    fn exists(&self, path: &Path) -> bool;
+}
+
+// Where the devices of one category come from. Anything that can list them,
+// a mock, a remote daemon or a saved snapshot, can stand in for the system.
+pub trait DeviceProvider<D: CfhdbDevice>: Send + Sync {
+    fn get_devices(&self) -> Option<Vec<D>>;
+
+    fn get_device(&self, id: &str) -> Result<D, io::Error> {
+        let id = D::normalize_id(id)?;
+        find_device(self.get_devices(), |x| x.get_id() == id)
+    }
+}
+
+// The category specific names the commands were written against, every
+// provider of the category has them
+#[cfg(feature = "usb")]
+pub trait UsbBackend: DeviceProvider<CfhdbUsbDevice> {
+    fn get_device_from_busid(&self, busid: &str) -> Result<CfhdbUsbDevice, io::Error> {
+        self.get_device(busid)
+    }
+}
+
+#[cfg(feature = "usb")]
+impl<T: DeviceProvider<CfhdbUsbDevice>> UsbBackend for T {}
+
+#[cfg(feature = "pci")]
+pub trait PciBackend: DeviceProvider<CfhdbPciDevice> {
+    fn get_device_from_busid(&self, busid: &str) -> Result<CfhdbPciDevice, io::Error> {
+        self.get_device(busid)
+    }
+}
+
+#[cfg(feature = "pci")]
+impl<T: DeviceProvider<CfhdbPciDevice>> PciBackend for T {}
+
+#[cfg(feature = "bt")]
+pub trait BluerBackend: DeviceProvider<CfhdbBtDevice> {
+    fn get_device_from_address(&self, address: &str) -> Result<CfhdbBtDevice, io::Error> {
+        self.get_device(address)
+    }
+}
+
+#[cfg(feature = "bt")]
+impl<T: DeviceProvider<CfhdbBtDevice>> BluerBackend for T {}
+
+// DMI comes out of sysfs, so every sysfs, fake ones included, provides it
+#[cfg(feature = "dmi")]
+impl<S: SysfsProvider> DeviceProvider<CfhdbDmiInfo> for S {
+    fn get_devices(&self) -> Option<Vec<CfhdbDmiInfo>> {
+        Some(vec![CfhdbDmiInfo::get_dmi_from(self)])
+    }
+}
+
+fn find_device<T>(devices: Option<Vec<T>>, predicate: impl Fn(&T) -> bool) -> Result<T, io::Error> {
+    let devices = match devices {
+        Some(t) => t,
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Could not get devices",
+            ));
+        }
+    };
+    match devices.into_iter().find(|x| predicate(x)) {
+        Some(device) => Ok(device),
+        None => Err(io::Error::new(ErrorKind::NotFound, "no matching device")),
+    }
+}
+
+// Real backends
+
+pub struct SystemSysfs;
+
+impl SysfsProvider for SystemSysfs {
+    fn read_to_string(&self, path: &Path) -> Result<String, io::Error> {
+        fs::read_to_string(path)
+    }
+
+    fn write(&self, path: &Path, contents: &str) -> Result<(), io::Error> {
+        fs::write(path, contents)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+#[cfg(feature = "usb")]
+pub struct SystemUsb;
+
+#[cfg(feature = "usb")]
+impl DeviceProvider<CfhdbUsbDevice> for SystemUsb {
+    fn get_devices(&self) -> Option<Vec<CfhdbUsbDevice>> {
+        CfhdbUsbDevice::get_devices()
+    }
+
+    fn get_device(&self, id: &str) -> Result<CfhdbUsbDevice, io::Error> {
+        Ok(CfhdbUsbDevice::get_device_from_busid(id)?)
+    }
+}
+
+#[cfg(feature = "pci")]
+pub struct SystemPci;
+
+#[cfg(feature = "pci")]
+impl DeviceProvider<CfhdbPciDevice> for SystemPci {
+    fn get_devices(&self) -> Option<Vec<CfhdbPciDevice>> {
+        CfhdbPciDevice::get_devices()
+    }
+
+    fn get_device(&self, id: &str) -> Result<CfhdbPciDevice, io::Error> {
+        CfhdbPciDevice::get_device_from_busid(id)
+    }
+}
+
+#[cfg(feature = "bt")]
+pub struct SystemBluer;
+
+#[cfg(feature = "bt")]
+impl DeviceProvider<CfhdbBtDevice> for SystemBluer {
+    fn get_devices(&self) -> Option<Vec<CfhdbBtDevice>> {
+        CfhdbBtDevice::get_devices()
+    }
+
+    fn get_device(&self, id: &str) -> Result<CfhdbBtDevice, io::Error> {
+        Ok(CfhdbBtDevice::get_device_from_address(id)?)
+    }
+}
+
+// In-memory fakes
+
+#[derive(Default)]
+pub struct FakeSysfs {
+    files: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl FakeSysfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(self, path: impl Into<PathBuf>, contents: &str) -> Self {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.into(), contents.to_owned());
+        self
+    }
+}
+
+impl SysfsProvider for FakeSysfs {
+    fn read_to_string(&self, path: &Path) -> Result<String, io::Error> {
+        match self.files.lock().unwrap().get(path) {
+            Some(t) => Ok(t.clone()),
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &str) -> Result<(), io::Error> {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), contents.to_owned());
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+}
+
+#[cfg(feature = "usb")]
+#[derive(Default)]
+pub struct FakeUsb(pub Vec<CfhdbUsbDevice>);
+
+#[cfg(feature = "usb")]
+impl FakeUsb {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self(serde_json::from_str(json)?))
+    }
+}
+
+#[cfg(feature = "usb")]
+impl DeviceProvider<CfhdbUsbDevice> for FakeUsb {
+    fn get_devices(&self) -> Option<Vec<CfhdbUsbDevice>> {
+        Some(self.0.clone())
+    }
+}
+
+#[cfg(feature = "pci")]
+#[derive(Default)]
+pub struct FakePci(pub Vec<CfhdbPciDevice>);
+
+#[cfg(feature = "pci")]
+impl FakePci {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self(serde_json::from_str(json)?))
+    }
+}
+
+#[cfg(feature = "pci")]
+impl DeviceProvider<CfhdbPciDevice> for FakePci {
+    fn get_devices(&self) -> Option<Vec<CfhdbPciDevice>> {
+        Some(self.0.clone())
+    }
+}
+
+// Devices loaded this way are detached, so bt operations on them fail with
+// NotConnected instead of reaching bluez.
+#[cfg(feature = "bt")]
+#[derive(Default)]
+pub struct FakeBluer(pub Vec<CfhdbBtDevice>);
+
+#[cfg(feature = "bt")]
+impl FakeBluer {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self(serde_json::from_str(json)?))
+    }
+}
+
+#[cfg(feature = "bt")]
+impl DeviceProvider<CfhdbBtDevice> for FakeBluer {
+    fn get_devices(&self) -> Option<Vec<CfhdbBtDevice>> {
+        Some(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "usb")]
+    fn usb_device(busid: &str, class_code: &str) -> CfhdbUsbDevice {
+        serde_json::from_value(serde_json::json!({
+            "manufacturer_string_index": "Logitech",
+            "product_string_index": "USB Receiver",
+            "serial_number_string_index": "",
+            "protocol_code": "00",
+            "class_code": class_code,
+            "vendor_id": "046d",
+            "product_id": "c52b",
+            "usb_version": "2.0",
+            "bus_number": 1,
+            "port_number": 2,
+            "address": 3,
+            "sysfs_busid": busid,
+            "kernel_driver": "usbhid",
+            "started": true,
+            "enabled": true,
+            "speed": "full",
+            "available_profiles": null,
+        }))
+        .unwrap()
+    }
+
+    #[cfg(feature = "dmi")]
+    #[test]
+    fn dmi_strings_are_trimmed_and_missing_ones_unknown() {
+        let sysfs = FakeSysfs::new()
+            .with_file("/sys/class/dmi/id/sys_vendor", "LENOVO\n")
+            .with_file("/sys/class/dmi/id/product_name", "  21CB  \n")
+            .with_file("/sys/class/dmi/id/board_vendor", " \n");
+        let dmi = CfhdbDmiInfo::get_dmi_from(&sysfs);
+        assert_eq!(dmi.sys_vendor, "LENOVO");
+        assert_eq!(dmi.product_name, "21CB");
+        assert_eq!(dmi.board_vendor, "Unknown!");
+        assert_eq!(dmi.bios_version, "Unknown!");
+    }
+
+    #[cfg(feature = "usb")]
+    #[test]
+    fn devices_are_grouped_by_class() {
+        let usb = FakeUsb(vec![
+            usb_device("1-1", "03"),
+            usb_device("1-2", "08"),
+            usb_device("1-3", "03"),
+        ]);
+        let groups = crate::device::group_by_class(usb.get_devices().unwrap());
+        assert_eq!(groups.len(), 2);
+        let hid: Vec<String> = groups["03"].iter().map(|x| x.sysfs_busid.clone()).collect();
+        assert_eq!(hid, ["1-1", "1-3"]);
+        assert_eq!(groups["08"].len(), 1);
+    }
+
+    #[cfg(feature = "usb")]
+    #[test]
+    fn usb_device_lookup_by_busid() {
+        let usb = FakeUsb(vec![usb_device("1-1", "03"), usb_device("3-1.2", "08")]);
+        assert_eq!(
+            usb.get_device_from_busid(" 3-1.2 ").unwrap().sysfs_busid,
+            "3-1.2"
+        );
+        assert_eq!(
+            usb.get_device_from_busid("2-1").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            usb.get_device_from_busid("1-1; reboot").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{device::CfhdbDevice, paths::in_target_root};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+// The busids of the devices sysfs_helper.sh unbinds at boot, one per line.
+// Older helpers stored usb entries with the ":1.0" of the first interface
+// appended, reading drops it and the next write leaves it out.
+
+pub fn blacklist_path(category: &str) -> PathBuf {
+    in_target_root(format!("/etc/cfhdb/{}_blacklist", category))
+}
+
+fn normalize_entry(category: &str, line: &str) -> Option<String> {
+    let entry = line.trim();
+    let entry = match category {
+        "usb" => entry.strip_suffix(":1.0").unwrap_or(entry),
+        _ => entry,
+    };
+    (!entry.is_empty()).then(|| entry.to_owned())
+}
+
+pub fn read_blacklist(category: &str) -> Result<Vec<String>, io::Error> {
+    let data = match fs::read_to_string(blacklist_path(category)) {
+        Ok(t) => t,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut entries: Vec<String> = vec![];
+    for entry in data.lines().filter_map(|x| normalize_entry(category, x)) {
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+pub fn is_blacklisted(category: &str, id: &str) -> bool {
+    read_blacklist(category).is_ok_and(|x| x.iter().any(|x| x == id))
+}
+
+// Written next to the list and renamed over it, the boot time unbind never
+// sees half a file
+fn write_blacklist(category: &str, entries: &[String]) -> Result<(), io::Error> {
+    let path = blacklist_path(category);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data: String = entries.iter().map(|x| format!("{}\n", x)).collect();
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, &path)
+}
+
+// Both return whether the list changed. The id is validated and normalized
+// the way the category spells it, the device itself does not have to be
+// plugged in.
+pub fn add_to_blacklist<D: CfhdbDevice>(id: &str) -> Result<bool, io::Error> {
+    let id = D::normalize_id(id)?;
+    let mut entries = read_blacklist(D::CATEGORY)?;
+    if entries.contains(&id) {
+        return Ok(false);
+    }
+    entries.push(id);
+    write_blacklist(D::CATEGORY, &entries)?;
+    Ok(true)
+}
+
+pub fn remove_from_blacklist<D: CfhdbDevice>(id: &str) -> Result<bool, io::Error> {
+    let id = D::normalize_id(id)?;
+    let mut entries = read_blacklist(D::CATEGORY)?;
+    let count = entries.len();
+    entries.retain(|x| *x != id);
+    if entries.len() == count {
+        return Ok(false);
+    }
+    write_blacklist(D::CATEGORY, &entries)?;
+    Ok(true)
+}
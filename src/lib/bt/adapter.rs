@@ -0,0 +1,161 @@
+use super::CfhdbBtDevice;
+use crate::{error::CfhdbError, runtime};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbBtAdapter {
+    // hci0
+    pub name: String,
+    pub address: String,
+    pub alias: String,
+    pub powered: bool,
+    pub discoverable: bool,
+    pub pairable: bool,
+    pub discovering: bool,
+    // Bluer, None for snapshots loaded through serde
+    #[serde(skip)]
+    bluer_adapter: Option<bluer::Adapter>,
+}
+
+impl CfhdbBtAdapter {
+    async fn from_bluer(adapter: bluer::Adapter) -> Result<Self, bluer::Error> {
+        Ok(Self {
+            name: adapter.name().to_owned(),
+            address: CfhdbBtDevice::format_bt_address(adapter.address().await?.0),
+            alias: adapter.alias().await.unwrap_or("Unknown!".to_owned()),
+            powered: adapter.is_powered().await.unwrap_or_default(),
+            discoverable: adapter.is_discoverable().await.unwrap_or_default(),
+            pairable: adapter.is_pairable().await.unwrap_or_default(),
+            discovering: adapter.is_discovering().await.unwrap_or_default(),
+            bluer_adapter: Some(adapter),
+        })
+    }
+
+    fn bluer_adapter(&self) -> Result<&bluer::Adapter, CfhdbError> {
+        self.bluer_adapter.as_ref().ok_or(CfhdbError::Detached)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    pub(super) async fn get_adapters_in_session(
+        session: &bluer::Session,
+    ) -> Result<Vec<Self>, CfhdbError> {
+        let mut adapters = vec![];
+        for adapter_name in session.adapter_names().await? {
+            adapters.push(Self::from_bluer(session.adapter(&adapter_name)?).await?);
+        }
+        Ok(adapters)
+    }
+
+    async fn get_adapters_future() -> Result<Vec<Self>, CfhdbError> {
+        let session = bluer::Session::new().await?;
+        Self::get_adapters_in_session(&session).await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_adapters_async() -> Result<Vec<Self>, CfhdbError> {
+        Self::get_adapters_future().await
+    }
+
+    pub fn get_adapters() -> Result<Vec<Self>, CfhdbError> {
+        runtime::block_on(Self::get_adapters_future())?
+    }
+
+    // The named adapter, or the one bluez picks as default without a name
+    #[instrument(level = "debug", skip(session))]
+    pub(super) async fn get_adapter_in_session(
+        session: &bluer::Session,
+        name: Option<&str>,
+    ) -> Result<Self, CfhdbError> {
+        let adapter_names = session.adapter_names().await?;
+        if adapter_names.is_empty() {
+            return Err(CfhdbError::NoAdapter);
+        }
+        let adapter = match name {
+            Some(name) if adapter_names.iter().any(|x| x == name) => session.adapter(name)?,
+            Some(name) => return Err(CfhdbError::DeviceNotFound("bt", name.to_owned())),
+            None => session.default_adapter().await?,
+        };
+        Ok(Self::from_bluer(adapter).await?)
+    }
+
+    async fn get_adapter_future(name: Option<&str>) -> Result<Self, CfhdbError> {
+        let session = bluer::Session::new().await?;
+        Self::get_adapter_in_session(&session, name).await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_adapter_async(name: Option<&str>) -> Result<Self, CfhdbError> {
+        Self::get_adapter_future(name).await
+    }
+
+    pub fn get_adapter(name: Option<&str>) -> Result<Self, CfhdbError> {
+        runtime::block_on(Self::get_adapter_future(name))?
+    }
+
+    // Powering an adapter off disconnects everything on it
+    #[instrument(level = "debug", skip(self), fields(adapter = %self.name), err)]
+    async fn set_powered_future(&self, powered: bool) -> Result<(), CfhdbError> {
+        self.bluer_adapter()?.set_powered(powered).await?;
+        CfhdbBtDevice::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn set_powered_async(&self, powered: bool) -> Result<(), CfhdbError> {
+        self.set_powered_future(powered).await
+    }
+
+    pub fn set_powered(&self, powered: bool) -> Result<(), CfhdbError> {
+        runtime::block_on(self.set_powered_future(powered))?
+    }
+
+    // bluez turns discoverable off again once its discoverable timeout runs
+    // out, 3 minutes unless main.conf says otherwise
+    #[instrument(level = "debug", skip(self), fields(adapter = %self.name), err)]
+    async fn set_discoverable_future(&self, discoverable: bool) -> Result<(), CfhdbError> {
+        self.bluer_adapter()?.set_discoverable(discoverable).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn set_discoverable_async(&self, discoverable: bool) -> Result<(), CfhdbError> {
+        self.set_discoverable_future(discoverable).await
+    }
+
+    pub fn set_discoverable(&self, discoverable: bool) -> Result<(), CfhdbError> {
+        runtime::block_on(self.set_discoverable_future(discoverable))?
+    }
+
+    #[instrument(level = "debug", skip(self), fields(adapter = %self.name), err)]
+    async fn set_pairable_future(&self, pairable: bool) -> Result<(), CfhdbError> {
+        self.bluer_adapter()?.set_pairable(pairable).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn set_pairable_async(&self, pairable: bool) -> Result<(), CfhdbError> {
+        self.set_pairable_future(pairable).await
+    }
+
+    pub fn set_pairable(&self, pairable: bool) -> Result<(), CfhdbError> {
+        runtime::block_on(self.set_pairable_future(pairable))?
+    }
+
+    // The name other devices see, an empty alias goes back to the system
+    // name bluez derives it from
+    #[instrument(level = "debug", skip(self), fields(adapter = %self.name), err)]
+    async fn set_alias_future(&self, alias: &str) -> Result<(), CfhdbError> {
+        self.bluer_adapter()?.set_alias(alias.to_owned()).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn set_alias_async(&self, alias: &str) -> Result<(), CfhdbError> {
+        self.set_alias_future(alias).await
+    }
+
+    pub fn set_alias(&self, alias: &str) -> Result<(), CfhdbError> {
+        runtime::block_on(self.set_alias_future(alias))?
+    }
+}
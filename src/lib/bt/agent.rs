@@ -0,0 +1,172 @@
+use super::CfhdbBtDevice;
+use bluer::agent::{
+    Agent, AgentHandle, AuthorizeService, DisplayPasskey, DisplayPinCode, ReqError, ReqResult,
+    RequestAuthorization, RequestConfirmation, RequestPasskey, RequestPinCode,
+};
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex};
+
+// What bluez asks while pairing, answered by the frontend. Devices are
+// passed by address. The callbacks may block waiting on the user, they run
+// on the blocking pool. Whatever a frontend leaves out is rejected.
+pub trait PairingAgent: Send + Sync {
+    // Legacy pairing, older keyboards and headsets
+    fn request_pin_code(&self, _device: &str) -> Option<String> {
+        None
+    }
+
+    fn display_pin_code(&self, _device: &str, _pin_code: &str) {}
+
+    fn request_passkey(&self, _device: &str) -> Option<u32> {
+        None
+    }
+
+    // Shown while the passkey is typed on the device, entered counts the
+    // digits typed so far
+    fn display_passkey(&self, _device: &str, _passkey: u32, _entered: u16) {}
+
+    // Numeric comparison, both sides show the passkey
+    fn request_confirmation(&self, _device: &str, _passkey: u32) -> bool {
+        false
+    }
+
+    // Just works pairing the device started itself
+    fn request_authorization(&self, _device: &str) -> bool {
+        false
+    }
+
+    fn authorize_service(&self, _device: &str, _service: &str) -> bool {
+        false
+    }
+}
+
+lazy_static! {
+    static ref PAIRING_AGENT: Mutex<Option<Arc<dyn PairingAgent>>> = Mutex::new(None);
+}
+
+// Without an agent pairing only works for devices that need no input, the
+// way it did before
+pub fn set_pairing_agent(agent: Arc<dyn PairingAgent>) {
+    *PAIRING_AGENT.lock().unwrap() = Some(agent);
+}
+
+pub fn clear_pairing_agent() {
+    *PAIRING_AGENT.lock().unwrap() = None;
+}
+
+async fn ask<T: Send + 'static>(
+    agent: Arc<dyn PairingAgent>,
+    callback: impl FnOnce(&dyn PairingAgent) -> T + Send + 'static,
+) -> ReqResult<T> {
+    tokio::task::spawn_blocking(move || callback(agent.as_ref()))
+        .await
+        .map_err(|_| ReqError::Canceled)
+}
+
+fn accepted(accept: bool) -> ReqResult<()> {
+    if accept {
+        Ok(())
+    } else {
+        Err(ReqError::Rejected)
+    }
+}
+
+fn device_address(device: bluer::Address) -> String {
+    CfhdbBtDevice::format_bt_address(device.0)
+}
+
+async fn request_pin_code(agent: Arc<dyn PairingAgent>, req: RequestPinCode) -> ReqResult<String> {
+    let device = device_address(req.device);
+    ask(agent, move |x| x.request_pin_code(&device))
+        .await?
+        .ok_or(ReqError::Rejected)
+}
+
+async fn display_pin_code(agent: Arc<dyn PairingAgent>, req: DisplayPinCode) -> ReqResult<()> {
+    let device = device_address(req.device);
+    ask(agent, move |x| x.display_pin_code(&device, &req.pincode)).await
+}
+
+async fn request_passkey(agent: Arc<dyn PairingAgent>, req: RequestPasskey) -> ReqResult<u32> {
+    let device = device_address(req.device);
+    ask(agent, move |x| x.request_passkey(&device))
+        .await?
+        .ok_or(ReqError::Rejected)
+}
+
+async fn display_passkey(agent: Arc<dyn PairingAgent>, req: DisplayPasskey) -> ReqResult<()> {
+    let device = device_address(req.device);
+    ask(agent, move |x| {
+        x.display_passkey(&device, req.passkey, req.entered)
+    })
+    .await
+}
+
+async fn request_confirmation(
+    agent: Arc<dyn PairingAgent>,
+    req: RequestConfirmation,
+) -> ReqResult<()> {
+    let device = device_address(req.device);
+    accepted(ask(agent, move |x| x.request_confirmation(&device, req.passkey)).await?)
+}
+
+async fn request_authorization(
+    agent: Arc<dyn PairingAgent>,
+    req: RequestAuthorization,
+) -> ReqResult<()> {
+    let device = device_address(req.device);
+    accepted(ask(agent, move |x| x.request_authorization(&device)).await?)
+}
+
+async fn authorize_service(agent: Arc<dyn PairingAgent>, req: AuthorizeService) -> ReqResult<()> {
+    let device = device_address(req.device);
+    let service = req.service.to_string();
+    accepted(ask(agent, move |x| x.authorize_service(&device, &service)).await?)
+}
+
+// Registers the agent set with set_pairing_agent on the session, until the
+// handle is dropped. bluez only asks the agent of the connection that called
+// Pair, the device has to be paired through this session.
+pub(crate) async fn register(
+    session: &bluer::Session,
+) -> Result<Option<AgentHandle>, bluer::Error> {
+    let agent = match PAIRING_AGENT.lock().unwrap().clone() {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let (a, b, c, d, e, f, g) = (
+        agent.clone(),
+        agent.clone(),
+        agent.clone(),
+        agent.clone(),
+        agent.clone(),
+        agent.clone(),
+        agent,
+    );
+    let bluer_agent = Agent {
+        request_default: false,
+        request_pin_code: Some(Box::new(move |req| {
+            Box::pin(request_pin_code(a.clone(), req))
+        })),
+        display_pin_code: Some(Box::new(move |req| {
+            Box::pin(display_pin_code(b.clone(), req))
+        })),
+        request_passkey: Some(Box::new(move |req| {
+            Box::pin(request_passkey(c.clone(), req))
+        })),
+        display_passkey: Some(Box::new(move |req| {
+            Box::pin(display_passkey(d.clone(), req))
+        })),
+        request_confirmation: Some(Box::new(move |req| {
+            Box::pin(request_confirmation(e.clone(), req))
+        })),
+        request_authorization: Some(Box::new(move |req| {
+            Box::pin(request_authorization(f.clone(), req))
+        })),
+        authorize_service: Some(Box::new(move |req| {
+            Box::pin(authorize_service(g.clone(), req))
+        })),
+        ..Default::default()
+    };
+    Ok(Some(session.register_agent(bluer_agent).await?))
+}
@@ -0,0 +1,158 @@
+// Decodes the class of device bluez reports, see the assigned numbers for
+// the baseband. Bits 2 to 7 are the minor class, bits 8 to 12 the major one
+// and the rest are service class bits, which are ignored here.
+
+fn major(class: u32) -> u32 {
+    (class >> 8) & 0x1f
+}
+
+fn minor(class: u32) -> u32 {
+    (class >> 2) & 0x3f
+}
+
+// The category devices are grouped by, also used for the bt_class_name_
+// locale keys
+pub fn major_class(class: u32) -> &'static str {
+    match major(class) {
+        0 => "misc",
+        1 => "computer",
+        2 => "phone",
+        3 => "network",
+        4 => "audio_video",
+        5 => "peripheral",
+        6 => "imaging",
+        7 => "wearable",
+        8 => "toy",
+        9 => "health",
+        _ => "unknown",
+    }
+}
+
+// What kind of device it is within the major class, as the suffix of a
+// bt_minor_class_ locale key
+pub fn minor_class(class: u32) -> Option<&'static str> {
+    let minor = minor(class);
+    let name = match major(class) {
+        1 => match minor {
+            1 => "desktop_workstation",
+            2 => "server",
+            3 => "laptop",
+            4 => "handheld_pc_pda",
+            5 => "palm_sized_pc_pda",
+            6 => "wearable_computer",
+            7 => "tablet",
+            _ => return None,
+        },
+        2 => match minor {
+            1 => "cellular",
+            2 => "cordless",
+            3 => "smartphone",
+            4 => "wired_modem",
+            5 => "isdn_access",
+            _ => return None,
+        },
+        4 => match minor {
+            1 => "headset",
+            2 => "hands_free",
+            4 => "microphone",
+            5 => "loudspeaker",
+            6 => "headphones",
+            7 => "portable_audio",
+            8 => "car_audio",
+            9 => "set_top_box",
+            10 => "hifi_audio",
+            11 => "vcr",
+            12 => "video_camera",
+            13 => "camcorder",
+            14 => "video_monitor",
+            15 => "video_display_and_loudspeaker",
+            16 => "video_conferencing",
+            18 => "gaming_toy",
+            _ => return None,
+        },
+        // The upper two bits tell keyboards from pointing devices, the lower
+        // four the kind of the rest
+        5 => match (minor >> 4, minor & 0x0f) {
+            (3, _) => "keyboard_and_pointing_device",
+            (1, _) => "keyboard",
+            (2, _) => "pointing_device",
+            (_, 1) => "joystick",
+            (_, 2) => "gamepad",
+            (_, 3) => "remote_control",
+            (_, 4) => "sensing_device",
+            (_, 5) => "digitizer_tablet",
+            (_, 6) => "card_reader",
+            (_, 7) => "digital_pen",
+            (_, 8) => "handheld_scanner",
+            (_, 9) => "handheld_gestural_input",
+            _ => return None,
+        },
+        // A bit mask, a printer with a scanner is both
+        6 => match minor {
+            t if t & 0x20 != 0 => "printer",
+            t if t & 0x10 != 0 => "scanner",
+            t if t & 0x08 != 0 => "camera",
+            t if t & 0x04 != 0 => "display",
+            _ => return None,
+        },
+        7 => match minor {
+            1 => "wristwatch",
+            2 => "pager",
+            3 => "jacket",
+            4 => "helmet",
+            5 => "glasses",
+            _ => return None,
+        },
+        8 => match minor {
+            1 => "robot",
+            2 => "vehicle",
+            3 => "doll",
+            4 => "controller",
+            5 => "game",
+            _ => return None,
+        },
+        9 => match minor {
+            1 => "blood_pressure_monitor",
+            2 => "thermometer",
+            3 => "weighing_scale",
+            4 => "glucose_meter",
+            5 => "pulse_oximeter",
+            6 => "heart_rate_monitor",
+            7 => "health_data_display",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minor_classes_decode() {
+        let cases = [
+            // Laptop
+            (0x00010c, Some("laptop")),
+            // Headset, with the audio and rendering service bits
+            (0x240404, Some("headset")),
+            (0x200418, Some("headphones")),
+            (0x002540, Some("keyboard")),
+            (0x002580, Some("pointing_device")),
+            (0x0025c0, Some("keyboard_and_pointing_device")),
+            (0x002508, Some("gamepad")),
+            // Printer and scanner at once
+            (0x0006c0, Some("printer")),
+            (0x000620, Some("camera")),
+            (0x000704, Some("wristwatch")),
+            // Uncategorized minor classes and majors without minor ones
+            (0x000100, None),
+            (0x000300, None),
+            (0x001f00, None),
+        ];
+        for (class, expected) in cases {
+            assert_eq!(minor_class(class), expected, "{:#08x}", class);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use super::CfhdbBtDevice;
+use crate::{error::CfhdbError, runtime};
+use bluer::{AdapterEvent, AdapterProperty, Address, DeviceEvent, DeviceProperty, SessionEvent};
+use futures::{
+    stream::{BoxStream, SelectAll},
+    StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::Sender,
+    thread::{self, JoinHandle},
+};
+use tracing::{debug, warn};
+
+// What bluez reports about adapters and the devices on them. Properties
+// without an event of their own come as changes, the value as text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum CfhdbBtEvent {
+    AdapterAdded {
+        adapter: String,
+    },
+    AdapterRemoved {
+        adapter: String,
+    },
+    AdapterChanged {
+        adapter: String,
+        property: String,
+        value: String,
+    },
+    DeviceAdded {
+        adapter: String,
+        address: String,
+    },
+    DeviceRemoved {
+        adapter: String,
+        address: String,
+    },
+    Connected {
+        adapter: String,
+        address: String,
+    },
+    Disconnected {
+        adapter: String,
+        address: String,
+    },
+    BatteryChanged {
+        adapter: String,
+        address: String,
+        battery_level: u8,
+    },
+    DeviceChanged {
+        adapter: String,
+        address: String,
+        property: String,
+        value: String,
+    },
+}
+
+enum Source {
+    Session(SessionEvent),
+    Adapter(String, AdapterEvent),
+    Device(String, Address, DeviceEvent),
+}
+
+fn adapter_change(adapter: &str, property: AdapterProperty) -> Option<CfhdbBtEvent> {
+    let (property, value) = match property {
+        AdapterProperty::Powered(t) => ("powered", t.to_string()),
+        AdapterProperty::Discoverable(t) => ("discoverable", t.to_string()),
+        AdapterProperty::Pairable(t) => ("pairable", t.to_string()),
+        AdapterProperty::Discovering(t) => ("discovering", t.to_string()),
+        AdapterProperty::Alias(t) => ("alias", t),
+        _ => return None,
+    };
+    Some(CfhdbBtEvent::AdapterChanged {
+        adapter: adapter.to_owned(),
+        property: property.to_owned(),
+        value,
+    })
+}
+
+// Rssi changes with every advertisement and is left out
+fn device_change(
+    adapter: &str,
+    address: Address,
+    property: DeviceProperty,
+) -> Option<CfhdbBtEvent> {
+    let adapter = adapter.to_owned();
+    let address = CfhdbBtDevice::format_bt_address(address.0);
+    let (property, value) = match property {
+        DeviceProperty::Connected(true) => {
+            return Some(CfhdbBtEvent::Connected { adapter, address })
+        }
+        DeviceProperty::Connected(false) => {
+            return Some(CfhdbBtEvent::Disconnected { adapter, address })
+        }
+        DeviceProperty::BatteryPercentage(battery_level) => {
+            return Some(CfhdbBtEvent::BatteryChanged {
+                adapter,
+                address,
+                battery_level,
+            })
+        }
+        DeviceProperty::Paired(t) => ("paired", t.to_string()),
+        DeviceProperty::Trusted(t) => ("trusted", t.to_string()),
+        DeviceProperty::Blocked(t) => ("blocked", t.to_string()),
+        DeviceProperty::Alias(t) => ("alias", t),
+        DeviceProperty::Name(t) => ("name", t),
+        _ => return None,
+    };
+    Some(CfhdbBtEvent::DeviceChanged {
+        adapter,
+        address,
+        property: property.to_owned(),
+        value,
+    })
+}
+
+async fn watch_adapter(
+    session: &bluer::Session,
+    adapter_name: &str,
+    streams: &mut SelectAll<BoxStream<'static, Source>>,
+) -> Result<(), bluer::Error> {
+    let adapter = session.adapter(adapter_name)?;
+    let name = adapter_name.to_owned();
+    streams.push(
+        adapter
+            .events()
+            .await?
+            .map(move |x| Source::Adapter(name.clone(), x))
+            .boxed(),
+    );
+    for address in adapter.device_addresses().await? {
+        watch_device(&adapter, address, streams).await?;
+    }
+    Ok(())
+}
+
+async fn watch_device(
+    adapter: &bluer::Adapter,
+    address: Address,
+    streams: &mut SelectAll<BoxStream<'static, Source>>,
+) -> Result<(), bluer::Error> {
+    let name = adapter.name().to_owned();
+    streams.push(
+        adapter
+            .device(address)?
+            .events()
+            .await?
+            .map(move |x| Source::Device(name.clone(), address, x))
+            .boxed(),
+    );
+    Ok(())
+}
+
+async fn follow_events(
+    session: bluer::Session,
+    mut streams: SelectAll<BoxStream<'static, Source>>,
+    sender: Sender<CfhdbBtEvent>,
+) -> Result<(), bluer::Error> {
+    while let Some(source) = streams.next().await {
+        let event = match source {
+            Source::Session(SessionEvent::AdapterAdded(adapter)) => {
+                watch_adapter(&session, &adapter, &mut streams).await?;
+                Some(CfhdbBtEvent::AdapterAdded { adapter })
+            }
+            Source::Session(SessionEvent::AdapterRemoved(adapter)) => {
+                Some(CfhdbBtEvent::AdapterRemoved { adapter })
+            }
+            Source::Adapter(adapter, AdapterEvent::DeviceAdded(address)) => {
+                watch_device(&session.adapter(&adapter)?, address, &mut streams).await?;
+                Some(CfhdbBtEvent::DeviceAdded {
+                    adapter,
+                    address: CfhdbBtDevice::format_bt_address(address.0),
+                })
+            }
+            Source::Adapter(adapter, AdapterEvent::DeviceRemoved(address)) => {
+                Some(CfhdbBtEvent::DeviceRemoved {
+                    adapter,
+                    address: CfhdbBtDevice::format_bt_address(address.0),
+                })
+            }
+            Source::Adapter(adapter, AdapterEvent::PropertyChanged(property)) => {
+                adapter_change(&adapter, property)
+            }
+            Source::Device(adapter, address, DeviceEvent::PropertyChanged(property)) => {
+                device_change(&adapter, address, property)
+            }
+        };
+        if let Some(event) = event {
+            debug!(?event, "bluetooth event");
+            CfhdbBtDevice::invalidate_cache();
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Follows bluez on a thread of its own until the receiver goes away. Unlike
+// hotplug::watch_bluetooth this takes the D-Bus signals, so connections and
+// battery levels are reported as they change. Nothing is reported for what
+// is already there when it starts.
+pub fn watch_events(sender: Sender<CfhdbBtEvent>) -> Result<JoinHandle<()>, io::Error> {
+    let session = runtime::block_on(bluer::Session::new())?.map_err(io::Error::other)?;
+    watch_events_in_session(session, sender)
+}
+
+// The same on a session the caller keeps, see CfhdbBtSession::watch_events
+pub(super) fn watch_events_in_session(
+    session: bluer::Session,
+    sender: Sender<CfhdbBtEvent>,
+) -> Result<JoinHandle<()>, io::Error> {
+    let bluetooth_error = |e: bluer::Error| io::Error::other(e);
+    let streams = runtime::block_on(async {
+        let mut streams = SelectAll::new();
+        streams.push(session.events().await?.map(Source::Session).boxed());
+        for adapter_name in session.adapter_names().await? {
+            watch_adapter(&session, &adapter_name, &mut streams).await?;
+        }
+        Ok::<_, bluer::Error>(streams)
+    })?
+    .map_err(bluetooth_error)?;
+    Ok(thread::spawn(move || {
+        if let Err(e) = runtime::block_on(follow_events(session, streams, sender))
+            .and_then(|x| x.map_err(CfhdbError::from))
+        {
+            warn!(error = %e, "stopped following bluetooth events");
+        }
+    }))
+}
+
+// Percentages `bt monitor --battery` reports a device dropping to
+pub const DEFAULT_BATTERY_THRESHOLDS: [u8; 3] = [20, 10, 5];
+
+// Which way a battery level went through a threshold
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BatteryCrossing {
+    Below,
+    Above,
+}
+
+// Remembers the last battery level of each device to tell when one passes a
+// threshold. A level jumping over several thresholds at once reports the
+// furthest one, so a device going from 25% to 8% is reported once, below 10.
+pub struct BatteryWatch {
+    thresholds: Vec<u8>,
+    levels: HashMap<String, u8>,
+}
+
+impl BatteryWatch {
+    pub fn new(thresholds: &[u8]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        Self {
+            thresholds,
+            levels: HashMap::new(),
+        }
+    }
+
+    // Levels known before watching, these are never reported themselves
+    pub fn seed(&mut self, address: &str, battery_level: u8) {
+        self.levels.insert(address.to_owned(), battery_level);
+    }
+
+    pub fn forget(&mut self, address: &str) {
+        self.levels.remove(address);
+    }
+
+    // A device seen for the first time counts as coming from full, one that
+    // connects already low is reported right away
+    pub fn update(&mut self, address: &str, battery_level: u8) -> Option<(BatteryCrossing, u8)> {
+        let previous = self
+            .levels
+            .insert(address.to_owned(), battery_level)
+            .unwrap_or(100);
+        if battery_level < previous {
+            self.thresholds
+                .iter()
+                .find(|&&t| battery_level <= t && t < previous)
+                .map(|&t| (BatteryCrossing::Below, t))
+        } else {
+            self.thresholds
+                .iter()
+                .rev()
+                .find(|&&t| previous <= t && t < battery_level)
+                .map(|&t| (BatteryCrossing::Above, t))
+        }
+    }
+}
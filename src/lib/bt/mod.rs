@@ -1,12 +1,33 @@
-use serde::{Serialize, Serializer};
+pub mod adapter;
+pub mod agent;
+pub mod classes;
+pub mod events;
+pub mod services;
+
+use crate::{
+    bt::{adapter::CfhdbBtAdapter, events::CfhdbBtEvent},
+    cache::{DeviceCache, DEFAULT_DEVICE_CACHE_TTL},
+    cancel::CancellationToken,
+    device::{group_by_class, CfhdbDevice},
+    error::CfhdbError,
+    ids::BtAddress,
+    matching::{match_profiles, MatchField},
+    modules::ModuleOption,
+    profile::CfhdbProfile,
+    runtime,
+};
+use bluer::AdapterEvent;
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::HashMap,
-    fs,
-    io::{self, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
-    sync::{Arc, Mutex},
+    io,
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
 };
-use tokio::runtime::Runtime;
+use tracing::{debug, instrument, warn};
 
 // Implement Serialize for Arc<Mutex<Option<Vec<Arc<CfhdbBtProfile>>>>>
 
@@ -32,13 +53,39 @@ impl Serialize for ProfileWrapper {
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+// Loaded snapshots start without profiles, see usb::ProfileWrapper
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let _codenames: Option<Vec<String>> = Option::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
+lazy_static! {
+    static ref BT_DEVICE_CACHE: DeviceCache<CfhdbBtDevice> =
+        DeviceCache::new(DEFAULT_DEVICE_CACHE_TTL);
+}
+
+fn default_major_class() -> String {
+    "unknown".to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbBtDevice {
     // String identification
     pub alias: String,
     pub name: String,
     // Vendor IDs
     pub class_id: String,
+    // Decoded from class_id, the major class is what devices are grouped by
+    #[serde(default = "default_major_class")]
+    pub major_class: String,
+    // The suffix of a bt_minor_class_ locale key, like the major class
+    #[serde(default)]
+    pub minor_class: Option<String>,
     // modalias
     pub modalias_vendor_id: String,
     pub modalias_product_id: String,
@@ -51,176 +98,271 @@ pub struct CfhdbBtDevice {
     pub blocked: bool,
     pub address: String,
     pub battery_level: u8,
+    // Signal strength in dBm as of the last inquiry or advertisement, None
+    // for devices out of range
+    #[serde(default)]
+    pub rssi: Option<i16>,
+    // Transmit power the device advertises in dBm, the difference to rssi is
+    // the path loss
+    #[serde(default)]
+    pub tx_power: Option<i16>,
+    // Service UUIDs the device advertises or bluez resolved over SDP and
+    // GATT, sorted
+    #[serde(default)]
+    pub uuids: Vec<String>,
     // Cfhdb Extras
     pub available_profiles: ProfileWrapper,
-    // Bluer
-    #[serde(skip_serializing)]
-    bluer_device: bluer::Device
+    // Bluer, None for snapshots loaded through serde
+    #[serde(skip)]
+    bluer_device: Option<bluer::Device>,
+    // The session the device was found in, removing and pairing go through
+    // it instead of connecting to bluez again
+    #[serde(skip)]
+    bluer_session: Option<bluer::Session>,
 }
 
 impl CfhdbBtDevice {
-    pub fn set_available_profiles(profile_data: &[CfhdbBtProfile], device: &Self) {
-        let mut available_profiles: Vec<Arc<CfhdbBtProfile>> = vec![];
-        for profile in profile_data.iter() {
-            let matching = {
-                if (profile.blacklisted_class_ids.contains(&"*".to_owned())
-                    || profile.blacklisted_class_ids.contains(&device.class_id))
-                    || (profile.blacklisted_bt_names.contains(&"*".to_owned())
-                        || profile.blacklisted_bt_names.contains(&device.name))
-                    || (profile
-                        .blacklisted_modalias_device_ids
-                        .contains(&"*".to_owned())
-                        || profile
-                            .blacklisted_modalias_device_ids
-                            .contains(&device.modalias_device_id))
-                    || (profile
-                        .blacklisted_modalias_product_ids
-                        .contains(&"*".to_owned())
-                        || profile
-                            .blacklisted_modalias_product_ids
-                            .contains(&device.modalias_product_id))
-                    || (profile
-                        .blacklisted_modalias_vendor_ids
-                        .contains(&"*".to_owned())
-                        || profile
-                            .blacklisted_modalias_vendor_ids
-                            .contains(&device.modalias_vendor_id))
-                {
-                    false
-                } else {
-                    let mut result = true;
-                    for (profile_field, info_field) in [
-                        (&profile.bt_names, &device.name),
-                        (&profile.modalias_device_ids, &device.modalias_device_id),
-                        (&profile.modalias_product_ids, &device.modalias_product_id),
-                        (&profile.modalias_vendor_ids, &device.modalias_vendor_id),
-                    ] {
-                        if profile_field.contains(&"*".to_owned())
-                            || profile_field.contains(info_field)
-                        {
-                            continue;
-                        } else {
-                            result = false;
-                            break;
-                        }
-                    }
-                    result
-                }
-            };
+    #[instrument(
+        level = "trace",
+        skip_all,
+        fields(device = %device.address, profiles = profile_data.len())
+    )]
+    pub fn get_available_profiles(
+        profile_data: &[Arc<CfhdbBtProfile>],
+        device: &Self,
+    ) -> Vec<Arc<CfhdbBtProfile>> {
+        match_profiles(profile_data, device)
+    }
 
-            if matching {
-                available_profiles.push(Arc::new(profile.clone()));
-            };
+    pub fn attach_available_profiles(&self, profiles: Vec<Arc<CfhdbBtProfile>>) {
+        *self.available_profiles.0.lock().unwrap() = if profiles.is_empty() {
+            None
+        } else {
+            Some(profiles)
+        };
+    }
 
-            if !available_profiles.is_empty() {
-                *device.available_profiles.0.lock().unwrap() = Some(available_profiles.clone());
-            };
-        }
+    pub fn set_available_profiles(profile_data: &[Arc<CfhdbBtProfile>], device: &Self) {
+        device.attach_available_profiles(Self::get_available_profiles(profile_data, device));
     }
 
-    pub fn disconnect_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.disconnect().await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
-        }
+    // Deserialized snapshots carry no bluez handle and can't be operated on
+    pub fn is_detached(&self) -> bool {
+        self.bluer_device.is_none()
     }
 
-    pub fn connect_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.connect().await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
-        }
+    fn bluer_device(&self) -> Result<&bluer::Device, CfhdbError> {
+        self.bluer_device.as_ref().ok_or(CfhdbError::Detached)
     }
 
-    pub fn block_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.set_blocked(true).await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
-        }
+    fn bluer_session(&self) -> Result<&bluer::Session, CfhdbError> {
+        self.bluer_session.as_ref().ok_or(CfhdbError::Detached)
     }
 
-    pub fn unblock_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.set_blocked(false).await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
-        }
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn disconnect_device_future(&self) -> Result<(), CfhdbError> {
+        self.bluer_device()?.disconnect().await?;
+        Self::invalidate_cache();
+        Ok(())
     }
 
-    pub fn trust_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.set_trusted(true).await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
-        }
+    #[cfg(feature = "async")]
+    pub async fn disconnect_device_async(&self) -> Result<(), CfhdbError> {
+        self.disconnect_device_future().await
     }
 
-    pub fn untrust_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.set_trusted(false).await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
-        }
+    pub fn disconnect_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.disconnect_device_future())?
     }
 
-    pub fn pair_device(&self) -> Result<(), io::Error> {
-        let bluer_future = async {
-            let bluer_device = &self.bluer_device;
-            bluer_device.pair().await
-        };
-        let rt = Runtime::new()?;
-        match rt.block_on(bluer_future) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(ErrorKind::Other, "failed")),
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn connect_device_future(&self) -> Result<(), CfhdbError> {
+        self.bluer_device()?.connect().await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn connect_device_async(&self) -> Result<(), CfhdbError> {
+        self.connect_device_future().await
+    }
+
+    pub fn connect_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.connect_device_future())?
+    }
+
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn block_device_future(&self) -> Result<(), CfhdbError> {
+        self.bluer_device()?.set_blocked(true).await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn block_device_async(&self) -> Result<(), CfhdbError> {
+        self.block_device_future().await
+    }
+
+    pub fn block_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.block_device_future())?
+    }
+
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn unblock_device_future(&self) -> Result<(), CfhdbError> {
+        self.bluer_device()?.set_blocked(false).await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn unblock_device_async(&self) -> Result<(), CfhdbError> {
+        self.unblock_device_future().await
+    }
+
+    pub fn unblock_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.unblock_device_future())?
+    }
+
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn trust_device_future(&self) -> Result<(), CfhdbError> {
+        self.bluer_device()?.set_trusted(true).await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn trust_device_async(&self) -> Result<(), CfhdbError> {
+        self.trust_device_future().await
+    }
+
+    pub fn trust_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.trust_device_future())?
+    }
+
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn untrust_device_future(&self) -> Result<(), CfhdbError> {
+        self.bluer_device()?.set_trusted(false).await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn untrust_device_async(&self) -> Result<(), CfhdbError> {
+        self.untrust_device_future().await
+    }
+
+    pub fn untrust_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.untrust_device_future())?
+    }
+
+    // Forgets the device, bluez drops its pairing keys and trust along with
+    // it and it has to be paired again to be used
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn remove_device_future(&self) -> Result<(), CfhdbError> {
+        let device = self.bluer_device()?;
+        self.bluer_session()?
+            .adapter(device.adapter_name())?
+            .remove_device(device.address())
+            .await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn remove_device_async(&self) -> Result<(), CfhdbError> {
+        self.remove_device_future().await
+    }
+
+    pub fn remove_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.remove_device_future())?
+    }
+
+    #[instrument(level = "debug", skip(self), fields(address = %self.address), err)]
+    async fn pair_device_future(&self) -> Result<(), CfhdbError> {
+        let device = self.bluer_device()?;
+        let session = self.bluer_session()?;
+        let _agent = agent::register(session).await?;
+        session
+            .adapter(device.adapter_name())?
+            .device(device.address())?
+            .pair()
+            .await?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn pair_device_async(&self) -> Result<(), CfhdbError> {
+        self.pair_device_future().await
+    }
+
+    pub fn pair_device(&self) -> Result<(), CfhdbError> {
+        runtime::block_on(self.pair_device_future())?
+    }
+
+    async fn pair_device_cancellable_future(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<(), CfhdbError> {
+        // Dropping the pairing future has bluez cancel the pairing
+        tokio::select! {
+            result = self.pair_device_future() => result,
+            _ = cancel.cancelled() => Err(CfhdbError::Cancelled),
         }
     }
 
-    pub fn get_device_from_address(address: &str) -> Result<CfhdbBtDevice, io::Error> {
-        let devices = match CfhdbBtDevice::get_devices() {
-            Some(t) => t,
-            None => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Could not get bt devices",
-                ));
-            }
-        };
-        match devices.iter().find(|x| x.address == address) {
-            Some(device) => Ok(device.clone()),
-            None => Err(io::Error::new(
-                ErrorKind::NotFound,
-                "no bt device with matching busid",
+    #[cfg(feature = "async")]
+    pub async fn pair_device_cancellable_async(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<(), CfhdbError> {
+        self.pair_device_cancellable_future(cancel).await
+    }
+
+    pub fn pair_device_cancellable(&self, cancel: &CancellationToken) -> Result<(), CfhdbError> {
+        runtime::block_on(self.pair_device_cancellable_future(cancel))?
+    }
+
+    #[instrument(level = "debug")]
+    async fn get_device_from_address_future(address: &str) -> Result<CfhdbBtDevice, CfhdbError> {
+        let session = bluer::Session::new().await?;
+        Self::get_device_from_address_in_session(&session, address).await
+    }
+
+    async fn get_device_from_address_in_session(
+        session: &bluer::Session,
+        address: &str,
+    ) -> Result<CfhdbBtDevice, CfhdbError> {
+        let address: BtAddress = address.parse()?;
+        Self::require_adapter(session).await?;
+        let devices = CfhdbBtDevice::get_devices_in_session(session).await?;
+        match devices.into_iter().find(|x| x.address == address.as_str()) {
+            Some(device) => Ok(device),
+            None => Err(CfhdbError::DeviceNotFound(
+                "bt",
+                address.as_str().to_owned(),
             )),
         }
     }
 
+    // Enumerating without an adapter finds no devices, the lookups tell that
+    // apart from a device that is not there
+    async fn require_adapter(session: &bluer::Session) -> Result<(), CfhdbError> {
+        if session.adapter_names().await?.is_empty() {
+            return Err(CfhdbError::NoAdapter);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_device_from_address_async(address: &str) -> Result<CfhdbBtDevice, CfhdbError> {
+        Self::get_device_from_address_future(address).await
+    }
+
+    pub fn get_device_from_address(address: &str) -> Result<CfhdbBtDevice, CfhdbError> {
+        runtime::block_on(Self::get_device_from_address_future(address))?
+    }
+
     fn format_bt_address(bytes: [u8; 6]) -> String {
         bytes
             .iter()
@@ -229,10 +371,78 @@ impl CfhdbBtDevice {
             .join(":")
     }
 
-    //
+    async fn from_bluer(
+        session: &bluer::Session,
+        adapter_name: &str,
+        device: bluer::Device,
+    ) -> Result<Self, bluer::Error> {
+        let device_modalias = device.modalias().await?;
+        let class = device.class().await.unwrap_or_default();
+
+        Ok(Self {
+            alias: device.alias().await.unwrap_or("Unknown!".to_owned()),
+            name: device
+                .name()
+                .await
+                .unwrap_or(None)
+                .unwrap_or("Unknown!".to_owned()),
+            class_id: match class {
+                Some(x) => x.to_string(),
+                None => "Unknown!".to_owned(),
+            },
+            major_class: class.map_or("unknown", classes::major_class).to_owned(),
+            minor_class: class.and_then(classes::minor_class).map(|x| x.to_owned()),
+            modalias_device_id: match &device_modalias {
+                Some(t) => t.device.to_string(),
+                None => "Unknown!".to_owned(),
+            },
+            modalias_vendor_id: match &device_modalias {
+                Some(t) => t.vendor.to_string(),
+                None => "Unknown!".to_owned(),
+            },
+            modalias_product_id: match &device_modalias {
+                Some(t) => t.product.to_string(),
+                None => "Unknown!".to_owned(),
+            },
+            adapter: adapter_name.to_owned(),
+            paired: device.is_paired().await.unwrap_or_default(),
+            connected: device.is_connected().await.unwrap_or_default(),
+            trusted: device.is_trusted().await.unwrap_or_default(),
+            blocked: device.is_blocked().await.unwrap_or_default(),
+            battery_level: device
+                .battery_percentage()
+                .await
+                .unwrap_or_default()
+                .unwrap_or_default(),
+            rssi: device.rssi().await.unwrap_or_default(),
+            tx_power: device.tx_power().await.unwrap_or_default(),
+            uuids: {
+                let mut uuids: Vec<String> = device
+                    .uuids()
+                    .await
+                    .unwrap_or_default()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| x.to_string())
+                    .collect();
+                uuids.sort();
+                uuids
+            },
+            address: Self::format_bt_address(device.address().0),
+            bluer_device: Some(device),
+            bluer_session: Some(session.clone()),
+            available_profiles: ProfileWrapper(Arc::default()),
+        })
+    }
+
     async fn get_devices_future() -> Result<Vec<Self>, bluer::Error> {
         // Initialize
         let session = bluer::Session::new().await?;
+        Self::get_devices_in_session(&session).await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_devices_in_session(session: &bluer::Session) -> Result<Vec<Self>, bluer::Error> {
         let adapter_names = session.adapter_names().await?;
         let mut devices = vec![];
 
@@ -241,78 +451,341 @@ impl CfhdbBtDevice {
             let bt_devices = adapter.device_addresses().await?;
 
             for addr in bt_devices {
-                let device = adapter.device(addr)?;
-
-                let device_modalias = device.modalias().await?;
-
-                devices.push(Self {
-                    alias: device.alias().await.unwrap_or("Unknown!".to_owned()),
-                    name: device
-                        .name()
-                        .await
-                        .unwrap_or(None)
-                        .unwrap_or("Unknown!".to_owned()),
-                    class_id: match device.class().await {
-                        Ok(t) => match t {
-                            Some(x) => x.to_string(),
-                            None => "Unknown!".to_owned(),
-                        },
-                        Err(_) => "Unknown!".to_owned(),
-                    },
-                    modalias_device_id: match &device_modalias {
-                        Some(t) => t.device.to_string(),
-                        None => "Unknown!".to_owned(),
-                    },
-                    modalias_vendor_id: match &device_modalias {
-                        Some(t) => t.vendor.to_string(),
-                        None => "Unknown!".to_owned(),
-                    },
-                    modalias_product_id: match &device_modalias {
-                        Some(t) => t.product.to_string(),
-                        None => "Unknown!".to_owned(),
-                    },
-                    adapter: adapter_name.clone(),
-                    paired: device.is_paired().await.unwrap_or_default(),
-                    connected: device.is_connected().await.unwrap_or_default(),
-                    trusted: device.is_trusted().await.unwrap_or_default(),
-                    blocked: device.is_blocked().await.unwrap_or_default(),
-                    battery_level: device.battery_percentage().await.unwrap_or_default().unwrap_or_default(),
-                    address: Self::format_bt_address(addr.0),
-                    bluer_device: device,
-                    available_profiles: ProfileWrapper(Arc::default()),
-                });
+                devices
+                    .push(Self::from_bluer(session, &adapter_name, adapter.device(addr)?).await?);
             }
         }
 
+        debug!(count = devices.len(), "enumerated bt devices");
         Ok(devices)
     }
 
+    #[cfg(feature = "async")]
+    pub async fn get_devices_async() -> Option<Vec<Self>> {
+        Self::get_devices_future().await.ok()
+    }
+
     pub fn get_devices() -> Option<Vec<Self>> {
-        let rt = Runtime::new().unwrap();
-        match rt.block_on(Self::get_devices_future()) {
+        match runtime::block_on(Self::get_devices_future())
+            .and_then(|x| x.map_err(CfhdbError::from))
+        {
             Ok(t) => return Some(t),
-            Err(_) => return None,
+            Err(e) => {
+                warn!(error = %e, "could not get bt devices");
+                return None;
+            }
         };
     }
 
-    pub fn create_class_hashmap(devices: Vec<Self>) -> HashMap<String, Vec<Self>> {
-        let mut map: HashMap<String, Vec<Self>> = HashMap::new();
+    async fn get_devices_checked_in_session(
+        session: &bluer::Session,
+    ) -> Result<Vec<Self>, CfhdbError> {
+        Self::require_adapter(session).await?;
+        Ok(Self::get_devices_in_session(session).await?)
+    }
 
-        for device in devices {
-            // Use the entry API to get or create a Vec for the key
-            map.entry(device.class_id.clone())
-                .or_insert_with(Vec::new)
-                .push(device);
+    // Discovery on the default adapter. Each device is handed to found once,
+    // the first time bluez reports it, which includes known devices still in
+    // range. Runs for timeout and returns everything found, with an error if
+    // cancelled before that.
+    #[instrument(level = "debug", skip(session, cancel, found), err)]
+    async fn scan_devices_in_session(
+        session: &bluer::Session,
+        timeout: Duration,
+        cancel: &CancellationToken,
+        found: &mut dyn FnMut(&Self),
+    ) -> Result<Vec<Self>, CfhdbError> {
+        Self::require_adapter(session).await?;
+        let adapter = session.default_adapter().await?;
+        let adapter_name = adapter.name().to_owned();
+        // Discovery stops once the stream is dropped
+        let mut events = adapter.discover_devices().await?;
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut devices: Vec<Self> = vec![];
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(AdapterEvent::DeviceAdded(addr)) => {
+                        let address = Self::format_bt_address(addr.0);
+                        if devices.iter().any(|x| x.address == address) {
+                            continue;
+                        }
+                        let device =
+                            Self::from_bluer(session, &adapter_name, adapter.device(addr)?).await?;
+                        found(&device);
+                        devices.push(device);
+                    }
+                    Some(_) => {}
+                    None => break,
+                },
+                _ = &mut deadline => break,
+                _ = cancel.cancelled() => return Err(CfhdbError::Cancelled),
+            }
+        }
+        debug!(count = devices.len(), "scanned bt devices");
+        Self::invalidate_cache();
+        Ok(devices)
+    }
+
+    async fn scan_devices_future(
+        timeout: Duration,
+        cancel: &CancellationToken,
+        found: &mut dyn FnMut(&Self),
+    ) -> Result<Vec<Self>, CfhdbError> {
+        let session = bluer::Session::new().await?;
+        Self::scan_devices_in_session(&session, timeout, cancel, found).await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn scan_devices_async(
+        timeout: Duration,
+        cancel: &CancellationToken,
+        mut found: impl FnMut(&Self),
+    ) -> Result<Vec<Self>, CfhdbError> {
+        Self::scan_devices_future(timeout, cancel, &mut found).await
+    }
+
+    pub fn scan_devices(
+        timeout: Duration,
+        cancel: &CancellationToken,
+        mut found: impl FnMut(&Self),
+    ) -> Result<Vec<Self>, CfhdbError> {
+        runtime::block_on(Self::scan_devices_future(timeout, cancel, &mut found))?
+    }
+
+    async fn get_devices_cancellable_in_session(
+        session: &bluer::Session,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Self>, CfhdbError> {
+        tokio::select! {
+            result = Self::get_devices_checked_in_session(session) => result,
+            _ = cancel.cancelled() => Err(CfhdbError::Cancelled),
         }
+    }
 
-        map
+    async fn get_devices_cancellable_future(
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Self>, CfhdbError> {
+        let session = tokio::select! {
+            session = bluer::Session::new() => session?,
+            _ = cancel.cancelled() => return Err(CfhdbError::Cancelled),
+        };
+        Self::get_devices_cancellable_in_session(&session, cancel).await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_devices_cancellable_async(
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Self>, CfhdbError> {
+        Self::get_devices_cancellable_future(cancel).await
+    }
+
+    pub fn get_devices_cancellable(cancel: &CancellationToken) -> Result<Vec<Self>, CfhdbError> {
+        runtime::block_on(Self::get_devices_cancellable_future(cancel))?
+    }
+
+    pub fn get_devices_cached() -> Option<Vec<Self>> {
+        BT_DEVICE_CACHE.get_or_refresh(Self::get_devices)
+    }
+
+    pub fn set_cache_ttl(ttl: Duration) {
+        BT_DEVICE_CACHE.set_ttl(ttl)
+    }
+
+    pub fn invalidate_cache() {
+        BT_DEVICE_CACHE.invalidate()
+    }
+
+    pub fn create_class_hashmap(devices: Vec<Self>) -> HashMap<String, Vec<Self>> {
+        group_by_class(devices)
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl CfhdbDevice for CfhdbBtDevice {
+    type Profile = CfhdbBtProfile;
+    const CATEGORY: &'static str = "bt";
+
+    fn get_match_fields<'a>(&'a self, profile: &'a CfhdbBtProfile) -> Vec<MatchField<'a>> {
+        vec![
+            // Profiles can't ask for a class, only rule one out
+            MatchField::blacklist_only(&profile.blacklisted_class_ids, &self.class_id),
+            MatchField::new(&profile.bt_names, &profile.blacklisted_bt_names, &self.name),
+            MatchField::new(
+                &profile.modalias_device_ids,
+                &profile.blacklisted_modalias_device_ids,
+                &self.modalias_device_id,
+            ),
+            MatchField::new(
+                &profile.modalias_product_ids,
+                &profile.blacklisted_modalias_product_ids,
+                &self.modalias_product_id,
+            ),
+            MatchField::new(
+                &profile.modalias_vendor_ids,
+                &profile.blacklisted_modalias_vendor_ids,
+                &self.modalias_vendor_id,
+            ),
+        ]
+    }
+
+    fn get_id(&self) -> String {
+        self.address.clone()
+    }
+
+    fn get_class(&self) -> String {
+        self.major_class.clone()
+    }
+
+    fn get_display_fields(&self) -> (String, String) {
+        (self.alias.clone(), self.name.clone())
+    }
+
+    fn get_matched_profiles(&self) -> Option<Vec<Arc<CfhdbBtProfile>>> {
+        self.available_profiles.0.lock().unwrap().clone()
+    }
+
+    fn attach_profiles(&self, profiles: Vec<Arc<CfhdbBtProfile>>) {
+        self.attach_available_profiles(profiles)
+    }
+
+    fn iter_all() -> Option<Box<dyn Iterator<Item = Self>>> {
+        Some(Box::new(Self::get_devices()?.into_iter()))
+    }
+
+    fn normalize_id(id: &str) -> Result<String, io::Error> {
+        Ok(id.parse::<BtAddress>()?.as_str().to_owned())
+    }
+
+    fn enable_device(&self) -> Result<(), io::Error> {
+        Ok(self.unblock_device()?)
+    }
+
+    fn disable_device(&self) -> Result<(), io::Error> {
+        Ok(self.block_device()?)
+    }
+}
+
+// A persistent bluer session that callers can keep around (and share with
+// their own tokio runtime) instead of libcfhdb opening a new D-Bus connection
+// for every call. Devices and adapters found through it keep using it for
+// their own operations.
+#[derive(Clone)]
+pub struct CfhdbBtSession(pub bluer::Session);
+
+impl CfhdbBtSession {
+    pub fn new() -> Result<Self, CfhdbError> {
+        Ok(Self(runtime::block_on(bluer::Session::new())??))
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn new_async() -> Result<Self, CfhdbError> {
+        Ok(Self(bluer::Session::new().await?))
+    }
+
+    pub fn from_session(session: bluer::Session) -> Self {
+        Self(session)
+    }
+
+    pub fn session(&self) -> &bluer::Session {
+        &self.0
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_devices_async(&self) -> Option<Vec<CfhdbBtDevice>> {
+        CfhdbBtDevice::get_devices_in_session(&self.0).await.ok()
+    }
+
+    pub fn get_devices(&self) -> Option<Vec<CfhdbBtDevice>> {
+        runtime::block_on(CfhdbBtDevice::get_devices_in_session(&self.0))
+            .and_then(|x| x.map_err(CfhdbError::from))
+            .ok()
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_device_from_address_async(
+        &self,
+        address: &str,
+    ) -> Result<CfhdbBtDevice, CfhdbError> {
+        CfhdbBtDevice::get_device_from_address_in_session(&self.0, address).await
+    }
+
+    pub fn get_device_from_address(&self, address: &str) -> Result<CfhdbBtDevice, CfhdbError> {
+        runtime::block_on(CfhdbBtDevice::get_device_from_address_in_session(
+            &self.0, address,
+        ))?
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_devices_cancellable_async(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<CfhdbBtDevice>, CfhdbError> {
+        CfhdbBtDevice::get_devices_cancellable_in_session(&self.0, cancel).await
+    }
+
+    pub fn get_devices_cancellable(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<CfhdbBtDevice>, CfhdbError> {
+        runtime::block_on(CfhdbBtDevice::get_devices_cancellable_in_session(
+            &self.0, cancel,
+        ))?
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn scan_devices_async(
+        &self,
+        timeout: Duration,
+        cancel: &CancellationToken,
+        mut found: impl FnMut(&CfhdbBtDevice),
+    ) -> Result<Vec<CfhdbBtDevice>, CfhdbError> {
+        CfhdbBtDevice::scan_devices_in_session(&self.0, timeout, cancel, &mut found).await
+    }
+
+    pub fn scan_devices(
+        &self,
+        timeout: Duration,
+        cancel: &CancellationToken,
+        mut found: impl FnMut(&CfhdbBtDevice),
+    ) -> Result<Vec<CfhdbBtDevice>, CfhdbError> {
+        runtime::block_on(CfhdbBtDevice::scan_devices_in_session(
+            &self.0, timeout, cancel, &mut found,
+        ))?
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_adapters_async(&self) -> Result<Vec<CfhdbBtAdapter>, CfhdbError> {
+        CfhdbBtAdapter::get_adapters_in_session(&self.0).await
+    }
+
+    pub fn get_adapters(&self) -> Result<Vec<CfhdbBtAdapter>, CfhdbError> {
+        runtime::block_on(CfhdbBtAdapter::get_adapters_in_session(&self.0))?
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_adapter_async(
+        &self,
+        name: Option<&str>,
+    ) -> Result<CfhdbBtAdapter, CfhdbError> {
+        CfhdbBtAdapter::get_adapter_in_session(&self.0, name).await
+    }
+
+    pub fn get_adapter(&self, name: Option<&str>) -> Result<CfhdbBtAdapter, CfhdbError> {
+        runtime::block_on(CfhdbBtAdapter::get_adapter_in_session(&self.0, name))?
+    }
+
+    pub fn watch_events(&self, sender: Sender<CfhdbBtEvent>) -> Result<JoinHandle<()>, io::Error> {
+        events::watch_events_in_session(self.0.clone(), sender)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CfhdbBtProfile {
     pub codename: String,
     pub i18n_desc: String,
+    #[serde(default = "crate::profile::default_icon_name")]
     pub icon_name: String,
     pub license: String,
     pub class_ids: Vec<String>,
@@ -325,53 +798,129 @@ pub struct CfhdbBtProfile {
     pub blacklisted_modalias_vendor_ids: Vec<String>,
     pub blacklisted_modalias_device_ids: Vec<String>,
     pub blacklisted_modalias_product_ids: Vec<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_packages")]
     pub packages: Option<Vec<String>>,
+    #[serde(default = "crate::profile::default_check_script")]
     pub check_script: String,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub install_script: Option<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub remove_script: Option<String>,
     pub experimental: bool,
     pub removable: bool,
     pub veiled: bool,
+    pub unsandboxed_check: bool,
+    pub check_is_pure: bool,
+    pub flatpaks: Option<Vec<String>>,
+    pub snaps: Option<Vec<String>>,
+    pub signature: Option<String>,
+    pub superseded_by: Option<String>,
+    pub regenerate_initramfs: bool,
+    #[serde(with = "crate::modules::options_map")]
+    pub module_options: Vec<ModuleOption>,
+    pub blacklisted_modules: Vec<String>,
+    pub kernel_parameters: Vec<String>,
     pub priority: i32,
 }
 
 impl CfhdbBtProfile {
     pub fn get_profile_from_codename(
         codename: &str,
-        profiles: Vec<CfhdbBtProfile>,
-    ) -> Result<Self, io::Error> {
+        profiles: &[Arc<CfhdbBtProfile>],
+    ) -> Result<Self, CfhdbError> {
         match profiles.iter().find(|x| x.codename == codename) {
-            Some(profile) => Ok(profile.clone()),
-            None => Err(io::Error::new(
-                ErrorKind::NotFound,
-                "no bt profile with matching codename",
-            )),
+            Some(profile) => Ok(profile.as_ref().clone()),
+            None => Err(CfhdbError::ProfileNotFound("bt", codename.to_owned())),
         }
     }
+}
 
-    pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+impl CfhdbProfile for CfhdbBtProfile {
+    fn get_codename(&self) -> &str {
+        &self.codename
+    }
+
+    fn get_i18n_desc(&self) -> &str {
+        &self.i18n_desc
+    }
+
+    fn get_icon_name(&self) -> &str {
+        &self.icon_name
+    }
+
+    fn get_license(&self) -> &str {
+        &self.license
+    }
+
+    fn get_packages(&self) -> Option<&[String]> {
+        self.packages.as_deref()
+    }
+
+    fn get_check_script(&self) -> &str {
+        &self.check_script
+    }
+
+    fn get_install_script(&self) -> Option<&str> {
+        self.install_script.as_deref()
+    }
+
+    fn get_remove_script(&self) -> Option<&str> {
+        self.remove_script.as_deref()
+    }
+
+    fn is_experimental(&self) -> bool {
+        self.experimental
+    }
+
+    fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    fn is_veiled(&self) -> bool {
+        self.veiled
+    }
+
+    fn get_priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn is_check_unsandboxed(&self) -> bool {
+        self.unsandboxed_check
+    }
+
+    fn get_signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn is_check_pure(&self) -> bool {
+        self.check_is_pure
+    }
+
+    fn get_flatpaks(&self) -> Option<&[String]> {
+        self.flatpaks.as_deref()
+    }
+
+    fn get_snaps(&self) -> Option<&[String]> {
+        self.snaps.as_deref()
+    }
+
+    fn get_superseded_by(&self) -> Option<&str> {
+        self.superseded_by.as_deref()
+    }
+
+    fn regenerates_initramfs(&self) -> bool {
+        self.regenerate_initramfs
+    }
+
+    fn get_module_options(&self) -> &[ModuleOption] {
+        &self.module_options
+    }
+
+    fn get_blacklisted_modules(&self) -> &[String] {
+        &self.blacklisted_modules
+    }
+
+    fn get_kernel_parameters(&self) -> &[String] {
+        &self.kernel_parameters
     }
 }
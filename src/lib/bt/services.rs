@@ -0,0 +1,55 @@
+// Names of the service class and GATT service UUIDs devices commonly
+// advertise, from the Bluetooth SIG assigned numbers. All of them are 16 bit
+// ones on top of the base UUID.
+const BASE_UUID_SUFFIX: &str = "-0000-1000-8000-00805f9b34fb";
+
+const SERVICE_NAMES: &[(u16, &str)] = &[
+    (0x1101, "Serial Port"),
+    (0x1103, "Dial-up Networking"),
+    (0x1105, "OBEX Object Push"),
+    (0x1106, "OBEX File Transfer"),
+    (0x1108, "Headset"),
+    (0x110a, "Audio Source"),
+    (0x110b, "Audio Sink"),
+    (0x110c, "A/V Remote Control Target"),
+    (0x110d, "Advanced Audio Distribution"),
+    (0x110e, "A/V Remote Control"),
+    (0x110f, "A/V Remote Control Controller"),
+    (0x1112, "Headset Audio Gateway"),
+    (0x1115, "PAN User"),
+    (0x1116, "Network Access Point"),
+    (0x111e, "Handsfree"),
+    (0x111f, "Handsfree Audio Gateway"),
+    (0x1124, "Human Interface Device"),
+    (0x112d, "SIM Access"),
+    (0x112f, "Phonebook Access Server"),
+    (0x1132, "Message Access Server"),
+    (0x1200, "PnP Information"),
+    (0x1800, "Generic Access"),
+    (0x1801, "Generic Attribute"),
+    (0x180a, "Device Information"),
+    (0x180d, "Heart Rate"),
+    (0x180f, "Battery Service"),
+    (0x1812, "HID over GATT"),
+    (0x1813, "Scan Parameters"),
+    (0x1816, "Cycling Speed and Cadence"),
+    (0x1818, "Cycling Power"),
+    (0x1826, "Fitness Machine"),
+    (0x184e, "Audio Stream Control"),
+    (0x184f, "Broadcast Audio Scan"),
+    (0x1850, "Published Audio Capabilities"),
+    (0x1853, "Common Audio"),
+    (0xfe2c, "Google Fast Pair"),
+];
+
+// Takes the lowercase 128 bit form bluez reports
+pub fn service_name(uuid: &str) -> Option<&'static str> {
+    let short = uuid
+        .strip_suffix(BASE_UUID_SUFFIX)?
+        .strip_prefix("0000")
+        .and_then(|x| u16::from_str_radix(x, 16).ok())?;
+    SERVICE_NAMES
+        .iter()
+        .find(|(id, _)| *id == short)
+        .map(|(_, name)| *name)
+}
@@ -0,0 +1,148 @@
+use lazy_static::lazy_static;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_DEVICE_CACHE_TTL: Duration = Duration::from_secs(5);
+pub const DEFAULT_STATUS_CACHE_TTL: Duration = Duration::from_secs(30);
+// Pure checks only look at the filesystem inside a read-only sandbox, their
+// result can only change through a package operation, which clears the cache.
+pub const PURE_STATUS_CACHE_TTL: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    // (codename, check_script hash) -> (checked at, installed)
+    static ref STATUS_CACHE: Mutex<HashMap<(String, u64), (Instant, bool)>> =
+        Mutex::new(HashMap::new());
+    static ref STATUS_CACHE_TTL: Mutex<Duration> = Mutex::new(DEFAULT_STATUS_CACHE_TTL);
+}
+
+struct CacheEntry<T> {
+    created: Instant,
+    fingerprint: Option<u64>,
+    devices: Vec<T>,
+}
+
+// In-process cache for device enumeration, so consecutive operations don't
+// re-scan whole buses. Entries expire after the TTL, on a manual invalidate(),
+// or as soon as the fingerprint (e.g. the sysfs device list) changes, which is
+// how hotplug events get picked up.
+// Note: cloned devices share their available_profiles slot with the cached copy.
+pub struct DeviceCache<T> {
+    ttl: Mutex<Duration>,
+    fingerprint: Option<fn() -> u64>,
+    entry: Mutex<Option<CacheEntry<T>>>,
+}
+
+impl<T: Clone> DeviceCache<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl: Mutex::new(ttl),
+            fingerprint: None,
+            entry: Mutex::new(None),
+        }
+    }
+
+    pub fn with_fingerprint(ttl: Duration, fingerprint: fn() -> u64) -> Self {
+        Self {
+            ttl: Mutex::new(ttl),
+            fingerprint: Some(fingerprint),
+            entry: Mutex::new(None),
+        }
+    }
+
+    pub fn set_ttl(&self, ttl: Duration) {
+        *self.ttl.lock().unwrap() = ttl;
+    }
+
+    pub fn invalidate(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+
+    pub fn get_or_refresh<F>(&self, refresh: F) -> Option<Vec<T>>
+    where
+        F: FnOnce() -> Option<Vec<T>>,
+    {
+        let ttl = *self.ttl.lock().unwrap();
+        let fingerprint = self.fingerprint.map(|f| f());
+        let mut entry = self.entry.lock().unwrap();
+        if let Some(cached) = &*entry {
+            if cached.created.elapsed() < ttl && cached.fingerprint == fingerprint {
+                return Some(cached.devices.clone());
+            }
+        }
+        let devices = refresh()?;
+        *entry = Some(CacheEntry {
+            created: Instant::now(),
+            fingerprint,
+            devices: devices.clone(),
+        });
+        Some(devices)
+    }
+}
+
+// Hash of the entries in a sysfs bus directory, changes whenever a device is
+// added or removed.
+pub fn sysfs_fingerprint(path: &str) -> u64 {
+    let mut names: Vec<String> = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => vec![],
+    };
+    names.sort();
+    let mut hasher = DefaultHasher::new();
+    names.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn hash_str(string: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    string.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Check script results are keyed by codename and a hash of the script itself,
+// so a profile database update that changes the script never reuses a stale result.
+pub fn get_cached_status<F>(codename: &str, check_script: &str, check: F) -> bool
+where
+    F: FnOnce() -> bool,
+{
+    let ttl = *STATUS_CACHE_TTL.lock().unwrap();
+    get_cached_status_with_ttl(codename, check_script, ttl, check)
+}
+
+pub fn get_cached_status_with_ttl<F>(
+    codename: &str,
+    check_script: &str,
+    ttl: Duration,
+    check: F,
+) -> bool
+where
+    F: FnOnce() -> bool,
+{
+    let key = (codename.to_owned(), hash_str(check_script));
+    if let Some((checked_at, status)) = STATUS_CACHE.lock().unwrap().get(&key) {
+        if checked_at.elapsed() < ttl {
+            return *status;
+        }
+    }
+    let status = check();
+    STATUS_CACHE
+        .lock()
+        .unwrap()
+        .insert(key, (Instant::now(), status));
+    status
+}
+
+pub fn set_status_cache_ttl(ttl: Duration) {
+    *STATUS_CACHE_TTL.lock().unwrap() = ttl;
+}
+
+pub fn invalidate_status_cache() {
+    STATUS_CACHE.lock().unwrap().clear();
+}
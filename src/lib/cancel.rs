@@ -0,0 +1,57 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+// Cheap to clone handle a frontend keeps to abort a running enumeration,
+// download, script or pairing attempt from another thread or task.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<CancellationState>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> Result<(), io::Error> {
+        if self.is_cancelled() {
+            Err(cancelled_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn cancelled(&self) {
+        loop {
+            // Register before checking the flag so a cancel() in between isn't missed
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+pub fn cancelled_error() -> io::Error {
+    io::Error::new(ErrorKind::Interrupted, "operation cancelled")
+}
@@ -0,0 +1,456 @@
+use crate::{
+    audit::{self, AuditAction, AuditRecord},
+    blacklist,
+    device::CfhdbDevice,
+    error::CfhdbError,
+    hooks, installed,
+    journal::JournalEntry,
+    packages::PackageBackend,
+    privilege,
+    profile::{
+        self, CfhdbProfile, PartialInstallError, ProfileOperationOptions, ProfileOperationOutcome,
+    },
+    script,
+    transaction::{Transaction, TransactionKind},
+};
+#[cfg(feature = "bt")]
+use crate::{
+    backend::{BluerBackend, SystemBluer},
+    bt::{CfhdbBtDevice, CfhdbBtProfile},
+    ids::BtAddress,
+};
+#[cfg(feature = "pci")]
+use crate::{
+    backend::{PciBackend, SystemPci},
+    ids::PciAddress,
+    pci::{CfhdbPciDevice, CfhdbPciProfile},
+};
+#[cfg(feature = "dmi")]
+use crate::{
+    backend::{SysfsProvider, SystemSysfs},
+    dmi::{CfhdbDmiInfo, CfhdbDmiProfile},
+};
+#[cfg(feature = "usb")]
+use crate::{
+    backend::{SystemUsb, UsbBackend},
+    ids::SysfsBusId,
+    udev,
+    usb::{CfhdbUsbDevice, CfhdbUsbProfile},
+};
+#[cfg(any(feature = "bt", feature = "pci", feature = "usb"))]
+use std::{collections::HashMap, io::ErrorKind};
+use std::{fmt, io, sync::Arc};
+
+// The core of every CLI command. Nothing in here prints or exits, the binary
+// (or a GUI) decides how to render the results and which exit code to use.
+
+#[derive(Debug)]
+pub enum CommandError {
+    // The bus could not be enumerated at all
+    EnumerationFailed,
+    DeviceNotFound,
+    NoProfilesAvailable,
+    ProfileNotFound,
+    Io(io::Error),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::EnumerationFailed => write!(f, "could not enumerate devices"),
+            CommandError::DeviceNotFound => write!(f, "no matching device"),
+            CommandError::NoProfilesAvailable => write!(f, "no profiles available"),
+            CommandError::ProfileNotFound => write!(f, "no profile with matching codename"),
+            CommandError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    // The same failure with the category and the device id or profile
+    // codename the command was run for, which is what callers outside a
+    // single command report
+    pub fn into_cfhdb_error(self, category: &'static str, id: &str) -> CfhdbError {
+        match self {
+            CommandError::EnumerationFailed => CfhdbError::EnumerationFailed(category),
+            CommandError::DeviceNotFound => CfhdbError::DeviceNotFound(category, id.to_owned()),
+            CommandError::NoProfilesAvailable => CfhdbError::NoProfilesAvailable(category),
+            CommandError::ProfileNotFound => CfhdbError::ProfileNotFound(category, id.to_owned()),
+            CommandError::Io(e) => CfhdbError::Io(e),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::Io(e)
+    }
+}
+
+// Lookups report a missing device as NotFound and a malformed identifier as
+// InvalidInput, anything else means the enumeration itself failed.
+#[cfg(any(feature = "bt", feature = "pci", feature = "usb"))]
+fn lookup_error(e: io::Error) -> CommandError {
+    match e.kind() {
+        ErrorKind::NotFound => CommandError::DeviceNotFound,
+        ErrorKind::InvalidInput => CommandError::Io(e),
+        _ => CommandError::EnumerationFailed,
+    }
+}
+
+pub fn get_sorted_profiles<D>(device: &D) -> Result<Vec<Arc<D::Profile>>, CommandError>
+where
+    D: CfhdbDevice,
+    D::Profile: CfhdbProfile,
+{
+    match device.get_matched_profiles() {
+        Some(mut profiles) => {
+            profiles.sort_by_key(|k| k.get_priority());
+            Ok(profiles)
+        }
+        None => Err(CommandError::NoProfilesAvailable),
+    }
+}
+
+// Whether an action needs root, directly or through the escalation backend.
+// Listing, JSON export and bluetooth actions, which bluez allows the active
+// session user, all work unprivileged.
+pub fn is_privileged(action: AuditAction) -> bool {
+    match action {
+        AuditAction::Install
+        | AuditAction::Uninstall
+        | AuditAction::Enable
+        | AuditAction::Disable
+        | AuditAction::Start
+        | AuditAction::Stop
+        | AuditAction::Reset
+        | AuditAction::Authorize
+        | AuditAction::Deauthorize
+        | AuditAction::Power => true,
+        AuditAction::Pair
+        | AuditAction::Connect
+        | AuditAction::Disconnect
+        | AuditAction::Block
+        | AuditAction::Unblock
+        | AuditAction::Trust
+        | AuditAction::Untrust
+        | AuditAction::Remove => false,
+    }
+}
+
+fn record_change(record: &AuditRecord) {
+    audit::record(record);
+    hooks::run_hooks(record);
+}
+
+// Lookup failures never reach the device, so only attempted actions are
+// audited. Takes the io::Error of the CfhdbDevice methods as well as the
+// CfhdbError of the category specific ones.
+#[cfg(any(feature = "bt", feature = "pci", feature = "usb"))]
+pub fn run_device_action<D, E, F>(
+    target: Result<D, E>,
+    audit_action: AuditAction,
+    action: impl FnOnce(&D) -> Result<(), F>,
+) -> Result<(), CommandError>
+where
+    D: CfhdbDevice,
+    E: Into<io::Error>,
+    F: Into<io::Error>,
+{
+    let device = target.map_err(|e| lookup_error(e.into()))?;
+    let result: Result<(), io::Error> = action(&device).map_err(Into::into);
+    record_change(&AuditRecord::new(
+        audit_action,
+        Some(&device.get_id()),
+        None,
+        &result,
+    ));
+    Ok(result?)
+}
+
+// Adds a device to the boot time blacklist or takes it off, the device does
+// not have to be plugged in. Root edits the list directly, anyone else goes
+// through the privileged helper like disable_device does. Returns whether
+// the list changed.
+pub fn set_blacklisted<D: CfhdbDevice>(id: &str, blacklisted: bool) -> Result<bool, CommandError> {
+    let id = D::normalize_id(id)?;
+    let result = if privilege::is_root() {
+        if blacklisted {
+            blacklist::add_to_blacklist::<D>(&id)
+        } else {
+            blacklist::remove_from_blacklist::<D>(&id)
+        }
+    } else {
+        let listed = blacklist::is_blacklisted(D::CATEGORY, &id);
+        let operation = if blacklisted {
+            "disable_device"
+        } else {
+            "enable_device"
+        };
+        privilege::run_sysfs_helper(&[operation, D::CATEGORY, &id]).map(|_| listed != blacklisted)
+    };
+    let audit_action = if blacklisted {
+        AuditAction::Disable
+    } else {
+        AuditAction::Enable
+    };
+    record_change(&AuditRecord::new(audit_action, Some(&id), None, &result));
+    Ok(result?)
+}
+
+// Keeps a usb device deauthorized through a udev rule, or drops the rule and
+// authorizes it again. Returns whether the rules changed.
+#[cfg(feature = "usb")]
+pub fn set_persistently_disabled(busid: &SysfsBusId, disabled: bool) -> Result<bool, CommandError> {
+    let (result, audit_action) = if disabled {
+        (udev::persist_disable(busid), AuditAction::Disable)
+    } else {
+        (udev::persist_enable(busid), AuditAction::Enable)
+    };
+    record_change(&AuditRecord::new(
+        audit_action,
+        Some(busid.as_str()),
+        None,
+        &result,
+    ));
+    CfhdbUsbDevice::invalidate_cache();
+    Ok(result?)
+}
+
+// Scripts left behind by profile calls made outside these wrappers must not
+// end up in the next record
+fn start_profile_audit() {
+    script::take_executed_scripts();
+}
+
+fn record_profile_audit<T>(action: AuditAction, codename: &str, result: &Result<T, io::Error>) {
+    let mut record = AuditRecord::new(action, None, Some(codename), result);
+    record.scripts = script::take_executed_scripts();
+    record_change(&record);
+}
+
+pub fn install_profile<P: CfhdbProfile, E>(
+    target: Result<P, E>,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<ProfileOperationOutcome, CommandError> {
+    let profile = target.map_err(|_| CommandError::ProfileNotFound)?;
+    start_profile_audit();
+    let result = profile::install_profile_with_options(&profile, backend, options);
+    record_profile_audit(AuditAction::Install, profile.get_codename(), &result);
+    if result.is_ok() {
+        installed::record_installed(&profile);
+    }
+    Ok(result?)
+}
+
+pub fn uninstall_profile<P: CfhdbProfile, E>(
+    target: Result<P, E>,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, CommandError> {
+    let profile = target.map_err(|_| CommandError::ProfileNotFound)?;
+    start_profile_audit();
+    let result = profile::uninstall_profile(&profile, backend);
+    record_profile_audit(AuditAction::Uninstall, profile.get_codename(), &result);
+    if result.is_ok() {
+        installed::forget_installed(profile.get_codename());
+    }
+    Ok(result?)
+}
+
+pub fn rollback_partial_install(
+    partial: &PartialInstallError,
+    backend: &dyn PackageBackend,
+) -> Result<(), CommandError> {
+    start_profile_audit();
+    let result =
+        profile::rollback_partial_install(partial, backend, &ProfileOperationOptions::default());
+    record_profile_audit(AuditAction::Uninstall, &partial.codename, &result);
+    Ok(result?)
+}
+
+pub fn resume_interrupted_install(
+    entry: &JournalEntry,
+    backend: &dyn PackageBackend,
+) -> Result<(), CommandError> {
+    start_profile_audit();
+    let result = profile::resume_interrupted(entry, backend, &ProfileOperationOptions::default());
+    record_profile_audit(AuditAction::Install, &entry.codename, &result);
+    Ok(result?)
+}
+
+pub fn rollback_interrupted_install(
+    entry: &JournalEntry,
+    backend: &dyn PackageBackend,
+) -> Result<(), CommandError> {
+    start_profile_audit();
+    let result = profile::rollback_interrupted(entry, backend, &ProfileOperationOptions::default());
+    record_profile_audit(AuditAction::Uninstall, &entry.codename, &result);
+    Ok(result?)
+}
+
+// Audited as the operation the rollback amounts to
+pub fn rollback_transaction(transaction: &Transaction) -> Result<(), CommandError> {
+    start_profile_audit();
+    let result = profile::rollback_transaction(transaction, &ProfileOperationOptions::default());
+    let action = match transaction.kind {
+        TransactionKind::Uninstall => AuditAction::Install,
+        _ => AuditAction::Uninstall,
+    };
+    record_profile_audit(action, &transaction.codename, &result);
+    if result.is_ok() && transaction.kind == TransactionKind::Install {
+        installed::forget_installed(&transaction.codename);
+    }
+    Ok(result?)
+}
+
+// USB
+
+#[cfg(feature = "usb")]
+pub fn list_usb_devices(
+    profiles: &[Arc<CfhdbUsbProfile>],
+) -> Result<HashMap<String, Vec<CfhdbUsbDevice>>, CommandError> {
+    list_usb_devices_from(&SystemUsb, profiles)
+}
+
+#[cfg(feature = "usb")]
+pub fn list_usb_devices_from(
+    backend: &dyn UsbBackend,
+    profiles: &[Arc<CfhdbUsbProfile>],
+) -> Result<HashMap<String, Vec<CfhdbUsbDevice>>, CommandError> {
+    let devices = backend
+        .get_devices()
+        .ok_or(CommandError::EnumerationFailed)?;
+    for device in &devices {
+        CfhdbUsbDevice::set_available_profiles(profiles, device);
+    }
+    Ok(CfhdbUsbDevice::create_class_hashmap(devices))
+}
+
+#[cfg(feature = "usb")]
+pub fn get_usb_device_with_profiles(
+    busid: &SysfsBusId,
+    profiles: &[Arc<CfhdbUsbProfile>],
+) -> Result<CfhdbUsbDevice, CommandError> {
+    get_usb_device_with_profiles_from(&SystemUsb, busid, profiles)
+}
+
+#[cfg(feature = "usb")]
+pub fn get_usb_device_with_profiles_from(
+    backend: &dyn UsbBackend,
+    busid: &SysfsBusId,
+    profiles: &[Arc<CfhdbUsbProfile>],
+) -> Result<CfhdbUsbDevice, CommandError> {
+    let device = backend
+        .get_device_from_busid(busid.as_str())
+        .map_err(lookup_error)?;
+    CfhdbUsbDevice::set_available_profiles(profiles, &device);
+    Ok(device)
+}
+
+// PCI
+
+#[cfg(feature = "pci")]
+pub fn list_pci_devices(
+    profiles: &[Arc<CfhdbPciProfile>],
+) -> Result<HashMap<String, Vec<CfhdbPciDevice>>, CommandError> {
+    list_pci_devices_from(&SystemPci, profiles)
+}
+
+#[cfg(feature = "pci")]
+pub fn list_pci_devices_from(
+    backend: &dyn PciBackend,
+    profiles: &[Arc<CfhdbPciProfile>],
+) -> Result<HashMap<String, Vec<CfhdbPciDevice>>, CommandError> {
+    let devices = backend
+        .get_devices()
+        .ok_or(CommandError::EnumerationFailed)?;
+    for device in &devices {
+        CfhdbPciDevice::set_available_profiles(profiles, device);
+    }
+    Ok(CfhdbPciDevice::create_class_hashmap(devices))
+}
+
+#[cfg(feature = "pci")]
+pub fn get_pci_device_with_profiles(
+    address: &PciAddress,
+    profiles: &[Arc<CfhdbPciProfile>],
+) -> Result<CfhdbPciDevice, CommandError> {
+    get_pci_device_with_profiles_from(&SystemPci, address, profiles)
+}
+
+#[cfg(feature = "pci")]
+pub fn get_pci_device_with_profiles_from(
+    backend: &dyn PciBackend,
+    address: &PciAddress,
+    profiles: &[Arc<CfhdbPciProfile>],
+) -> Result<CfhdbPciDevice, CommandError> {
+    let device = backend
+        .get_device_from_busid(address.as_str())
+        .map_err(lookup_error)?;
+    CfhdbPciDevice::set_available_profiles(profiles, &device);
+    Ok(device)
+}
+
+// BT
+
+#[cfg(feature = "bt")]
+pub fn list_bt_devices(
+    profiles: &[Arc<CfhdbBtProfile>],
+) -> Result<HashMap<String, Vec<CfhdbBtDevice>>, CommandError> {
+    list_bt_devices_from(&SystemBluer, profiles)
+}
+
+#[cfg(feature = "bt")]
+pub fn list_bt_devices_from(
+    backend: &dyn BluerBackend,
+    profiles: &[Arc<CfhdbBtProfile>],
+) -> Result<HashMap<String, Vec<CfhdbBtDevice>>, CommandError> {
+    let devices = backend
+        .get_devices()
+        .ok_or(CommandError::EnumerationFailed)?;
+    for device in &devices {
+        CfhdbBtDevice::set_available_profiles(profiles, device);
+    }
+    Ok(CfhdbBtDevice::create_class_hashmap(devices))
+}
+
+#[cfg(feature = "bt")]
+pub fn get_bt_device_with_profiles(
+    address: &BtAddress,
+    profiles: &[Arc<CfhdbBtProfile>],
+) -> Result<CfhdbBtDevice, CommandError> {
+    get_bt_device_with_profiles_from(&SystemBluer, address, profiles)
+}
+
+#[cfg(feature = "bt")]
+pub fn get_bt_device_with_profiles_from(
+    backend: &dyn BluerBackend,
+    address: &BtAddress,
+    profiles: &[Arc<CfhdbBtProfile>],
+) -> Result<CfhdbBtDevice, CommandError> {
+    let device = backend
+        .get_device_from_address(address.as_str())
+        .map_err(lookup_error)?;
+    CfhdbBtDevice::set_available_profiles(profiles, &device);
+    Ok(device)
+}
+
+// DMI
+
+#[cfg(feature = "dmi")]
+pub fn get_dmi_info_with_profiles(profiles: &[Arc<CfhdbDmiProfile>]) -> CfhdbDmiInfo {
+    get_dmi_info_with_profiles_from(&SystemSysfs, profiles)
+}
+
+#[cfg(feature = "dmi")]
+pub fn get_dmi_info_with_profiles_from(
+    sysfs: &dyn SysfsProvider,
+    profiles: &[Arc<CfhdbDmiProfile>],
+) -> CfhdbDmiInfo {
+    let info = CfhdbDmiInfo::get_dmi_from(sysfs);
+    CfhdbDmiInfo::set_available_profiles(profiles, &info);
+    info
+}
@@ -0,0 +1,128 @@
+use crate::{
+    cancel::CancellationToken,
+    progress::{report, ProgressEvent, ProgressSink, ProgressStage},
+};
+use serde::de::DeserializeOwned;
+use std::{
+    fs,
+    io::{self, ErrorKind, Read},
+    path::Path,
+    time::Duration,
+};
+use tracing::{debug, instrument, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseSource {
+    Remote,
+    Cache,
+}
+
+fn download(
+    url: &str,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+) -> Result<String, io::Error> {
+    let client = match reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+    {
+        Ok(t) => t,
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let mut response = match client.get(url).send() {
+        Ok(t) => t,
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let total = response.content_length().unwrap_or_default() as usize;
+    let mut body = vec![];
+    let mut buffer = [0u8; 8192];
+    loop {
+        if let Some(token) = cancel {
+            token.check()?;
+        }
+        let read = response.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&buffer[..read]);
+        if total > 0 {
+            report(
+                progress,
+                ProgressEvent::percent(ProgressStage::Downloading, body.len(), total),
+            );
+        }
+    }
+    match String::from_utf8(body) {
+        Ok(t) => Ok(t),
+        Err(e) => Err(io::Error::new(ErrorKind::InvalidData, e)),
+    }
+}
+
+// Downloads a profile database, refreshing the local copy on success and
+// falling back to it when the download fails.
+#[instrument(skip(progress, cancel))]
+pub fn fetch_profile_database(
+    url: &str,
+    cached_db_path: &Path,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(String, DatabaseSource), io::Error> {
+    report(progress, ProgressEvent::stage(ProgressStage::Downloading));
+    match download(url, progress, cancel) {
+        Ok(cache) => {
+            debug!(bytes = cache.len(), "downloaded profile database");
+            if let Some(parent) = cached_db_path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            let _ = fs::write(cached_db_path, &cache);
+            Ok((cache, DatabaseSource::Remote))
+        }
+        // A cancelled download must not silently fall back to the cache
+        Err(e) if e.kind() == ErrorKind::Interrupted => Err(e),
+        Err(e) => {
+            warn!(error = %e, "profile database download failed, trying the local copy");
+            if cached_db_path.exists() {
+                Ok((fs::read_to_string(cached_db_path)?, DatabaseSource::Cache))
+            } else {
+                Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    "profile database could not be downloaded and no local copy exists",
+                ))
+            }
+        }
+    }
+}
+
+// Reads the profiles of a database as downloaded, {"profiles": [...]}. A
+// translated description under "i18n_desc[<locale>]" takes the place of
+// i18n_desc, and a profile that does not fit the struct is skipped instead
+// of failing the whole database.
+pub fn parse_profile_database<P: DeserializeOwned>(
+    data: &str,
+    locale: &str,
+) -> Result<Vec<P>, io::Error> {
+    let mut database: serde_json::Value = serde_json::from_str(data)?;
+    let entries = match database.get_mut("profiles").and_then(|x| x.as_array_mut()) {
+        Some(t) => std::mem::take(t),
+        None => return Ok(vec![]),
+    };
+    let localized_key = format!("i18n_desc[{}]", locale);
+    let mut profiles = vec![];
+    for mut entry in entries {
+        if let Some(fields) = entry.as_object_mut() {
+            let localized = fields
+                .get(&localized_key)
+                .and_then(|x| x.as_str())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_owned());
+            if let Some(localized) = localized {
+                fields.insert("i18n_desc".to_owned(), localized.into());
+            }
+        }
+        match serde_json::from_value(entry) {
+            Ok(t) => profiles.push(t),
+            Err(e) => warn!(error = %e, "skipping invalid profile"),
+        }
+    }
+    Ok(profiles)
+}
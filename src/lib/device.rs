@@ -0,0 +1,83 @@
+use crate::{error::CfhdbError, matching::MatchField};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+// Common surface shared by every hardware category, so the CLI and frontends
+// can handle bt/usb/pci/dmi devices without a copy of each function per category.
+pub trait CfhdbDevice {
+    type Profile;
+    // "pci", "usb", "bt" or "dmi", as on the command line
+    const CATEGORY: &'static str;
+
+    // Identifier used to target the device (sysfs busid, bt address, ...)
+    fn get_id(&self) -> String;
+    // Key devices are grouped by in listings
+    fn get_class(&self) -> String;
+    // (vendor, name) pair shown in tables
+    fn get_display_fields(&self) -> (String, String);
+    // The fields profiles are matched on, paired with the id lists of the
+    // profile for each of them. matching::match_profiles does the rest.
+    fn get_match_fields<'a>(&'a self, profile: &'a Self::Profile) -> Vec<MatchField<'a>>;
+    fn get_matched_profiles(&self) -> Option<Vec<Arc<Self::Profile>>>;
+    fn attach_profiles(&self, profiles: Vec<Arc<Self::Profile>>);
+    // The devices of the category as they are probed, None if the bus could
+    // not be enumerated at all
+    fn iter_all() -> Option<Box<dyn Iterator<Item = Self>>>
+    where
+        Self: Sized;
+
+    // Turns an id as a user typed it into the form get_id() returns, and
+    // refuses malformed ones before anything is enumerated
+    fn normalize_id(id: &str) -> Result<String, io::Error>
+    where
+        Self: Sized,
+    {
+        Ok(id.to_owned())
+    }
+
+    fn enable_device(&self) -> Result<(), io::Error> {
+        Err(unsupported_operation())
+    }
+
+    fn disable_device(&self) -> Result<(), io::Error> {
+        Err(unsupported_operation())
+    }
+
+    fn start_device(&self) -> Result<(), io::Error> {
+        Err(unsupported_operation())
+    }
+
+    fn stop_device(&self) -> Result<(), io::Error> {
+        Err(unsupported_operation())
+    }
+}
+
+fn unsupported_operation() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "operation not supported for this device type",
+    )
+}
+
+// Devices keyed by get_class(), the way listings show them
+pub fn group_by_class<D: CfhdbDevice>(
+    devices: impl IntoIterator<Item = D>,
+) -> HashMap<String, Vec<D>> {
+    let mut map: HashMap<String, Vec<D>> = HashMap::new();
+    for device in devices {
+        map.entry(device.get_class()).or_default().push(device);
+    }
+    map
+}
+
+// Any device type in one call, probing stops at the first match
+pub fn find_by_id<D: CfhdbDevice>(id: &str) -> Result<D, CfhdbError> {
+    let id = D::normalize_id(id)?;
+    let mut devices = D::iter_all().ok_or(CfhdbError::EnumerationFailed(D::CATEGORY))?;
+    devices
+        .find(|x| x.get_id() == id)
+        .ok_or(CfhdbError::DeviceNotFound(D::CATEGORY, id))
+}
@@ -1,10 +1,17 @@
-use serde::{Serialize, Serializer};
+use crate::{
+    backend::{SysfsProvider, SystemSysfs},
+    device::CfhdbDevice,
+    error::CfhdbError,
+    matching::{match_profiles, MatchField},
+    modules::ModuleOption,
+    profile::CfhdbProfile,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    fs::{self},
-    io::{self, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    path::Path,
     sync::{Arc, Mutex},
 };
+use tracing::instrument;
 
 // Implement Serialize for Arc<Mutex<Option<Vec<Arc<CfhdbDmiProfile>>>>>
 
@@ -30,7 +37,18 @@ impl Serialize for ProfileWrapper {
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+// Loaded snapshots start without profiles, see usb::ProfileWrapper
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let _codenames: Option<Vec<String>> = Option::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbDmiInfo {
     // BIOS
     pub bios_date: String,
@@ -54,9 +72,9 @@ pub struct CfhdbDmiInfo {
 }
 
 impl CfhdbDmiInfo {
-    fn get_dmi_string(string: &str) -> Option<String> {
+    fn get_dmi_string(sysfs: &dyn SysfsProvider, string: &str) -> Option<String> {
         let dmi_string_path = format!("/sys/class/dmi/id/{}", string);
-        match fs::read_to_string(dmi_string_path) {
+        match sysfs.read_to_string(Path::new(&dmi_string_path)) {
             Ok(content) => {
                 if content.trim().is_empty() {
                     return None;
@@ -69,95 +87,153 @@ impl CfhdbDmiInfo {
         return None;
     }
 
-    pub fn set_available_profiles(profile_data: &[CfhdbDmiProfile], info: &Self) {
-        let mut available_profiles: Vec<Arc<CfhdbDmiProfile>> = vec![];
-        for profile in profile_data.iter() {
-            let matching = {
-                if
-                // BIOS
-                profile.blacklisted_bios_vendors.contains(&"*".to_owned())
-                    || profile.blacklisted_bios_vendors.contains(&info.bios_vendor)
-                    // BOARD
-                    || profile.blacklisted_board_asset_tags.contains(&"*".to_owned())
-                    || profile.blacklisted_board_asset_tags.contains(&info.board_asset_tag)
-                    || profile.blacklisted_board_names.contains(&"*".to_owned())
-                    || profile.blacklisted_board_names.contains(&info.board_name)
-                    || profile.blacklisted_board_vendors.contains(&"*".to_owned())
-                    || profile.blacklisted_board_vendors.contains(&info.board_vendor)
-                    // PRODUCT
-                    || profile.blacklisted_product_families.contains(&"*".to_owned())
-                    || profile.blacklisted_product_families.contains(&info.product_family)
-                    || profile.blacklisted_product_names.contains(&"*".to_owned())
-                    || profile.blacklisted_product_names.contains(&info.product_name)
-                    || profile.blacklisted_product_skus.contains(&"*".to_owned())
-                    || profile.blacklisted_product_skus.contains(&info.product_sku)
-                    // Sys
-                    || profile.blacklisted_sys_vendors.contains(&"*".to_owned())
-                    || profile.blacklisted_sys_vendors.contains(&info.sys_vendor)
-                {
-                    false
-                } else {
-                    let mut result = true;
-                    for (profile_field, info_field) in [
-                        (&profile.bios_vendors, &info.bios_vendor),
-                        (&profile.board_asset_tags, &info.board_asset_tag),
-                        (&profile.board_names, &info.board_name),
-                        (&profile.board_vendors, &info.board_vendor),
-                        (&profile.product_families, &info.product_family),
-                        (&profile.product_names, &info.product_name),
-                        (&profile.product_skus, &info.product_sku),
-                        (&profile.sys_vendors, &info.sys_vendor),
-                    ] {
-                        if profile_field.contains(&"*".to_owned())
-                            || profile_field.contains(info_field)
-                        {
-                            continue;
-                        } else {
-                            result = false;
-                            break;
-                        }
-                    }
-                    result
-                }
-            };
+    #[instrument(level = "trace", skip_all, fields(profiles = profile_data.len()))]
+    pub fn get_available_profiles(
+        profile_data: &[Arc<CfhdbDmiProfile>],
+        info: &Self,
+    ) -> Vec<Arc<CfhdbDmiProfile>> {
+        match_profiles(profile_data, info)
+    }
+
+    pub fn attach_available_profiles(&self, profiles: Vec<Arc<CfhdbDmiProfile>>) {
+        *self.available_profiles.0.lock().unwrap() = if profiles.is_empty() {
+            None
+        } else {
+            Some(profiles)
+        };
+    }
 
-            if matching {
-                available_profiles.push(Arc::new(profile.clone()));
-            };
+    pub fn set_available_profiles(profile_data: &[Arc<CfhdbDmiProfile>], info: &Self) {
+        info.attach_available_profiles(Self::get_available_profiles(profile_data, info));
+    }
 
-            if !available_profiles.is_empty() {
-                *info.available_profiles.0.lock().unwrap() = Some(available_profiles.clone());
-            };
-        }
+    #[cfg(feature = "async")]
+    pub async fn get_dmi_async() -> Self {
+        tokio::task::spawn_blocking(Self::get_dmi)
+            .await
+            .expect("DMI reader task panicked")
     }
 
     pub fn get_dmi() -> Self {
+        Self::get_dmi_from(&SystemSysfs)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    pub fn get_dmi_from(sysfs: &dyn SysfsProvider) -> Self {
         let dmi = Self {
-            bios_date: Self::get_dmi_string("bios_date").unwrap_or("Unknown!".to_owned()),
-            bios_release: Self::get_dmi_string("bios_release").unwrap_or("Unknown!".to_owned()),
-            bios_vendor: Self::get_dmi_string("bios_vendor").unwrap_or("Unknown!".to_owned()),
-            bios_version: Self::get_dmi_string("bios_version").unwrap_or("Unknown!".to_owned()),
-            board_asset_tag: Self::get_dmi_string("board_asset_tag")
+            bios_date: Self::get_dmi_string(sysfs, "bios_date").unwrap_or("Unknown!".to_owned()),
+            bios_release: Self::get_dmi_string(sysfs, "bios_release")
+                .unwrap_or("Unknown!".to_owned()),
+            bios_vendor: Self::get_dmi_string(sysfs, "bios_vendor")
+                .unwrap_or("Unknown!".to_owned()),
+            bios_version: Self::get_dmi_string(sysfs, "bios_version")
+                .unwrap_or("Unknown!".to_owned()),
+            board_asset_tag: Self::get_dmi_string(sysfs, "board_asset_tag")
+                .unwrap_or("Unknown!".to_owned()),
+            board_name: Self::get_dmi_string(sysfs, "board_name").unwrap_or("Unknown!".to_owned()),
+            board_vendor: Self::get_dmi_string(sysfs, "board_vendor")
+                .unwrap_or("Unknown!".to_owned()),
+            board_version: Self::get_dmi_string(sysfs, "board_version")
                 .unwrap_or("Unknown!".to_owned()),
-            board_name: Self::get_dmi_string("board_name").unwrap_or("Unknown!".to_owned()),
-            board_vendor: Self::get_dmi_string("board_vendor").unwrap_or("Unknown!".to_owned()),
-            board_version: Self::get_dmi_string("board_version").unwrap_or("Unknown!".to_owned()),
-            product_family: Self::get_dmi_string("product_family").unwrap_or("Unknown!".to_owned()),
-            product_name: Self::get_dmi_string("product_name").unwrap_or("Unknown!".to_owned()),
-            product_sku: Self::get_dmi_string("product_sku").unwrap_or("Unknown!".to_owned()),
-            product_version: Self::get_dmi_string("product_version")
+            product_family: Self::get_dmi_string(sysfs, "product_family")
                 .unwrap_or("Unknown!".to_owned()),
-            sys_vendor: Self::get_dmi_string("sys_vendor").unwrap_or("Unknown!".to_owned()),
+            product_name: Self::get_dmi_string(sysfs, "product_name")
+                .unwrap_or("Unknown!".to_owned()),
+            product_sku: Self::get_dmi_string(sysfs, "product_sku")
+                .unwrap_or("Unknown!".to_owned()),
+            product_version: Self::get_dmi_string(sysfs, "product_version")
+                .unwrap_or("Unknown!".to_owned()),
+            sys_vendor: Self::get_dmi_string(sysfs, "sys_vendor").unwrap_or("Unknown!".to_owned()),
             available_profiles: ProfileWrapper(Arc::default()),
         };
         dmi
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl CfhdbDevice for CfhdbDmiInfo {
+    type Profile = CfhdbDmiProfile;
+    const CATEGORY: &'static str = "dmi";
+
+    fn get_match_fields<'a>(&'a self, profile: &'a CfhdbDmiProfile) -> Vec<MatchField<'a>> {
+        vec![
+            // BIOS
+            MatchField::new(
+                &profile.bios_vendors,
+                &profile.blacklisted_bios_vendors,
+                &self.bios_vendor,
+            ),
+            // BOARD
+            MatchField::new(
+                &profile.board_asset_tags,
+                &profile.blacklisted_board_asset_tags,
+                &self.board_asset_tag,
+            ),
+            MatchField::new(
+                &profile.board_names,
+                &profile.blacklisted_board_names,
+                &self.board_name,
+            ),
+            MatchField::new(
+                &profile.board_vendors,
+                &profile.blacklisted_board_vendors,
+                &self.board_vendor,
+            ),
+            // PRODUCT
+            MatchField::new(
+                &profile.product_families,
+                &profile.blacklisted_product_families,
+                &self.product_family,
+            ),
+            MatchField::new(
+                &profile.product_names,
+                &profile.blacklisted_product_names,
+                &self.product_name,
+            ),
+            MatchField::new(
+                &profile.product_skus,
+                &profile.blacklisted_product_skus,
+                &self.product_sku,
+            ),
+            // Sys
+            MatchField::new(
+                &profile.sys_vendors,
+                &profile.blacklisted_sys_vendors,
+                &self.sys_vendor,
+            ),
+        ]
+    }
+
+    fn get_id(&self) -> String {
+        self.product_name.clone()
+    }
+
+    fn get_class(&self) -> String {
+        "dmi".to_owned()
+    }
+
+    fn get_display_fields(&self) -> (String, String) {
+        (self.sys_vendor.clone(), self.product_name.clone())
+    }
+
+    fn get_matched_profiles(&self) -> Option<Vec<Arc<CfhdbDmiProfile>>> {
+        self.available_profiles.0.lock().unwrap().clone()
+    }
+
+    fn attach_profiles(&self, profiles: Vec<Arc<CfhdbDmiProfile>>) {
+        self.attach_available_profiles(profiles)
+    }
+
+    fn iter_all() -> Option<Box<dyn Iterator<Item = Self>>> {
+        Some(Box::new(std::iter::once(Self::get_dmi())))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CfhdbDmiProfile {
     pub codename: String,
     pub i18n_desc: String,
+    #[serde(default = "crate::profile::default_icon_name")]
     pub icon_name: String,
     pub license: String,
     // BIOS
@@ -186,53 +262,129 @@ pub struct CfhdbDmiProfile {
     // Sys
     pub blacklisted_sys_vendors: Vec<String>,
     //
+    #[serde(deserialize_with = "crate::profile::deserialize_packages")]
     pub packages: Option<Vec<String>>,
+    #[serde(default = "crate::profile::default_check_script")]
     pub check_script: String,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub install_script: Option<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub remove_script: Option<String>,
     pub experimental: bool,
     pub removable: bool,
     pub veiled: bool,
+    pub unsandboxed_check: bool,
+    pub check_is_pure: bool,
+    pub flatpaks: Option<Vec<String>>,
+    pub snaps: Option<Vec<String>>,
+    pub signature: Option<String>,
+    pub superseded_by: Option<String>,
+    pub regenerate_initramfs: bool,
+    #[serde(with = "crate::modules::options_map")]
+    pub module_options: Vec<ModuleOption>,
+    pub blacklisted_modules: Vec<String>,
+    pub kernel_parameters: Vec<String>,
     pub priority: i32,
 }
 
 impl CfhdbDmiProfile {
     pub fn get_profile_from_codename(
         codename: &str,
-        profiles: Vec<CfhdbDmiProfile>,
-    ) -> Result<Self, io::Error> {
+        profiles: &[Arc<CfhdbDmiProfile>],
+    ) -> Result<Self, CfhdbError> {
         match profiles.iter().find(|x| x.codename == codename) {
-            Some(profile) => Ok(profile.clone()),
-            None => Err(io::Error::new(
-                ErrorKind::NotFound,
-                "no dmi profile with matching codename",
-            )),
+            Some(profile) => Ok(profile.as_ref().clone()),
+            None => Err(CfhdbError::ProfileNotFound("dmi", codename.to_owned())),
         }
     }
+}
 
-    pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+impl CfhdbProfile for CfhdbDmiProfile {
+    fn get_codename(&self) -> &str {
+        &self.codename
+    }
+
+    fn get_i18n_desc(&self) -> &str {
+        &self.i18n_desc
+    }
+
+    fn get_icon_name(&self) -> &str {
+        &self.icon_name
+    }
+
+    fn get_license(&self) -> &str {
+        &self.license
+    }
+
+    fn get_packages(&self) -> Option<&[String]> {
+        self.packages.as_deref()
+    }
+
+    fn get_check_script(&self) -> &str {
+        &self.check_script
+    }
+
+    fn get_install_script(&self) -> Option<&str> {
+        self.install_script.as_deref()
+    }
+
+    fn get_remove_script(&self) -> Option<&str> {
+        self.remove_script.as_deref()
+    }
+
+    fn is_experimental(&self) -> bool {
+        self.experimental
+    }
+
+    fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    fn is_veiled(&self) -> bool {
+        self.veiled
+    }
+
+    fn get_priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn is_check_unsandboxed(&self) -> bool {
+        self.unsandboxed_check
+    }
+
+    fn get_signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn is_check_pure(&self) -> bool {
+        self.check_is_pure
+    }
+
+    fn get_flatpaks(&self) -> Option<&[String]> {
+        self.flatpaks.as_deref()
+    }
+
+    fn get_snaps(&self) -> Option<&[String]> {
+        self.snaps.as_deref()
+    }
+
+    fn get_superseded_by(&self) -> Option<&str> {
+        self.superseded_by.as_deref()
+    }
+
+    fn regenerates_initramfs(&self) -> bool {
+        self.regenerate_initramfs
+    }
+
+    fn get_module_options(&self) -> &[ModuleOption] {
+        &self.module_options
+    }
+
+    fn get_blacklisted_modules(&self) -> &[String] {
+        &self.blacklisted_modules
+    }
+
+    fn get_kernel_parameters(&self) -> &[String] {
+        &self.kernel_parameters
     }
 }
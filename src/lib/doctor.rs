@@ -0,0 +1,158 @@
+use crate::{
+    lsm::{self, Lsm},
+    paths::find_in_path,
+    privilege::{self, SYSFS_HELPER},
+    script::LOCK_SCRIPT_HELPER,
+};
+use serde::Serialize;
+use std::{io::ErrorKind, path::Path};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DoctorStatus {
+    Ok,
+    Warning,
+    Error,
+}
+
+// One line of `cfhdb doctor`. The detail is meant for humans and not stable.
+#[derive(Serialize, Debug, Clone)]
+pub struct DoctorCheck {
+    pub name: &'static str,
+    pub status: DoctorStatus,
+    pub detail: String,
+}
+
+impl DoctorCheck {
+    fn new(name: &'static str, status: DoctorStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+fn check_lsm() -> Vec<DoctorCheck> {
+    let active = match lsm::get_active_lsm() {
+        Some(t) => t,
+        None => {
+            return vec![DoctorCheck::new(
+                "lsm",
+                DoctorStatus::Ok,
+                "no SELinux or AppArmor policy is active",
+            )]
+        }
+    };
+    let mut checks = vec![];
+    match active.lsm {
+        Lsm::SELinux if !active.enforcing => checks.push(DoctorCheck::new(
+            "lsm",
+            DoctorStatus::Ok,
+            "SELinux is permissive, denials are only logged",
+        )),
+        Lsm::SELinux => {
+            checks.push(DoctorCheck::new(
+                "lsm",
+                DoctorStatus::Ok,
+                "SELinux is enforcing",
+            ));
+            if find_in_path("chcon").is_none() {
+                checks.push(DoctorCheck::new(
+                    "selinux_labels",
+                    DoctorStatus::Warning,
+                    format!(
+                        "chcon is missing, profile scripts cannot be relabeled to {} and may be denied",
+                        lsm::SELINUX_SCRIPT_TYPE
+                    ),
+                ));
+            }
+            checks.push(match lsm::count_selinux_denials() {
+                Ok(0) => DoctorCheck::new(
+                    "selinux_denials",
+                    DoctorStatus::Ok,
+                    "no denials mentioning cfhdb in the audit log",
+                ),
+                Ok(count) => DoctorCheck::new(
+                    "selinux_denials",
+                    DoctorStatus::Warning,
+                    format!(
+                        "{} denials mentioning cfhdb in the audit log, see ausearch -m avc -c cfhdb",
+                        count
+                    ),
+                ),
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => DoctorCheck::new(
+                    "selinux_denials",
+                    DoctorStatus::Warning,
+                    "the audit log is only readable by root, run doctor as root to look for denials",
+                ),
+                Err(_) => DoctorCheck::new(
+                    "selinux_denials",
+                    DoctorStatus::Warning,
+                    "no audit log found, denials may go unreported",
+                ),
+            });
+        }
+        Lsm::AppArmor => {
+            checks.push(DoctorCheck::new(
+                "lsm",
+                DoctorStatus::Ok,
+                "AppArmor is enabled",
+            ));
+            if lsm::userns_restricted() {
+                checks.push(DoctorCheck::new(
+                    "apparmor_userns",
+                    DoctorStatus::Warning,
+                    "unprivileged user namespaces are restricted and bwrap has no profile, check scripts fall back to systemd-run",
+                ));
+            }
+        }
+    }
+    checks
+}
+
+fn check_escalation() -> DoctorCheck {
+    if privilege::is_root() {
+        return DoctorCheck::new("escalation", DoctorStatus::Ok, "running as root");
+    }
+    match privilege::get_escalation_backend() {
+        Ok(t) => DoctorCheck::new("escalation", DoctorStatus::Ok, format!("using {}", t)),
+        Err(e) => DoctorCheck::new("escalation", DoctorStatus::Error, e.to_string()),
+    }
+}
+
+fn check_sandbox() -> DoctorCheck {
+    if find_in_path("bwrap").is_some() && !lsm::userns_restricted() {
+        DoctorCheck::new("sandbox", DoctorStatus::Ok, "check scripts run under bwrap")
+    } else if find_in_path("systemd-run").is_some() {
+        DoctorCheck::new(
+            "sandbox",
+            DoctorStatus::Ok,
+            "check scripts run under systemd-run",
+        )
+    } else {
+        DoctorCheck::new(
+            "sandbox",
+            DoctorStatus::Warning,
+            "neither bwrap nor systemd-run is usable, check scripts run unsandboxed",
+        )
+    }
+}
+
+fn check_helpers() -> Vec<DoctorCheck> {
+    [LOCK_SCRIPT_HELPER, SYSFS_HELPER]
+        .iter()
+        .filter(|x| !Path::new(x).exists())
+        .map(|x| DoctorCheck::new("helpers", DoctorStatus::Error, format!("{} is missing", x)))
+        .collect()
+}
+
+// Looks for the environment problems that make profile scripts and device
+// actions fail without a useful error, most of all LSM denials.
+pub fn run_checks() -> Vec<DoctorCheck> {
+    let mut checks = check_lsm();
+    checks.push(check_escalation());
+    checks.push(check_sandbox());
+    checks.extend(check_helpers());
+    checks
+}
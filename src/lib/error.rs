@@ -0,0 +1,70 @@
+use std::io::{self, ErrorKind};
+use thiserror::Error;
+
+// What the bt, usb and dmi APIs fail with. It converts into an io::Error
+// with a matching kind for code that only deals with those, and the original
+// CfhdbError stays reachable through get_ref().
+#[derive(Debug, Error)]
+pub enum CfhdbError {
+    // bluez refused the request or D-Bus itself failed, the bluer error kind
+    // tells which
+    #[cfg(feature = "bt")]
+    #[error("bluetooth request failed: {0}")]
+    Bluetooth(#[from] bluer::Error),
+    #[error("no bluetooth adapter found")]
+    NoAdapter,
+    #[cfg(feature = "usb")]
+    #[error("usb request failed: {0}")]
+    Usb(#[from] rusb::Error),
+    // Reading a device attribute, or the privileged helper writing one
+    #[error("sysfs access failed: {0}")]
+    Sysfs(#[source] io::Error),
+    #[error("{0} failed with status {1}")]
+    Script(String, i32),
+    #[error("network request failed: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("could not enumerate {0} devices")]
+    EnumerationFailed(&'static str),
+    #[error("no {0} device with id {1}")]
+    DeviceNotFound(&'static str, String),
+    #[error("no {0} profile with codename {1}")]
+    ProfileNotFound(&'static str, String),
+    #[error("no profiles available for the {0} device")]
+    NoProfilesAvailable(&'static str),
+    // The install or uninstall script of a profile, or the package backend
+    // it went through, failed
+    #[error("profile operation failed: {0}")]
+    ProfileOperation(#[source] io::Error),
+    // A deserialized snapshot, it has no handle to operate the device with
+    #[error("device snapshot has no live handle")]
+    Detached,
+    #[error("operation cancelled")]
+    Cancelled,
+    // The tokio runtime behind the blocking bluetooth calls could not be
+    // created
+    #[error("could not start the async runtime: {0}")]
+    Runtime(#[source] io::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl CfhdbError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CfhdbError::Sysfs(e) | CfhdbError::ProfileOperation(e) | CfhdbError::Io(e) => e.kind(),
+            CfhdbError::DeviceNotFound(..) | CfhdbError::ProfileNotFound(..) => ErrorKind::NotFound,
+            CfhdbError::Detached => ErrorKind::NotConnected,
+            CfhdbError::Cancelled => ErrorKind::Interrupted,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<CfhdbError> for io::Error {
+    fn from(e: CfhdbError) -> Self {
+        match e {
+            CfhdbError::Io(t) => t,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
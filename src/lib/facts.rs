@@ -0,0 +1,99 @@
+use crate::{
+    installed::InstalledProfile,
+    status::{DeviceStatus, RebootStatus},
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceFacts {
+    pub category: &'static str,
+    pub id: String,
+    pub vendor: String,
+    pub name: String,
+    pub profiles: Vec<String>,
+    pub installed: Vec<String>,
+}
+
+// Flat on purpose, playbooks get at everything as
+// ansible_local.cfhdb.<key> without digging through nested objects
+#[derive(Serialize, Debug, Clone)]
+pub struct HardwareFacts {
+    pub cfhdb_version: String,
+    pub reboot_required: bool,
+    pub device_count: usize,
+    pub categories: Vec<&'static str>,
+    // Codenames, sorted and without duplicates
+    pub matched_profiles: Vec<String>,
+    pub installed_profiles: Vec<String>,
+    // Recommended for a device that has no profile installed
+    pub missing_profiles: Vec<String>,
+    // category_id of the devices the database has no profile for
+    pub uncovered_devices: Vec<String>,
+    // Keyed by category_id with everything but letters and digits turned into
+    // underscores, the form Ansible wants for fact names
+    pub devices: BTreeMap<String, DeviceFacts>,
+}
+
+fn fact_key(category: &str, id: &str) -> String {
+    format!("{}_{}", category, id)
+        .chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x } else { '_' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn sorted(mut list: Vec<String>) -> Vec<String> {
+    list.sort();
+    list.dedup();
+    list
+}
+
+pub fn collect_facts(
+    cfhdb_version: &str,
+    devices: &[DeviceStatus],
+    installed: &[InstalledProfile],
+    reboot: &RebootStatus,
+) -> HardwareFacts {
+    let mut categories: Vec<&'static str> = devices.iter().map(|x| x.category).collect();
+    categories.sort();
+    categories.dedup();
+    HardwareFacts {
+        cfhdb_version: cfhdb_version.to_owned(),
+        reboot_required: reboot.required,
+        device_count: devices.len(),
+        categories,
+        matched_profiles: sorted(devices.iter().flat_map(|x| x.profiles.clone()).collect()),
+        installed_profiles: sorted(installed.iter().map(|x| x.codename.clone()).collect()),
+        missing_profiles: sorted(
+            devices
+                .iter()
+                .filter(|x| x.installed.is_empty())
+                .filter_map(|x| x.profiles.first().cloned())
+                .collect(),
+        ),
+        uncovered_devices: sorted(
+            devices
+                .iter()
+                .filter(|x| !x.is_covered())
+                .map(|x| fact_key(x.category, &x.id))
+                .collect(),
+        ),
+        devices: devices
+            .iter()
+            .map(|x| {
+                (
+                    fact_key(x.category, &x.id),
+                    DeviceFacts {
+                        category: x.category,
+                        id: x.id.clone(),
+                        vendor: x.vendor.clone(),
+                        name: x.name.clone(),
+                        profiles: x.profiles.clone(),
+                        installed: x.installed.clone(),
+                    },
+                )
+            })
+            .collect(),
+    }
+}
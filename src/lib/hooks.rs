@@ -0,0 +1,122 @@
+use crate::{audit::AuditRecord, privilege};
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+pub const HOOKS_DIR: &str = "/etc/cfhdb/hooks.d";
+
+// How long a hook may run before it is killed, the operation it follows
+// waits for it until then
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Hooks run with the rights of cfhdb, as root that means only files nobody
+// else could have written
+fn is_trusted(path: &Path) -> bool {
+    let metadata = match fs::metadata(path) {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    if !privilege::is_root() {
+        return true;
+    }
+    metadata.uid() == 0 && metadata.permissions().mode() & 0o022 == 0
+}
+
+// Executable regular files in name order, so admins can number them
+fn hook_paths(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(t) => t,
+        Err(_) => return vec![],
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| {
+            fs::metadata(x).is_ok_and(|x| x.is_file() && x.permissions().mode() & 0o111 != 0)
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+// Called after every audited change. Hooks see the record as CFHDB_*
+// variables, and like auditing a failing or hanging hook never fails the
+// operation.
+pub fn run_hooks(record: &AuditRecord) {
+    let dir = Path::new(HOOKS_DIR);
+    if !dir.is_dir() {
+        return;
+    }
+    if !is_trusted(dir) {
+        warn!(dir = %dir.display(), "ignoring hooks, the directory is writable by other users");
+        return;
+    }
+    for path in hook_paths(dir) {
+        if !is_trusted(&path) {
+            warn!(hook = %path.display(), "ignoring hook writable by other users");
+            continue;
+        }
+        debug!(hook = %path.display(), event = record.action.as_str(), "running hook");
+        let handle = duct::cmd!(&path)
+            .env("CFHDB_EVENT", record.action.as_str())
+            .env("CFHDB_DEVICE", record.device.as_deref().unwrap_or_default())
+            .env(
+                "CFHDB_PROFILE",
+                record.profile.as_deref().unwrap_or_default(),
+            )
+            .env(
+                "CFHDB_RESULT",
+                if record.success { "success" } else { "failure" },
+            )
+            .env("CFHDB_ERROR", record.error.as_deref().unwrap_or_default())
+            .env("CFHDB_USER", &record.user)
+            .env("CFHDB_TIMESTAMP", record.timestamp.to_string())
+            .stdin_null()
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .start();
+        let handle = match handle {
+            Ok(t) => t,
+            Err(e) => {
+                warn!(hook = %path.display(), error = %e, "could not run hook");
+                continue;
+            }
+        };
+        let deadline = Instant::now() + HOOK_TIMEOUT;
+        loop {
+            match handle.try_wait() {
+                Ok(Some(output)) if output.status.success() => break,
+                Ok(Some(output)) => {
+                    warn!(
+                        hook = %path.display(),
+                        status = %output.status,
+                        stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                        "hook failed"
+                    );
+                    break;
+                }
+                Ok(None) if Instant::now() >= deadline => {
+                    let _ = handle.kill();
+                    warn!(
+                        hook = %path.display(),
+                        timeout_secs = HOOK_TIMEOUT.as_secs(),
+                        "hook did not finish in time, killed it"
+                    );
+                    break;
+                }
+                Ok(None) => thread::sleep(HOOK_POLL_INTERVAL),
+                Err(e) => {
+                    warn!(hook = %path.display(), error = %e, "could not run hook");
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::paths::find_in_path;
+#[cfg(feature = "bt")]
+use crate::{error::CfhdbError, runtime};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "bt")]
+use std::{collections::HashSet, time::Duration};
+use std::{
+    io::{self, BufRead, ErrorKind},
+    sync::mpsc::Sender,
+    thread::{self, JoinHandle},
+};
+use tracing::{debug, warn};
+
+// BlueZ has no device events without D-Bus signal handling, the known
+// addresses are compared instead
+#[cfg(feature = "bt")]
+const BLUETOOTH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// What `cfhdbd --monitor` does with the recommended profile of new hardware
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HotplugPolicy {
+    // Only log what would be installed
+    DryRun,
+    // Announce it over D-Bus, a desktop client asks the user
+    #[default]
+    Offer,
+    // Install it right away
+    Apply,
+}
+
+impl HotplugPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HotplugPolicy::DryRun => "dry-run",
+            HotplugPolicy::Offer => "offer",
+            HotplugPolicy::Apply => "apply",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HotplugAction {
+    Add,
+    Remove,
+    Change,
+    // A driver took or released the device
+    Bind,
+    Unbind,
+}
+
+impl HotplugAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HotplugAction::Add => "add",
+            HotplugAction::Remove => "remove",
+            HotplugAction::Change => "change",
+            HotplugAction::Bind => "bind",
+            HotplugAction::Unbind => "unbind",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotplugEvent {
+    pub category: &'static str,
+    pub action: HotplugAction,
+    // The pci or usb busid, or the bluetooth address
+    pub id: String,
+}
+
+// One line of `udevadm monitor --udev`, for example
+// UDEV  [1234.567890] add      /devices/pci0000:00/0000:00:14.0/usb1/1-2 (usb)
+pub fn parse_udev_line(line: &str) -> Option<HotplugEvent> {
+    let mut fields = line.split_whitespace();
+    if fields.next()? != "UDEV" {
+        return None;
+    }
+    let _timestamp = fields.next()?;
+    let action = match fields.next()? {
+        "add" => HotplugAction::Add,
+        "remove" => HotplugAction::Remove,
+        "change" => HotplugAction::Change,
+        "bind" => HotplugAction::Bind,
+        "unbind" => HotplugAction::Unbind,
+        _ => return None,
+    };
+    let id = fields.next()?.rsplit('/').next()?.to_owned();
+    let category = match fields.next()? {
+        "(pci)" => "pci",
+        "(usb)" => "usb",
+        _ => return None,
+    };
+    // usb interfaces (1-2:1.0) arrive next to the device itself
+    if id.is_empty() || (category == "usb" && id.contains(':')) {
+        return None;
+    }
+    Some(HotplugEvent {
+        category,
+        action,
+        id,
+    })
+}
+
+// Follows udev on a thread of its own until udevadm exits or the receiver
+// goes away. Events come after udev processed them, so added devices already
+// have their drivers and sysfs entries in place when profiles are matched.
+pub fn watch_udev(sender: Sender<HotplugEvent>) -> Result<JoinHandle<()>, io::Error> {
+    let udevadm = find_in_path("udevadm")
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "udevadm is not installed"))?;
+    let reader = duct::cmd!(
+        udevadm,
+        "monitor",
+        "--udev",
+        "--subsystem-match=pci",
+        "--subsystem-match=usb/usb_device"
+    )
+    .stdin_null()
+    .stderr_null()
+    .reader()?;
+    Ok(thread::spawn(move || {
+        for line in io::BufReader::new(&reader).lines().map_while(Result::ok) {
+            if let Some(event) = parse_udev_line(&line) {
+                debug!(
+                    category = event.category,
+                    action = event.action.as_str(),
+                    id = %event.id,
+                    "udev event"
+                );
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = reader.kill();
+        warn!("stopped following udev events");
+    }))
+}
+
+#[cfg(feature = "bt")]
+async fn bluetooth_addresses(session: &bluer::Session) -> Result<HashSet<String>, bluer::Error> {
+    let mut addresses = HashSet::new();
+    for adapter_name in session.adapter_names().await? {
+        let adapter = session.adapter(&adapter_name)?;
+        addresses.extend(
+            adapter
+                .device_addresses()
+                .await?
+                .into_iter()
+                .map(|x| x.to_string()),
+        );
+    }
+    Ok(addresses)
+}
+
+// Reports bluetooth devices BlueZ learns about or forgets, until the receiver
+// goes away. Devices known when it starts are not reported.
+#[cfg(feature = "bt")]
+pub fn watch_bluetooth(sender: Sender<HotplugEvent>) -> Result<JoinHandle<()>, io::Error> {
+    let bluetooth_error = |e: bluer::Error| io::Error::other(e);
+    let session = runtime::block_on(bluer::Session::new())?.map_err(bluetooth_error)?;
+    let mut known = runtime::block_on(bluetooth_addresses(&session))?.map_err(bluetooth_error)?;
+    Ok(thread::spawn(move || loop {
+        thread::sleep(BLUETOOTH_POLL_INTERVAL);
+        let current = match runtime::block_on(bluetooth_addresses(&session))
+            .and_then(|x| x.map_err(CfhdbError::from))
+        {
+            Ok(t) => t,
+            Err(e) => {
+                warn!(error = %e, "could not list bluetooth devices");
+                continue;
+            }
+        };
+        let added = current.difference(&known).map(|x| (HotplugAction::Add, x));
+        let removed = known
+            .difference(&current)
+            .map(|x| (HotplugAction::Remove, x));
+        for (action, address) in added.chain(removed) {
+            let event = HotplugEvent {
+                category: "bt",
+                action,
+                id: address.clone(),
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+        known = current;
+    }))
+}
@@ -0,0 +1,144 @@
+#[cfg(feature = "pci")]
+use crate::pci::CfhdbPciProfile;
+#[cfg(feature = "usb")]
+use crate::usb::CfhdbUsbProfile;
+use std::collections::BTreeMap;
+
+pub const HWDB_FILE: &str = "/etc/udev/hwdb.d/60-cfhdb.hwdb";
+const HWDB_DELIMITER: &str = "CFHDB_HWDB_EOF";
+
+// One modalias glob a profile matches. Blacklisted ids cannot be expressed in
+// hwdb, the properties name candidates that cfhdb itself may still reject.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HwdbMatch {
+    pub pattern: String,
+    pub codename: String,
+    pub priority: i32,
+}
+
+fn modalias_id(id: &str, width: usize) -> String {
+    if id == "*" {
+        return "*".to_owned();
+    }
+    format!("{:0>1$}", id.to_uppercase(), width)
+}
+
+// pci:v<vendor>d<device>sv<subvendor>sd<subdevice>bc<base>sc<sub>i<interface>
+#[cfg(feature = "pci")]
+pub fn pci_matches(profile: &CfhdbPciProfile) -> Vec<HwdbMatch> {
+    let mut matches = vec![];
+    for vendor in &profile.vendor_ids {
+        for device in &profile.device_ids {
+            for class in &profile.class_ids {
+                let class = match class.as_str() {
+                    "*" => "*".to_owned(),
+                    t if t.len() == 4 => {
+                        format!("bc{}sc{}i*", &t[..2].to_uppercase(), &t[2..].to_uppercase())
+                    }
+                    t => format!("bc{}*", t.to_uppercase()),
+                };
+                matches.push(HwdbMatch {
+                    pattern: format!(
+                        "pci:v{}d{}sv*sd*{}",
+                        modalias_id(vendor, 8),
+                        modalias_id(device, 8),
+                        class
+                    )
+                    .replace("**", "*"),
+                    codename: profile.codename.clone(),
+                    priority: profile.priority,
+                });
+            }
+        }
+    }
+    matches
+}
+
+// usb:v<vendor>p<product>d<release>...ic<interface class>..., the class only
+// shows up in the modalias of interfaces
+#[cfg(feature = "usb")]
+pub fn usb_matches(profile: &CfhdbUsbProfile) -> Vec<HwdbMatch> {
+    let mut matches = vec![];
+    for vendor in &profile.vendor_ids {
+        for product in &profile.product_ids {
+            for class in &profile.class_codes {
+                let class = match class.as_str() {
+                    "*" => "*".to_owned(),
+                    t => format!("*ic{}*", modalias_id(t, 2)),
+                };
+                matches.push(HwdbMatch {
+                    pattern: format!(
+                        "usb:v{}p{}{}",
+                        modalias_id(vendor, 4),
+                        modalias_id(product, 4),
+                        class
+                    )
+                    .replace("**", "*"),
+                    codename: profile.codename.clone(),
+                    priority: profile.priority,
+                });
+            }
+        }
+    }
+    matches
+}
+
+// CFHDB_PROFILE_NVIDIA_OPEN for nvidia-open
+fn property_name(codename: &str) -> String {
+    let name: String = codename
+        .chars()
+        .map(|x| {
+            if x.is_ascii_alphanumeric() {
+                x.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("CFHDB_PROFILE_{}", name)
+}
+
+// One property per profile, valued with its priority. udev merges the
+// properties of every entry a device matches, so overlapping globs add up
+// instead of overriding each other.
+pub fn render_hwdb(matches: &[HwdbMatch]) -> String {
+    let mut entries: BTreeMap<&str, BTreeMap<String, i32>> = BTreeMap::new();
+    for entry in matches {
+        entries
+            .entry(&entry.pattern)
+            .or_default()
+            .insert(property_name(&entry.codename), entry.priority);
+    }
+    let mut out = String::from("# Generated by cfhdb hwdb, changes are overwritten\n");
+    for (pattern, properties) in entries {
+        out.push('\n');
+        out.push_str(pattern);
+        out.push('\n');
+        out.push_str(" CFHDB_MATCH=1\n");
+        for (name, priority) in properties {
+            out.push_str(&format!(" {}={}\n", name, priority));
+        }
+    }
+    out
+}
+
+pub fn compose_install_script(hwdb: &str) -> String {
+    [
+        "set -e".to_owned(),
+        "mkdir -p /etc/udev/hwdb.d".to_owned(),
+        format!(
+            "cat > {} <<'{}'\n{}{}",
+            HWDB_FILE, HWDB_DELIMITER, hwdb, HWDB_DELIMITER
+        ),
+        "systemd-hwdb update".to_owned(),
+    ]
+    .join("\n")
+}
+
+pub fn compose_remove_script() -> String {
+    [
+        format!("rm -f {}", HWDB_FILE),
+        "systemd-hwdb update".to_owned(),
+    ]
+    .join("\n")
+}
@@ -0,0 +1,319 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    str::FromStr,
+};
+
+// Validated device identifiers. Parsing normalizes the spelling to the one the
+// enumeration code produces, so a lookup only misses when the device is
+// actually absent, and a typo is reported as such instead of "not found".
+
+fn invalid_input(kind: &str, value: &str, expected: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "'{}' is not a valid {}, expected something like {}",
+            value, kind, expected
+        ),
+    )
+}
+
+fn is_hex(part: &str, len: usize) -> bool {
+    part.len() == len && part.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_number(part: &str) -> bool {
+    !part.is_empty() && part.chars().all(|c| c.is_ascii_digit())
+}
+
+// Bluetooth MAC address, stored upper case as bluez reports it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BtAddress(String);
+
+impl BtAddress {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for BtAddress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split(':').collect();
+        if parts.len() != 6 || !parts.iter().all(|x| is_hex(x, 2)) {
+            return Err(invalid_input("bluetooth address", s, "AA:BB:CC:DD:EE:FF"));
+        }
+        Ok(Self(s.trim().to_uppercase()))
+    }
+}
+
+// USB sysfs bus id, e.g. 3-1.2 for port 2 of the hub on port 1 of bus 3
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SysfsBusId(String);
+
+impl SysfsBusId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for SysfsBusId {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim();
+        let valid = match value.split_once('-') {
+            Some((bus, ports)) => is_number(bus) && ports.split('.').all(is_number),
+            None => false,
+        };
+        if !valid {
+            return Err(invalid_input("usb bus id", s, "3-1.2"));
+        }
+        Ok(Self(value.to_owned()))
+    }
+}
+
+// Stays the same whichever port a usb device is plugged into, id: and an
+// FNV-1a hash of vendor:product:serial. Devices without a serial number can't
+// be told apart from others of the same model and have none.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UsbStableId(String);
+
+impl UsbStableId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // unbind_blacklist.sh computes the same hash from sysfs at boot
+    pub fn compute(vendor_id: &str, product_id: &str, serial: &str) -> Self {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in format!("{}:{}:{}", vendor_id, product_id, serial).bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        Self(format!("id:{:016x}", hash))
+    }
+}
+
+impl FromStr for UsbStableId {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_prefix("id:") {
+            Some(hash) if is_hex(hash, 16) => Ok(Self(format!("id:{}", hash.to_lowercase()))),
+            _ => Err(invalid_input("usb stable id", s, "id:0123456789abcdef")),
+        }
+    }
+}
+
+// A usb device as it can be named on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbTarget {
+    BusId(SysfsBusId),
+    // serial:<serial number>, as the device reports it
+    Serial(String),
+    StableId(UsbStableId),
+}
+
+impl FromStr for UsbTarget {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim();
+        if let Some(serial) = value.strip_prefix("serial:") {
+            if serial.is_empty() {
+                return Err(invalid_input("usb serial number", s, "serial:0123ABC"));
+            }
+            return Ok(Self::Serial(serial.to_owned()));
+        }
+        if value.starts_with("id:") {
+            return Ok(Self::StableId(value.parse()?));
+        }
+        Ok(Self::BusId(value.parse()?))
+    }
+}
+
+impl fmt::Display for UsbTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BusId(x) => write!(f, "{}", x),
+            Self::Serial(x) => write!(f, "serial:{}", x),
+            Self::StableId(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+// PCI address in domain:bus:device.function form, the domain may be left out
+// and defaults to 0000
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PciAddress(String);
+
+impl PciAddress {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for PciAddress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_lowercase();
+        let (slot, function) = match value.rsplit_once('.') {
+            Some(t) => t,
+            None => return Err(invalid_input("pci address", s, "0000:00:02.0")),
+        };
+        let parts: Vec<&str> = slot.split(':').collect();
+        let (domain, bus, device) = match parts.as_slice() {
+            [bus, device] => ("0000", *bus, *device),
+            [domain, bus, device] => (*domain, *bus, *device),
+            _ => return Err(invalid_input("pci address", s, "0000:00:02.0")),
+        };
+        if !is_hex(domain, 4)
+            || !is_hex(bus, 2)
+            || !is_hex(device, 2)
+            || !matches!(function, "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7")
+        {
+            return Err(invalid_input("pci address", s, "0000:00:02.0"));
+        }
+        Ok(Self(format!("{}:{}:{}.{}", domain, bus, device, function)))
+    }
+}
+
+macro_rules! impl_id_conversions {
+    ($($id:ty),*) => {
+        $(
+            impl fmt::Display for $id {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{}", self.0)
+                }
+            }
+
+            impl AsRef<str> for $id {
+                fn as_ref(&self) -> &str {
+                    &self.0
+                }
+            }
+
+            impl TryFrom<String> for $id {
+                type Error = io::Error;
+
+                fn try_from(value: String) -> Result<Self, Self::Error> {
+                    value.parse()
+                }
+            }
+
+            impl From<$id> for String {
+                fn from(value: $id) -> Self {
+                    value.0
+                }
+            }
+        )*
+    };
+}
+
+impl_id_conversions!(BtAddress, SysfsBusId, UsbStableId, PciAddress);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same vectors come out of usb_stable_id in unbind_blacklist.sh
+    #[test]
+    fn stable_ids_are_fnv1a() {
+        assert_eq!(
+            UsbStableId::compute("046d", "c52b", "0123ABC").as_str(),
+            "id:6d02ae2be236ba05"
+        );
+        assert_eq!(
+            UsbStableId::compute("046d", "c52b", "").as_str(),
+            "id:38fccf4946004597"
+        );
+    }
+
+    #[test]
+    fn identifiers_are_normalized() {
+        for (value, expected) in [
+            ("00:02.0", "0000:00:02.0"),
+            ("0000:0A:1F.7", "0000:0a:1f.7"),
+            (" 0001:00:02.0 ", "0001:00:02.0"),
+        ] {
+            assert_eq!(value.parse::<PciAddress>().unwrap().as_str(), expected);
+        }
+        for (value, expected) in [("3-1.2", "3-1.2"), ("1-4", "1-4")] {
+            assert_eq!(value.parse::<SysfsBusId>().unwrap().as_str(), expected);
+        }
+        assert_eq!(
+            "aa:bb:cc:dd:ee:0f".parse::<BtAddress>().unwrap().as_str(),
+            "AA:BB:CC:DD:EE:0F"
+        );
+        assert_eq!(
+            "id:6D02AE2BE236BA05"
+                .parse::<UsbStableId>()
+                .unwrap()
+                .as_str(),
+            "id:6d02ae2be236ba05"
+        );
+    }
+
+    #[test]
+    fn malformed_identifiers_are_refused() {
+        for value in [
+            "",
+            "00:02",
+            "00:02.8",
+            "0:02.0",
+            "00:02.0.1",
+            "zz:02.0",
+            "0000:00:00:02.0",
+        ] {
+            assert!(value.parse::<PciAddress>().is_err(), "{:?}", value);
+        }
+        for value in ["", "1-", "a-1", "1-2/../x", "1-2..3", "1.2", "-1"] {
+            assert!(value.parse::<SysfsBusId>().is_err(), "{:?}", value);
+        }
+        for value in [
+            "",
+            "AA:BB:CC:DD:EE",
+            "AA:BB:CC:DD:EE:FF:00",
+            "AABBCCDDEEFF",
+            "AA:BB:CC:DD:EE:GG",
+        ] {
+            assert!(value.parse::<BtAddress>().is_err(), "{:?}", value);
+        }
+        for value in [
+            "",
+            "id:123",
+            "id:6d02ae2be236ba0",
+            "id:6d02ae2be236ba05a",
+            "id:6d02ae2be236ba0g",
+            "6d02ae2be236ba05",
+        ] {
+            assert!(value.parse::<UsbStableId>().is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn usb_targets_are_told_apart() {
+        assert_eq!(
+            "serial:0123ABC".parse::<UsbTarget>().unwrap(),
+            UsbTarget::Serial("0123ABC".to_owned())
+        );
+        assert_eq!(
+            "id:6D02AE2BE236BA05".parse::<UsbTarget>().unwrap(),
+            UsbTarget::StableId("id:6d02ae2be236ba05".parse().unwrap())
+        );
+        assert_eq!(
+            "3-1.2".parse::<UsbTarget>().unwrap(),
+            UsbTarget::BusId("3-1.2".parse().unwrap())
+        );
+        for value in ["", "serial:", "id:123", "1-2/../x"] {
+            assert!(value.parse::<UsbTarget>().is_err(), "{:?}", value);
+        }
+    }
+}
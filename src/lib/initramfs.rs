@@ -0,0 +1,30 @@
+// Storage and early KMS drivers only take effect at boot once they are in the
+// initramfs. Which generator a system uses is decided by the script itself,
+// so it also picks the right one inside a --root chroot. update-initramfs
+// goes first, on Debian it also fronts dracut when that is installed.
+const REGENERATE_MARKER: &str = "# cfhdb regenerate initramfs";
+const REGENERATE_SCRIPT: &str = r#"# cfhdb regenerate initramfs
+if command -v update-initramfs > /dev/null; then
+    update-initramfs -u -k all
+elif command -v dracut > /dev/null; then
+    dracut --regenerate-all --force
+elif command -v mkinitcpio > /dev/null; then
+    mkinitcpio -P
+else
+    echo "cfhdb: no initramfs generator found, skipping the regeneration" >&2
+fi"#;
+
+// For scripts that were composed earlier and only kept as text, like the one
+// in the install journal
+pub fn script_regenerates(script: &str) -> bool {
+    script.lines().any(|x| x == REGENERATE_MARKER)
+}
+
+// Appended once at the end, after the packages and the profile script, so a
+// transaction regenerates a single time however many hooks it triggered
+pub(crate) fn append_to(script: Option<String>, regenerate: bool) -> Option<String> {
+    match script {
+        Some(t) if regenerate => Some(format!("{}\n{}", t, REGENERATE_SCRIPT)),
+        t => t,
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{
+    paths::{in_target_root, user_state_dir},
+    profile::CfhdbProfile,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+pub const SYSTEM_INSTALLED_STATE_PATH: &str = "/var/lib/cfhdb/installed.json";
+
+// The definition a profile had when it was installed, so `cfhdb upgrade` can
+// tell what changed in the database since.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstalledProfile {
+    pub codename: String,
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    #[serde(default)]
+    pub packages: Vec<String>,
+    #[serde(default)]
+    pub flatpaks: Vec<String>,
+    #[serde(default)]
+    pub snaps: Vec<String>,
+    pub install_script: Option<String>,
+}
+
+impl InstalledProfile {
+    pub fn from_profile<P: CfhdbProfile + ?Sized>(profile: &P) -> Self {
+        Self {
+            codename: profile.get_codename().to_owned(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            packages: profile.get_packages().unwrap_or_default().to_vec(),
+            flatpaks: profile.get_flatpaks().unwrap_or_default().to_vec(),
+            snaps: profile.get_snaps().unwrap_or_default().to_vec(),
+            install_script: profile.get_install_script().map(|x| x.to_owned()),
+        }
+    }
+
+    fn all_packages(&self) -> impl Iterator<Item = &String> {
+        self.packages
+            .iter()
+            .chain(self.flatpaks.iter())
+            .chain(self.snaps.iter())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProfileChange {
+    // Dropped packages are reported but never removed by an upgrade, other
+    // software may have come to depend on them
+    Packages {
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    InstallScript,
+    SupersededBy {
+        codename: String,
+    },
+}
+
+pub fn changes<P: CfhdbProfile + ?Sized>(
+    installed: &InstalledProfile,
+    profile: &P,
+) -> Vec<ProfileChange> {
+    let mut changes = vec![];
+    if let Some(codename) = profile.get_superseded_by() {
+        changes.push(ProfileChange::SupersededBy {
+            codename: codename.to_owned(),
+        });
+        return changes;
+    }
+    let current = InstalledProfile::from_profile(profile);
+    let added: Vec<String> = current
+        .all_packages()
+        .filter(|x| !installed.all_packages().any(|y| y == *x))
+        .cloned()
+        .collect();
+    let removed: Vec<String> = installed
+        .all_packages()
+        .filter(|x| !current.all_packages().any(|y| y == *x))
+        .cloned()
+        .collect();
+    if !added.is_empty() || !removed.is_empty() {
+        changes.push(ProfileChange::Packages { added, removed });
+    }
+    if current.install_script != installed.install_script {
+        changes.push(ProfileChange::InstallScript);
+    }
+    changes
+}
+
+// Like the audit log, root keeps the system state and other users fall back
+// to their own state directory
+fn user_installed_state_path() -> Option<PathBuf> {
+    Some(user_state_dir()?.join("installed.json"))
+}
+
+fn read_state(path: &Path) -> Vec<InstalledProfile> {
+    let data = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(_) => return vec![],
+    };
+    match serde_json::from_str(&data) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "ignoring unreadable installed profile state");
+            vec![]
+        }
+    }
+}
+
+fn write_state(path: &Path, state: &[InstalledProfile]) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data = serde_json::to_string_pretty(state)?;
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, path)
+}
+
+fn update_state(update: impl Fn(&mut Vec<InstalledProfile>)) {
+    let system_path = in_target_root(SYSTEM_INSTALLED_STATE_PATH);
+    let mut state = read_state(&system_path);
+    update(&mut state);
+    let written =
+        write_state(&system_path, &state).or_else(|_| match user_installed_state_path() {
+            Some(path) => {
+                let mut state = read_state(&path);
+                update(&mut state);
+                write_state(&path, &state)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no writable installed profile state location",
+            )),
+        });
+    if let Err(e) = written {
+        warn!(error = %e, "could not update installed profile state");
+    }
+}
+
+// Recording never fails the install it describes, problems are only logged
+pub fn record_installed<P: CfhdbProfile + ?Sized>(profile: &P) {
+    let entry = InstalledProfile::from_profile(profile);
+    update_state(|state| {
+        state.retain(|x| x.codename != entry.codename);
+        state.push(entry.clone());
+    });
+}
+
+// Clears both locations, an older record from a user run would otherwise
+// outlive the uninstall
+pub fn forget_installed(codename: &str) {
+    update_state(|state| state.retain(|x| x.codename != codename));
+    if let Some(path) = user_installed_state_path() {
+        let mut state = read_state(&path);
+        if state.iter().any(|x| x.codename == codename) {
+            state.retain(|x| x.codename != codename);
+            if let Err(e) = write_state(&path, &state) {
+                warn!(error = %e, "could not update installed profile state");
+            }
+        }
+    }
+}
+
+// System and user state merged, the newer record wins
+pub fn get_installed() -> Vec<InstalledProfile> {
+    let mut records = read_state(&in_target_root(SYSTEM_INSTALLED_STATE_PATH));
+    if let Some(path) = user_installed_state_path() {
+        records.extend(read_state(&path));
+    }
+    let mut newest: HashMap<String, InstalledProfile> = HashMap::new();
+    for record in records {
+        match newest.get(&record.codename) {
+            Some(t) if t.timestamp >= record.timestamp => {}
+            _ => {
+                newest.insert(record.codename.clone(), record);
+            }
+        }
+    }
+    let mut records: Vec<InstalledProfile> = newest.into_values().collect();
+    records.sort_by(|a, b| a.codename.cmp(&b.codename));
+    records
+}
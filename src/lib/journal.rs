@@ -0,0 +1,295 @@
+use crate::{
+    paths::{in_target_root, user_state_dir},
+    progress::{ProgressEvent, ProgressSink},
+    snapshot::Snapshot,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+pub const SYSTEM_JOURNAL_DIR: &str = "/var/lib/cfhdb/journal";
+// Echoed by composed install scripts before each stage, never shown to users
+pub(crate) const STAGE_MARKER: &str = "::cfhdb-stage::";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalStage {
+    // Nothing ran yet, the lock script was about to start
+    Prepared,
+    Download,
+    Packages,
+    Script,
+}
+
+impl JournalStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalStage::Prepared => "prepared",
+            JournalStage::Download => "download",
+            JournalStage::Packages => "packages",
+            JournalStage::Script => "script",
+        }
+    }
+
+    fn from_marker(line: &str) -> Option<Self> {
+        match line.strip_prefix(STAGE_MARKER)?.trim() {
+            "download" => Some(JournalStage::Download),
+            "packages" => Some(JournalStage::Packages),
+            "script" => Some(JournalStage::Script),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn stage_marker(stage: JournalStage) -> String {
+    format!("echo '{} {}'", STAGE_MARKER, stage.as_str())
+}
+
+// An install that is running or was cut short. It is written before each
+// stage and removed once the install returns, so any entry found by a later
+// run belongs to an install that was killed or lost power halfway.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub id: String,
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    // The cfhdb process running the install
+    pub pid: u32,
+    pub codename: String,
+    pub stage: JournalStage,
+    // Every package set of the profile by backend name, and the entries of
+    // each that were already installed before the install started
+    pub package_sets: Vec<(String, Vec<String>)>,
+    pub installed_before: Vec<(String, Vec<String>)>,
+    // The composed script, package installs are safe to repeat so resuming
+    // runs it again from the top
+    pub install_script: String,
+    pub remove_script: Option<String>,
+    // The state before anything ran, when snapshots are configured
+    #[serde(default)]
+    pub snapshot: Option<Snapshot>,
+}
+
+fn user_journal_dir() -> Option<PathBuf> {
+    Some(user_state_dir()?.join("journal"))
+}
+
+fn write_entry(dir: &Path, entry: &JournalEntry) -> Result<PathBuf, io::Error> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.json", entry.id));
+    let temp_path = path.with_extension("json.tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    file.write_all(serde_json::to_string_pretty(entry)?.as_bytes())?;
+    // The whole point is surviving a power loss, so it has to be on disk
+    // before the stage it describes starts
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)?;
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(path)
+}
+
+// The entry is replaced on every stage, so the lock telling a running install
+// apart from one that was cut short sits in a file next to it
+fn lock_path(entry_path: &Path) -> PathBuf {
+    entry_path.with_extension("lock")
+}
+
+// Held until the install returns, the kernel drops it when the process dies
+fn lock_entry(entry_path: &Path) -> Result<fs::File, io::Error> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(lock_path(entry_path))?;
+    file.try_lock()?;
+    Ok(file)
+}
+
+pub(crate) struct Journal {
+    path: Option<PathBuf>,
+    entry: Mutex<JournalEntry>,
+    _lock: Option<fs::File>,
+}
+
+impl Journal {
+    pub(crate) fn begin(
+        codename: &str,
+        package_sets: Vec<(String, Vec<String>)>,
+        installed_before: Vec<(String, Vec<String>)>,
+        install_script: &str,
+        remove_script: Option<&str>,
+        snapshot: Option<Snapshot>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        let entry = JournalEntry {
+            id: format!("{}-{}", timestamp, process::id()),
+            timestamp,
+            pid: process::id(),
+            codename: codename.to_owned(),
+            stage: JournalStage::Prepared,
+            package_sets,
+            installed_before,
+            install_script: install_script.to_owned(),
+            remove_script: remove_script.map(|x| x.to_owned()),
+            snapshot,
+        };
+        // Like the audit log, users that cannot write the system journal keep
+        // their own. An install without a journal still goes ahead.
+        let written = write_entry(&in_target_root(SYSTEM_JOURNAL_DIR), &entry).or_else(|_| {
+            match user_journal_dir() {
+                Some(dir) => write_entry(&dir, &entry),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no writable journal location",
+                )),
+            }
+        });
+        let path = match written {
+            Ok(t) => Some(t),
+            Err(e) => {
+                warn!(error = %e, "could not write the install journal");
+                None
+            }
+        };
+        let lock = path.as_deref().and_then(|x| match lock_entry(x) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                warn!(error = %e, "could not lock the install journal");
+                None
+            }
+        });
+        Self {
+            path,
+            entry: Mutex::new(entry),
+            _lock: lock,
+        }
+    }
+
+    fn advance(&self, stage: JournalStage) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.stage = stage;
+        debug!(stage = stage.as_str(), "install stage started");
+        if let Some(path) = &self.path {
+            if let Err(e) = write_entry(path.parent().unwrap_or(Path::new("/")), &entry) {
+                warn!(error = %e, "could not update the install journal");
+            }
+        }
+    }
+
+    // The install returned, whether it worked or not is reported elsewhere
+    pub(crate) fn finish(self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::remove_file(path) {
+                warn!(error = %e, "could not remove the install journal");
+            }
+            let _ = fs::remove_file(lock_path(path));
+        }
+    }
+}
+
+// Sits between the lock script and the caller's sink, turning the stage
+// markers into journal updates and passing everything else through
+pub(crate) struct JournalSink<'a> {
+    pub journal: &'a Journal,
+    pub inner: Option<&'a dyn ProgressSink>,
+}
+
+impl ProgressSink for JournalSink<'_> {
+    fn report(&self, event: ProgressEvent) {
+        if let Some(stage) = event
+            .log_line
+            .as_deref()
+            .and_then(JournalStage::from_marker)
+        {
+            self.journal.advance(stage);
+            return;
+        }
+        match self.inner {
+            Some(sink) => sink.report(event),
+            None => {
+                if let Some(line) = &event.log_line {
+                    println!("{}", line);
+                }
+            }
+        }
+    }
+}
+
+fn read_entries(dir: &Path) -> Vec<(PathBuf, JournalEntry)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(t) => t,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.extension().is_some_and(|x| x == "json"))
+        .filter_map(|path| {
+            let entry = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+            Some((path, entry))
+        })
+        .collect()
+}
+
+fn all_entries() -> Vec<(PathBuf, JournalEntry)> {
+    let mut entries = read_entries(&in_target_root(SYSTEM_JOURNAL_DIR));
+    if let Some(dir) = user_journal_dir() {
+        entries.extend(read_entries(&dir));
+    }
+    entries
+}
+
+// Another cfhdb or cfhdbd may be installing right now, its entry is not
+// interrupted. Only an entry whose lock can be taken is, one we can't even
+// open counts as running. Entries without a lock file fall back to whether
+// their process is still around.
+fn is_running(path: &Path, pid: u32) -> bool {
+    match fs::File::open(lock_path(path)) {
+        Ok(file) => file.try_lock().is_err(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            pid == process::id()
+                || fs::read_to_string(format!("/proc/{}/comm", pid))
+                    .is_ok_and(|x| matches!(x.trim(), "cfhdb" | "cfhdbd"))
+        }
+        Err(_) => true,
+    }
+}
+
+pub fn interrupted() -> Vec<JournalEntry> {
+    let mut entries: Vec<JournalEntry> = all_entries()
+        .into_iter()
+        .filter(|(path, x)| !is_running(path, x.pid))
+        .map(|(_, x)| x)
+        .collect();
+    entries.sort_by_key(|x| x.timestamp);
+    entries
+}
+
+pub fn discard(id: &str) -> Result<(), io::Error> {
+    for (path, entry) in all_entries() {
+        if entry.id == id {
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(lock_path(&path));
+        }
+    }
+    Ok(())
+}
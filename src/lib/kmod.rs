@@ -0,0 +1,190 @@
+use crate::{packages::PackageSpec, paths::find_in_path};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+const DKMS_TREE: &str = "/var/lib/dkms";
+const AKMODS_CACHE: &str = "/var/cache/akmods";
+
+// Out of tree kernel modules get built by a package hook, and a failed build
+// does not fail the package transaction. Profiles shipping such modules are
+// checked afterwards so a broken driver is reported instead of a success.
+pub fn builds_kernel_modules(packages: &[String]) -> bool {
+    packages
+        .iter()
+        .any(|x| x.contains("-dkms") || x.starts_with("dkms-") || x.starts_with("akmod-"))
+}
+
+pub fn running_kernel() -> Result<String, io::Error> {
+    Ok(fs::read_to_string("/proc/sys/kernel/osrelease")?
+        .trim()
+        .to_owned())
+}
+
+struct DkmsEntry {
+    module: String,
+    version: String,
+    kernel: Option<String>,
+    state: String,
+}
+
+// Handles both "name/version, kernel, arch: state" from current dkms and the
+// older "name, version, kernel, arch: state"
+fn read_dkms_status() -> Vec<DkmsEntry> {
+    let output = match find_in_path("dkms") {
+        Some(dkms) => duct::cmd!(dkms, "status")
+            .stderr_null()
+            .read()
+            .unwrap_or_default(),
+        None => return vec![],
+    };
+    output
+        .lines()
+        .filter_map(|line| {
+            let (left, state) = line.rsplit_once(": ")?;
+            let fields: Vec<&str> = left.split(", ").collect();
+            let (module, version, kernel) = match fields[0].split_once('/') {
+                Some((module, version)) => (module, version, fields.get(1)),
+                None => (fields[0], *fields.get(1)?, fields.get(2)),
+            };
+            Some(DkmsEntry {
+                module: module.to_owned(),
+                version: version.to_owned(),
+                kernel: kernel.map(|x| x.to_string()),
+                state: state.trim().to_owned(),
+            })
+        })
+        .collect()
+}
+
+// The dkms modules registered before an install, so only the ones the
+// install added get checked
+pub struct ModuleSnapshot {
+    dkms: HashSet<(String, String)>,
+}
+
+pub fn snapshot() -> ModuleSnapshot {
+    ModuleSnapshot {
+        dkms: read_dkms_status()
+            .into_iter()
+            .map(|x| (x.module, x.version))
+            .collect(),
+    }
+}
+
+fn is_loadable(module: &str, kernel: &str) -> bool {
+    duct::cmd!("modinfo", "-k", kernel, module)
+        .stdout_null()
+        .stderr_null()
+        .run()
+        .is_ok()
+}
+
+fn module_error(module: &str, kernel: &str, problem: &str, log: Option<PathBuf>) -> io::Error {
+    let message = match log {
+        Some(t) => format!(
+            "kernel module {} {} for {}, see {}",
+            module,
+            problem,
+            kernel,
+            t.display()
+        ),
+        None => format!("kernel module {} {} for {}", module, problem, kernel),
+    };
+    io::Error::other(message)
+}
+
+fn verify_dkms(before: &ModuleSnapshot, kernel: &str) -> Result<(), io::Error> {
+    let entries = read_dkms_status();
+    let added: HashSet<(&str, &str)> = entries
+        .iter()
+        .filter(|x| !before.dkms.contains(&(x.module.clone(), x.version.clone())))
+        .map(|x| (x.module.as_str(), x.version.as_str()))
+        .collect();
+    for (module, version) in added {
+        let name = format!("{}/{}", module, version);
+        let log = Path::new(DKMS_TREE)
+            .join(module)
+            .join(version)
+            .join("build/make.log");
+        let log = Some(log).filter(|x| x.exists());
+        let installed = entries.iter().any(|x| {
+            x.module == module
+                && x.version == version
+                && x.kernel.as_deref() == Some(kernel)
+                && x.state.starts_with("installed")
+        });
+        if !installed {
+            return Err(module_error(&name, kernel, "did not build", log));
+        }
+        if !is_loadable(module, kernel) {
+            return Err(module_error(
+                &name,
+                kernel,
+                "built but cannot be found by modprobe",
+                log,
+            ));
+        }
+        debug!(module = %name, kernel, "dkms module built");
+    }
+    Ok(())
+}
+
+// akmod-foo builds kmod-foo-<kernel>, failed builds leave a .failed.log
+fn verify_akmod(package: &str, kernel: &str) -> Result<(), io::Error> {
+    let package = PackageSpec::parse(package).map(|x| x.name)?;
+    let name = match package.strip_prefix("akmod-") {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let kmod_package = format!("kmod-{}-{}", name, kernel);
+    let built = duct::cmd!("rpm", "-q", &kmod_package)
+        .stdout_null()
+        .stderr_null()
+        .run()
+        .is_ok();
+    let failed_log = fs::read_dir(Path::new(AKMODS_CACHE).join(name))
+        .ok()
+        .and_then(|entries| {
+            entries
+                .filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| {
+                    x.to_string_lossy().ends_with(".failed.log")
+                        && x.to_string_lossy().contains(kernel)
+                })
+                .max_by_key(|x| x.metadata().and_then(|m| m.modified()).ok())
+        });
+    if !built {
+        let log = failed_log.or(Some(Path::new(AKMODS_CACHE).join("akmods.log")));
+        return Err(module_error(name, kernel, "did not build", log));
+    }
+    if !is_loadable(name, kernel) {
+        return Err(module_error(
+            name,
+            kernel,
+            "built but cannot be found by modprobe",
+            failed_log,
+        ));
+    }
+    debug!(module = name, kernel, "akmod built");
+    Ok(())
+}
+
+pub fn verify_modules(before: &ModuleSnapshot, packages: &[String]) -> Result<(), io::Error> {
+    let kernel = match running_kernel() {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(error = %e, "could not tell the running kernel, skipping module checks");
+            return Ok(());
+        }
+    };
+    verify_dkms(before, &kernel)?;
+    for package in packages {
+        verify_akmod(package, &kernel)?;
+    }
+    Ok(())
+}
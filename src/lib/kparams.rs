@@ -0,0 +1,226 @@
+use crate::paths::{in_target_root, validate_file_name};
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+// One file per profile with its parameters, one per line. The bootloader
+// configuration is rebuilt from all of them, so two profiles asking for the
+// same parameter keep it until both are gone.
+pub const KPARAMS_DIR: &str = "/etc/cfhdb/kparams.d";
+const KPARAMS_DELIMITER: &str = "CFHDB_KPARAMS_EOF";
+
+// Decided inside the script so it also finds the bootloader of a --root
+// target. kernelstub goes first, on Pop!_OS it owns the systemd-boot entries.
+// kernelstub and /etc/kernel/cmdline only have the one command line, what the
+// last sync added is kept in kparams.applied to take it back out again.
+const SYNC_SCRIPT: &str = r#"# cfhdb kernel parameters
+PARAMS="$(cat /etc/cfhdb/kparams.d/*.conf 2> /dev/null | sort -u | tr '\n' ' ' | sed 's/ *$//')"
+OLD="$(cat /etc/cfhdb/kparams.applied 2> /dev/null || true)"
+if command -v kernelstub > /dev/null; then
+    for param in $OLD; do kernelstub -d "$param" > /dev/null || true; done
+    for param in $PARAMS; do kernelstub -a "$param" > /dev/null; done
+elif [ -f /etc/default/grub ]; then
+    mkdir -p /etc/default/grub.d
+    if [ -n "$PARAMS" ]; then
+        printf '# Generated by cfhdb, changes are overwritten\n[ -n "$CFHDB_KPARAMS" ] || GRUB_CMDLINE_LINUX_DEFAULT="$GRUB_CMDLINE_LINUX_DEFAULT %s"\nCFHDB_KPARAMS=1\n' "$PARAMS" > /etc/default/grub.d/60-cfhdb.cfg
+    else
+        rm -f /etc/default/grub.d/60-cfhdb.cfg
+    fi
+    # Not every grub reads grub.d, the guard above keeps a second read harmless
+    if ! grep -q 'grub.d/60-cfhdb.cfg' /etc/default/grub; then
+        printf '\n[ -f /etc/default/grub.d/60-cfhdb.cfg ] && . /etc/default/grub.d/60-cfhdb.cfg\n' >> /etc/default/grub
+    fi
+    if command -v update-grub > /dev/null; then
+        update-grub
+    elif command -v grub2-mkconfig > /dev/null; then
+        grub2-mkconfig -o /boot/grub2/grub.cfg
+    else
+        grub-mkconfig -o /boot/grub/grub.cfg
+    fi
+elif [ -f /etc/kernel/cmdline ] || command -v bootctl > /dev/null; then
+    [ -f /etc/kernel/cmdline ] || tr -s ' ' < /proc/cmdline | sed 's/BOOT_IMAGE=[^ ]* *//;s/initrd=[^ ]* *//g' > /etc/kernel/cmdline
+    CMDLINE=" $(cat /etc/kernel/cmdline) "
+    for param in $OLD; do CMDLINE="${CMDLINE// "$param" / }"; done
+    for param in $PARAMS; do CMDLINE="$CMDLINE$param "; done
+    echo $CMDLINE > /etc/kernel/cmdline
+    for kernel in /lib/modules/*; do
+        if [ -f "$kernel/vmlinuz" ]; then
+            kernel-install add "$(basename "$kernel")" "$kernel/vmlinuz"
+        fi
+    done
+else
+    echo "cfhdb: no supported bootloader found, kernel parameters not applied" >&2
+fi
+mkdir -p /etc/cfhdb
+echo "$PARAMS" > /etc/cfhdb/kparams.applied"#;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bootloader {
+    Kernelstub,
+    Grub,
+    SystemdBoot,
+    Unknown,
+}
+
+impl Bootloader {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Bootloader::Kernelstub => "kernelstub",
+            Bootloader::Grub => "grub",
+            Bootloader::SystemdBoot => "systemd-boot",
+            Bootloader::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ManagedParameter {
+    pub parameter: String,
+    // Codenames of the profiles that asked for it
+    pub profiles: Vec<String>,
+    // On the command line the running kernel was booted with
+    pub active: bool,
+}
+
+// The same order of checks as the sync script, on the target root if any
+pub fn detect_bootloader() -> Bootloader {
+    let has_program = |name: &str| {
+        ["/usr/bin", "/usr/sbin", "/bin", "/sbin"]
+            .iter()
+            .any(|x| in_target_root(Path::new(x).join(name)).exists())
+    };
+    if has_program("kernelstub") {
+        Bootloader::Kernelstub
+    } else if in_target_root("/etc/default/grub").exists() {
+        Bootloader::Grub
+    } else if in_target_root("/etc/kernel/cmdline").exists() || has_program("bootctl") {
+        Bootloader::SystemdBoot
+    } else {
+        Bootloader::Unknown
+    }
+}
+
+// Anything that would need quoting on a command line is refused, the
+// parameters end up in shell scripts and bootloader configs
+pub fn validate_parameter(parameter: &str) -> Result<(), io::Error> {
+    if parameter.is_empty()
+        || !parameter
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || "_-.,:=/+".contains(x))
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' is not a kernel parameter cfhdb can manage", parameter),
+        ));
+    }
+    Ok(())
+}
+
+fn profile_file(codename: &str) -> Result<String, io::Error> {
+    validate_file_name(codename)?;
+    Ok(format!("{}/{}.conf", KPARAMS_DIR, codename))
+}
+
+pub fn compose_sync_script() -> String {
+    SYNC_SCRIPT.to_owned()
+}
+
+pub fn compose_install_script(codename: &str, parameters: &[String]) -> Result<String, io::Error> {
+    for parameter in parameters {
+        validate_parameter(parameter)?;
+    }
+    Ok([
+        format!("mkdir -p {}", KPARAMS_DIR),
+        format!(
+            "cat > {} <<'{}'\n{}\n{}",
+            profile_file(codename)?,
+            KPARAMS_DELIMITER,
+            parameters.join("\n"),
+            KPARAMS_DELIMITER
+        ),
+        SYNC_SCRIPT.to_owned(),
+    ]
+    .join("\n"))
+}
+
+pub fn compose_remove_script(codename: &str) -> Result<String, io::Error> {
+    Ok([
+        format!("rm -f {}", profile_file(codename)?),
+        SYNC_SCRIPT.to_owned(),
+    ]
+    .join("\n"))
+}
+
+// Sorted by parameter, read from the target root if any
+pub fn managed_parameters() -> Vec<ManagedParameter> {
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let active: Vec<&str> = cmdline.split_whitespace().collect();
+    let mut parameters: Vec<ManagedParameter> = vec![];
+    let mut files: Vec<_> = match fs::read_dir(in_target_root(KPARAMS_DIR)) {
+        Ok(t) => t.flatten().map(|x| x.path()).collect(),
+        Err(_) => return parameters,
+    };
+    files.sort();
+    for path in files
+        .iter()
+        .filter(|x| x.extension().is_some_and(|x| x == "conf"))
+    {
+        let codename = match path.file_stem() {
+            Some(t) => t.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let content = fs::read_to_string(path).unwrap_or_default();
+        for parameter in content.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match parameters.iter_mut().find(|x| x.parameter == parameter) {
+                Some(t) => t.profiles.push(codename.clone()),
+                None => parameters.push(ManagedParameter {
+                    parameter: parameter.to_owned(),
+                    profiles: vec![codename.clone()],
+                    active: active.contains(&parameter),
+                }),
+            }
+        }
+    }
+    parameters.sort_by(|a, b| a.parameter.cmp(&b.parameter));
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameters_without_shell_syntax_are_accepted() {
+        for parameter in [
+            "quiet",
+            "nvidia-drm.modeset=1",
+            "amdgpu.ppfeaturemask=0xffffffff",
+            "video=DP-1:1920x1080",
+            "root=/dev/sda1",
+            "isolcpus=1,2,3",
+        ] {
+            assert!(validate_parameter(parameter).is_ok(), "{}", parameter);
+        }
+    }
+
+    #[test]
+    fn parameters_needing_quoting_are_refused() {
+        for parameter in [
+            "",
+            "a b",
+            "quiet;reboot",
+            "x=$(id)",
+            "x=\"y\"",
+            "x='y'",
+            "a\nb",
+            "x=`id`",
+            "x=a|b",
+        ] {
+            assert!(validate_parameter(parameter).is_err(), "{:?}", parameter);
+        }
+    }
+}
@@ -1,4 +1,51 @@
+pub mod audit;
+pub mod autoinstall;
+pub mod backend;
+pub mod blacklist;
+#[cfg(feature = "bt")]
 pub mod bt;
+pub mod cache;
+pub mod cancel;
+pub mod commands;
+pub mod database;
+pub mod device;
+#[cfg(feature = "dmi")]
 pub mod dmi;
+pub mod doctor;
+pub mod error;
+pub mod facts;
+pub mod hooks;
+pub mod hotplug;
+pub mod hwdb;
+pub mod ids;
+pub mod initramfs;
+pub mod installed;
+pub mod journal;
+pub mod kmod;
+pub mod kparams;
+pub mod lock;
+pub mod lsm;
+pub mod matching;
+pub mod metrics;
+pub mod modules;
+pub mod packages;
+pub mod paths;
+#[cfg(feature = "pci")]
 pub mod pci;
+pub mod privilege;
+pub mod probe;
+pub mod profile;
+pub mod progress;
+#[cfg(feature = "bt")]
+pub mod runtime;
+pub mod script;
+pub mod signing;
+pub mod snapshot;
+pub mod state;
+pub mod status;
+pub mod store;
+pub mod systemd;
+pub mod transaction;
+pub mod udev;
+#[cfg(feature = "usb")]
 pub mod usb;
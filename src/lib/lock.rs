@@ -0,0 +1,343 @@
+use crate::{
+    cancel::{cancelled_error, CancellationToken},
+    paths::in_target_root,
+    progress::{report, ProgressEvent, ProgressSink, ProgressStage},
+};
+use lazy_static::lazy_static;
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind, Read, Seek, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+use users::get_current_username;
+
+// Only root can open the lock file, anyone who could open it could also take
+// the lock and keep it, stalling every install behind it. Runs that elevate
+// take it inside the lock helper instead.
+pub const LOCK_DIR: &str = "/run/cfhdb";
+pub const LOCK_FILE_PATH: &str = "/run/cfhdb/script.lock";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How often a waiting run says what it is still waiting for
+const WAIT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(300);
+// Process names whose presence means the system package database is busy
+const PACKAGE_MANAGERS: &[&str] = &[
+    "apt", "apt-get", "aptitude", "dpkg", "pikman", "dnf", "rpm", "pacman", "zypper",
+];
+
+// dpkg, apt and rpm hold fcntl locks on these while they work, which shows up
+// in /proc/locks even for users who can't open the files
+const PACKAGE_DATABASE_LOCKS: &[&str] = &[
+    "/var/lib/dpkg/lock-frontend",
+    "/var/lib/dpkg/lock",
+    "/var/lib/apt/lists/lock",
+    "/var/cache/apt/archives/lock",
+    "/var/lib/rpm/.rpm.lock",
+    "/usr/lib/sysimage/rpm/.rpm.lock",
+];
+// pacman marks its database busy by the mere existence of this file
+const PACMAN_DB_LOCK: &str = "/var/lib/pacman/db.lck";
+const ZYPP_PID_FILE: &str = "/run/zypp.pid";
+
+lazy_static! {
+    // None waits for as long as it takes
+    static ref LOCK_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(Some(DEFAULT_LOCK_TIMEOUT));
+}
+
+#[derive(Debug, Clone)]
+pub struct LockHolder {
+    // Unknown for locks that are just a file, like pacman's
+    pub pid: Option<u32>,
+    pub name: String,
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "{} (pid {})", self.name, pid),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Held for as long as a lock script runs. The flock is tied to the open file,
+// so it goes away with the process and a crashed run can never leave a stale
+// lock behind, only stale holder details which get overwritten on the next run.
+pub struct ScriptLock {
+    _file: fs::File,
+}
+
+// Lock scripts queue behind other cfhdb and package manager runs for up to
+// the timeout before giving up. A zero timeout fails right away.
+pub fn set_lock_timeout(timeout: Option<Duration>) {
+    *LOCK_TIMEOUT.lock().unwrap() = timeout;
+}
+
+pub fn get_lock_timeout() -> Option<Duration> {
+    *LOCK_TIMEOUT.lock().unwrap()
+}
+
+// Waiting without a timeout
+pub fn set_wait_for_lock(wait: bool) {
+    set_lock_timeout(if wait {
+        None
+    } else {
+        Some(DEFAULT_LOCK_TIMEOUT)
+    });
+}
+
+fn process_name(pid: u32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|x| x.trim().to_owned())
+}
+
+fn find_running_package_manager() -> Option<LockHolder> {
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .find_map(|pid| {
+            let name = process_name(pid)?;
+            if PACKAGE_MANAGERS.contains(&name.as_str()) {
+                Some(LockHolder {
+                    pid: Some(pid),
+                    name,
+                })
+            } else {
+                None
+            }
+        })
+}
+
+// The holder writes "pid user" into the lock file. A pid that no longer
+// exists means the details are stale, not the lock. Details anyone but root
+// could have written are not shown at all.
+fn read_lock_holder(file: &mut fs::File) -> Option<LockHolder> {
+    let metadata = file.metadata().ok()?;
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        return None;
+    }
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    let mut fields = contents.split_whitespace();
+    let pid: u32 = fields.next()?.parse().ok()?;
+    let user = fields.next().unwrap_or("???");
+    let name = process_name(pid)?;
+    Some(LockHolder {
+        pid: Some(pid),
+        name: format!("{} run by {}", name, user),
+    })
+}
+
+// The "major:minor:inode" form /proc/locks uses, in hex like the kernel
+fn lock_file_id(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let dev = metadata.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    Some(format!("{:02x}:{:02x}:{}", major, minor, metadata.ino()))
+}
+
+// Catches package database locks held by frontends we don't know by name,
+// like PackageKit or unattended-upgrades
+fn find_locked_package_database() -> Option<LockHolder> {
+    // With a target root it is the package manager of the target that works
+    let watched: Vec<(String, PathBuf)> = PACKAGE_DATABASE_LOCKS
+        .iter()
+        .map(in_target_root)
+        .filter_map(|path| Some((lock_file_id(&path)?, path)))
+        .collect();
+    if let Ok(locks) = fs::read_to_string("/proc/locks") {
+        for line in locks.lines() {
+            // Waiters are listed with a "->" marker, only holders matter
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(1) == Some(&"->") || fields.len() < 6 {
+                continue;
+            }
+            let pid = match fields[4].parse::<u32>() {
+                Ok(t) if t != process::id() => t,
+                _ => continue,
+            };
+            if let Some((_, path)) = watched.iter().find(|(id, _)| id == fields[5]) {
+                return Some(LockHolder {
+                    pid: Some(pid),
+                    name: format!(
+                        "{} holding {}",
+                        process_name(pid).unwrap_or("???".to_owned()),
+                        path.display()
+                    ),
+                });
+            }
+        }
+    }
+    if let Some(pid) = fs::read_to_string(ZYPP_PID_FILE)
+        .ok()
+        .and_then(|x| x.trim().parse::<u32>().ok())
+    {
+        if let Some(name) = process_name(pid) {
+            return Some(LockHolder {
+                pid: Some(pid),
+                name: format!("{} holding the zypp lock", name),
+            });
+        }
+    }
+    let pacman_lock = in_target_root(PACMAN_DB_LOCK);
+    if pacman_lock.exists() {
+        return Some(LockHolder {
+            pid: None,
+            name: format!(
+                "{}, remove it if no pacman is running",
+                pacman_lock.display()
+            ),
+        });
+    }
+    None
+}
+
+fn write_lock_holder(file: &mut fs::File) -> Result<(), io::Error> {
+    let user = get_current_username()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or("???".to_owned());
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{} {}", process::id(), user)?;
+    file.flush()
+}
+
+fn busy_error(holder: Option<LockHolder>) -> io::Error {
+    let holder = match holder {
+        Some(t) => t.to_string(),
+        None => "an unknown process".to_owned(),
+    };
+    io::Error::new(
+        ErrorKind::WouldBlock,
+        format!("the package lock is held by {}", holder),
+    )
+}
+
+fn open_lock_file() -> Result<fs::File, io::Error> {
+    let dir = Path::new(LOCK_DIR);
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let dir_metadata = fs::symlink_metadata(dir)?;
+    if !dir_metadata.is_dir() || dir_metadata.uid() != 0 || dir_metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not a root owned directory", LOCK_DIR),
+        ));
+    }
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(LOCK_FILE_PATH)?;
+    // Through the descriptor, one left by an older version may be wider open
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+// Polls take until it hands back what it waited for. Err(holder) means busy,
+// reported every so often and given up on after the lock timeout.
+fn wait_for<T>(
+    stage: ProgressStage,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+    mut take: impl FnMut() -> Result<Result<T, Option<LockHolder>>, io::Error>,
+) -> Result<T, io::Error> {
+    let timeout = get_lock_timeout();
+    let started = Instant::now();
+    let mut last_report: Option<Instant> = None;
+    loop {
+        if cancel.is_some_and(|x| x.is_cancelled()) {
+            return Err(cancelled_error());
+        }
+        let holder = match take()? {
+            Ok(t) => return Ok(t),
+            Err(holder) => holder,
+        };
+        let waited = started.elapsed();
+        if timeout.is_some_and(|x| waited >= x) {
+            return Err(busy_error(holder));
+        }
+        if last_report.is_none_or(|x| x.elapsed() >= WAIT_REPORT_INTERVAL) {
+            let reason = busy_error(holder);
+            warn!(%reason, waited = waited.as_secs(), "waiting for the package lock");
+            report(
+                progress,
+                ProgressEvent::log_line(
+                    stage,
+                    &format!(
+                        "waiting for the package lock, {} ({}s)",
+                        reason,
+                        waited.as_secs()
+                    ),
+                ),
+            );
+            last_report = Some(Instant::now());
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+// Waits out package managers that are already running. This needs no
+// privileges, so it happens before elevating and the cfhdb lock itself is
+// left to the lock helper.
+pub fn wait_for_package_managers(
+    stage: ProgressStage,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(), io::Error> {
+    wait_for(stage, progress, cancel, || {
+        Ok(
+            match find_running_package_manager().or_else(find_locked_package_database) {
+                None => Ok(()),
+                Some(package_manager) => Err(Some(package_manager)),
+            },
+        )
+    })
+}
+
+// Only for root running scripts without the lock helper
+pub fn acquire(
+    stage: ProgressStage,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+) -> Result<ScriptLock, io::Error> {
+    let mut file = open_lock_file()?;
+    wait_for(stage, progress, cancel, || match file.try_lock() {
+        Ok(()) => match find_running_package_manager().or_else(find_locked_package_database) {
+            None => {
+                write_lock_holder(&mut file)?;
+                debug!("acquired script lock");
+                Ok(Ok(()))
+            }
+            Some(package_manager) => {
+                file.unlock()?;
+                Ok(Err(Some(package_manager)))
+            }
+        },
+        Err(fs::TryLockError::WouldBlock) => Ok(Err(read_lock_holder(&mut file))),
+        Err(fs::TryLockError::Error(e)) => Err(e),
+    })?;
+    Ok(ScriptLock { _file: file })
+}
+
+// How long the lock helper waits for the lock, "wait" for no limit
+pub fn helper_timeout_arg() -> String {
+    match get_lock_timeout() {
+        Some(t) => t.as_secs().to_string(),
+        None => "wait".to_owned(),
+    }
+}
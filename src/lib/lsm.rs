@@ -0,0 +1,126 @@
+use crate::paths::find_in_path;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{fs, io, path::Path};
+use tracing::{debug, warn};
+
+const LSM_LIST_PATH: &str = "/sys/kernel/security/lsm";
+const SELINUX_ENFORCE_PATH: &str = "/sys/fs/selinux/enforce";
+const APPARMOR_ENABLED_PATH: &str = "/sys/module/apparmor/parameters/enabled";
+const APPARMOR_USERNS_RESTRICT_PATH: &str =
+    "/proc/sys/kernel/apparmor_restrict_unprivileged_userns";
+const APPARMOR_PROFILE_DIR: &str = "/etc/apparmor.d";
+const AUDIT_LOG_PATH: &str = "/var/log/audit/audit.log";
+// Type that lets both an unconfined shell and a systemd unit execute the
+// scripts we hand out, /tmp and /var/lib would get user_tmp_t and var_lib_t
+pub const SELINUX_SCRIPT_TYPE: &str = "bin_t";
+
+lazy_static! {
+    static ref ACTIVE_LSM: Option<ActiveLsm> = read_active_lsm();
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Lsm {
+    SELinux,
+    AppArmor,
+}
+
+impl Lsm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lsm::SELinux => "selinux",
+            Lsm::AppArmor => "apparmor",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveLsm {
+    pub lsm: Lsm,
+    // SELinux in permissive mode only logs denials, AppArmor is treated as
+    // enforcing whenever it is enabled since profiles pick their own mode
+    pub enforcing: bool,
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|x| x.trim().to_owned())
+}
+
+// Only the major LSMs that confine processes matter here, the rest of the
+// stack (yama, lockdown, landlock...) never gets in the way of a root script.
+fn read_active_lsm() -> Option<ActiveLsm> {
+    let stack = read_trimmed(LSM_LIST_PATH).unwrap_or_default();
+    let listed = |name: &str| stack.split(',').any(|x| x == name);
+    let active = if listed("selinux") || Path::new(SELINUX_ENFORCE_PATH).exists() {
+        Some(ActiveLsm {
+            lsm: Lsm::SELinux,
+            enforcing: read_trimmed(SELINUX_ENFORCE_PATH).as_deref() == Some("1"),
+        })
+    } else if listed("apparmor") || read_trimmed(APPARMOR_ENABLED_PATH).as_deref() == Some("Y") {
+        Some(ActiveLsm {
+            lsm: Lsm::AppArmor,
+            enforcing: true,
+        })
+    } else {
+        None
+    };
+    debug!(?active, "detected active LSM");
+    active
+}
+
+pub fn get_active_lsm() -> Option<ActiveLsm> {
+    *ACTIVE_LSM
+}
+
+// Gives a script the SELinux type it needs to be executed by the helpers.
+// Nothing to do for AppArmor, which confines by path rather than by label.
+pub(crate) fn label_script(path: &Path) {
+    if !get_active_lsm().is_some_and(|x| x.lsm == Lsm::SELinux) {
+        return;
+    }
+    let chcon = match find_in_path("chcon") {
+        Some(t) => t,
+        None => {
+            warn!(path = %path.display(), "SELinux is active but chcon is missing, script keeps its default label");
+            return;
+        }
+    };
+    if let Err(e) = duct::cmd!(chcon, "-t", SELINUX_SCRIPT_TYPE, path)
+        .stdout_null()
+        .stderr_null()
+        .run()
+    {
+        warn!(path = %path.display(), error = %e, "could not relabel script");
+    }
+}
+
+// Ubuntu 23.10 and later deny unprivileged user namespaces to anything
+// without an AppArmor profile granting them, which breaks bwrap unless the
+// distribution ships a profile for it.
+pub fn userns_restricted() -> bool {
+    if !get_active_lsm().is_some_and(|x| x.lsm == Lsm::AppArmor) {
+        return false;
+    }
+    if read_trimmed(APPARMOR_USERNS_RESTRICT_PATH).as_deref() != Some("1") {
+        return false;
+    }
+    let has_bwrap_profile = fs::read_dir(APPARMOR_PROFILE_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("bwrap"))
+        })
+        .unwrap_or(false);
+    !has_bwrap_profile
+}
+
+// AVC denials mentioning cfhdb in the audit log. The log is only readable by
+// root, so an unprivileged run gets PermissionDenied rather than a count.
+pub fn count_selinux_denials() -> Result<usize, io::Error> {
+    let log = fs::read_to_string(AUDIT_LOG_PATH)?;
+    Ok(log
+        .lines()
+        .filter(|x| x.contains("avc:") && x.contains("denied") && x.contains("cfhdb"))
+        .count())
+}
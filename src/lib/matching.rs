@@ -0,0 +1,220 @@
+use crate::device::CfhdbDevice;
+use std::sync::Arc;
+use tracing::debug;
+
+// One device field as a profile sees it: the ids the profile accepts for it,
+// the ids it refuses and the value the device has. None accepts any value.
+pub struct MatchField<'a> {
+    pub accepted: Option<&'a [String]>,
+    pub blacklisted: &'a [String],
+    pub value: &'a str,
+}
+
+impl<'a> MatchField<'a> {
+    pub fn new(accepted: &'a [String], blacklisted: &'a [String], value: &'a str) -> Self {
+        MatchField {
+            accepted: Some(accepted),
+            blacklisted,
+            value,
+        }
+    }
+
+    // For fields profiles can only exclude devices on
+    pub fn blacklist_only(blacklisted: &'a [String], value: &'a str) -> Self {
+        MatchField {
+            accepted: None,
+            blacklisted,
+            value,
+        }
+    }
+
+    fn accepts(&self) -> bool {
+        match self.accepted {
+            Some(t) => id_list_contains(t, self.value),
+            None => true,
+        }
+    }
+}
+
+// Profile id lists match a device field when they contain it or the "*" wildcard
+pub(crate) fn id_list_contains(list: &[String], id: &str) -> bool {
+    list.iter().any(|x| x == "*" || x == id)
+}
+
+// A single blacklisted field excludes the profile, otherwise every field has
+// to be accepted
+pub fn fields_match(fields: &[MatchField]) -> bool {
+    !fields
+        .iter()
+        .any(|x| id_list_contains(x.blacklisted, x.value))
+        && fields.iter().all(|x| x.accepts())
+}
+
+// The profiles of profile_data that apply to the device, in database order.
+// A new category only has to describe its fields in get_match_fields.
+pub fn match_profiles<D: CfhdbDevice>(
+    profile_data: &[Arc<D::Profile>],
+    device: &D,
+) -> Vec<Arc<D::Profile>> {
+    let available_profiles: Vec<Arc<D::Profile>> = profile_data
+        .iter()
+        .filter(|x| fields_match(&device.get_match_fields(x)))
+        .map(Arc::clone)
+        .collect();
+    debug!(matched = available_profiles.len(), "matched profiles");
+    available_profiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn ids(list: &[&str]) -> Vec<String> {
+        list.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn fields_match_table() {
+        // accepted, blacklisted, value, expected
+        let cases: [(&[&str], &[&str], &str, bool); 8] = [
+            (&["10de"], &[], "10de", true),
+            (&["10de"], &[], "8086", false),
+            (&["*"], &[], "8086", true),
+            (&["*"], &["*"], "8086", false),
+            (&["10de"], &["*"], "10de", false),
+            (&["10de"], &["10de"], "10de", false),
+            (&["*"], &["1002"], "10de", true),
+            (&[], &[], "10de", false),
+        ];
+        for (accepted, blacklisted, value, expected) in cases {
+            let (accepted, blacklisted) = (ids(accepted), ids(blacklisted));
+            let fields = [MatchField::new(&accepted, &blacklisted, value)];
+            assert_eq!(
+                fields_match(&fields),
+                expected,
+                "accepted {:?}, blacklisted {:?}, value {}",
+                accepted,
+                blacklisted,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn blacklist_only_field_accepts_anything_not_blacklisted() {
+        let empty = ids(&[]);
+        let blacklisted = ids(&["0c03"]);
+        assert!(fields_match(&[MatchField::blacklist_only(&empty, "0300")]));
+        assert!(fields_match(&[MatchField::blacklist_only(
+            &blacklisted,
+            "0300"
+        )]));
+        assert!(!fields_match(&[MatchField::blacklist_only(
+            &blacklisted,
+            "0c03"
+        )]));
+    }
+
+    #[test]
+    fn every_field_has_to_match() {
+        let (vendors, products, none) = (ids(&["10de"]), ids(&["*"]), ids(&[]));
+        let fields = [
+            MatchField::new(&vendors, &none, "10de"),
+            MatchField::new(&products, &none, "2484"),
+        ];
+        assert!(fields_match(&fields));
+        let fields = [
+            MatchField::new(&vendors, &none, "8086"),
+            MatchField::new(&products, &none, "2484"),
+        ];
+        assert!(!fields_match(&fields));
+        assert!(fields_match(&[]));
+    }
+
+    struct TestProfile {
+        codename: &'static str,
+        vendor_ids: Vec<String>,
+        blacklisted_vendor_ids: Vec<String>,
+    }
+
+    struct TestDevice {
+        vendor_id: String,
+        profiles: Mutex<Option<Vec<Arc<TestProfile>>>>,
+    }
+
+    impl CfhdbDevice for TestDevice {
+        type Profile = TestProfile;
+        const CATEGORY: &'static str = "test";
+
+        fn get_id(&self) -> String {
+            self.vendor_id.clone()
+        }
+
+        fn get_class(&self) -> String {
+            String::new()
+        }
+
+        fn get_display_fields(&self) -> (String, String) {
+            (String::new(), String::new())
+        }
+
+        fn get_match_fields<'a>(&'a self, profile: &'a TestProfile) -> Vec<MatchField<'a>> {
+            vec![MatchField::new(
+                &profile.vendor_ids,
+                &profile.blacklisted_vendor_ids,
+                &self.vendor_id,
+            )]
+        }
+
+        fn get_matched_profiles(&self) -> Option<Vec<Arc<TestProfile>>> {
+            self.profiles.lock().unwrap().clone()
+        }
+
+        fn attach_profiles(&self, profiles: Vec<Arc<TestProfile>>) {
+            *self.profiles.lock().unwrap() = Some(profiles);
+        }
+
+        fn iter_all() -> Option<Box<dyn Iterator<Item = Self>>> {
+            None
+        }
+    }
+
+    fn profile(
+        codename: &'static str,
+        accepted: &[&str],
+        blacklisted: &[&str],
+    ) -> Arc<TestProfile> {
+        Arc::new(TestProfile {
+            codename,
+            vendor_ids: ids(accepted),
+            blacklisted_vendor_ids: ids(blacklisted),
+        })
+    }
+
+    #[test]
+    fn match_profiles_keeps_database_order() {
+        let profiles = [
+            profile("generic", &["*"], &[]),
+            profile("nvidia", &["10de"], &[]),
+            profile("not-nvidia", &["*"], &["10de"]),
+            profile("intel", &["8086"], &[]),
+            profile("nothing", &[], &[]),
+            profile("refused", &["10de"], &["*"]),
+        ];
+        let device = TestDevice {
+            vendor_id: "10de".to_owned(),
+            profiles: Mutex::new(None),
+        };
+        let matched: Vec<&str> = match_profiles(&profiles, &device)
+            .iter()
+            .map(|x| x.codename)
+            .collect();
+        assert_eq!(matched, ["generic", "nvidia"]);
+        // The matched profiles are the ones of the database, not copies
+        assert!(Arc::ptr_eq(
+            &match_profiles(&profiles, &device)[1],
+            &profiles[1]
+        ));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, warn};
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:9877";
+
+// Builds the Prometheus text exposition format, one family after the other
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // kind is gauge or counter, every sample of the family follows
+    pub fn family(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn handle(mut stream: TcpStream, metrics: &Mutex<String>) -> Result<(), io::Error> {
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are read and ignored, a close with unread data resets the
+    // connection before the scraper got the body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut fields = request_line.split_whitespace();
+    let (status, content_type, body) = match (fields.next(), fields.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.lock().unwrap().clone(),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// Serves the last rendered metrics on /metrics, scrapes never wait for a
+// collection to run
+pub fn serve_metrics(listen: &str, metrics: Arc<Mutex<String>>) -> Result<(), io::Error> {
+    let listener = TcpListener::bind(listen)?;
+    debug!(listen, "serving metrics");
+    for stream in listener.incoming() {
+        let result = stream.and_then(|x| handle(x, &metrics));
+        if let Err(e) = result {
+            warn!(error = %e, "metrics request failed");
+        }
+    }
+    Ok(())
+}
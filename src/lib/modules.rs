@@ -0,0 +1,502 @@
+use crate::{
+    device::CfhdbDevice,
+    kmod,
+    paths::{in_target_root, validate_file_name},
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+use tracing::debug;
+
+pub const MODPROBE_DIR: &str = "/etc/modprobe.d";
+// Blacklists and options set with `cfhdb modules`, every profile that
+// declares some gets a cfhdb-<codename>.conf of its own
+pub const LOCAL_DROPIN: &str = "cfhdb-local.conf";
+const DROPIN_DELIMITER: &str = "CFHDB_MODPROBE_EOF";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModuleOption {
+    pub module: String,
+    // Space separated key=value pairs, as modprobe takes them
+    pub options: String,
+}
+
+// Profiles list their module options as {"module": "options", ...}
+pub(crate) mod options_map {
+    use super::ModuleOption;
+    use serde::{ser::SerializeMap, Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S>(options: &[ModuleOption], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(options.len()))?;
+        for option in options {
+            map.serialize_entry(&option.module, &option.options)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<ModuleOption>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map: BTreeMap<String, String> = BTreeMap::deserialize(deserializer)?;
+        Ok(map
+            .into_iter()
+            .map(|(module, options)| ModuleOption { module, options })
+            .collect())
+    }
+}
+
+// The part of a modprobe.d file cfhdb writes. Other directives are dropped
+// when a file is parsed, which is fine for the files cfhdb owns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModprobeDropin {
+    pub blacklist: Vec<String>,
+    pub options: Vec<ModuleOption>,
+}
+
+impl ModprobeDropin {
+    pub fn parse(content: &str) -> Self {
+        let mut dropin = ModprobeDropin::default();
+        for line in content.lines().map(|x| x.trim()) {
+            let mut fields = line.splitn(3, char::is_whitespace);
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("blacklist"), Some(module), None) => dropin.blacklist.push(module.to_owned()),
+                (Some("options"), Some(module), Some(options)) => {
+                    dropin.options.push(ModuleOption {
+                        module: module.to_owned(),
+                        options: options.trim().to_owned(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        dropin
+    }
+
+    pub fn render(&self, header: &str) -> String {
+        let mut out = format!("# {}\n", header);
+        for module in &self.blacklist {
+            out.push_str(&format!("blacklist {}\n", module));
+        }
+        for option in &self.options {
+            out.push_str(&format!("options {} {}\n", option.module, option.options));
+        }
+        out
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blacklist.is_empty() && self.options.is_empty()
+    }
+
+    // Names and options end up in a heredoc, nothing may break out of it
+    pub fn validate(&self) -> Result<(), io::Error> {
+        for module in self
+            .blacklist
+            .iter()
+            .chain(self.options.iter().map(|x| &x.module))
+        {
+            validate_module_name(module)?;
+        }
+        for option in &self.options {
+            if option.options.trim().is_empty() || option.options.contains('\n') {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid options for module {}", option.module),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_module_name(name: &str) -> Result<(), io::Error> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' is not a kernel module name", name),
+        ));
+    }
+    Ok(())
+}
+
+// The kernel treats dashes and underscores in module names the same and
+// /proc/modules lists them with underscores
+pub fn normalize_module_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ModuleState {
+    pub name: String,
+    pub loaded: bool,
+    pub blacklisted: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceModules {
+    pub category: &'static str,
+    pub id: String,
+    pub vendor: String,
+    pub name: String,
+    // The driver bound right now, None for devices without one
+    pub driver: Option<String>,
+    // Every module the modaliases of the device resolve to, the bound one
+    // included
+    pub modules: Vec<ModuleState>,
+}
+
+pub fn loaded_modules() -> HashSet<String> {
+    fs::read_to_string("/proc/modules")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| x.split_whitespace().next())
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+// Blacklisted anywhere in modprobe.d, not only by cfhdb. With a target root
+// it is the configuration of the target.
+pub fn blacklisted_modules() -> HashSet<String> {
+    let entries = match fs::read_dir(in_target_root(MODPROBE_DIR)) {
+        Ok(t) => t,
+        Err(_) => return HashSet::new(),
+    };
+    entries
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| x.extension().is_some_and(|x| x == "conf"))
+        .filter_map(|x| fs::read_to_string(x).ok())
+        .flat_map(|x| ModprobeDropin::parse(&x).blacklist)
+        .map(|x| normalize_module_name(&x))
+        .collect()
+}
+
+fn link_name(path: &Path) -> Option<String> {
+    Some(
+        fs::read_link(path)
+            .ok()?
+            .file_name()?
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+lazy_static! {
+    // (glob, module) for every alias of the running kernel
+    static ref MODULE_ALIASES: Vec<(String, String)> = load_module_aliases();
+}
+
+// Built in drivers have their aliases in modules.builtin.alias, kmod looks
+// at both the same way
+fn load_module_aliases() -> Vec<(String, String)> {
+    let kernel = match kmod::running_kernel() {
+        Ok(t) => t,
+        Err(e) => {
+            debug!(error = %e, "no running kernel, modaliases stay unresolved");
+            return vec![];
+        }
+    };
+    let dir = Path::new("/lib/modules").join(kernel);
+    let mut aliases = vec![];
+    for file_name in ["modules.alias", "modules.builtin.alias"] {
+        let data = match fs::read_to_string(dir.join(file_name)) {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        aliases.extend(data.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next()? != "alias" {
+                return None;
+            }
+            let pattern = fields.next()?.to_owned();
+            Some((pattern, normalize_module_name(fields.next()?)))
+        }));
+    }
+    debug!(count = aliases.len(), "loaded module aliases");
+    aliases
+}
+
+// The [...] set at the start of pattern, its length and whether c is in it.
+// None when it is never closed, fnmatch takes the [ literally then.
+fn match_set(pattern: &[u8], c: u8) -> Option<(usize, bool)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        // A ] right after the opening one is part of the set
+        if start == b']' && !first {
+            break;
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(end)) if *end != b']' => {
+                matched |= (start..=*end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= start == c;
+                i += 1;
+            }
+        }
+    }
+    Some((i + 1, matched != negated))
+}
+
+// fnmatch without flags, which is what kmod matches aliases with
+fn alias_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last *, to let it take one more character
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match match_set(&pattern[p..], text[t]) {
+                Some((len, matched)) => matched.then_some(p + len),
+                None => (text[t] == b'[').then_some(p + 1),
+            },
+            Some(c) => (*c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (next, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+// What `modprobe --resolve-alias` prints, read straight from the alias files
+// of the running kernel instead of running modprobe for every device
+pub fn resolve_modalias(modalias: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for (pattern, module) in MODULE_ALIASES.iter() {
+        if alias_matches(pattern.as_bytes(), modalias.as_bytes()) && !names.contains(module) {
+            names.push(module.clone());
+        }
+    }
+    names
+}
+
+// Drivers of usb devices bind to their interfaces, which show up as
+// <busid>:<config>.<interface> directories next to the device attributes
+pub fn device_modules<D: CfhdbDevice>(
+    category: &'static str,
+    device: &D,
+    sysfs_path: &Path,
+    loaded: &HashSet<String>,
+    blacklisted: &HashSet<String>,
+) -> DeviceModules {
+    let mut dirs = vec![sysfs_path.to_path_buf()];
+    if let Ok(entries) = fs::read_dir(sysfs_path) {
+        dirs.extend(
+            entries
+                .flatten()
+                .filter(|x| x.file_name().to_string_lossy().contains(':'))
+                .map(|x| x.path()),
+        );
+    }
+    let driver = dirs.iter().find_map(|x| link_name(&x.join("driver")));
+    let mut names: Vec<String> = vec![];
+    for dir in &dirs {
+        let bound = link_name(&dir.join("driver").join("module"));
+        let aliased = fs::read_to_string(dir.join("modalias"))
+            .map(|x| resolve_modalias(x.trim()))
+            .unwrap_or_default();
+        for name in bound.into_iter().chain(aliased) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    let (vendor, name) = device.get_display_fields();
+    DeviceModules {
+        category,
+        id: device.get_id(),
+        vendor,
+        name,
+        driver,
+        modules: names
+            .into_iter()
+            .map(|x| ModuleState {
+                loaded: loaded.contains(&x),
+                blacklisted: blacklisted.contains(&x),
+                name: x,
+            })
+            .collect(),
+    }
+}
+
+pub fn read_local_dropin() -> ModprobeDropin {
+    fs::read_to_string(in_target_root(MODPROBE_DIR).join(LOCAL_DROPIN))
+        .map(|x| ModprobeDropin::parse(&x))
+        .unwrap_or_default()
+}
+
+pub fn profile_dropin_name(codename: &str) -> Result<String, io::Error> {
+    validate_file_name(codename)?;
+    Ok(format!("cfhdb-{}.conf", codename))
+}
+
+// Run through the lock script like any other change to the system. An empty
+// drop-in removes the file instead of leaving a header behind.
+pub fn compose_write_script(
+    file_name: &str,
+    dropin: &ModprobeDropin,
+    header: &str,
+) -> Result<String, io::Error> {
+    if dropin.is_empty() {
+        return Ok(compose_remove_script(file_name));
+    }
+    dropin.validate()?;
+    Ok([
+        format!("mkdir -p {}", MODPROBE_DIR),
+        format!(
+            "cat > {}/{} <<'{}'\n{}{}",
+            MODPROBE_DIR,
+            file_name,
+            DROPIN_DELIMITER,
+            dropin.render(header),
+            DROPIN_DELIMITER
+        ),
+    ]
+    .join("\n"))
+}
+
+pub fn compose_remove_script(file_name: &str) -> String {
+    format!("rm -f {}/{}", MODPROBE_DIR, file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected results are what glibc fnmatch(pattern, text, 0) returns
+    fn check(cases: &[(&str, &str, bool)]) {
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                alias_matches(pattern.as_bytes(), text.as_bytes()),
+                *expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn stars_backtrack() {
+        check(&[
+            ("a*c", "abcbc", true),
+            ("a*bc", "abcbc", true),
+            ("*x*y", "axbxcy", true),
+            ("a*b", "acbd", false),
+            ("*", "", true),
+        ]);
+    }
+
+    #[test]
+    fn question_marks_take_one_character() {
+        check(&[("a?c", "abc", true), ("a?c", "ac", false), ("?", "", false)]);
+    }
+
+    #[test]
+    fn sets_ranges_and_negation() {
+        check(&[
+            ("[a-c]x", "bx", true),
+            ("[a-c]x", "dx", false),
+            ("[!a-c]x", "dx", true),
+            ("[!a-c]x", "bx", false),
+            ("[^0-9]", "a", true),
+            ("[a-]", "-", true),
+        ]);
+    }
+
+    #[test]
+    fn leading_bracket_is_part_of_the_set() {
+        check(&[
+            ("[]a]", "]", true),
+            ("[]a]", "a", true),
+            ("[]a]", "b", false),
+            ("[!]]", "]", false),
+            ("[!]]", "x", true),
+        ]);
+    }
+
+    #[test]
+    fn unclosed_bracket_is_literal() {
+        check(&[
+            ("a[bc", "a[bc", true),
+            ("a[bc", "ab", false),
+            ("[", "[", true),
+        ]);
+    }
+
+    #[test]
+    fn kernel_aliases_match() {
+        check(&[
+            (
+                "usb:v046DpC52Bd*dc*dsc*dp*ic03isc01ip02in*",
+                "usb:v046DpC52Bd1201dc00dsc00dp00ic03isc01ip02in00",
+                true,
+            ),
+            (
+                "usb:v046DpC52Bd*dc*dsc*dp*ic03isc01ip02in*",
+                "usb:v046DpC52Bd1201dc00dsc00dp00ic03isc01ip01in00",
+                false,
+            ),
+            (
+                "usb:v*p*d*dc*dsc*dp*ic09isc*ip*in*",
+                "usb:v1D6Bp0002d0605dc09dsc00dp01ic09isc00ip00in00",
+                true,
+            ),
+            ("usb:v0BDAp[08]179d*", "usb:v0BDAp8179d0000dcFF", true),
+            ("usb:v0BDAp[08]179d*", "usb:v0BDAp1179d0000dcFF", false),
+            (
+                "pci:v000010DEd*sv*sd*bc03sc*i*",
+                "pci:v000010DEd00002684sv00001043sd000088E2bc03sc00i00",
+                true,
+            ),
+            (
+                "pci:v000010DEd*sv*sd*bc03sc*i*",
+                "pci:v00001002d0000744Csv00001043sd000088E2bc03sc00i00",
+                false,
+            ),
+            (
+                "pci:v00008086d0000[0-9A-F]*",
+                "pci:v00008086d0000A7A0",
+                true,
+            ),
+        ]);
+    }
+}
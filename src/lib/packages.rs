@@ -0,0 +1,1142 @@
+use crate::{lock::get_lock_timeout, paths::find_in_path};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    sync::Mutex,
+};
+use tracing::debug;
+
+lazy_static! {
+    static ref CONFFILE_POLICY: Mutex<ConffilePolicy> = Mutex::new(ConffilePolicy::default());
+}
+
+// What dpkg does with a config file the admin changed when the package ships
+// a new one. Asking is not an option inside the lock script.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConffilePolicy {
+    // Keep the admin's version, the new one lands next to it as .dpkg-dist
+    #[default]
+    Keep,
+    // Take the packaged version, the old one is kept as .dpkg-old
+    Replace,
+}
+
+// Package managers that can wait on their own lock get the cfhdb lock timeout,
+// in seconds, in case something grabs the lock while the script runs
+fn lock_timeout_secs() -> Option<u64> {
+    get_lock_timeout().map(|x| x.as_secs())
+}
+
+pub fn set_conffile_policy(policy: ConffilePolicy) {
+    *CONFFILE_POLICY.lock().unwrap() = policy;
+}
+
+// The dpkg flag for the current policy, apt passes it through Dpkg::Options
+fn dpkg_conffile_flag() -> &'static str {
+    match *CONFFILE_POLICY.lock().unwrap() {
+        ConffilePolicy::Keep => "--force-confold",
+        ConffilePolicy::Replace => "--force-confnew",
+    }
+}
+
+// Builds the package manager invocations for the install pipeline. Commands
+// are argument vectors that only get quoted into the lock script at the very
+// end, so a package name can never turn into shell syntax.
+pub trait PackageBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn install_args(&self, packages: &[String]) -> Vec<String>;
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String>;
+    // Extra environment that keeps the package manager and its hooks from
+    // prompting. The flags for that are part of the install and uninstall args.
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+    // Succeeds only when the package is installed, run without privileges
+    fn query_args(&self, package: &str) -> Vec<String>;
+    // Picks per package failures out of the package manager output
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)>;
+    // What an install would pull in and how much it weighs, asked without
+    // privileges and without changing anything
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error>;
+
+    // Fetches the packages without installing them, so an interruption while
+    // downloading leaves the system untouched. None installs in one go.
+    fn download_args(&self, _packages: &[String]) -> Option<Vec<String>> {
+        None
+    }
+
+    // Finishes whatever an interrupted run of the package manager left half
+    // configured, before anything else can be installed or removed
+    fn repair_args(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    // How a versioned entry is spelled on the command line. Most package
+    // managers take "name>=version" as is.
+    fn translate_spec(&self, spec: &PackageSpec) -> Result<String, io::Error> {
+        Ok(spec.to_string())
+    }
+
+    // Fails before anything runs when no available version satisfies the
+    // constraint. Backends that enforce constraints themselves leave it to
+    // their own resolver.
+    fn check_constraint(&self, _spec: &PackageSpec) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn is_installed(&self, package: &str) -> bool {
+        let args = self.query_args(package);
+        duct::cmd(&args[0], &args[1..])
+            .stdout_null()
+            .stderr_null()
+            .unchecked()
+            .run()
+            .is_ok_and(|x| x.status.success())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase", tag = "status", content = "reason")]
+pub enum PackageStatus {
+    Installed,
+    Removed,
+    Failed(String),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PackageResult {
+    pub name: String,
+    #[serde(flatten)]
+    pub status: PackageStatus,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionOp {
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "=")]
+    Eq,
+}
+
+impl VersionOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VersionOp::Ge => ">=",
+            VersionOp::Gt => ">",
+            VersionOp::Le => "<=",
+            VersionOp::Lt => "<",
+            VersionOp::Eq => "=",
+        }
+    }
+}
+
+// A profile package entry, "nvidia-driver" or "nvidia-driver>=550"
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PackageSpec {
+    pub name: String,
+    pub constraint: Option<(VersionOp, String)>,
+}
+
+impl PackageSpec {
+    // Names and versions are restricted to what package managers accept, so
+    // nothing can be read as an option or as shell syntax
+    pub fn parse(entry: &str) -> Result<Self, io::Error> {
+        let re = Regex::new(
+            r"^([A-Za-z0-9+_.@][A-Za-z0-9+_.@:/-]*?)\s*(?:(>=|<=|=|>|<)\s*([A-Za-z0-9+_.:~-]+))?$",
+        )
+        .unwrap();
+        let captures = match re.captures(entry.trim()) {
+            Some(t) => t,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("'{}' is not a valid package entry", entry),
+                ))
+            }
+        };
+        let constraint = match (captures.get(2), captures.get(3)) {
+            (Some(op), Some(version)) => Some((
+                match op.as_str() {
+                    ">=" => VersionOp::Ge,
+                    ">" => VersionOp::Gt,
+                    "<=" => VersionOp::Le,
+                    "<" => VersionOp::Lt,
+                    _ => VersionOp::Eq,
+                },
+                version.as_str().to_owned(),
+            )),
+            _ => None,
+        };
+        Ok(Self {
+            name: captures[1].to_owned(),
+            constraint,
+        })
+    }
+}
+
+impl fmt::Display for PackageSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.constraint {
+            Some((op, version)) => write!(f, "{}{}{}", self.name, op.as_str(), version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+fn parse_specs(packages: &[String]) -> Result<Vec<PackageSpec>, io::Error> {
+    packages.iter().map(|x| PackageSpec::parse(x)).collect()
+}
+
+fn unsupported_constraint(backend: &dyn PackageBackend, spec: &PackageSpec) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "{} does not support version constraints like {}",
+            backend.name(),
+            spec
+        ),
+    )
+}
+
+// Sizes are in bytes, None when the backend can't tell
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PackagePlan {
+    pub backend: &'static str,
+    pub requested: Vec<String>,
+    // Requested packages plus everything they pull in
+    pub to_install: Vec<String>,
+    pub download_size: Option<u64>,
+    pub installed_size: Option<u64>,
+}
+
+impl PackagePlan {
+    fn new(backend: &dyn PackageBackend, requested: &[String]) -> Self {
+        Self {
+            backend: backend.name(),
+            requested: requested.to_vec(),
+            to_install: vec![],
+            download_size: None,
+            installed_size: None,
+        }
+    }
+}
+
+// Runs a query and hands back its combined output, along with whether it
+// exited cleanly since some dry runs always report failure
+fn query_output(args: &[String]) -> Result<(bool, String), io::Error> {
+    let output = duct::cmd(&args[0], &args[1..])
+        .env("LC_ALL", "C")
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()?;
+    Ok((
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+fn query_failed(args: &[String], output: &str) -> io::Error {
+    io::Error::other(format!("{} failed: {}", args[0], output.trim()))
+}
+
+// Reads "12.3 MB", "45 M" or "1.5 MiB" style sizes. Binary prefixes and bare
+// single letter units (dnf) count in 1024s, kB/MB/GB (apt) in 1000s.
+fn parse_size(number: &str, unit: &str) -> Option<u64> {
+    let number: f64 = number.replace(',', "").parse().ok()?;
+    let base: f64 = if unit.contains('i') || unit.len() == 1 {
+        1024.0
+    } else {
+        1000.0
+    };
+    let exponent = match unit.chars().next().map(|x| x.to_ascii_uppercase()) {
+        None | Some('B') => 0,
+        Some('K') => 1,
+        Some('M') => 2,
+        Some('G') => 3,
+        Some('T') => 4,
+        _ => return None,
+    };
+    Some((number * base.powi(exponent)) as u64)
+}
+
+fn find_size(output: &str, pattern: &str) -> Option<u64> {
+    let re = Regex::new(pattern).unwrap();
+    let captures = re.captures(output)?;
+    parse_size(&captures[1], &captures[2])
+}
+
+fn to_args(args: &[&str], packages: &[String]) -> Vec<String> {
+    args.iter()
+        .map(|x| x.to_string())
+        .chain(packages.iter().cloned())
+        .collect()
+}
+
+fn collect_failures(output: &str, patterns: &[(&str, &str)]) -> Vec<(String, String)> {
+    let mut failures = vec![];
+    for (pattern, reason) in patterns {
+        let re = Regex::new(pattern).unwrap();
+        for captures in re.captures_iter(output) {
+            // Resolvers echo versioned targets back whole, results go by name
+            let name = captures[1]
+                .trim_matches(['\'', '"'])
+                .split(['<', '>', '='])
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_owned();
+            if !failures.iter().any(|(x, _)| *x == name) {
+                failures.push((name, reason.to_string()));
+            }
+        }
+    }
+    failures
+}
+
+// Without a timeout apt would give up on a busy lock right away, a day is as
+// good as forever here
+fn apt_lock_timeout_args() -> Vec<String> {
+    vec![
+        "-o".to_owned(),
+        format!(
+            "DPkg::Lock::Timeout={}",
+            lock_timeout_secs().unwrap_or(86400)
+        ),
+    ]
+}
+
+pub struct AptBackend;
+
+impl PackageBackend for AptBackend {
+    fn name(&self) -> &'static str {
+        "apt"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        let conffile = format!("Dpkg::Options::={}", dpkg_conffile_flag());
+        let mut args = to_args(
+            &[
+                "apt-get",
+                "install",
+                "-y",
+                "-o",
+                "Dpkg::Options::=--force-confdef",
+                "-o",
+                &conffile,
+            ],
+            &[],
+        );
+        args.extend(apt_lock_timeout_args());
+        args.extend(packages.iter().cloned());
+        args
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        let mut args = to_args(&["apt-get", "purge", "-y"], &[]);
+        args.extend(apt_lock_timeout_args());
+        args.extend(packages.iter().cloned());
+        args
+    }
+
+    fn download_args(&self, packages: &[String]) -> Option<Vec<String>> {
+        let mut args = to_args(&["apt-get", "install", "-y", "--download-only"], &[]);
+        args.extend(apt_lock_timeout_args());
+        args.extend(packages.iter().cloned());
+        Some(args)
+    }
+
+    fn repair_args(&self) -> Option<Vec<String>> {
+        Some(to_args(
+            &[
+                "dpkg",
+                "--configure",
+                "-a",
+                "--force-confdef",
+                dpkg_conffile_flag(),
+            ],
+            &[],
+        ))
+    }
+
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        // needrestart and apt-listchanges ask questions from dpkg hooks
+        vec![
+            ("DEBIAN_FRONTEND", "noninteractive".to_owned()),
+            ("NEEDRESTART_MODE", "a".to_owned()),
+            ("APT_LISTCHANGES_FRONTEND", "none".to_owned()),
+        ]
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        to_args(
+            &["dpkg-query", "-W", "-f=${db:Status-Status}"],
+            &[package.to_owned()],
+        )
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        collect_failures(
+            output,
+            &[
+                (r"Unable to locate package (\S+)", "not found"),
+                (
+                    r"Package '?(\S+?)'? has no installation candidate",
+                    "no installation candidate",
+                ),
+                (r"(\S+) : Depends: ", "unmet dependencies"),
+            ],
+        )
+    }
+
+    // The simulation lists what gets installed, --print-uris the archive sizes
+    // and apt-cache the unpacked sizes, all without needing root
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = PackagePlan::new(self, packages);
+        let args = to_args(&["apt-get", "install", "-s"], packages);
+        let (success, output) = query_output(&args)?;
+        if !success {
+            return Err(query_failed(&args, &output));
+        }
+        let re = Regex::new(r"(?m)^Inst (\S+)").unwrap();
+        plan.to_install = re.captures_iter(&output).map(|x| x[1].to_owned()).collect();
+        if plan.to_install.is_empty() {
+            plan.download_size = Some(0);
+            plan.installed_size = Some(0);
+            return Ok(plan);
+        }
+        let args = to_args(&["apt-get", "install", "--print-uris", "-qq"], packages);
+        if let Ok((true, output)) = query_output(&args) {
+            plan.download_size = Some(
+                output
+                    .lines()
+                    .filter_map(|x| x.split_whitespace().nth(2)?.parse::<u64>().ok())
+                    .sum(),
+            );
+        }
+        let args = to_args(
+            &["apt-cache", "show", "--no-all-versions"],
+            &plan.to_install,
+        );
+        if let Ok((true, output)) = query_output(&args) {
+            let re = Regex::new(r"(?m)^Installed-Size: (\d+)").unwrap();
+            plan.installed_size = Some(
+                re.captures_iter(&output)
+                    .filter_map(|x| x[1].parse::<u64>().ok())
+                    .sum::<u64>()
+                    * 1024,
+            );
+        }
+        Ok(plan)
+    }
+
+    // apt only pins exact versions, everything else is checked up front
+    // against the candidate and then installed by name
+    fn translate_spec(&self, spec: &PackageSpec) -> Result<String, io::Error> {
+        match &spec.constraint {
+            Some((VersionOp::Eq, version)) => Ok(format!("{}={}", spec.name, version)),
+            _ => Ok(spec.name.clone()),
+        }
+    }
+
+    fn check_constraint(&self, spec: &PackageSpec) -> Result<(), io::Error> {
+        let (op, version) = match &spec.constraint {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let args = to_args(&["apt-cache", "policy"], std::slice::from_ref(&spec.name));
+        let (_, output) = query_output(&args)?;
+        let re = Regex::new(r"(?m)^\s*Candidate: (\S+)").unwrap();
+        let candidate = match re.captures(&output) {
+            Some(t) if &t[1] != "(none)" => t[1].to_owned(),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} is required but no version of it is available", spec),
+                ))
+            }
+        };
+        let dpkg_op = match op {
+            VersionOp::Ge => "ge",
+            VersionOp::Gt => "gt",
+            VersionOp::Le => "le",
+            VersionOp::Lt => "lt",
+            VersionOp::Eq => "eq",
+        };
+        let args = to_args(
+            &["dpkg", "--compare-versions"],
+            &[candidate.clone(), dpkg_op.to_owned(), version.clone()],
+        );
+        match query_output(&args)? {
+            (true, _) => Ok(()),
+            (false, _) => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{} is required but the newest available version is {}",
+                    spec, candidate
+                ),
+            )),
+        }
+    }
+
+    // dpkg-query also knows removed packages that left their config behind
+    fn is_installed(&self, package: &str) -> bool {
+        let args = self.query_args(package);
+        duct::cmd(&args[0], &args[1..])
+            .stderr_null()
+            .read()
+            .is_ok_and(|x| x.trim() == "installed")
+    }
+}
+
+// PikaOS installs through pikman, a wrapper that hands apt style arguments on
+// to apt. Queries, plans and repairs go to apt and dpkg directly.
+pub struct PikmanBackend;
+
+// The apt invocation with pikman in place of apt-get
+fn through_pikman(mut args: Vec<String>) -> Vec<String> {
+    args[0] = "pikman".to_owned();
+    args
+}
+
+impl PackageBackend for PikmanBackend {
+    fn name(&self) -> &'static str {
+        "pikman"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        through_pikman(AptBackend.install_args(packages))
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        through_pikman(AptBackend.uninstall_args(packages))
+    }
+
+    fn download_args(&self, packages: &[String]) -> Option<Vec<String>> {
+        AptBackend.download_args(packages)
+    }
+
+    fn repair_args(&self) -> Option<Vec<String>> {
+        AptBackend.repair_args()
+    }
+
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        AptBackend.environment()
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        AptBackend.query_args(package)
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        AptBackend.parse_failures(output)
+    }
+
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = AptBackend.plan_install(packages)?;
+        plan.backend = self.name();
+        Ok(plan)
+    }
+
+    fn translate_spec(&self, spec: &PackageSpec) -> Result<String, io::Error> {
+        AptBackend.translate_spec(spec)
+    }
+
+    fn check_constraint(&self, spec: &PackageSpec) -> Result<(), io::Error> {
+        AptBackend.check_constraint(spec)
+    }
+
+    fn is_installed(&self, package: &str) -> bool {
+        AptBackend.is_installed(package)
+    }
+}
+
+pub struct DnfBackend;
+
+impl PackageBackend for DnfBackend {
+    fn name(&self) -> &'static str {
+        "dnf"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(&["dnf", "install", "-y"], packages)
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(&["dnf", "remove", "-y"], packages)
+    }
+
+    fn download_args(&self, packages: &[String]) -> Option<Vec<String>> {
+        Some(to_args(
+            &["dnf", "install", "-y", "--downloadonly"],
+            packages,
+        ))
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        to_args(&["rpm", "-q", "--whatprovides"], &[package.to_owned()])
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        collect_failures(
+            output,
+            &[
+                (r"No match for argument: (\S+)", "not found"),
+                (
+                    r"package (\S+) requires .*, but none of the providers can be installed",
+                    "unmet dependencies",
+                ),
+            ],
+        )
+    }
+
+    // dnf reads a versioned provide as one argument with spaces around the operator
+    fn translate_spec(&self, spec: &PackageSpec) -> Result<String, io::Error> {
+        match &spec.constraint {
+            Some((op, version)) => Ok(format!("{} {} {}", spec.name, op.as_str(), version)),
+            None => Ok(spec.name.clone()),
+        }
+    }
+
+    // --assumeno prints the whole transaction and then exits non-zero
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = PackagePlan::new(self, packages);
+        let args = to_args(&["dnf", "install", "--assumeno"], packages);
+        let (_, output) = query_output(&args)?;
+        if !self.parse_failures(&output).is_empty() {
+            return Err(query_failed(&args, &output));
+        }
+        // Transaction rows are " name arch version repo size"
+        let re = Regex::new(r"(?m)^ (\S+)\s+\S+\s+\S+\s+\S+\s+[\d.]+ [kMG]?i?B?$").unwrap();
+        plan.to_install = re.captures_iter(&output).map(|x| x[1].to_owned()).collect();
+        plan.download_size = find_size(&output, r"Total download size: ([\d.,]+) ?(\S+)");
+        plan.installed_size = find_size(&output, r"Installed size: ([\d.,]+) ?(\S+)");
+        if plan.to_install.is_empty() && output.contains("Nothing to do") {
+            plan.download_size = Some(0);
+            plan.installed_size = Some(0);
+        }
+        Ok(plan)
+    }
+}
+
+pub struct PacmanBackend;
+
+impl PackageBackend for PacmanBackend {
+    fn name(&self) -> &'static str {
+        "pacman"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(&["pacman", "-S", "--needed", "--noconfirm"], packages)
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(&["pacman", "-Rns", "--noconfirm"], packages)
+    }
+
+    fn download_args(&self, packages: &[String]) -> Option<Vec<String>> {
+        Some(to_args(
+            &["pacman", "-Sw", "--needed", "--noconfirm"],
+            packages,
+        ))
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        to_args(&["pacman", "-Q"], &[package.to_owned()])
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        collect_failures(
+            output,
+            &[
+                (r"target not found: (\S+)", "not found"),
+                (
+                    r"unable to satisfy dependency '[^']+' required by (\S+)",
+                    "unmet dependencies",
+                ),
+            ],
+        )
+    }
+
+    // --print resolves the targets with their dependencies without a lock.
+    // pacman only knows download sizes before installing.
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = PackagePlan::new(self, packages);
+        let args = to_args(
+            &[
+                "pacman",
+                "-S",
+                "--needed",
+                "--print",
+                "--print-format",
+                "%n %s",
+            ],
+            packages,
+        );
+        let (success, output) = query_output(&args)?;
+        if !success {
+            return Err(query_failed(&args, &output));
+        }
+        let mut download_size = 0;
+        for line in output.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(name), Some(size)) = (fields.next(), fields.next()) {
+                if let Ok(size) = size.parse::<u64>() {
+                    plan.to_install.push(name.to_owned());
+                    download_size += size;
+                }
+            }
+        }
+        plan.download_size = Some(download_size);
+        Ok(plan)
+    }
+}
+
+pub struct ZypperBackend;
+
+impl PackageBackend for ZypperBackend {
+    fn name(&self) -> &'static str {
+        "zypper"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(
+            &[
+                "zypper",
+                "--non-interactive",
+                "install",
+                "--auto-agree-with-licenses",
+            ],
+            packages,
+        )
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(
+            &["zypper", "--non-interactive", "remove", "--clean-deps"],
+            packages,
+        )
+    }
+
+    fn download_args(&self, packages: &[String]) -> Option<Vec<String>> {
+        Some(to_args(
+            &[
+                "zypper",
+                "--non-interactive",
+                "install",
+                "--download-only",
+                "--auto-agree-with-licenses",
+            ],
+            packages,
+        ))
+    }
+
+    // A negative timeout makes zypp wait forever
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![(
+            "ZYPP_LOCK_TIMEOUT",
+            lock_timeout_secs().map_or("-1".to_owned(), |x| x.to_string()),
+        )]
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        to_args(&["rpm", "-q", "--whatprovides"], &[package.to_owned()])
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        collect_failures(
+            output,
+            &[
+                (r"Package '(\S+)' not found", "not found"),
+                (r"No provider of '(\S+)' found", "not found"),
+                (
+                    r"nothing provides '[^']+' needed by the to be installed (\S+)",
+                    "unmet dependencies",
+                ),
+            ],
+        )
+    }
+
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = PackagePlan::new(self, packages);
+        let args = to_args(
+            &["zypper", "--non-interactive", "install", "--dry-run"],
+            packages,
+        );
+        let (success, output) = query_output(&args)?;
+        if !success {
+            return Err(query_failed(&args, &output));
+        }
+        // The new packages are listed indented under their heading
+        let mut in_new_list = false;
+        for line in output.lines() {
+            if line.starts_with("The following") {
+                in_new_list = line.contains("NEW package");
+            } else if line.trim().is_empty() {
+                in_new_list = false;
+            } else if in_new_list {
+                plan.to_install
+                    .extend(line.split_whitespace().map(|x| x.to_owned()));
+            }
+        }
+        plan.download_size = find_size(&output, r"Overall download size: ([\d.,]+) (\S+?)\.");
+        plan.installed_size = find_size(&output, r"additional ([\d.,]+) (\S+?) will be used");
+        Ok(plan)
+    }
+}
+
+// Flatpak apps install system wide from flathub, the remote most vendor
+// control panels and firmware tools are published on
+pub struct FlatpakBackend;
+
+impl PackageBackend for FlatpakBackend {
+    fn name(&self) -> &'static str {
+        "flatpak"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(
+            &[
+                "flatpak",
+                "install",
+                "--system",
+                "--noninteractive",
+                "-y",
+                "flathub",
+            ],
+            packages,
+        )
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(
+            &["flatpak", "uninstall", "--system", "--noninteractive", "-y"],
+            packages,
+        )
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        to_args(&["flatpak", "info", "--system"], &[package.to_owned()])
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        collect_failures(output, &[(r"Nothing matches (\S+) in remote", "not found")])
+    }
+
+    fn translate_spec(&self, spec: &PackageSpec) -> Result<String, io::Error> {
+        match spec.constraint {
+            Some(_) => Err(unsupported_constraint(self, spec)),
+            None => Ok(spec.name.clone()),
+        }
+    }
+
+    // Runtimes an app needs are not counted, remote-info only knows the app
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = PackagePlan::new(self, packages);
+        let mut download_size = 0;
+        let mut installed_size = 0;
+        for package in packages {
+            if self.is_installed(package) {
+                continue;
+            }
+            let args = to_args(
+                &["flatpak", "remote-info", "--system", "flathub"],
+                &[package.to_owned()],
+            );
+            let (success, output) = query_output(&args)?;
+            if !success {
+                return Err(query_failed(&args, &output));
+            }
+            plan.to_install.push(package.clone());
+            download_size += find_size(&output, r"Download: ([\d.,]+)\s(\S+)").unwrap_or_default();
+            installed_size +=
+                find_size(&output, r"Installed: ([\d.,]+)\s(\S+)").unwrap_or_default();
+        }
+        plan.download_size = Some(download_size);
+        plan.installed_size = Some(installed_size);
+        Ok(plan)
+    }
+}
+
+pub struct SnapBackend;
+
+impl PackageBackend for SnapBackend {
+    fn name(&self) -> &'static str {
+        "snap"
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(&["snap", "install"], packages)
+    }
+
+    fn uninstall_args(&self, packages: &[String]) -> Vec<String> {
+        to_args(&["snap", "remove"], packages)
+    }
+
+    fn query_args(&self, package: &str) -> Vec<String> {
+        to_args(&["snap", "list"], &[package.to_owned()])
+    }
+
+    fn parse_failures(&self, output: &str) -> Vec<(String, String)> {
+        collect_failures(output, &[(r#"snap "(\S+)" not found"#, "not found")])
+    }
+
+    fn translate_spec(&self, spec: &PackageSpec) -> Result<String, io::Error> {
+        match spec.constraint {
+            Some(_) => Err(unsupported_constraint(self, spec)),
+            None => Ok(spec.name.clone()),
+        }
+    }
+
+    // The snap store does not publish sizes through the CLI
+    fn plan_install(&self, packages: &[String]) -> Result<PackagePlan, io::Error> {
+        let mut plan = PackagePlan::new(self, packages);
+        for package in packages {
+            if self.is_installed(package) {
+                continue;
+            }
+            let args = to_args(&["snap", "info"], &[package.to_owned()]);
+            let (success, output) = query_output(&args)?;
+            if !success {
+                return Err(query_failed(&args, &output));
+            }
+            plan.to_install.push(package.clone());
+        }
+        Ok(plan)
+    }
+}
+
+// Picks the backend of the running distribution by its package manager binary
+pub fn detect_package_backend() -> Option<Box<dyn PackageBackend>> {
+    let backend: Box<dyn PackageBackend> = if find_in_path("apt-get").is_some() {
+        Box::new(AptBackend)
+    } else if find_in_path("dnf").is_some() {
+        Box::new(DnfBackend)
+    } else if find_in_path("pacman").is_some() {
+        Box::new(PacmanBackend)
+    } else if find_in_path("zypper").is_some() {
+        Box::new(ZypperBackend)
+    } else {
+        return None;
+    };
+    debug!(backend = backend.name(), "detected package backend");
+    Some(backend)
+}
+
+pub(crate) fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+fn command_line(backend: &dyn PackageBackend, args: &[String]) -> String {
+    backend
+        .environment()
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, shell_quote(value)))
+        .chain(args.iter().map(|x| shell_quote(x)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn translate_specs(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+) -> Result<Vec<String>, io::Error> {
+    parse_specs(packages)?
+        .iter()
+        .map(|x| backend.translate_spec(x))
+        .collect()
+}
+
+pub fn install_command_line(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+) -> Result<String, io::Error> {
+    let targets = translate_specs(backend, packages)?;
+    Ok(command_line(backend, &backend.install_args(&targets)))
+}
+
+pub fn download_command_line(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+) -> Result<Option<String>, io::Error> {
+    let targets = translate_specs(backend, packages)?;
+    Ok(backend
+        .download_args(&targets)
+        .map(|args| command_line(backend, &args)))
+}
+
+pub fn repair_command_line(backend: &dyn PackageBackend) -> Option<String> {
+    backend
+        .repair_args()
+        .map(|args| command_line(backend, &args))
+}
+
+// Removal goes by name, whatever version ended up installed
+pub fn uninstall_command_line(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+) -> Result<String, io::Error> {
+    let names: Vec<String> = parse_specs(packages)?.into_iter().map(|x| x.name).collect();
+    Ok(command_line(backend, &backend.uninstall_args(&names)))
+}
+
+pub fn check_constraints(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+) -> Result<(), io::Error> {
+    for spec in parse_specs(packages)? {
+        backend.check_constraint(&spec)?;
+    }
+    Ok(())
+}
+
+pub fn plan_install(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+) -> Result<PackagePlan, io::Error> {
+    check_constraints(backend, packages)?;
+    let mut plan = backend.plan_install(&translate_specs(backend, packages)?)?;
+    plan.requested = packages.to_vec();
+    Ok(plan)
+}
+
+// The entries whose package is already on the system
+pub fn installed_entries(backend: &dyn PackageBackend, packages: &[String]) -> Vec<String> {
+    packages
+        .iter()
+        .filter(|x| PackageSpec::parse(x).is_ok_and(|spec| backend.is_installed(&spec.name)))
+        .cloned()
+        .collect()
+}
+
+// Works out what happened to each package once the lock script is done. Known
+// failures come from the output, everything else is checked against the
+// package database.
+pub fn collect_results(
+    backend: &dyn PackageBackend,
+    packages: &[String],
+    installing: bool,
+    output: Option<&str>,
+) -> Vec<PackageResult> {
+    let failures = output
+        .map(|x| backend.parse_failures(x))
+        .unwrap_or_default();
+    packages
+        .iter()
+        .map(|package| {
+            let spec = PackageSpec::parse(package).unwrap_or(PackageSpec {
+                name: package.clone(),
+                constraint: None,
+            });
+            let status =
+                match failures.iter().find(|(name, _)| *name == spec.name) {
+                    Some((_, reason)) => match &spec.constraint {
+                        Some((op, version)) if reason == "not found" => PackageStatus::Failed(
+                            format!("no version {}{} available", op.as_str(), version),
+                        ),
+                        _ => PackageStatus::Failed(reason.clone()),
+                    },
+                    None => match (installing, backend.is_installed(&spec.name)) {
+                        (true, true) => PackageStatus::Installed,
+                        (false, false) => PackageStatus::Removed,
+                        (true, false) => PackageStatus::Failed("not installed".to_owned()),
+                        (false, true) => PackageStatus::Failed("still installed".to_owned()),
+                    },
+                };
+            PackageResult {
+                name: package.clone(),
+                status,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_specs_parse() {
+        assert_eq!(
+            PackageSpec::parse(" nvidia-driver-550 ").unwrap(),
+            PackageSpec {
+                name: "nvidia-driver-550".to_owned(),
+                constraint: None,
+            }
+        );
+        assert_eq!(
+            PackageSpec::parse("mesa >= 24.0~rc1").unwrap(),
+            PackageSpec {
+                name: "mesa".to_owned(),
+                constraint: Some((VersionOp::Ge, "24.0~rc1".to_owned())),
+            }
+        );
+        assert_eq!(
+            PackageSpec::parse("libc6:i386<2.39").unwrap().constraint,
+            Some((VersionOp::Lt, "2.39".to_owned()))
+        );
+        assert_eq!(
+            PackageSpec::parse("firmware=1:20240101")
+                .unwrap()
+                .to_string(),
+            "firmware=1:20240101"
+        );
+    }
+
+    #[test]
+    fn package_specs_refuse_options_and_shell_syntax() {
+        for entry in [
+            "",
+            "-y",
+            "--purge",
+            "foo;reboot",
+            "foo bar",
+            "$(id)",
+            "foo>=",
+        ] {
+            assert!(PackageSpec::parse(entry).is_err(), "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn sizes_parse() {
+        let cases = [
+            ("512", "B", Some(512)),
+            ("12", "kB", Some(12_000)),
+            ("1.5", "MB", Some(1_500_000)),
+            ("1,024", "kB", Some(1_024_000)),
+            ("45", "M", Some(45 * 1024 * 1024)),
+            ("1.5", "MiB", Some(1_572_864)),
+            ("2", "GiB", Some(2 * 1024 * 1024 * 1024)),
+            ("1", "", Some(1)),
+            ("1", "PB", None),
+            ("many", "MB", None),
+        ];
+        for (number, unit, expected) in cases {
+            assert_eq!(parse_size(number, unit), expected, "{} {}", number, unit);
+        }
+    }
+
+    #[test]
+    fn pikman_takes_the_apt_arguments() {
+        let packages = vec!["mesa".to_owned()];
+        let apt = AptBackend.install_args(&packages);
+        let pikman = PikmanBackend.install_args(&packages);
+        assert_eq!(pikman[0], "pikman");
+        assert_eq!(pikman[1..], apt[1..]);
+        assert_eq!(
+            PikmanBackend.uninstall_args(&packages)[..2],
+            ["pikman", "purge"]
+        );
+    }
+}
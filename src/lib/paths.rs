@@ -0,0 +1,76 @@
+use lazy_static::lazy_static;
+use std::{
+    env,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const SYSTEM_CACHE_DIR: &str = "/var/cache/cfhdb";
+
+lazy_static! {
+    // The system an installer is setting up. Devices are still enumerated on
+    // the live system, everything cfhdb writes goes below this root instead.
+    static ref TARGET_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+pub fn set_target_root(root: Option<PathBuf>) {
+    *TARGET_ROOT.lock().unwrap() = root;
+}
+
+pub fn get_target_root() -> Option<PathBuf> {
+    TARGET_ROOT.lock().unwrap().clone()
+}
+
+// Where an absolute system path lives on the target, the path itself when
+// there is no target root
+pub fn in_target_root(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match get_target_root() {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+// Per user locations following the XDG base directory spec, used by the
+// rootless mode so unprivileged runs never need write access to /var.
+fn xdg_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    let base = match env::var_os(variable) {
+        Some(t) if !t.is_empty() => PathBuf::from(t),
+        _ => PathBuf::from(env::var_os("HOME")?).join(fallback),
+    };
+    Some(base.join("cfhdb"))
+}
+
+pub fn user_cache_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+pub fn user_state_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+// For codenames that end up in file names, nothing that could leave the
+// directory or needs quoting in a script
+pub(crate) fn validate_file_name(name: &str) -> Result<(), io::Error> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || "-_.".contains(x))
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' cannot be used in a file name", name),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn find_in_path(program: &str) -> Option<PathBuf> {
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}
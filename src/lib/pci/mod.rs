@@ -1,13 +1,25 @@
+use crate::{
+    cache::{sysfs_fingerprint, DeviceCache, DEFAULT_DEVICE_CACHE_TTL},
+    cancel::CancellationToken,
+    device::{find_by_id, group_by_class, CfhdbDevice},
+    ids::PciAddress,
+    matching::{match_profiles, MatchField},
+    modules::ModuleOption,
+    privilege,
+    profile::CfhdbProfile,
+    progress::{report, ProgressEvent, ProgressSink, ProgressStage},
+};
+use lazy_static::lazy_static;
 use regex::Regex;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{self, BufRead, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    io::{self, BufRead, ErrorKind},
     sync::{Arc, Mutex},
+    time::Duration,
 };
-use users::get_current_username;
+use tracing::{debug, instrument};
 
 // Implement Serialize for Arc<Mutex<Option<Vec<Arc<CfhdbPciProfile>>>>>
 
@@ -33,7 +45,39 @@ impl Serialize for ProfileWrapper {
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+// Loaded snapshots start without profiles, see usb::ProfileWrapper
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let _codenames: Option<Vec<String>> = Option::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
+fn from_hex(hex_number: u32, fill: usize) -> String {
+    format!("{:01$x}", hex_number, fill)
+}
+
+struct PciHeader {
+    class_name: String,
+    vendor_name: String,
+    device_name: String,
+    class_id: String,
+    device_id: String,
+    vendor_id: String,
+    sysfs_busid: String,
+}
+
+lazy_static! {
+    static ref PCI_DEVICE_CACHE: DeviceCache<CfhdbPciDevice> = DeviceCache::with_fingerprint(
+        DEFAULT_DEVICE_CACHE_TTL,
+        || sysfs_fingerprint("/sys/bus/pci/devices")
+    );
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbPciDevice {
     // String identification
     pub class_name: String,
@@ -71,36 +115,28 @@ impl CfhdbPciDevice {
         return None;
     }
 
-    pub fn set_available_profiles(profile_data: &[CfhdbPciProfile], device: &Self) {
-        let mut available_profiles: Vec<Arc<CfhdbPciProfile>> = vec![];
-        for profile in profile_data.iter() {
-            let matching = {
-                if (profile.blacklisted_class_ids.contains(&"*".to_owned())
-                    || profile.blacklisted_class_ids.contains(&device.class_id))
-                    || (profile.blacklisted_vendor_ids.contains(&"*".to_owned())
-                        || profile.blacklisted_vendor_ids.contains(&device.vendor_id))
-                    || (profile.blacklisted_device_ids.contains(&"*".to_owned())
-                        || profile.blacklisted_device_ids.contains(&device.device_id))
-                {
-                    false
-                } else {
-                    (profile.class_ids.contains(&"*".to_owned())
-                        || profile.class_ids.contains(&device.class_id))
-                        && (profile.vendor_ids.contains(&"*".to_owned())
-                            || profile.vendor_ids.contains(&device.vendor_id))
-                        && (profile.device_ids.contains(&"*".to_owned())
-                            || profile.device_ids.contains(&device.device_id))
-                }
-            };
+    #[instrument(
+        level = "trace",
+        skip_all,
+        fields(device = %device.sysfs_busid, profiles = profile_data.len())
+    )]
+    pub fn get_available_profiles(
+        profile_data: &[Arc<CfhdbPciProfile>],
+        device: &Self,
+    ) -> Vec<Arc<CfhdbPciProfile>> {
+        match_profiles(profile_data, device)
+    }
 
-            if matching {
-                available_profiles.push(Arc::new(profile.clone()));
-            };
+    pub fn attach_available_profiles(&self, profiles: Vec<Arc<CfhdbPciProfile>>) {
+        *self.available_profiles.0.lock().unwrap() = if profiles.is_empty() {
+            None
+        } else {
+            Some(profiles)
+        };
+    }
 
-            if !available_profiles.is_empty() {
-                *device.available_profiles.0.lock().unwrap() = Some(available_profiles.clone());
-            };
-        }
+    pub fn set_available_profiles(profile_data: &[Arc<CfhdbPciProfile>], device: &Self) {
+        device.attach_available_profiles(Self::get_available_profiles(profile_data, device));
     }
 
     fn get_started(busid: &str) -> Result<bool, io::Error> {
@@ -149,201 +185,272 @@ impl CfhdbPciDevice {
     }
 
     pub fn stop_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
+        privilege::run_sysfs_helper(&["stop_device", "pci", &self.sysfs_busid])?;
+        Self::invalidate_cache();
         Ok(())
     }
 
     pub fn start_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "pci",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "pci",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        };
-        cmd.run()?;
+        privilege::run_sysfs_helper(&[
+            "start_device",
+            "pci",
+            &self.sysfs_busid,
+            &Self::get_modinfo_name(&self.sysfs_busid).unwrap_or_default(),
+        ])?;
+        Self::invalidate_cache();
         Ok(())
     }
 
     pub fn enable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
+        privilege::run_sysfs_helper(&["enable_device", "pci", &self.sysfs_busid])?;
+        Self::invalidate_cache();
         Ok(())
     }
 
     pub fn disable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
+        privilege::run_sysfs_helper(&["disable_device", "pci", &self.sysfs_busid])?;
+        Self::invalidate_cache();
         Ok(())
     }
 
+    #[instrument(level = "debug")]
     pub fn get_device_from_busid(busid: &str) -> Result<CfhdbPciDevice, io::Error> {
-        let devices = match CfhdbPciDevice::get_devices() {
-            Some(t) => t,
-            None => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Could not get pci devices",
-                ));
-            }
-        };
-        match devices.iter().find(|x| x.sysfs_busid == busid) {
-            Some(device) => Ok(device.clone()),
-            None => Err(io::Error::new(
-                ErrorKind::NotFound,
-                "no pci device with matching busid",
-            )),
-        }
+        Ok(find_by_id(busid)?)
     }
 
-    pub fn get_devices() -> Option<Vec<Self>> {
-        let from_hex =
-            |hex_number: u32, fill: usize| -> String { format!("{:01$x}", hex_number, fill) };
+    #[cfg(feature = "async")]
+    pub async fn get_devices_async() -> Option<Vec<Self>> {
+        tokio::task::spawn_blocking(Self::get_devices)
+            .await
+            .unwrap_or(None)
+    }
 
+    #[instrument(level = "debug")]
+    fn get_headers() -> Option<Vec<PciHeader>> {
         // Initialize
         let mut pacc = libpci::PCIAccess::new(true);
 
         // Get hardware devices
         let pci_devices = pacc.devices()?;
-        let mut devices = vec![];
+        let mut headers = vec![];
 
         for mut iter in pci_devices.iter_mut() {
             // fill in header info we need
             iter.fill_info(libpci::Fill::IDENT as u32 | libpci::Fill::CLASS as u32);
 
-            let item_class = iter.class()?;
-            let item_vendor = iter.vendor()?;
-            let item_device = iter.device()?;
-            let item_class_id = from_hex(iter.class_id()? as _, 4).to_uppercase();
-            let item_device_id = from_hex(iter.device_id()? as _, 4);
-            let item_vendor_id = from_hex(iter.vendor_id()? as _, 4);
-            let item_sysfs_busid = format!(
-                "{}:{}:{}.{}",
-                from_hex(iter.domain()? as _, 4),
-                from_hex(iter.bus()? as _, 2),
-                from_hex(iter.dev()? as _, 2),
-                iter.func()?,
-            );
-            let item_started = Self::get_started(&item_sysfs_busid);
-            let item_enabled = Self::get_enabled(&item_sysfs_busid);
-            let item_sysfs_id = "".to_owned();
-            let item_kernel_driver =
-                Self::get_kernel_driver(&item_sysfs_busid).unwrap_or("Unknown".to_string());
-
-            devices.push(Self {
-                class_name: item_class,
-                device_name: item_device,
-                vendor_name: item_vendor,
-                class_id: item_class_id,
-                device_id: item_device_id,
-                vendor_id: item_vendor_id,
-                started: match item_started {
-                    Ok(t) => {
-                        if item_kernel_driver != "Unknown" {
-                            Some(t)
-                        } else {
-                            None
-                        }
-                    }
-                    Err(_) => None,
-                },
-                enabled: item_enabled,
-                sysfs_busid: item_sysfs_busid,
-                sysfs_id: item_sysfs_id,
-                kernel_driver: item_kernel_driver,
-                available_profiles: ProfileWrapper(Arc::default()),
+            headers.push(PciHeader {
+                class_name: iter.class()?,
+                vendor_name: iter.vendor()?,
+                device_name: iter.device()?,
+                class_id: from_hex(iter.class_id()? as _, 4).to_uppercase(),
+                device_id: from_hex(iter.device_id()? as _, 4),
+                vendor_id: from_hex(iter.vendor_id()? as _, 4),
+                sysfs_busid: format!(
+                    "{}:{}:{}.{}",
+                    from_hex(iter.domain()? as _, 4),
+                    from_hex(iter.bus()? as _, 2),
+                    from_hex(iter.dev()? as _, 2),
+                    iter.func()?,
+                ),
             });
         }
 
-        let mut uniq_devices = vec![];
-        for device in devices.iter() {
-            // Check if already in list
-            let found = uniq_devices.iter().any(|x: &Self| {
-                (device.sysfs_busid == x.sysfs_busid) && (device.sysfs_id == x.sysfs_id)
-            });
+        Some(headers)
+    }
 
-            if !found {
-                uniq_devices.push(device.clone());
-            }
+    fn from_header(header: PciHeader) -> Self {
+        let item_started = Self::get_started(&header.sysfs_busid);
+        let item_enabled = Self::get_enabled(&header.sysfs_busid);
+        let item_sysfs_id = "".to_owned();
+        let item_kernel_driver =
+            Self::get_kernel_driver(&header.sysfs_busid).unwrap_or("Unknown".to_string());
+
+        Self {
+            class_name: header.class_name,
+            device_name: header.device_name,
+            vendor_name: header.vendor_name,
+            class_id: header.class_id,
+            device_id: header.device_id,
+            vendor_id: header.vendor_id,
+            started: match item_started {
+                Ok(t) => {
+                    if item_kernel_driver != "Unknown" {
+                        Some(t)
+                    } else {
+                        None
+                    }
+                }
+                Err(_) => None,
+            },
+            enabled: item_enabled,
+            sysfs_busid: header.sysfs_busid,
+            sysfs_id: item_sysfs_id,
+            kernel_driver: item_kernel_driver,
+            available_profiles: ProfileWrapper(Arc::default()),
         }
-        Some(uniq_devices)
     }
 
-    pub fn create_class_hashmap(devices: Vec<Self>) -> HashMap<String, Vec<Self>> {
-        let mut map: HashMap<String, Vec<Self>> = HashMap::new();
+    fn iter_devices_reporting<'a>(
+        progress: Option<&'a dyn ProgressSink>,
+    ) -> Option<impl Iterator<Item = Self> + 'a> {
+        report(progress, ProgressEvent::stage(ProgressStage::Enumerating));
+        let headers = Self::get_headers()?;
+        let total = headers.len();
+        let mut seen_busids = HashSet::new();
+
+        Some(
+            headers
+                .into_iter()
+                .enumerate()
+                .filter_map(move |(index, header)| {
+                    report(
+                        progress,
+                        ProgressEvent::percent(ProgressStage::Enumerating, index + 1, total),
+                    );
+                    // Check if already in list
+                    if !seen_busids.insert(header.sysfs_busid.clone()) {
+                        return None;
+                    }
+                    Some(Self::from_header(header))
+                }),
+        )
+    }
+
+    // libpci only hands out its device list while the access handle is borrowed,
+    // so the config space headers are read upfront and the sysfs probing for each
+    // device happens as the iterator advances.
+    pub fn iter_devices() -> Option<impl Iterator<Item = Self>> {
+        Self::iter_devices_reporting(None)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    pub fn get_devices_with_progress(progress: &dyn ProgressSink) -> Option<Vec<Self>> {
+        let devices = Self::iter_devices_reporting(Some(progress))?.collect();
+        progress.report(ProgressEvent::stage(ProgressStage::Finished));
+        Some(devices)
+    }
 
+    #[instrument(level = "debug", skip_all)]
+    pub fn get_devices_cancellable(
+        progress: Option<&dyn ProgressSink>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Self>, io::Error> {
+        let devices = match Self::iter_devices_reporting(progress) {
+            Some(t) => t,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Could not get pci devices",
+                ));
+            }
+        };
+        let mut result = vec![];
         for device in devices {
-            // Use the entry API to get or create a Vec for the key
-            map.entry(device.class_id.clone())
-                .or_insert_with(Vec::new)
-                .push(device);
+            cancel.check()?;
+            result.push(device);
         }
+        Ok(result)
+    }
 
-        map
+    #[instrument(level = "debug")]
+    pub fn get_devices() -> Option<Vec<Self>> {
+        let devices: Vec<Self> = Self::iter_devices()?.collect();
+        debug!(count = devices.len(), "enumerated pci devices");
+        Some(devices)
+    }
+
+    pub fn get_devices_cached() -> Option<Vec<Self>> {
+        PCI_DEVICE_CACHE.get_or_refresh(Self::get_devices)
+    }
+
+    pub fn set_cache_ttl(ttl: Duration) {
+        PCI_DEVICE_CACHE.set_ttl(ttl)
+    }
+
+    pub fn invalidate_cache() {
+        PCI_DEVICE_CACHE.invalidate()
+    }
+
+    pub fn create_class_hashmap(devices: Vec<Self>) -> HashMap<String, Vec<Self>> {
+        group_by_class(devices)
+    }
+}
+
+impl CfhdbDevice for CfhdbPciDevice {
+    type Profile = CfhdbPciProfile;
+    const CATEGORY: &'static str = "pci";
+
+    fn get_match_fields<'a>(&'a self, profile: &'a CfhdbPciProfile) -> Vec<MatchField<'a>> {
+        vec![
+            MatchField::new(
+                &profile.class_ids,
+                &profile.blacklisted_class_ids,
+                &self.class_id,
+            ),
+            MatchField::new(
+                &profile.vendor_ids,
+                &profile.blacklisted_vendor_ids,
+                &self.vendor_id,
+            ),
+            MatchField::new(
+                &profile.device_ids,
+                &profile.blacklisted_device_ids,
+                &self.device_id,
+            ),
+        ]
+    }
+
+    fn get_id(&self) -> String {
+        self.sysfs_busid.clone()
+    }
+
+    fn get_class(&self) -> String {
+        self.class_id.clone()
+    }
+
+    fn get_display_fields(&self) -> (String, String) {
+        (self.vendor_name.clone(), self.device_name.clone())
+    }
+
+    fn get_matched_profiles(&self) -> Option<Vec<Arc<CfhdbPciProfile>>> {
+        self.available_profiles.0.lock().unwrap().clone()
+    }
+
+    fn attach_profiles(&self, profiles: Vec<Arc<CfhdbPciProfile>>) {
+        self.attach_available_profiles(profiles)
+    }
+
+    fn iter_all() -> Option<Box<dyn Iterator<Item = Self>>> {
+        Some(Box::new(Self::iter_devices()?))
+    }
+
+    fn normalize_id(id: &str) -> Result<String, io::Error> {
+        Ok(id.parse::<PciAddress>()?.as_str().to_owned())
+    }
+
+    fn enable_device(&self) -> Result<(), io::Error> {
+        CfhdbPciDevice::enable_device(self)
+    }
+
+    fn disable_device(&self) -> Result<(), io::Error> {
+        CfhdbPciDevice::disable_device(self)
+    }
+
+    fn start_device(&self) -> Result<(), io::Error> {
+        CfhdbPciDevice::start_device(self)
+    }
+
+    fn stop_device(&self) -> Result<(), io::Error> {
+        CfhdbPciDevice::stop_device(self)
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CfhdbPciProfile {
     pub codename: String,
     pub i18n_desc: String,
+    #[serde(default = "crate::profile::default_icon_name")]
     pub icon_name: String,
     pub license: String,
     pub class_ids: Vec<String>,
@@ -352,53 +459,132 @@ pub struct CfhdbPciProfile {
     pub blacklisted_class_ids: Vec<String>,
     pub blacklisted_vendor_ids: Vec<String>,
     pub blacklisted_device_ids: Vec<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_packages")]
     pub packages: Option<Vec<String>>,
+    #[serde(default = "crate::profile::default_check_script")]
     pub check_script: String,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub install_script: Option<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub remove_script: Option<String>,
     pub experimental: bool,
     pub removable: bool,
     pub veiled: bool,
+    pub unsandboxed_check: bool,
+    pub check_is_pure: bool,
+    pub flatpaks: Option<Vec<String>>,
+    pub snaps: Option<Vec<String>>,
+    pub signature: Option<String>,
+    pub superseded_by: Option<String>,
+    pub regenerate_initramfs: bool,
+    #[serde(with = "crate::modules::options_map")]
+    pub module_options: Vec<ModuleOption>,
+    pub blacklisted_modules: Vec<String>,
+    pub kernel_parameters: Vec<String>,
     pub priority: i32,
 }
 
 impl CfhdbPciProfile {
     pub fn get_profile_from_codename(
         codename: &str,
-        profiles: Vec<CfhdbPciProfile>,
+        profiles: &[Arc<CfhdbPciProfile>],
     ) -> Result<Self, io::Error> {
         match profiles.iter().find(|x| x.codename == codename) {
-            Some(profile) => Ok(profile.clone()),
+            Some(profile) => Ok(profile.as_ref().clone()),
             None => Err(io::Error::new(
                 ErrorKind::NotFound,
                 "no pci profile with matching codename",
             )),
         }
     }
+}
 
-    pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+impl CfhdbProfile for CfhdbPciProfile {
+    fn get_codename(&self) -> &str {
+        &self.codename
+    }
+
+    fn get_i18n_desc(&self) -> &str {
+        &self.i18n_desc
+    }
+
+    fn get_icon_name(&self) -> &str {
+        &self.icon_name
+    }
+
+    fn get_license(&self) -> &str {
+        &self.license
+    }
+
+    fn get_packages(&self) -> Option<&[String]> {
+        self.packages.as_deref()
+    }
+
+    fn get_check_script(&self) -> &str {
+        &self.check_script
+    }
+
+    fn get_install_script(&self) -> Option<&str> {
+        self.install_script.as_deref()
+    }
+
+    fn get_remove_script(&self) -> Option<&str> {
+        self.remove_script.as_deref()
+    }
+
+    fn is_experimental(&self) -> bool {
+        self.experimental
+    }
+
+    fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    fn is_veiled(&self) -> bool {
+        self.veiled
+    }
+
+    fn get_priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn is_check_unsandboxed(&self) -> bool {
+        self.unsandboxed_check
+    }
+
+    fn get_signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn is_check_pure(&self) -> bool {
+        self.check_is_pure
+    }
+
+    fn get_flatpaks(&self) -> Option<&[String]> {
+        self.flatpaks.as_deref()
+    }
+
+    fn get_snaps(&self) -> Option<&[String]> {
+        self.snaps.as_deref()
+    }
+
+    fn get_superseded_by(&self) -> Option<&str> {
+        self.superseded_by.as_deref()
+    }
+
+    fn regenerates_initramfs(&self) -> bool {
+        self.regenerate_initramfs
+    }
+
+    fn get_module_options(&self) -> &[ModuleOption] {
+        &self.module_options
+    }
+
+    fn get_blacklisted_modules(&self) -> &[String] {
+        &self.blacklisted_modules
+    }
+
+    fn get_kernel_parameters(&self) -> &[String] {
+        &self.kernel_parameters
     }
 }
@@ -0,0 +1,214 @@
+use crate::paths::find_in_path;
+use lazy_static::lazy_static;
+use std::{
+    fmt,
+    io::{self, BufRead, ErrorKind, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    str::FromStr,
+    sync::Mutex,
+};
+use tracing::debug;
+use users::get_current_username;
+
+pub const SYSFS_HELPER: &str = "/usr/lib/cfhdb/scripts/sysfs_helper.sh";
+// Seconds the helper waits for the next operation before it exits
+const HELPER_IDLE_TIMEOUT: u64 = 300;
+
+lazy_static! {
+    // None means autodetect on first use
+    static ref ESCALATION_BACKEND: Mutex<Option<EscalationBackend>> = Mutex::new(None);
+    static ref SYSFS_HELPER_SESSION: Mutex<Option<HelperSession>> = Mutex::new(None);
+}
+
+// How root helpers get started for a regular user. pkexec needs polkit, which
+// headless servers often lack, the others only need to be installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscalationBackend {
+    Pkexec,
+    Run0,
+    Sudo,
+    Doas,
+}
+
+impl EscalationBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EscalationBackend::Pkexec => "pkexec",
+            EscalationBackend::Run0 => "run0",
+            EscalationBackend::Sudo => "sudo",
+            EscalationBackend::Doas => "doas",
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        if find_in_path(self.as_str()).is_none() {
+            return false;
+        }
+        match self {
+            EscalationBackend::Pkexec => [
+                "/usr/lib/polkit-1/polkitd",
+                "/usr/libexec/polkitd",
+                "/usr/lib/polkit/polkitd",
+            ]
+            .iter()
+            .any(|x| Path::new(x).exists()),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for EscalationBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for EscalationBackend {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "pkexec" => Ok(EscalationBackend::Pkexec),
+            "run0" => Ok(EscalationBackend::Run0),
+            "sudo" => Ok(EscalationBackend::Sudo),
+            "doas" => Ok(EscalationBackend::Doas),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "'{}' is not a known escalation backend, expected pkexec, run0, sudo or doas",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+// First installed backend in order of preference
+pub fn detect_escalation_backend() -> Option<EscalationBackend> {
+    [
+        EscalationBackend::Pkexec,
+        EscalationBackend::Run0,
+        EscalationBackend::Sudo,
+        EscalationBackend::Doas,
+    ]
+    .into_iter()
+    .find(|x| x.is_available())
+}
+
+pub fn set_escalation_backend(backend: Option<EscalationBackend>) {
+    *ESCALATION_BACKEND.lock().unwrap() = backend;
+}
+
+pub fn get_escalation_backend() -> Result<EscalationBackend, io::Error> {
+    let mut configured = ESCALATION_BACKEND.lock().unwrap();
+    if let Some(backend) = *configured {
+        return Ok(backend);
+    }
+    match detect_escalation_backend() {
+        Some(t) => {
+            debug!(backend = %t, "detected escalation backend");
+            *configured = Some(t);
+            Ok(t)
+        }
+        None => Err(io::Error::new(
+            ErrorKind::NotFound,
+            "none of pkexec, run0, sudo or doas is installed",
+        )),
+    }
+}
+
+pub fn is_root() -> bool {
+    get_current_username().is_some_and(|x| x == "root")
+}
+
+// Runs program directly as root, or through the escalation backend otherwise
+pub fn elevated_cmd(program: &str, args: &[&str]) -> Result<duct::Expression, io::Error> {
+    if is_root() {
+        return Ok(duct::cmd(program, args));
+    }
+    let mut full_args = vec![program];
+    full_args.extend_from_slice(args);
+    Ok(duct::cmd(get_escalation_backend()?.as_str(), full_args))
+}
+
+// sysfs_helper.sh in serve mode, started through the escalation backend once
+// and then fed one operation per line
+struct HelperSession {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: io::BufReader<ChildStdout>,
+}
+
+impl HelperSession {
+    fn spawn() -> Result<Self, io::Error> {
+        let backend = get_escalation_backend()?;
+        debug!(backend = %backend, "starting the privileged helper");
+        let mut child = Command::new(backend.as_str())
+            .arg(SYSFS_HELPER)
+            .arg("serve")
+            .arg(HELPER_IDLE_TIMEOUT.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = io::BufReader::new(child.stdout.take().unwrap());
+        Ok(HelperSession {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    // The exit status of the operation, None once the helper is gone
+    fn request(&mut self, args: &[&str]) -> Option<i32> {
+        writeln!(self.stdin, "{}", args.join(" ")).ok()?;
+        self.stdin.flush().ok()?;
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        line.trim().parse().ok()
+    }
+}
+
+impl Drop for HelperSession {
+    fn drop(&mut self) {
+        // The helper exits once its stdin is closed, which the kill covers
+        // too when it is stuck
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Runs a sysfs_helper.sh operation as root. Regular users go through a helper
+// that stays up between operations, so a batch of device changes asks for
+// the password once instead of once per device.
+pub fn run_sysfs_helper(args: &[&str]) -> Result<(), io::Error> {
+    if is_root() {
+        duct::cmd(SYSFS_HELPER, args).run()?;
+        return Ok(());
+    }
+    let mut session = SYSFS_HELPER_SESSION.lock().unwrap();
+    let mut status = session.as_mut().and_then(|x| x.request(args));
+    if status.is_none() {
+        // Not started yet, or it exited after the idle timeout
+        let mut fresh = HelperSession::spawn()?;
+        status = fresh.request(args);
+        *session = Some(fresh);
+    }
+    match status {
+        Some(0) => Ok(()),
+        Some(code) => Err(io::Error::other(format!(
+            "sysfs helper {} failed with status {}",
+            args[0], code
+        ))),
+        None => {
+            *session = None;
+            Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "the privileged helper did not start, authentication may have been refused",
+            ))
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{paths::find_in_path, privilege, status::DeviceStatus};
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    io::{self, ErrorKind},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::instrument;
+
+// The linux-hardware.org client, the site only takes probes in its format
+pub const HW_PROBE: &str = "hw-probe";
+
+// What a device contributes to a probe. Bus ids and bluetooth addresses are
+// left out, together with serials they could single out a machine.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProbeDevice {
+    pub category: &'static str,
+    pub vendor: String,
+    pub name: String,
+    // Codenames of the matched profiles, empty for hardware the database
+    // does not cover yet
+    pub profiles: Vec<String>,
+    pub installed: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HardwareProbe {
+    pub cfhdb_version: String,
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    pub devices: Vec<ProbeDevice>,
+}
+
+pub fn build_probe(cfhdb_version: &str, devices: &[DeviceStatus]) -> HardwareProbe {
+    let mut devices: Vec<ProbeDevice> = devices
+        .iter()
+        .map(|x| ProbeDevice {
+            category: x.category,
+            vendor: x.vendor.clone(),
+            name: x.name.clone(),
+            profiles: x.profiles.clone(),
+            installed: x.installed.clone(),
+        })
+        .collect();
+    // Enumeration order follows the bus layout, which is not needed either
+    devices.sort_by(|a, b| (a.category, &a.vendor, &a.name).cmp(&(b.category, &b.vendor, &b.name)));
+    HardwareProbe {
+        cfhdb_version: cfhdb_version.to_owned(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default(),
+        devices,
+    }
+}
+
+fn parse_probe_url(output: &str) -> Option<String> {
+    let re = Regex::new(r"https://linux-hardware\.org/\?probe=[0-9a-f]+").unwrap();
+    re.find(output).map(|x| x.as_str().to_owned())
+}
+
+// hw-probe collects and uploads on its own, the probe of build_probe is not
+// part of it. -probe limits it to the hardware and its logs, -all would add
+// the system logs as well. It needs root to read everything and anonymizes
+// serials and addresses before the upload. Returns the probe URL it printed.
+#[instrument]
+pub fn upload_probe() -> Result<String, io::Error> {
+    if find_in_path(HW_PROBE).is_none() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} is not installed", HW_PROBE),
+        ));
+    }
+    let output = privilege::elevated_cmd(HW_PROBE, &["-probe", "-upload"])?
+        .stdin_null()
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match parse_probe_url(&stdout) {
+        Some(t) if output.status.success() => Ok(t),
+        _ => Err(io::Error::other(format!(
+            "{} failed: {}",
+            HW_PROBE,
+            stdout.trim()
+        ))),
+    }
+}
@@ -0,0 +1,857 @@
+use crate::{
+    cache::{
+        get_cached_status, get_cached_status_with_ttl, invalidate_status_cache,
+        PURE_STATUS_CACHE_TTL,
+    },
+    cancel::CancellationToken,
+    initramfs,
+    journal::{self, stage_marker, Journal, JournalEntry, JournalSink, JournalStage},
+    kmod, kparams,
+    modules::{self, ModprobeDropin, ModuleOption},
+    packages::{
+        check_constraints, collect_results, download_command_line, install_command_line,
+        installed_entries, plan_install as plan_install_packages, repair_command_line,
+        uninstall_command_line, FlatpakBackend, PackageBackend, PackagePlan, PackageStatus,
+        SnapBackend,
+    },
+    progress::{report, ProgressEvent, ProgressSink, ProgressStage},
+    script::{run_check_script, run_in_lock_script_with_options, CheckSandbox},
+    signing::verify_profile_scripts,
+    snapshot::{self, Snapshot},
+    transaction::{self, Transaction, TransactionKind},
+};
+use serde::{Deserialize, Deserializer};
+use std::{error::Error, fmt, io};
+use tracing::{debug, instrument, warn};
+
+// Common surface shared by the profiles of every hardware category, so
+// install/uninstall/status go through one pipeline instead of a copy per category.
+pub trait CfhdbProfile {
+    fn get_codename(&self) -> &str;
+    fn get_i18n_desc(&self) -> &str;
+    fn get_icon_name(&self) -> &str;
+    fn get_license(&self) -> &str;
+    fn get_packages(&self) -> Option<&[String]>;
+    fn get_check_script(&self) -> &str;
+    fn get_install_script(&self) -> Option<&str>;
+    fn get_remove_script(&self) -> Option<&str>;
+    fn is_experimental(&self) -> bool;
+    fn is_removable(&self) -> bool;
+    fn is_veiled(&self) -> bool;
+    fn get_priority(&self) -> i32;
+    // Opt out of the check script sandbox, for checks that need the network or
+    // write access to the system
+    fn is_check_unsandboxed(&self) -> bool;
+    fn get_signature(&self) -> Option<&str>;
+    // The check only inspects the filesystem. It is always sandboxed, even if
+    // is_check_unsandboxed() says otherwise, and its result is cached longer.
+    fn is_check_pure(&self) -> bool;
+    // App ids installed from flathub and snap names, next to the distro packages
+    fn get_flatpaks(&self) -> Option<&[String]>;
+    fn get_snaps(&self) -> Option<&[String]>;
+    // Codename of the profile that replaces this one, `cfhdb upgrade` moves
+    // installs over to it
+    fn get_superseded_by(&self) -> Option<&str>;
+    // Rebuild the initramfs after installing or removing, for storage and GPU
+    // drivers that have to be there at boot
+    fn regenerates_initramfs(&self) -> bool;
+    // Written to a modprobe.d file owned by the profile instead of echoed
+    // from its scripts, so removing the profile takes them back out
+    fn get_module_options(&self) -> &[ModuleOption];
+    fn get_blacklisted_modules(&self) -> &[String];
+    // Added to the kernel command line through the bootloader for as long as
+    // the profile is installed
+    fn get_kernel_parameters(&self) -> &[String];
+
+    fn get_status(&self) -> bool {
+        if self.is_check_pure() {
+            get_cached_status_with_ttl(
+                self.get_codename(),
+                self.get_check_script(),
+                PURE_STATUS_CACHE_TTL,
+                || run_profile_check_script(self),
+            )
+        } else {
+            get_cached_status(self.get_codename(), self.get_check_script(), || {
+                run_profile_check_script(self)
+            })
+        }
+    }
+}
+
+fn run_profile_check_script<P: CfhdbProfile + ?Sized>(profile: &P) -> bool {
+    if let Err(e) = verify_profile_scripts(profile) {
+        warn!(error = %e, "refusing to run check script");
+        return false;
+    }
+    let sandbox = if profile.is_check_unsandboxed() && !profile.is_check_pure() {
+        CheckSandbox::Disabled
+    } else {
+        CheckSandbox::Enabled
+    };
+    run_check_script(profile.get_check_script(), sandbox)
+}
+
+// Field defaults and formats of the profile databases, shared by the serde
+// derives of every category
+pub(crate) fn default_icon_name() -> String {
+    "package-x-generic".to_owned()
+}
+
+// A profile without a check is never reported as installed
+pub(crate) fn default_check_script() -> String {
+    "false".to_owned()
+}
+
+// Databases write "Option::is_none" for a profile without the script
+pub(crate) fn deserialize_script<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let script: Option<String> = Option::deserialize(deserializer)?;
+    Ok(script.filter(|x| x != "Option::is_none"))
+}
+
+// Any string in place of the list, usually "Option::is_none", means the
+// profile has no distro packages
+pub(crate) fn deserialize_packages<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Packages {
+        List(Vec<String>),
+        // Only there to accept the string, its content is not used
+        Marker(#[allow(dead_code)] String),
+    }
+    Ok(match Option::deserialize(deserializer)? {
+        Some(Packages::List(t)) => Some(t),
+        Some(Packages::Marker(_)) | None => None,
+    })
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct ProfileOperationOptions<'a> {
+    pub progress: Option<&'a dyn ProgressSink>,
+    pub cancel: Option<&'a CancellationToken>,
+    // Remove the packages an install added when its script fails afterwards
+    pub atomic: bool,
+    // Install even if the check script passes, to apply a changed definition
+    pub reapply: bool,
+}
+
+// Packages a failed install left on the system, grouped by the backend that
+// installed them. It travels inside the io::Error so frontends can offer to
+// remove them with rollback_partial_install.
+#[derive(Debug, Clone)]
+pub struct PartialInstallError {
+    pub codename: String,
+    pub message: String,
+    pub installed: Vec<(&'static str, Vec<String>)>,
+}
+
+impl PartialInstallError {
+    pub fn from_io_error(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    pub fn packages(&self) -> Vec<&str> {
+        self.installed
+            .iter()
+            .flat_map(|(_, packages)| packages.iter().map(|x| x.as_str()))
+            .collect()
+    }
+}
+
+impl fmt::Display for PartialInstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for PartialInstallError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileOperationOutcome {
+    Completed,
+    AlreadyInstalled,
+    NotInstalled,
+    NothingToDo,
+}
+
+// The header comment names the operation, so the copy the lock helper keeps
+// under /var/lib/cfhdb/transactions can be traced back to a profile.
+fn compose_script(
+    operation: &str,
+    codename: &str,
+    packages_cmd: Option<String>,
+    script: Option<&str>,
+) -> Option<String> {
+    let header = format!("#! /bin/bash\n# cfhdb {} {}\nset -e", operation, codename);
+    match (packages_cmd, script) {
+        (Some(a), Some(b)) => Some(format!("{}\n{}\n{}", header, a, b)),
+        (Some(a), None) => Some(format!("{}\n{}", header, a)),
+        (None, Some(b)) => Some(format!("{}\n{}", header, b)),
+        (None, None) => None,
+    }
+}
+
+// The install or remove script of the profile, with the configuration cfhdb
+// manages for it (modprobe.d file, kernel parameters) written before the
+// install script and removed after the remove script
+fn profile_script<P: CfhdbProfile>(
+    profile: &P,
+    install: bool,
+) -> Result<Option<String>, io::Error> {
+    let codename = profile.get_codename();
+    let script = if install {
+        profile.get_install_script()
+    } else {
+        profile.get_remove_script()
+    };
+    let mut managed = vec![];
+    let dropin = ModprobeDropin {
+        blacklist: profile.get_blacklisted_modules().to_vec(),
+        options: profile.get_module_options().to_vec(),
+    };
+    if !dropin.is_empty() {
+        let file_name = modules::profile_dropin_name(codename)?;
+        managed.push(if install {
+            modules::compose_write_script(
+                &file_name,
+                &dropin,
+                &format!(
+                    "Generated by cfhdb for the {} profile, removed with it",
+                    codename
+                ),
+            )?
+        } else {
+            modules::compose_remove_script(&file_name)
+        });
+    }
+    let parameters = profile.get_kernel_parameters();
+    if !parameters.is_empty() {
+        managed.push(if install {
+            kparams::compose_install_script(codename, parameters)?
+        } else {
+            kparams::compose_remove_script(codename)?
+        });
+    }
+    if managed.is_empty() {
+        return Ok(script.map(|x| x.to_owned()));
+    }
+    let managed = managed.join("\n");
+    Ok(Some(match script {
+        Some(t) if install => format!("{}\n{}", managed, t),
+        Some(t) => format!("{}\n{}", t, managed),
+        None => managed,
+    }))
+}
+
+// Every backend a profile declares packages for, distro packages first so
+// flatpak and snap are there by the time a profile pulls them in.
+fn package_sets<'a, P: CfhdbProfile>(
+    profile: &'a P,
+    backend: &'a dyn PackageBackend,
+) -> Vec<(&'a dyn PackageBackend, &'a [String])> {
+    [
+        (backend, profile.get_packages()),
+        (
+            &FlatpakBackend as &dyn PackageBackend,
+            profile.get_flatpaks(),
+        ),
+        (&SnapBackend as &dyn PackageBackend, profile.get_snaps()),
+    ]
+    .into_iter()
+    .filter_map(|(backend, packages)| match packages {
+        Some(t) if !t.is_empty() => Some((backend, t)),
+        _ => None,
+    })
+    .collect()
+}
+
+// Downloads go first where the package manager can split them off, and each
+// stage announces itself so the install journal can follow along
+pub fn compose_install_script<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+) -> Result<Option<String>, io::Error> {
+    let package_sets = package_sets(profile, backend);
+    let mut downloads = vec![];
+    for (backend, packages) in &package_sets {
+        downloads.extend(download_command_line(*backend, packages)?);
+    }
+    let mut packages_cmds = vec![];
+    if !downloads.is_empty() {
+        packages_cmds.push(stage_marker(JournalStage::Download));
+        packages_cmds.extend(downloads);
+    }
+    if !package_sets.is_empty() {
+        packages_cmds.push(stage_marker(JournalStage::Packages));
+    }
+    for (backend, packages) in package_sets {
+        packages_cmds.push(install_command_line(backend, packages)?);
+    }
+    let script = profile_script(profile, true)?
+        .map(|x| format!("{}\n{}", stage_marker(JournalStage::Script), x));
+    Ok(initramfs::append_to(
+        compose_script(
+            "install",
+            profile.get_codename(),
+            Some(packages_cmds.join("\n")).filter(|x| !x.is_empty()),
+            script.as_deref(),
+        ),
+        profile.regenerates_initramfs(),
+    ))
+}
+
+pub fn compose_uninstall_script<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+) -> Result<Option<String>, io::Error> {
+    let mut packages_cmds = vec![];
+    for (backend, packages) in package_sets(profile, backend).into_iter().rev() {
+        packages_cmds.push(uninstall_command_line(backend, packages)?);
+    }
+    let script = profile_script(profile, false)?;
+    Ok(initramfs::append_to(
+        compose_script(
+            "uninstall",
+            profile.get_codename(),
+            Some(packages_cmds.join("\n")).filter(|x| !x.is_empty()),
+            script.as_deref(),
+        ),
+        profile.regenerates_initramfs(),
+    ))
+}
+
+// Package sets are recorded by backend name, anything but flatpak and snap
+// went through the distro backend
+fn backend_by_name<'a>(name: &str, backend: &'a dyn PackageBackend) -> &'a dyn PackageBackend {
+    match name {
+        "flatpak" => &FlatpakBackend,
+        "snap" => &SnapBackend,
+        _ => backend,
+    }
+}
+
+// The profile packages that went in (or out) since `before` was taken
+fn changed_since<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+    before: &[(&'static str, Vec<String>)],
+    added: bool,
+) -> Vec<(&'static str, Vec<String>)> {
+    package_sets(profile, backend)
+        .into_iter()
+        .filter_map(|(backend, packages)| {
+            let before = before
+                .iter()
+                .find(|(name, _)| *name == backend.name())
+                .map(|(_, x)| x.as_slice())
+                .unwrap_or_default();
+            let after = installed_entries(backend, packages);
+            let changed: Vec<String> = if added {
+                after.into_iter().filter(|x| !before.contains(x)).collect()
+            } else {
+                before
+                    .iter()
+                    .filter(|x| !after.contains(x))
+                    .cloned()
+                    .collect()
+            };
+            Some((backend.name(), changed)).filter(|(_, x)| !x.is_empty())
+        })
+        .collect()
+}
+
+// Reinstalls or removes the given package sets, then runs the script that
+// takes the profile the rest of the way back
+fn compose_reverse_script<N: AsRef<str>>(
+    codename: &str,
+    backend: &dyn PackageBackend,
+    packages: &[(N, Vec<String>)],
+    reinstall: bool,
+    script: Option<&str>,
+    regenerate_initramfs: bool,
+) -> Result<Option<String>, io::Error> {
+    let mut packages_cmds = vec![];
+    if reinstall {
+        for (name, packages) in packages {
+            packages_cmds.push(install_command_line(
+                backend_by_name(name.as_ref(), backend),
+                packages,
+            )?);
+        }
+    } else {
+        for (name, packages) in packages.iter().rev() {
+            packages_cmds.push(uninstall_command_line(
+                backend_by_name(name.as_ref(), backend),
+                packages,
+            )?);
+        }
+    }
+    Ok(initramfs::append_to(
+        compose_script(
+            "rollback",
+            codename,
+            Some(packages_cmds.join("\n")).filter(|x| !x.is_empty()),
+            script,
+        ),
+        regenerate_initramfs,
+    ))
+}
+
+// Records a finished install or uninstall for `cfhdb rollback`. An install
+// is undone by removing only the packages it added plus the remove script, an
+// uninstall by putting back what it removed plus the install script.
+fn record_transaction<P: CfhdbProfile>(
+    kind: TransactionKind,
+    profile: &P,
+    backend: &dyn PackageBackend,
+    before: &[(&'static str, Vec<String>)],
+    snapshot: Option<Snapshot>,
+) {
+    let mut transaction = Transaction::new(kind, profile.get_codename());
+    transaction.snapshot = snapshot;
+    let reverse_script = if kind == TransactionKind::Install {
+        let added = changed_since(profile, backend, before, true);
+        let script = profile_script(profile, false).and_then(|script| {
+            compose_reverse_script(
+                profile.get_codename(),
+                backend,
+                &added,
+                false,
+                script.as_deref(),
+                profile.regenerates_initramfs(),
+            )
+        });
+        transaction.added = added
+            .into_iter()
+            .map(|(name, x)| (name.to_owned(), x))
+            .collect();
+        script
+    } else {
+        let removed = changed_since(profile, backend, before, false);
+        let script = profile_script(profile, true).and_then(|script| {
+            compose_reverse_script(
+                profile.get_codename(),
+                backend,
+                &removed,
+                true,
+                script.as_deref(),
+                profile.regenerates_initramfs(),
+            )
+        });
+        transaction.removed = removed
+            .into_iter()
+            .map(|(name, x)| (name.to_owned(), x))
+            .collect();
+        script
+    };
+    transaction.reverse_script = match reverse_script {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(error = %e, "could not compose the script that reverts this operation");
+            None
+        }
+    };
+    transaction::record(&transaction);
+}
+
+// Runs the reverse script of a recorded transaction and records the rollback,
+// so the next rollback goes one transaction further back
+pub fn rollback_transaction(
+    reverted: &Transaction,
+    options: &ProfileOperationOptions,
+) -> Result<(), io::Error> {
+    let script = match &reverted.reverse_script {
+        Some(t) => t,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("transaction {} cannot be reverted", reverted.id),
+            ))
+        }
+    };
+    let stage = if reverted.kind == TransactionKind::Uninstall {
+        ProgressStage::Installing
+    } else {
+        ProgressStage::Uninstalling
+    };
+    let result = run_in_lock_script_with_options(script, stage, options.progress, options.cancel);
+    invalidate_status_cache();
+    result?;
+    let mut transaction = Transaction::new(TransactionKind::Rollback, &reverted.codename);
+    transaction.added = reverted.removed.clone();
+    transaction.removed = reverted.added.clone();
+    transaction.reverts = Some(reverted.id.clone());
+    transaction::record(&transaction);
+    report(
+        options.progress,
+        ProgressEvent::stage(ProgressStage::Finished),
+    );
+    Ok(())
+}
+
+// Finishes an install the journal says was cut short. Whatever the package
+// manager left half configured is repaired first, then the install runs again
+// from the top, which skips the packages that already made it.
+pub fn resume_interrupted(
+    entry: &JournalEntry,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<(), io::Error> {
+    let script = compose_script(
+        "resume",
+        &entry.codename,
+        repair_command_line(backend),
+        Some(entry.install_script.as_str()),
+    )
+    .unwrap_or_default();
+    let result = run_in_lock_script_with_options(
+        &script,
+        ProgressStage::Installing,
+        options.progress,
+        options.cancel,
+    );
+    invalidate_status_cache();
+    result?;
+    journal::discard(&entry.id)
+}
+
+// Takes a cut short install back out, removing only the packages it added.
+// The remove script only runs if the install script got to start.
+pub fn rollback_interrupted(
+    entry: &JournalEntry,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<(), io::Error> {
+    let added: Vec<(&str, Vec<String>)> = entry
+        .package_sets
+        .iter()
+        .filter_map(|(name, packages)| {
+            let before = entry
+                .installed_before
+                .iter()
+                .find(|(x, _)| x == name)
+                .map(|(_, x)| x.as_slice())
+                .unwrap_or_default();
+            let added: Vec<String> = installed_entries(backend_by_name(name, backend), packages)
+                .into_iter()
+                .filter(|x| !before.contains(x))
+                .collect();
+            Some((name.as_str(), added)).filter(|(_, x)| !x.is_empty())
+        })
+        .collect();
+    let remove_script = match entry.stage {
+        JournalStage::Script => entry.remove_script.as_deref(),
+        _ => None,
+    };
+    let reverse = compose_reverse_script(
+        &entry.codename,
+        backend,
+        &added,
+        false,
+        remove_script,
+        initramfs::script_regenerates(&entry.install_script),
+    )?;
+    if let Some(script) = compose_script(
+        "rollback",
+        &entry.codename,
+        repair_command_line(backend),
+        reverse.as_deref(),
+    ) {
+        let result = run_in_lock_script_with_options(
+            &script,
+            ProgressStage::Uninstalling,
+            options.progress,
+            options.cancel,
+        );
+        invalidate_status_cache();
+        result?;
+    }
+    journal::discard(&entry.id)
+}
+
+// Removes what a failed install added, newest backend first like an uninstall
+pub fn rollback_partial_install(
+    partial: &PartialInstallError,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<(), io::Error> {
+    let script = match compose_reverse_script(
+        &partial.codename,
+        backend,
+        &partial.installed,
+        false,
+        None,
+        false,
+    )? {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let result = run_in_lock_script_with_options(
+        &script,
+        ProgressStage::Uninstalling,
+        options.progress,
+        options.cancel,
+    );
+    invalidate_status_cache();
+    result
+}
+
+// Turns a failed install into a PartialInstallError when packages went in
+// before the failure, or rolls them back right away for atomic installs
+fn partial_install_error<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+    installed_before: &[(&'static str, Vec<String>)],
+    error: io::Error,
+    options: &ProfileOperationOptions,
+) -> io::Error {
+    if error.kind() == io::ErrorKind::Interrupted {
+        return error;
+    }
+    let installed = changed_since(profile, backend, installed_before, true);
+    if installed.is_empty() {
+        return error;
+    }
+    let partial = PartialInstallError {
+        codename: profile.get_codename().to_owned(),
+        message: error.to_string(),
+        installed,
+    };
+    if !options.atomic {
+        return io::Error::new(error.kind(), partial);
+    }
+    let packages = partial.packages().join(", ");
+    match rollback_partial_install(&partial, backend, options) {
+        Ok(()) => io::Error::new(
+            error.kind(),
+            format!("{}\nrolled back: {}", error, packages),
+        ),
+        Err(e) => io::Error::new(
+            error.kind(),
+            format!("{}\nrolling back {} failed: {}", error, packages, e),
+        ),
+    }
+}
+
+// Asks every backend the profile uses what an install would bring in, for
+// confirmation prompts and dry runs
+pub fn plan_install<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+) -> Result<Vec<PackagePlan>, io::Error> {
+    package_sets(profile, backend)
+        .into_iter()
+        .map(|(backend, packages)| plan_install_packages(backend, packages))
+        .collect()
+}
+
+// Runs a composed script and reports what happened to each of the profile
+// packages, flatpaks and snaps. A failed run names the ones that failed in its
+// error.
+fn run_profile_script<P: CfhdbProfile>(
+    profile: &P,
+    script: &str,
+    stage: ProgressStage,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<(), io::Error> {
+    let result = run_in_lock_script_with_options(script, stage, options.progress, options.cancel);
+    invalidate_status_cache();
+    let package_sets = package_sets(profile, backend);
+    if package_sets.is_empty() {
+        return result;
+    }
+    if result
+        .as_ref()
+        .is_err_and(|e| e.kind() == io::ErrorKind::Interrupted)
+    {
+        return result;
+    }
+    let output = result.as_ref().err().map(|e| e.to_string());
+    let mut failed = vec![];
+    let results = package_sets.into_iter().flat_map(|(backend, packages)| {
+        collect_results(
+            backend,
+            packages,
+            stage == ProgressStage::Installing,
+            output.as_deref(),
+        )
+    });
+    for package in results {
+        debug!(package = %package.name, status = ?package.status, "package result");
+        if let PackageStatus::Failed(reason) = &package.status {
+            failed.push(format!("{} ({})", package.name, reason));
+        }
+        report(options.progress, ProgressEvent::package(stage, package));
+    }
+    match result {
+        Err(e) if !failed.is_empty() => Err(io::Error::new(
+            e.kind(),
+            format!("{}\nfailed packages: {}", e, failed.join(", ")),
+        )),
+        _ => result,
+    }
+}
+
+pub fn install_profile<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, io::Error> {
+    install_profile_with_options(profile, backend, &ProfileOperationOptions::default())
+}
+
+#[instrument(skip_all, fields(codename = profile.get_codename()))]
+pub fn install_profile_with_options<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<ProfileOperationOutcome, io::Error> {
+    report(
+        options.progress,
+        ProgressEvent::stage(ProgressStage::Installing),
+    );
+    verify_profile_scripts(profile)?;
+    // Always re-check before changing the system, a cached status may be stale
+    if !options.reapply && run_profile_check_script(profile) {
+        debug!("nothing to change, check script says already installed");
+        return Ok(ProfileOperationOutcome::AlreadyInstalled);
+    }
+    // A version the distro does not ship should fail here, with a clear
+    // message and before anything asks for root
+    for (backend, packages) in package_sets(profile, backend) {
+        check_constraints(backend, packages)?;
+    }
+    let installed_before: Vec<(&'static str, Vec<String>)> = package_sets(profile, backend)
+        .into_iter()
+        .map(|(backend, packages)| (backend.name(), installed_entries(backend, packages)))
+        .collect();
+    let packages = profile.get_packages().unwrap_or_default();
+    let module_snapshot = if kmod::builds_kernel_modules(packages) {
+        Some(kmod::snapshot())
+    } else {
+        None
+    };
+    let remove_script = profile_script(profile, false)?;
+    match compose_install_script(profile, backend)? {
+        Some(script) => {
+            let snapshot =
+                snapshot::create_snapshot(&format!("cfhdb install {}", profile.get_codename()))?;
+            let journal = Journal::begin(
+                profile.get_codename(),
+                package_sets(profile, backend)
+                    .into_iter()
+                    .map(|(backend, packages)| (backend.name().to_owned(), packages.to_vec()))
+                    .collect(),
+                installed_before
+                    .iter()
+                    .map(|(name, packages)| (name.to_string(), packages.clone()))
+                    .collect(),
+                &script,
+                remove_script.as_deref(),
+                snapshot.clone(),
+            );
+            let sink = JournalSink {
+                journal: &journal,
+                inner: options.progress,
+            };
+            let journaled = ProfileOperationOptions {
+                progress: Some(&sink),
+                ..*options
+            };
+            let result = run_profile_script(
+                profile,
+                &script,
+                ProgressStage::Installing,
+                backend,
+                &journaled,
+            );
+            journal.finish();
+            if let Err(e) = result {
+                return Err(partial_install_error(
+                    profile,
+                    backend,
+                    &installed_before,
+                    e,
+                    options,
+                ));
+            }
+            // A package transaction succeeds even when its module build failed
+            if let Some(before) = &module_snapshot {
+                kmod::verify_modules(before, packages)?;
+            }
+            record_transaction(
+                TransactionKind::Install,
+                profile,
+                backend,
+                &installed_before,
+                snapshot,
+            );
+            report(
+                options.progress,
+                ProgressEvent::stage(ProgressStage::Finished),
+            );
+            Ok(ProfileOperationOutcome::Completed)
+        }
+        None => Ok(ProfileOperationOutcome::NothingToDo),
+    }
+}
+
+pub fn uninstall_profile<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, io::Error> {
+    uninstall_profile_with_options(profile, backend, &ProfileOperationOptions::default())
+}
+
+#[instrument(skip_all, fields(codename = profile.get_codename()))]
+pub fn uninstall_profile_with_options<P: CfhdbProfile>(
+    profile: &P,
+    backend: &dyn PackageBackend,
+    options: &ProfileOperationOptions,
+) -> Result<ProfileOperationOutcome, io::Error> {
+    report(
+        options.progress,
+        ProgressEvent::stage(ProgressStage::Uninstalling),
+    );
+    verify_profile_scripts(profile)?;
+    if !run_profile_check_script(profile) {
+        debug!("nothing to change, check script says not installed");
+        return Ok(ProfileOperationOutcome::NotInstalled);
+    }
+    let installed_before: Vec<(&'static str, Vec<String>)> = package_sets(profile, backend)
+        .into_iter()
+        .map(|(backend, packages)| (backend.name(), installed_entries(backend, packages)))
+        .collect();
+    match compose_uninstall_script(profile, backend)? {
+        Some(script) => {
+            let snapshot =
+                snapshot::create_snapshot(&format!("cfhdb uninstall {}", profile.get_codename()))?;
+            run_profile_script(
+                profile,
+                &script,
+                ProgressStage::Uninstalling,
+                backend,
+                options,
+            )?;
+            record_transaction(
+                TransactionKind::Uninstall,
+                profile,
+                backend,
+                &installed_before,
+                snapshot,
+            );
+            report(
+                options.progress,
+                ProgressEvent::stage(ProgressStage::Finished),
+            );
+            Ok(ProfileOperationOutcome::Completed)
+        }
+        None => Ok(ProfileOperationOutcome::NothingToDo),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::packages::PackageResult;
+use serde::Serialize;
+use std::sync::mpsc;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStage {
+    Enumerating,
+    Downloading,
+    Matching,
+    Installing,
+    Uninstalling,
+    Finished,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProgressEvent {
+    pub stage: ProgressStage,
+    pub percent: Option<u8>,
+    pub log_line: Option<String>,
+    pub package: Option<PackageResult>,
+}
+
+impl ProgressEvent {
+    pub fn stage(stage: ProgressStage) -> Self {
+        Self {
+            stage,
+            percent: None,
+            log_line: None,
+            package: None,
+        }
+    }
+
+    pub fn percent(stage: ProgressStage, done: usize, total: usize) -> Self {
+        Self {
+            stage,
+            percent: Some(
+                (done.min(total) * 100)
+                    .checked_div(total)
+                    .map_or(100, |x| x as u8),
+            ),
+            log_line: None,
+            package: None,
+        }
+    }
+
+    pub fn log_line(stage: ProgressStage, line: &str) -> Self {
+        Self {
+            stage,
+            percent: None,
+            log_line: Some(line.to_owned()),
+            package: None,
+        }
+    }
+
+    pub fn package(stage: ProgressStage, result: PackageResult) -> Self {
+        Self {
+            stage,
+            percent: None,
+            log_line: None,
+            package: Some(result),
+        }
+    }
+}
+
+// Receives structured progress from long running library calls, so frontends
+// can drive real progress bars instead of freezing until the call returns.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
+
+impl ProgressSink for mpsc::Sender<ProgressEvent> {
+    fn report(&self, event: ProgressEvent) {
+        // A frontend that hung up on the channel just stops getting updates
+        let _ = self.send(event);
+    }
+}
+
+impl ProgressSink for tokio::sync::mpsc::UnboundedSender<ProgressEvent> {
+    fn report(&self, event: ProgressEvent) {
+        let _ = self.send(event);
+    }
+}
+
+pub(crate) fn report(progress: Option<&dyn ProgressSink>, event: ProgressEvent) {
+    if let Some(sink) = progress {
+        sink.report(event);
+    }
+}
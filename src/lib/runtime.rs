@@ -0,0 +1,59 @@
+use crate::error::CfhdbError;
+use lazy_static::lazy_static;
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock},
+};
+use tokio::runtime::{Builder, Handle, Runtime};
+
+// The futures are bluez round trips, the D-Bus connection task is all that
+// runs besides them. A worker per core only costs threads and descriptors.
+const WORKER_THREADS: usize = 2;
+
+// A single runtime shared by every blocking wrapper in libcfhdb, instead of
+// spinning one up (and tearing it down) per call. It is only created on the
+// first call that needs it, a failure to create it is returned from that call
+// and the next one tries again.
+static SHARED_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+lazy_static! {
+    static ref EXTERNAL_HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
+}
+
+fn shared_runtime() -> Result<&'static Runtime, CfhdbError> {
+    if let Some(runtime) = SHARED_RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(WORKER_THREADS)
+        .thread_name("libcfhdb-runtime")
+        .enable_all()
+        .build()
+        .map_err(CfhdbError::Runtime)?;
+    // Where another thread got there first its runtime is kept and this one
+    // dropped
+    Ok(SHARED_RUNTIME.get_or_init(|| runtime))
+}
+
+// Frontends that already run their own tokio runtime can hand us its handle,
+// the blocking wrappers will then drive their futures on it instead of
+// creating the shared runtime at all.
+// Note: blocking wrappers must not be called from inside that runtime's async
+// context, use the async API there instead.
+pub fn set_handle(handle: Handle) {
+    *EXTERNAL_HANDLE.lock().unwrap() = Some(handle);
+}
+
+pub fn clear_handle() {
+    *EXTERNAL_HANDLE.lock().unwrap() = None;
+}
+
+// Fails only where the shared runtime could not be created, running out of
+// file descriptors or threads usually
+pub fn block_on<F: Future>(future: F) -> Result<F::Output, CfhdbError> {
+    let external_handle = EXTERNAL_HANDLE.lock().unwrap().clone();
+    match external_handle {
+        Some(handle) => Ok(handle.block_on(future)),
+        None => Ok(shared_runtime()?.block_on(future)),
+    }
+}
@@ -0,0 +1,378 @@
+use crate::{
+    cancel::{cancelled_error, CancellationToken},
+    lock, lsm,
+    packages::shell_quote,
+    paths::{find_in_path, get_target_root},
+    privilege,
+    progress::{ProgressEvent, ProgressSink, ProgressStage},
+};
+use lazy_static::lazy_static;
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    fs,
+    io::{self, BufRead, ErrorKind, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, instrument, warn};
+
+// Scripts get a fresh file per invocation. The directory is root owned and
+// sticky so other users cannot rename or delete our file, and create_new
+// refuses to follow a symlink planted at the chosen name. Dropping the handle
+// removes the file again.
+const SCRIPT_DIR: &str = "/tmp";
+const SCRIPT_CREATE_ATTEMPTS: u32 = 16;
+// Fixed helper the polkit action is bound to. It records the script it is
+// given and runs it as a resource limited transient systemd unit, so a runaway
+// script can be stopped with systemctl and its output stays in the journal and
+// in a per transaction log under /var/log/cfhdb.
+pub const LOCK_SCRIPT_HELPER: &str = "/usr/lib/cfhdb/scripts/script_lock.sh";
+// The helper gave up waiting for the cfhdb lock, its last line names the holder
+const LOCK_BUSY_EXIT_CODE: i32 = 75;
+const CHROOT_DELIMITER: &str = "CFHDB_CHROOT_EOF";
+
+lazy_static! {
+    // Every script handed to the lock helper since the last take, so the
+    // audit record of an operation can keep exactly what ran as root
+    static ref EXECUTED_SCRIPTS: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+pub(crate) fn take_executed_scripts() -> Vec<String> {
+    std::mem::take(&mut *EXECUTED_SCRIPTS.lock().unwrap())
+}
+
+static SCRIPT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+struct TempScript {
+    path: PathBuf,
+}
+
+impl TempScript {
+    fn create(contents: &str) -> Result<Self, io::Error> {
+        let dir = Path::new(SCRIPT_DIR);
+        let dir_metadata = fs::metadata(dir)?;
+        if dir_metadata.uid() != 0 || dir_metadata.permissions().mode() & 0o1000 == 0 {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is not a root owned sticky directory", SCRIPT_DIR),
+            ));
+        }
+        for _ in 0..SCRIPT_CREATE_ATTEMPTS {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.subsec_nanos())
+                .unwrap_or_default();
+            let path = dir.join(format!(
+                "cfhdb-{}-{}-{:08x}.sh",
+                process::id(),
+                SCRIPT_COUNTER.fetch_add(1, Ordering::Relaxed),
+                nanos
+            ));
+            let mut file = match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o700)
+                .open(&path)
+            {
+                Ok(t) => t,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            // From here on Drop cleans up, even if writing fails
+            let script = Self { path };
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            lsm::label_script(script.path());
+            return Ok(script);
+        }
+        Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "could not find a free temporary script name",
+        ))
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "could not remove temporary script");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckSandbox {
+    Enabled,
+    Disabled,
+}
+
+// Check scripts run on every listing, so by default they only get a read-only
+// view of the system and no network. bubblewrap is preferred, systemd-run is
+// the fallback, and without either the script runs as is with a warning. bwrap
+// is skipped when AppArmor would refuse it the user namespace it needs.
+// With a target root the sandbox shows the target as /, so the check sees the
+// packages installed there.
+fn check_script_command(script: &Path, sandbox: CheckSandbox) -> duct::Expression {
+    let target_root = get_target_root();
+    if sandbox == CheckSandbox::Disabled && target_root.is_none() {
+        return duct::cmd!("bash", script);
+    }
+    let root = target_root.unwrap_or_else(|| PathBuf::from("/"));
+    if let Some(bwrap) = find_in_path("bwrap").filter(|_| !lsm::userns_restricted()) {
+        return duct::cmd(
+            bwrap,
+            [
+                OsStr::new("--ro-bind"),
+                root.as_os_str(),
+                OsStr::new("/"),
+                OsStr::new("--dev"),
+                OsStr::new("/dev"),
+                OsStr::new("--proc"),
+                OsStr::new("/proc"),
+                OsStr::new("--tmpfs"),
+                OsStr::new("/tmp"),
+                OsStr::new("--ro-bind"),
+                script.as_os_str(),
+                script.as_os_str(),
+                OsStr::new("--unshare-all"),
+                OsStr::new("--die-with-parent"),
+                OsStr::new("--new-session"),
+                OsStr::new("bash"),
+                script.as_os_str(),
+            ],
+        );
+    }
+    if let Some(systemd_run) = find_in_path("systemd-run") {
+        let root_directory = format!("--property=RootDirectory={}", root.display());
+        let script_bind = format!("--property=BindReadOnlyPaths={}", script.display());
+        let mut args: Vec<&OsStr> = vec![];
+        if !privilege::is_root() {
+            args.push(OsStr::new("--user"));
+        }
+        args.extend([
+            OsStr::new("--quiet"),
+            OsStr::new("--pipe"),
+            OsStr::new("--wait"),
+            OsStr::new("--collect"),
+            OsStr::new("--property=ProtectSystem=strict"),
+            OsStr::new("--property=ProtectHome=read-only"),
+            OsStr::new("--property=PrivateNetwork=yes"),
+            OsStr::new("--property=NoNewPrivileges=yes"),
+        ]);
+        if root != Path::new("/") {
+            args.extend([OsStr::new(&root_directory), OsStr::new(&script_bind)]);
+        }
+        args.extend([OsStr::new("bash"), script.as_os_str()]);
+        return duct::cmd(systemd_run, args);
+    }
+    if root != Path::new("/") {
+        warn!("neither bwrap nor systemd-run found, running check script in a plain chroot");
+        return duct::cmd!("chroot", root, "bash", "-s").stdin_path(script);
+    }
+    warn!("neither bwrap nor systemd-run found, running check script without a sandbox");
+    duct::cmd!("bash", script)
+}
+
+#[instrument(level = "debug", skip(check_script))]
+pub fn run_check_script(check_script: &str, sandbox: CheckSandbox) -> bool {
+    let script = match TempScript::create(&format!("#! /bin/bash\nset -e\n{}", check_script)) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(error = %e, "could not write check script");
+            return false;
+        }
+    };
+    let status = check_script_command(script.path(), sandbox)
+        .stderr_to_stdout()
+        .stdout_null()
+        .run()
+        .is_ok();
+    debug!(status, "check script finished");
+    status
+}
+
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How much of the output a failed script error carries
+const OUTPUT_TAIL_LINES: usize = 20;
+
+// Forwards the combined script output line by line, to the progress sink when
+// there is one and to our stdout otherwise, keeping the last lines around for
+// the error message. An exit with busy_exit_code means the lock could not be
+// taken and fails with WouldBlock.
+fn run_streaming(
+    cmd: duct::Expression,
+    stage: ProgressStage,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+    busy_exit_code: Option<i32>,
+) -> Result<(), io::Error> {
+    let reader = cmd.stderr_to_stdout().unchecked().reader()?;
+    let finished = AtomicBool::new(false);
+    let mut tail: VecDeque<String> = VecDeque::with_capacity(OUTPUT_TAIL_LINES);
+    let result = thread::scope(|scope| {
+        if let Some(token) = cancel {
+            scope.spawn(|| {
+                while !finished.load(Ordering::SeqCst) {
+                    if token.is_cancelled() {
+                        let _ = reader.kill();
+                        return;
+                    }
+                    thread::sleep(CANCEL_POLL_INTERVAL);
+                }
+            });
+        }
+        let mut result = Ok(());
+        for line in io::BufReader::new(&reader).lines() {
+            match line {
+                Ok(t) => {
+                    match progress {
+                        Some(sink) => sink.report(ProgressEvent::log_line(stage, &t)),
+                        None => println!("{}", t),
+                    }
+                    if tail.len() == OUTPUT_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(t);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        finished.store(true, Ordering::SeqCst);
+        result
+    });
+    // The output is read to EOF, so the exit status is in by now
+    let result = result.and_then(|_| match reader.try_wait()? {
+        Some(output) if !output.status.success() => {
+            if output.status.code().is_some() && output.status.code() == busy_exit_code {
+                return Err(io::Error::new(
+                    ErrorKind::WouldBlock,
+                    tail.pop_back()
+                        .unwrap_or("the package lock is held by another process".to_owned()),
+                ));
+            }
+            Err(io::Error::other(format!(
+                "script exited with {}",
+                output.status
+            )))
+        }
+        _ => Ok(()),
+    });
+    match cancel {
+        Some(token) if token.is_cancelled() => Err(cancelled_error()),
+        _ => result.map_err(|e| {
+            if tail.is_empty() || e.kind() == ErrorKind::WouldBlock {
+                return e;
+            }
+            let output = tail.into_iter().collect::<Vec<_>>().join("\n");
+            io::Error::new(e.kind(), format!("{}, last output:\n{}", e, output))
+        }),
+    }
+}
+
+// Runs the script inside the target root. Package managers there need the
+// API filesystems, installers usually mount them already and whatever is
+// missing is bind mounted for the run. The script is copied into the target
+// instead of fed on stdin, where a prompting maintainer script would eat it.
+fn chroot_script(root: &Path, script: &str) -> String {
+    format!(
+        r#"#! /bin/bash
+set -e
+ROOT={root}
+MOUNTED=()
+SCRIPT=""
+cleanup() {{
+    [ -n "$SCRIPT" ] && rm -f "$SCRIPT"
+    for dir in "${{MOUNTED[@]}}"; do umount -l "$dir" || true; done
+}}
+trap cleanup EXIT
+for dir in /dev /proc /sys /run; do
+    if ! mountpoint -q "$ROOT$dir"; then
+        mkdir -p "$ROOT$dir"
+        mount --rbind "$dir" "$ROOT$dir"
+        MOUNTED=("$ROOT$dir" "${{MOUNTED[@]}}")
+    fi
+done
+mkdir -p "$ROOT/tmp"
+SCRIPT=$(mktemp "$ROOT/tmp/cfhdb-XXXXXXXX.sh")
+cat > "$SCRIPT" <<'{delimiter}'
+{script}
+{delimiter}
+chroot "$ROOT" /bin/bash "/tmp/$(basename "$SCRIPT")" < /dev/null
+"#,
+        root = shell_quote(&root.to_string_lossy()),
+        delimiter = CHROOT_DELIMITER,
+        script = script
+    )
+}
+
+pub fn run_in_lock_script(script: &str) -> Result<(), io::Error> {
+    run_in_lock_script_with_options(script, ProgressStage::Installing, None, None)
+}
+
+#[instrument(skip(script, progress, cancel))]
+pub fn run_in_lock_script_with_options(
+    script: &str,
+    stage: ProgressStage,
+    progress: Option<&dyn ProgressSink>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(), io::Error> {
+    let use_helper = !privilege::is_root() || Path::new(LOCK_SCRIPT_HELPER).exists();
+    // The helper takes the cfhdb lock itself once it runs as root
+    let _lock = if use_helper {
+        lock::wait_for_package_managers(stage, progress, cancel)?;
+        None
+    } else {
+        Some(lock::acquire(stage, progress, cancel)?)
+    };
+    EXECUTED_SCRIPTS.lock().unwrap().push(script.to_owned());
+    let script = match get_target_root() {
+        Some(root) => TempScript::create(&chroot_script(&root, script))?,
+        None => TempScript::create(script)?,
+    };
+    let (final_cmd, busy_exit_code) = if use_helper {
+        let cmd = privilege::elevated_cmd(
+            LOCK_SCRIPT_HELPER,
+            &[
+                &script.path().to_string_lossy(),
+                &lock::helper_timeout_arg(),
+            ],
+        )?;
+        (cmd, Some(LOCK_BUSY_EXIT_CODE))
+    } else {
+        (duct::cmd!(script.path()), None)
+    };
+    // Nothing inside may wait for an answer, a prompt that slipped through
+    // reads EOF and fails instead of hanging behind the lock
+    let result = run_streaming(
+        final_cmd.stdin_null(),
+        stage,
+        progress,
+        cancel,
+        busy_exit_code,
+    );
+    drop(script);
+    if let Err(e) = &result {
+        warn!(error = %e, "script failed");
+    }
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) => Err(e),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::profile::CfhdbProfile;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, ErrorKind},
+    sync::Mutex,
+};
+use tracing::warn;
+
+lazy_static! {
+    static ref SCRIPT_VERIFIER: Mutex<ScriptVerifier> = Mutex::new(ScriptVerifier::default());
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignaturePolicy {
+    // Unsigned profiles run, a signature that does not verify is only logged
+    #[default]
+    Permissive,
+    // Only profiles signed by one of the trusted keys may run scripts
+    Strict,
+}
+
+// Profiles may carry a detached ed25519 signature (base64) over their scripts
+// and packages, made with one of the repository keys. This guards against a
+// compromised mirror serving a database with altered scripts.
+#[derive(Default)]
+pub struct ScriptVerifier {
+    policy: SignaturePolicy,
+    trusted_keys: Vec<VerifyingKey>,
+}
+
+impl ScriptVerifier {
+    // Keys are base64 encoded raw ed25519 public keys
+    pub fn new(policy: SignaturePolicy, trusted_keys: &[String]) -> Result<Self, io::Error> {
+        let mut keys = vec![];
+        for key in trusted_keys {
+            let bytes: [u8; 32] = match STANDARD.decode(key.trim()).ok().map(<[u8; 32]>::try_from) {
+                Some(Ok(t)) => t,
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("'{}' is not a valid ed25519 public key", key),
+                    ))
+                }
+            };
+            match VerifyingKey::from_bytes(&bytes) {
+                Ok(t) => keys.push(t),
+                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+            }
+        }
+        if policy == SignaturePolicy::Strict && keys.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "strict signature policy needs at least one trusted key",
+            ));
+        }
+        Ok(Self {
+            policy,
+            trusted_keys: keys,
+        })
+    }
+
+    pub fn get_policy(&self) -> SignaturePolicy {
+        self.policy
+    }
+
+    fn signature_is_valid<P: CfhdbProfile + ?Sized>(&self, profile: &P, signature: &str) -> bool {
+        let signature: [u8; 64] = match STANDARD
+            .decode(signature.trim())
+            .ok()
+            .map(<[u8; 64]>::try_from)
+        {
+            Some(Ok(t)) => t,
+            _ => return false,
+        };
+        let signature = Signature::from_bytes(&signature);
+        let payload = signed_payload(profile);
+        self.trusted_keys
+            .iter()
+            .any(|key| key.verify(&payload, &signature).is_ok())
+    }
+
+    pub fn verify<P: CfhdbProfile + ?Sized>(&self, profile: &P) -> Result<(), io::Error> {
+        let verified = match profile.get_signature() {
+            Some(signature) => self.signature_is_valid(profile, signature),
+            None if self.policy == SignaturePolicy::Strict => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("profile {} is not signed", profile.get_codename()),
+                ));
+            }
+            None => return Ok(()),
+        };
+        match (verified, self.policy) {
+            (true, _) => Ok(()),
+            (false, SignaturePolicy::Permissive) => {
+                warn!(
+                    codename = profile.get_codename(),
+                    "profile signature does not verify, running anyway"
+                );
+                Ok(())
+            }
+            (false, SignaturePolicy::Strict) => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "signature of profile {} does not match its scripts",
+                    profile.get_codename()
+                ),
+            )),
+        }
+    }
+}
+
+// The signed message, every field is NUL terminated and optional fields are
+// tagged so a missing script can't be confused with an empty one:
+// cfhdb-profile-v1, codename, check script, sandboxed|unsandboxed,
+// [-|+install script], [-|+remove script], [-|+space separated packages]
+pub fn signed_payload<P: CfhdbProfile + ?Sized>(profile: &P) -> Vec<u8> {
+    fn push_field(payload: &mut Vec<u8>, field: &str) {
+        payload.extend_from_slice(field.as_bytes());
+        payload.push(0);
+    }
+    fn push_optional(payload: &mut Vec<u8>, field: Option<&str>) {
+        match field {
+            Some(t) => push_field(payload, &format!("+{}", t)),
+            None => push_field(payload, "-"),
+        }
+    }
+    let mut payload = vec![];
+    push_field(&mut payload, "cfhdb-profile-v1");
+    push_field(&mut payload, profile.get_codename());
+    push_field(&mut payload, profile.get_check_script());
+    push_field(
+        &mut payload,
+        if profile.is_check_unsandboxed() {
+            "unsandboxed"
+        } else {
+            "sandboxed"
+        },
+    );
+    push_optional(&mut payload, profile.get_install_script());
+    push_optional(&mut payload, profile.get_remove_script());
+    push_optional(
+        &mut payload,
+        profile.get_packages().map(|x| x.join(" ")).as_deref(),
+    );
+    // Only appended when declared, so profiles signed before these existed
+    // keep verifying
+    if let Some(flatpaks) = profile.get_flatpaks() {
+        push_field(&mut payload, &format!("flatpaks:{}", flatpaks.join(" ")));
+    }
+    if let Some(snaps) = profile.get_snaps() {
+        push_field(&mut payload, &format!("snaps:{}", snaps.join(" ")));
+    }
+    if !profile.get_blacklisted_modules().is_empty() {
+        push_field(
+            &mut payload,
+            &format!("blacklist:{}", profile.get_blacklisted_modules().join(" ")),
+        );
+    }
+    for option in profile.get_module_options() {
+        push_field(
+            &mut payload,
+            &format!("options:{} {}", option.module, option.options),
+        );
+    }
+    if !profile.get_kernel_parameters().is_empty() {
+        push_field(
+            &mut payload,
+            &format!("kparams:{}", profile.get_kernel_parameters().join(" ")),
+        );
+    }
+    payload
+}
+
+pub fn set_script_verifier(verifier: ScriptVerifier) {
+    *SCRIPT_VERIFIER.lock().unwrap() = verifier;
+}
+
+pub fn verify_profile_scripts<P: CfhdbProfile + ?Sized>(profile: &P) -> Result<(), io::Error> {
+    SCRIPT_VERIFIER.lock().unwrap().verify(profile)
+}
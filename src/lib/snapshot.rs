@@ -0,0 +1,135 @@
+use crate::privilege;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, instrument};
+
+// Plain btrfs snapshots land here, outside of /.snapshots which snapper owns
+pub const BTRFS_SNAPSHOT_DIR: &str = "/.cfhdb-snapshots";
+
+lazy_static! {
+    static ref SNAPSHOT_TOOL: Mutex<Option<SnapshotTool>> = Mutex::new(None);
+}
+
+// What takes the filesystem snapshot before a profile script runs. Off unless
+// configured, none of them is a safe guess on an arbitrary system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotTool {
+    Snapper,
+    Timeshift,
+    Btrfs,
+}
+
+impl SnapshotTool {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotTool::Snapper => "snapper",
+            SnapshotTool::Timeshift => "timeshift",
+            SnapshotTool::Btrfs => "btrfs",
+        }
+    }
+}
+
+// Recorded with the transaction, so a broken install can be reverted with the
+// tool that took it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tool: SnapshotTool,
+    pub id: String,
+}
+
+pub fn set_snapshot_tool(tool: Option<SnapshotTool>) {
+    *SNAPSHOT_TOOL.lock().unwrap() = tool;
+}
+
+pub fn get_snapshot_tool() -> Option<SnapshotTool> {
+    *SNAPSHOT_TOOL.lock().unwrap()
+}
+
+fn run_elevated(program: &str, args: &[&str]) -> Result<String, io::Error> {
+    let output = privilege::elevated_cmd(program, args)?
+        .stdin_null()
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            program,
+            stdout.trim()
+        )));
+    }
+    Ok(stdout)
+}
+
+fn snapper_snapshot(description: &str) -> Result<String, io::Error> {
+    let output = run_elevated(
+        "snapper",
+        &[
+            "create",
+            "--type",
+            "single",
+            "--cleanup-algorithm",
+            "number",
+            "--print-number",
+            "--description",
+            description,
+        ],
+    )?;
+    Ok(output.trim().to_owned())
+}
+
+fn timeshift_snapshot(description: &str) -> Result<String, io::Error> {
+    let output = run_elevated(
+        "timeshift",
+        &["--create", "--scripted", "--comments", description],
+    )?;
+    let re = Regex::new(r"Tagged snapshot '([^']+)'").unwrap();
+    re.captures(&output)
+        .map(|x| x[1].to_owned())
+        .ok_or_else(|| io::Error::other("timeshift did not report the snapshot it created"))
+}
+
+fn btrfs_snapshot() -> Result<String, io::Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default();
+    let path = format!("{}/cfhdb-{}", BTRFS_SNAPSHOT_DIR, timestamp);
+    run_elevated("mkdir", &["-p", BTRFS_SNAPSHOT_DIR])?;
+    run_elevated("btrfs", &["subvolume", "snapshot", "-r", "/", &path])?;
+    Ok(path)
+}
+
+// Takes a snapshot with the configured tool and returns its id, the snapper
+// number, the timeshift name or the btrfs subvolume path. A snapshot that
+// was asked for but failed stops the operation, going ahead without the
+// safety net the admin configured would be a surprise.
+#[instrument]
+pub fn create_snapshot(description: &str) -> Result<Option<Snapshot>, io::Error> {
+    let tool = match get_snapshot_tool() {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let id = match tool {
+        SnapshotTool::Snapper => snapper_snapshot(description),
+        SnapshotTool::Timeshift => timeshift_snapshot(description),
+        SnapshotTool::Btrfs => btrfs_snapshot(),
+    }
+    .map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("could not take a {} snapshot: {}", tool.as_str(), e),
+        )
+    })?;
+    debug!(tool = tool.as_str(), id = %id, "filesystem snapshot taken");
+    Ok(Some(Snapshot { tool, id }))
+}
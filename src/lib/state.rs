@@ -0,0 +1,140 @@
+#[cfg(feature = "pci")]
+use crate::pci::CfhdbPciDevice;
+#[cfg(feature = "usb")]
+use crate::usb::CfhdbUsbDevice;
+use crate::{
+    blacklist,
+    installed::{self, InstalledProfile},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Bumped whenever an older cfhdb could no longer make sense of an export.
+// Version 2 added the ids of disabled devices, which older versions would
+// ignore and disable whatever sits at the address.
+pub const STATE_VERSION: u32 = 2;
+// The categories sysfs_helper.sh keeps a blacklist of disabled devices for
+const BLACKLIST_CATEGORIES: [&str; 2] = ["pci", "usb"];
+
+// What a disabled device is, as opposed to where it sits. The serial number
+// is only there for usb devices that have one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIds {
+    pub vendor_id: String,
+    pub product_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+}
+
+impl fmt::Display for DeviceIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.vendor_id, self.product_id)?;
+        if let Some(serial) = &self.serial {
+            write!(f, " ({})", serial)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DisabledDevice {
+    pub category: String,
+    pub id: String,
+    // None where the device was not there to read them from at export
+    #[serde(default)]
+    pub ids: Option<DeviceIds>,
+}
+
+impl DisabledDevice {
+    // The ids of the device at the same address on this machine, None where
+    // there is none. On cloned or reinstalled hardware the address can belong
+    // to another device, which must not be disabled in its place.
+    pub fn present_ids(&self) -> Option<DeviceIds> {
+        present_ids(&self.category, &self.id)
+    }
+}
+
+#[cfg_attr(not(any(feature = "pci", feature = "usb")), allow(unused_variables))]
+fn present_ids(category: &str, id: &str) -> Option<DeviceIds> {
+    match category {
+        #[cfg(feature = "pci")]
+        "pci" => CfhdbPciDevice::get_device_from_busid(id)
+            .ok()
+            .map(|x| DeviceIds {
+                vendor_id: x.vendor_id,
+                product_id: x.device_id,
+                serial: None,
+            }),
+        #[cfg(feature = "usb")]
+        "usb" => CfhdbUsbDevice::get_device_from_busid(id)
+            .ok()
+            .map(|x| DeviceIds {
+                vendor_id: x.vendor_id,
+                product_id: x.product_id,
+                // Only devices with a serial number get a stable id
+                serial: x.stable_id.map(|_| x.serial_number_string_index),
+            }),
+        _ => None,
+    }
+}
+
+// Everything `cfhdb state import` needs to give another machine the same
+// driver setup
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemState {
+    pub version: u32,
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    #[serde(default)]
+    pub profiles: Vec<InstalledProfile>,
+    #[serde(default)]
+    pub disabled_devices: Vec<DisabledDevice>,
+}
+
+fn disabled_devices() -> Vec<DisabledDevice> {
+    let mut devices = vec![];
+    for category in BLACKLIST_CATEGORIES {
+        devices.extend(
+            blacklist::read_blacklist(category)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| DisabledDevice {
+                    category: category.to_owned(),
+                    ids: present_ids(category, &id),
+                    id,
+                }),
+        );
+    }
+    devices
+}
+
+pub fn export_state() -> SystemState {
+    SystemState {
+        version: STATE_VERSION,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default(),
+        profiles: installed::get_installed(),
+        disabled_devices: disabled_devices(),
+    }
+}
+
+pub fn read_state(path: &Path) -> Result<SystemState, io::Error> {
+    let state: SystemState = serde_json::from_str(&fs::read_to_string(path)?)?;
+    if state.version > STATE_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "state version {} was written by a newer cfhdb, this one reads up to {}",
+                state.version, STATE_VERSION
+            ),
+        ));
+    }
+    Ok(state)
+}
@@ -0,0 +1,131 @@
+use crate::{device::CfhdbDevice, kmod, paths::find_in_path, profile::CfhdbProfile};
+use serde::Serialize;
+use std::{fs, path::Path};
+
+const DEBIAN_REBOOT_REQUIRED: &str = "/run/reboot-required";
+const DEBIAN_REBOOT_REQUIRED_PKGS: &str = "/run/reboot-required.pkgs";
+const MODULE_DIRS: [&str; 2] = ["/lib/modules", "/usr/lib/modules"];
+
+// One device in `cfhdb status`. A device with no matched profile has no
+// coverage in the database.
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceStatus {
+    pub category: &'static str,
+    pub id: String,
+    pub vendor: String,
+    pub name: String,
+    // Codenames by priority, veiled profiles are left out like in listings
+    pub profiles: Vec<String>,
+    pub installed: Vec<String>,
+}
+
+impl DeviceStatus {
+    pub fn is_covered(&self) -> bool {
+        !self.profiles.is_empty() || !self.installed.is_empty()
+    }
+}
+
+pub fn device_status<D>(category: &'static str, device: &D) -> DeviceStatus
+where
+    D: CfhdbDevice,
+    D::Profile: CfhdbProfile,
+{
+    let mut matched = device.get_matched_profiles().unwrap_or_default();
+    matched.sort_by_key(|x| x.get_priority());
+    let (vendor, name) = device.get_display_fields();
+    DeviceStatus {
+        category,
+        id: device.get_id(),
+        vendor,
+        name,
+        profiles: matched
+            .iter()
+            .filter(|x| !x.is_veiled())
+            .map(|x| x.get_codename().to_owned())
+            .collect(),
+        installed: matched
+            .iter()
+            .filter(|x| x.get_status())
+            .map(|x| x.get_codename().to_owned())
+            .collect(),
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RebootStatus {
+    pub required: bool,
+    // Human readable, one per source that asked for a reboot
+    pub reasons: Vec<String>,
+}
+
+// Debian and Ubuntu packages touch this file from their postinst
+fn debian_reboot_reason() -> Option<String> {
+    if !Path::new(DEBIAN_REBOOT_REQUIRED).exists() {
+        return None;
+    }
+    let packages = fs::read_to_string(DEBIAN_REBOOT_REQUIRED_PKGS)
+        .map(|x| {
+            let mut packages: Vec<&str> = x.lines().filter(|x| !x.is_empty()).collect();
+            packages.sort();
+            packages.dedup();
+            packages.join(", ")
+        })
+        .unwrap_or_default();
+    if packages.is_empty() {
+        Some("requested by an installed package".to_owned())
+    } else {
+        Some(format!("requested by {}", packages))
+    }
+}
+
+// dnf's needs-restarting exits 1 when core packages changed since boot
+fn dnf_reboot_reason() -> Option<String> {
+    let needs_restarting = find_in_path("needs-restarting")?;
+    let output = duct::cmd!(needs_restarting, "-r")
+        .stderr_null()
+        .unchecked()
+        .stdout_capture()
+        .run()
+        .ok()?;
+    if output.status.code() != Some(1) {
+        return None;
+    }
+    Some("core libraries or the kernel were updated since boot".to_owned())
+}
+
+// Distributions without a reboot hint (Arch, openSUSE) remove the module
+// tree of the running kernel once a newer kernel replaces it
+fn kernel_reboot_reason() -> Option<String> {
+    let kernel = kmod::running_kernel().ok()?;
+    let installed: Vec<String> = MODULE_DIRS
+        .iter()
+        .filter_map(|x| fs::read_dir(x).ok())
+        .flatten()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.path().join("modules.dep").exists())
+        .map(|x| x.file_name().to_string_lossy().into_owned())
+        .collect();
+    // Containers and live systems may ship no module tree at all
+    if installed.is_empty() || installed.contains(&kernel) {
+        return None;
+    }
+    Some(format!(
+        "the modules of the running kernel {} were removed by an update",
+        kernel
+    ))
+}
+
+pub fn pending_reboot() -> RebootStatus {
+    let reasons: Vec<String> = [
+        debian_reboot_reason(),
+        dnf_reboot_reason(),
+        kernel_reboot_reason(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    RebootStatus {
+        required: !reasons.is_empty(),
+        reasons,
+    }
+}
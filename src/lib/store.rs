@@ -0,0 +1,208 @@
+#[cfg(feature = "bt")]
+use crate::bt::CfhdbBtDevice;
+#[cfg(feature = "pci")]
+use crate::pci::CfhdbPciDevice;
+#[cfg(feature = "usb")]
+use crate::usb::CfhdbUsbDevice;
+use crate::{backend::DeviceProvider, device::CfhdbDevice, hotplug};
+use serde::Serialize;
+use std::{
+    io,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+// A hub or a pci rescan brings a burst of events, one refresh covers them
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub enum DeviceChange<D> {
+    Added(D),
+    // The device as it was last seen
+    Removed(D),
+    // Same id, but the driver, state or matched profiles changed
+    Updated(D),
+}
+
+type ProfileMatcher<D> = Box<dyn Fn(&D) + Send + Sync>;
+type Enumerator<D> = Box<dyn Fn() -> Option<Vec<D>> + Send + Sync>;
+
+struct StoreInner<D> {
+    category: &'static str,
+    enumerate: Enumerator<D>,
+    matcher: Option<ProfileMatcher<D>>,
+    // Each device with its JSON form, which is what updates are detected on
+    devices: Mutex<Vec<(D, serde_json::Value)>>,
+    subscribers: Mutex<Vec<Sender<DeviceChange<D>>>>,
+}
+
+// The devices of one category kept up to date for a frontend, which gets the
+// differences over channels instead of polling get_devices(). Clones share
+// the same store.
+pub struct DeviceStore<D> {
+    inner: Arc<StoreInner<D>>,
+}
+
+impl<D> Clone for DeviceStore<D> {
+    fn clone(&self) -> Self {
+        DeviceStore {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<D> DeviceStore<D>
+where
+    D: CfhdbDevice + Serialize + Clone + Send + 'static,
+{
+    pub fn new(category: &'static str, enumerate: fn() -> Option<Vec<D>>) -> Self {
+        Self::with_enumerator(category, Box::new(enumerate))
+    }
+
+    // Lists the devices through the provider instead of the system, a saved
+    // snapshot or a remote daemon for instance
+    pub fn from_provider(provider: impl DeviceProvider<D> + 'static) -> Self {
+        Self::with_enumerator(D::CATEGORY, Box::new(move || provider.get_devices()))
+    }
+
+    fn with_enumerator(category: &'static str, enumerate: Enumerator<D>) -> Self {
+        DeviceStore {
+            inner: Arc::new(StoreInner {
+                category,
+                enumerate,
+                matcher: None,
+                devices: Mutex::new(vec![]),
+                subscribers: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    // Runs on every enumerated device before it is compared, typically
+    // set_available_profiles with the profile database of the category.
+    // Only takes effect before the store is cloned or watched.
+    pub fn with_profile_matcher(mut self, matcher: impl Fn(&D) + Send + Sync + 'static) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.matcher = Some(Box::new(matcher));
+        }
+        self
+    }
+
+    pub fn category(&self) -> &'static str {
+        self.inner.category
+    }
+
+    pub fn devices(&self) -> Vec<D> {
+        self.inner
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(x, _)| x.clone())
+            .collect()
+    }
+
+    // Changes from the next refresh on, devices() has the ones before. A
+    // subscriber is dropped once its receiver is.
+    pub fn subscribe(&self) -> Receiver<DeviceChange<D>> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Enumerates again and sends the differences, the first refresh reports
+    // every device as added
+    pub fn refresh(&self) -> Result<(), io::Error> {
+        let enumerated = (self.inner.enumerate)().ok_or_else(|| {
+            io::Error::other(format!(
+                "could not enumerate {} devices",
+                self.inner.category
+            ))
+        })?;
+        let fresh: Vec<(D, serde_json::Value)> = enumerated
+            .into_iter()
+            .map(|device| {
+                if let Some(matcher) = &self.inner.matcher {
+                    matcher(&device);
+                }
+                let json = serde_json::to_value(&device).unwrap_or_default();
+                (device, json)
+            })
+            .collect();
+        let mut changes = vec![];
+        let mut devices = self.inner.devices.lock().unwrap();
+        for (device, _) in devices.iter() {
+            if !fresh.iter().any(|(x, _)| x.get_id() == device.get_id()) {
+                changes.push(DeviceChange::Removed(device.clone()));
+            }
+        }
+        for (device, json) in &fresh {
+            match devices.iter().find(|(x, _)| x.get_id() == device.get_id()) {
+                None => changes.push(DeviceChange::Added(device.clone())),
+                Some((_, old)) if old != json => {
+                    changes.push(DeviceChange::Updated(device.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        *devices = fresh;
+        drop(devices);
+        debug!(
+            category = self.inner.category,
+            changes = changes.len(),
+            "device store refreshed"
+        );
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|x| changes.iter().all(|change| x.send(change.clone()).is_ok()));
+        Ok(())
+    }
+
+    // Refreshes whenever udev, or BlueZ for bluetooth, reports a device of
+    // the category, until the watcher behind it stops
+    pub fn watch(&self) -> Result<JoinHandle<()>, io::Error> {
+        let (sender, receiver) = mpsc::channel();
+        match self.inner.category {
+            #[cfg(feature = "bt")]
+            "bt" => {
+                hotplug::watch_bluetooth(sender)?;
+            }
+            _ => {
+                hotplug::watch_udev(sender)?;
+            }
+        }
+        let store = self.clone();
+        Ok(thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                if event.category != store.inner.category {
+                    continue;
+                }
+                while receiver.recv_timeout(SETTLE_TIME).is_ok() {}
+                if let Err(e) = store.refresh() {
+                    warn!(category = store.inner.category, error = %e, "device store refresh failed");
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(feature = "pci")]
+pub fn pci_store() -> DeviceStore<CfhdbPciDevice> {
+    DeviceStore::new("pci", CfhdbPciDevice::get_devices)
+}
+
+#[cfg(feature = "usb")]
+pub fn usb_store() -> DeviceStore<CfhdbUsbDevice> {
+    DeviceStore::new("usb", CfhdbUsbDevice::get_devices)
+}
+
+#[cfg(feature = "bt")]
+pub fn bt_store() -> DeviceStore<CfhdbBtDevice> {
+    DeviceStore::new("bt", CfhdbBtDevice::get_devices)
+}
@@ -0,0 +1,138 @@
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+pub const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+pub const REFRESH_SERVICE: &str = "cfhdb-refresh.service";
+pub const REFRESH_TIMER: &str = "cfhdb-refresh.timer";
+const DEFAULT_SCHEDULE: &str = "daily";
+// Quoted heredoc delimiter, nothing inside the units is expanded by bash
+const UNIT_DELIMITER: &str = "CFHDB_UNIT";
+
+// What the generated timer does, read from the cfhdb config
+#[derive(Debug, Clone, Default)]
+pub struct TimerSettings {
+    // An OnCalendar expression, daily when left out
+    pub schedule: Option<String>,
+    // Run `cfhdb auto` instead of only refreshing the databases, so hardware
+    // connected since the last run gets its recommended profile
+    pub auto_apply: bool,
+    // Categories auto-apply is limited to, all of them when empty
+    pub categories: Vec<String>,
+}
+
+// Values end up in unit files and on the ExecStart line, where a newline
+// would start a new directive and whitespace a new argument
+fn checked_value<'a>(name: &str, value: &'a str) -> Result<&'a str, io::Error> {
+    if value.is_empty() || value.contains(UNIT_DELIMITER) || value.chars().any(|x| x.is_control()) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid {} {:?}", name, value),
+        ));
+    }
+    Ok(value)
+}
+
+fn exec_start(binary: &Path, settings: &TimerSettings) -> Result<String, io::Error> {
+    let binary = binary
+        .to_str()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "binary path is not UTF-8"))?;
+    let binary = checked_value("binary path", binary)?;
+    if binary.contains(char::is_whitespace) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("binary path {:?} contains whitespace", binary),
+        ));
+    }
+    if !settings.auto_apply {
+        return Ok(format!("{} refresh", binary));
+    }
+    for category in &settings.categories {
+        if !category.chars().all(|x| x.is_ascii_alphanumeric()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid category {:?}", category),
+            ));
+        }
+    }
+    // The timer runs unattended, auto-apply would otherwise wait for an answer
+    let mut command = format!("{} --assume-yes auto", binary);
+    if !settings.categories.is_empty() {
+        command.push_str(&format!(" --category={}", settings.categories.join(",")));
+    }
+    Ok(command)
+}
+
+pub fn service_unit(binary: &Path, settings: &TimerSettings) -> Result<String, io::Error> {
+    Ok(format!(
+        "[Unit]
+Description=Refreshes the cfhdb profile databases
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart={}
+",
+        exec_start(binary, settings)?
+    ))
+}
+
+pub fn timer_unit(settings: &TimerSettings) -> Result<String, io::Error> {
+    let schedule = checked_value(
+        "timer schedule",
+        settings.schedule.as_deref().unwrap_or(DEFAULT_SCHEDULE),
+    )?;
+    // Persistent catches up on runs missed while the machine was off, the
+    // delay keeps a fleet from hitting the database server at once
+    Ok(format!(
+        "[Unit]
+Description=Periodically refreshes the cfhdb profile databases
+
+[Timer]
+OnCalendar={}
+Persistent=true
+RandomizedDelaySec=1h
+
+[Install]
+WantedBy=timers.target
+",
+        schedule
+    ))
+}
+
+// Written through the lock script like every other change to the system, so
+// it is escalated and logged the same way
+pub fn compose_install_script(
+    binary: &Path,
+    settings: &TimerSettings,
+) -> Result<String, io::Error> {
+    let mut script = vec!["set -e".to_owned()];
+    for (name, unit) in [
+        (REFRESH_SERVICE, service_unit(binary, settings)?),
+        (REFRESH_TIMER, timer_unit(settings)?),
+    ] {
+        script.push(format!(
+            "cat > {}/{} <<'{}'\n{}{}",
+            SYSTEMD_UNIT_DIR, name, UNIT_DELIMITER, unit, UNIT_DELIMITER
+        ));
+    }
+    script.push("systemctl daemon-reload".to_owned());
+    script.push(format!("systemctl enable --now {}", REFRESH_TIMER));
+    Ok(script.join("\n"))
+}
+
+pub fn compose_remove_script() -> String {
+    [
+        format!("systemctl disable --now {} || true", REFRESH_TIMER),
+        format!(
+            "rm -f {dir}/{} {dir}/{}",
+            REFRESH_SERVICE,
+            REFRESH_TIMER,
+            dir = SYSTEMD_UNIT_DIR
+        ),
+        "systemctl daemon-reload".to_owned(),
+    ]
+    .join("\n")
+}
@@ -0,0 +1,158 @@
+use crate::{
+    paths::{in_target_root, user_state_dir},
+    snapshot::Snapshot,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, BufRead, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+pub const SYSTEM_TRANSACTION_LOG_PATH: &str = "/var/lib/cfhdb/transactions.log";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionKind {
+    Install,
+    Uninstall,
+    Rollback,
+}
+
+impl TransactionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Install => "install",
+            TransactionKind::Uninstall => "uninstall",
+            TransactionKind::Rollback => "rollback",
+        }
+    }
+}
+
+// What a profile operation changed and the script that undoes it. The script
+// itself is kept by the lock helper under /var/lib/cfhdb/transactions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    pub id: String,
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    pub kind: TransactionKind,
+    pub codename: String,
+    // Packages per backend name, only the ones this operation changed
+    #[serde(default)]
+    pub added: Vec<(String, Vec<String>)>,
+    #[serde(default)]
+    pub removed: Vec<(String, Vec<String>)>,
+    pub reverse_script: Option<String>,
+    // Set on rollbacks, the id of the transaction they reverted
+    pub reverts: Option<String>,
+    // Taken right before the operation when snapshots are configured
+    #[serde(default)]
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Transaction {
+    pub fn new(kind: TransactionKind, codename: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        Self {
+            id: format!("{}-{}", timestamp, process::id()),
+            timestamp,
+            kind,
+            codename: codename.to_owned(),
+            added: vec![],
+            removed: vec![],
+            reverse_script: None,
+            reverts: None,
+            snapshot: None,
+        }
+    }
+}
+
+fn user_transaction_log_path() -> Option<PathBuf> {
+    Some(user_state_dir()?.join("transactions.log"))
+}
+
+fn append_line(path: &Path, line: &str) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Reverse scripts run as root, so the log is only readable by its owner
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", line)
+}
+
+// Like auditing, a transaction that could not be recorded is only logged
+pub fn record(transaction: &Transaction) {
+    let line = match serde_json::to_string(transaction) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(error = %e, "could not serialize transaction");
+            return;
+        }
+    };
+    let written = append_line(&in_target_root(SYSTEM_TRANSACTION_LOG_PATH), &line).or_else(|_| {
+        match user_transaction_log_path() {
+            Some(path) => append_line(&path, &line),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no writable transaction log location",
+            )),
+        }
+    });
+    if let Err(e) = written {
+        warn!(error = %e, "could not record transaction");
+    }
+}
+
+fn read_transactions(path: &Path) -> Vec<Transaction> {
+    let file = match fs::File::open(path) {
+        Ok(t) => t,
+        Err(_) => return vec![],
+    };
+    io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+// System and user transactions merged, oldest first
+pub fn get_transactions() -> Vec<Transaction> {
+    let mut transactions = read_transactions(&in_target_root(SYSTEM_TRANSACTION_LOG_PATH));
+    if let Some(path) = user_transaction_log_path() {
+        transactions.extend(read_transactions(&path));
+    }
+    transactions.sort_by_key(|x| x.timestamp);
+    transactions
+}
+
+// The newest install or uninstall that was not rolled back yet, so repeated
+// rollbacks walk further back in time
+pub fn last_reversible() -> Option<Transaction> {
+    let transactions = get_transactions();
+    let reverted: HashSet<&str> = transactions
+        .iter()
+        .filter_map(|x| x.reverts.as_deref())
+        .collect();
+    transactions
+        .iter()
+        .rev()
+        .find(|x| {
+            x.kind != TransactionKind::Rollback
+                && x.reverse_script.is_some()
+                && !reverted.contains(x.id.as_str())
+        })
+        .cloned()
+}
@@ -0,0 +1,44 @@
+use crate::{ids::SysfsBusId, paths::in_target_root, privilege};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+};
+
+// Rules sysfs_helper.sh keeps for `cfhdb usb disable --persistent`, one line
+// per busid deauthorizing the device every time it is added. Unlike the boot
+// time blacklist they also catch a device plugged back in later.
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/90-cfhdb.rules";
+
+pub fn read_persistent_disables() -> Result<Vec<String>, io::Error> {
+    let data = match fs::read_to_string(in_target_root(UDEV_RULES_PATH)) {
+        Ok(t) => t,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    Ok(data
+        .lines()
+        .filter(|x| !x.trim_start().starts_with('#'))
+        .filter_map(|x| x.split_once("KERNEL==\""))
+        .filter_map(|(_, x)| x.split_once('"'))
+        .map(|(busid, _)| busid.to_owned())
+        .collect())
+}
+
+pub fn is_persistently_disabled(busid: &str) -> bool {
+    read_persistent_disables().is_ok_and(|x| x.iter().any(|x| x == busid))
+}
+
+// Both return whether a rule was added or removed. The device is
+// deauthorized or authorized right away too, and does not have to be plugged
+// in for the rule to change.
+pub fn persist_disable(busid: &SysfsBusId) -> Result<bool, io::Error> {
+    let listed = is_persistently_disabled(busid.as_str());
+    privilege::run_sysfs_helper(&["persist_disable_device", "usb", busid.as_str()])?;
+    Ok(!listed)
+}
+
+pub fn persist_enable(busid: &SysfsBusId) -> Result<bool, io::Error> {
+    let listed = is_persistently_disabled(busid.as_str());
+    privilege::run_sysfs_helper(&["persist_enable_device", "usb", busid.as_str()])?;
+    Ok(listed)
+}
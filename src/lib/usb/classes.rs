@@ -0,0 +1,87 @@
+// Names for the class, subclass and protocol triplets of usb interfaces,
+// after the class list at the end of usb.ids. None matches any subclass or
+// protocol, the first entry that matches wins so each class goes from its
+// most specific entries to its catch all.
+const USB_CLASS_NAMES: &[(u8, Option<u8>, Option<u8>, &str)] = &[
+    (0x01, Some(0x01), None, "Audio control"),
+    (0x01, Some(0x02), None, "Audio streaming"),
+    (0x01, Some(0x03), None, "MIDI streaming"),
+    (0x01, None, None, "Audio"),
+    (0x02, Some(0x02), None, "CDC ACM modem"),
+    (0x02, Some(0x06), None, "CDC Ethernet (ECM)"),
+    (0x02, Some(0x0a), None, "CDC mobile direct line"),
+    (0x02, Some(0x0d), None, "CDC network control (NCM)"),
+    (0x02, Some(0x0e), None, "CDC mobile broadband (MBIM)"),
+    (0x02, None, None, "Communications"),
+    (0x03, Some(0x01), Some(0x01), "HID Keyboard, boot protocol"),
+    (0x03, Some(0x01), Some(0x02), "HID Mouse, boot protocol"),
+    (0x03, Some(0x01), None, "HID, boot protocol"),
+    (0x03, Some(0x00), Some(0x01), "HID Keyboard"),
+    (0x03, Some(0x00), Some(0x02), "HID Mouse"),
+    (0x03, None, None, "HID"),
+    (0x05, None, None, "Physical interface"),
+    (0x06, Some(0x01), Some(0x01), "Still image capture (PTP)"),
+    (0x06, None, None, "Imaging"),
+    (0x07, Some(0x01), Some(0x01), "Printer, unidirectional"),
+    (0x07, Some(0x01), Some(0x02), "Printer, bidirectional"),
+    (0x07, Some(0x01), Some(0x03), "Printer, IEEE 1284.4"),
+    (0x07, Some(0x01), Some(0x04), "Printer, IPP over USB"),
+    (0x07, None, None, "Printer"),
+    (0x08, Some(0x04), None, "Mass storage, floppy (UFI)"),
+    (0x08, Some(0x06), Some(0x50), "Mass storage, SCSI bulk-only"),
+    (0x08, Some(0x06), Some(0x62), "Mass storage, SCSI (UAS)"),
+    (0x08, Some(0x06), None, "Mass storage, SCSI"),
+    (0x08, None, None, "Mass storage"),
+    (0x09, Some(0x00), Some(0x00), "Full speed hub"),
+    (0x09, Some(0x00), Some(0x01), "Hi-speed hub, single TT"),
+    (0x09, Some(0x00), Some(0x02), "Hi-speed hub, multiple TTs"),
+    (0x09, Some(0x00), Some(0x03), "SuperSpeed hub"),
+    (0x09, None, None, "Hub"),
+    (0x0a, None, None, "CDC data"),
+    (0x0b, None, None, "Smart card"),
+    (0x0d, None, None, "Content security"),
+    (0x0e, Some(0x01), None, "Video control"),
+    (0x0e, Some(0x02), None, "Video streaming"),
+    (0x0e, None, None, "Video"),
+    (0x0f, None, None, "Personal healthcare"),
+    (0x10, Some(0x01), None, "Audio/video control"),
+    (0x10, Some(0x02), None, "Audio/video data, video streaming"),
+    (0x10, Some(0x03), None, "Audio/video data, audio streaming"),
+    (0x10, None, None, "Audio/video"),
+    (0x11, None, None, "Billboard"),
+    (0x12, None, None, "USB Type-C bridge"),
+    (0x13, None, None, "Bulk display"),
+    (0x14, None, None, "MCTP over USB"),
+    (0x3c, None, None, "I3C"),
+    (0xdc, Some(0x01), Some(0x01), "USB2 compliance device"),
+    (0xdc, None, None, "Diagnostic"),
+    (0xe0, Some(0x01), Some(0x01), "Bluetooth"),
+    (0xe0, Some(0x01), Some(0x02), "Ultra wideband radio control"),
+    (0xe0, Some(0x01), Some(0x03), "RNDIS"),
+    (0xe0, Some(0x01), Some(0x04), "Bluetooth AMP controller"),
+    (0xe0, Some(0x02), None, "Wireless USB"),
+    (0xe0, None, None, "Wireless controller"),
+    (0xef, Some(0x02), Some(0x01), "Interface association"),
+    (0xef, Some(0x04), Some(0x01), "RNDIS over Ethernet"),
+    (0xef, Some(0x05), None, "USB3 vision"),
+    (0xef, None, None, "Miscellaneous"),
+    (0xfe, Some(0x01), Some(0x01), "Device firmware upgrade"),
+    (0xfe, Some(0x02), None, "IrDA bridge"),
+    (
+        0xfe,
+        Some(0x03),
+        Some(0x01),
+        "Test and measurement (USBTMC)",
+    ),
+    (0xfe, None, None, "Application specific"),
+    (0xff, None, None, "Vendor specific"),
+];
+
+pub fn class_name(class: u8, subclass: u8, protocol: u8) -> Option<&'static str> {
+    USB_CLASS_NAMES
+        .iter()
+        .find(|(c, s, p, _)| {
+            *c == class && s.is_none_or(|s| s == subclass) && p.is_none_or(|p| p == protocol)
+        })
+        .map(|(.., name)| *name)
+}
@@ -0,0 +1,179 @@
+use super::{classes, from_hex, CfhdbUsbDevice};
+use crate::error::CfhdbError;
+use serde::{Deserialize, Serialize};
+
+// The descriptors of a device as libusb parses them, what `lsusb -v` shows.
+// Codes and ids are spelled the way profiles match them, extra holds the
+// class specific descriptors that follow a config or interface in hex.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbDescriptors {
+    pub sysfs_busid: String,
+    pub device: CfhdbUsbDeviceDescriptor,
+    pub configurations: Vec<CfhdbUsbConfigDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbDeviceDescriptor {
+    pub usb_version: String,
+    pub device_version: String,
+    pub class_code: String,
+    pub sub_class_code: String,
+    pub protocol_code: String,
+    pub max_packet_size: u8,
+    pub vendor_id: String,
+    pub product_id: String,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    pub num_configurations: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbConfigDescriptor {
+    pub number: u8,
+    pub active: bool,
+    pub max_power_ma: u16,
+    pub self_powered: bool,
+    pub remote_wakeup: bool,
+    pub description_string_index: Option<u8>,
+    pub extra: String,
+    pub interfaces: Vec<CfhdbUsbInterfaceDescriptor>,
+}
+
+// One alternate setting of an interface
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbInterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class_code: String,
+    pub sub_class_code: String,
+    pub protocol_code: String,
+    pub class_name: String,
+    pub description_string_index: Option<u8>,
+    pub extra: String,
+    pub endpoints: Vec<CfhdbUsbEndpointDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbEndpointDescriptor {
+    pub address: String,
+    pub number: u8,
+    // in or out
+    pub direction: String,
+    // control, isochronous, bulk or interrupt
+    pub transfer_type: String,
+    // Only meaningful for isochronous endpoints
+    pub sync_type: String,
+    pub usage_type: String,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    pub extra: String,
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|x| from_hex(*x as _, 2))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn enum_name(value: impl std::fmt::Debug) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+fn endpoint_descriptor(endpoint: &rusb::EndpointDescriptor) -> CfhdbUsbEndpointDescriptor {
+    CfhdbUsbEndpointDescriptor {
+        address: format!("0x{}", from_hex(endpoint.address() as _, 2)),
+        number: endpoint.number(),
+        direction: enum_name(endpoint.direction()),
+        transfer_type: enum_name(endpoint.transfer_type()),
+        sync_type: enum_name(endpoint.sync_type()),
+        usage_type: enum_name(endpoint.usage_type()),
+        max_packet_size: endpoint.max_packet_size(),
+        interval: endpoint.interval(),
+        extra: hex_bytes(endpoint.extra().unwrap_or_default()),
+    }
+}
+
+fn interface_descriptor(interface: &rusb::InterfaceDescriptor) -> CfhdbUsbInterfaceDescriptor {
+    CfhdbUsbInterfaceDescriptor {
+        number: interface.interface_number(),
+        alternate_setting: interface.setting_number(),
+        class_code: from_hex(interface.class_code() as _, 2),
+        sub_class_code: from_hex(interface.sub_class_code() as _, 2),
+        protocol_code: from_hex(interface.protocol_code() as _, 2),
+        class_name: classes::class_name(
+            interface.class_code(),
+            interface.sub_class_code(),
+            interface.protocol_code(),
+        )
+        .unwrap_or("Unknown")
+        .to_owned(),
+        description_string_index: interface.description_string_index(),
+        extra: hex_bytes(interface.extra()),
+        endpoints: interface
+            .endpoint_descriptors()
+            .map(|x| endpoint_descriptor(&x))
+            .collect(),
+    }
+}
+
+fn config_descriptor(config: &rusb::ConfigDescriptor, active: bool) -> CfhdbUsbConfigDescriptor {
+    CfhdbUsbConfigDescriptor {
+        number: config.number(),
+        active,
+        max_power_ma: config.max_power(),
+        self_powered: config.self_powered(),
+        remote_wakeup: config.remote_wakeup(),
+        description_string_index: config.description_string_index(),
+        extra: hex_bytes(config.extra()),
+        interfaces: config
+            .interfaces()
+            .flat_map(|x| x.descriptors().collect::<Vec<_>>())
+            .map(|x| interface_descriptor(&x))
+            .collect(),
+    }
+}
+
+impl CfhdbUsbDescriptors {
+    // Takes the same targets as CfhdbUsbDevice::get_device_from_busid. A
+    // configuration libusb fails to read is left out rather than failing the
+    // whole dump.
+    pub fn from_target(target: &str) -> Result<Self, CfhdbError> {
+        let busid = CfhdbUsbDevice::resolve_busid(target)?;
+        let device = rusb::devices()?
+            .iter()
+            .find(|x| {
+                CfhdbUsbDevice::get_sysfs_id(x.bus_number(), x.address()).as_deref()
+                    == Some(busid.as_str())
+            })
+            .ok_or(CfhdbError::DeviceNotFound("usb", target.to_owned()))?;
+        let device_descriptor = device.device_descriptor()?;
+        let [manufacturer, product, serial_number] =
+            CfhdbUsbDevice::get_string_descriptors(&device, &device_descriptor, busid.as_str());
+        let active = device.active_config_descriptor().ok().map(|x| x.number());
+        let configurations = (0..device_descriptor.num_configurations())
+            .filter_map(|i| device.config_descriptor(i).ok())
+            .map(|x| config_descriptor(&x, active == Some(x.number())))
+            .collect();
+        Ok(Self {
+            sysfs_busid: busid.as_str().to_owned(),
+            device: CfhdbUsbDeviceDescriptor {
+                usb_version: device_descriptor.usb_version().to_string(),
+                device_version: device_descriptor.device_version().to_string(),
+                class_code: from_hex(device_descriptor.class_code() as _, 2),
+                sub_class_code: from_hex(device_descriptor.sub_class_code() as _, 2),
+                protocol_code: from_hex(device_descriptor.protocol_code() as _, 2),
+                max_packet_size: device_descriptor.max_packet_size(),
+                vendor_id: from_hex(device_descriptor.vendor_id() as _, 4),
+                product_id: from_hex(device_descriptor.product_id() as _, 4),
+                manufacturer,
+                product,
+                serial_number,
+                num_configurations: device_descriptor.num_configurations(),
+            },
+            configurations,
+        })
+    }
+}
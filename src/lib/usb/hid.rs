@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// The hid device the kernel made of one interface of a usb device, named
+// like 0003:046D:C52B.0001 in sysfs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbHidInterface {
+    // 1-2:1.0
+    pub interface: String,
+    pub hid_id: String,
+    // hid-generic, or the vendor driver that took it over
+    pub driver: Option<String>,
+    pub event_nodes: Vec<String>,
+    pub hidraw_nodes: Vec<String>,
+}
+
+fn link_name(path: &Path) -> Option<String> {
+    fs::read_link(path)
+        .ok()
+        .and_then(|link| link.file_name().map(|s| s.to_string_lossy().into_owned()))
+}
+
+// Names of the subdirectories of dir that start with prefix, sorted
+fn child_names(dir: &Path, prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|x| x.file_name().to_str().map(|x| x.to_owned()))
+                .filter(|x| x.starts_with(prefix))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+impl CfhdbUsbHidInterface {
+    fn sysfs_path(&self) -> PathBuf {
+        Path::new("/sys/bus/usb/devices")
+            .join(&self.interface)
+            .join(&self.hid_id)
+    }
+
+    // The hid devices below every interface of the device
+    pub fn from_sysfs(busid: &str) -> Vec<Self> {
+        let device_path = Path::new("/sys/bus/usb/devices").join(busid);
+        let mut hid_interfaces = vec![];
+        for interface in child_names(&device_path, &format!("{}:", busid)) {
+            let interface_path = device_path.join(&interface);
+            // The bus, vendor and product in hex, then an instance number
+            for hid_id in fs::read_dir(&interface_path)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(|x| x.file_name().to_str().map(|x| x.to_owned()))
+                .filter(|x| x.starts_with("0003:") && x.contains('.'))
+            {
+                let hid_path = interface_path.join(&hid_id);
+                let event_nodes = child_names(&hid_path.join("input"), "input")
+                    .into_iter()
+                    .flat_map(|x| child_names(&hid_path.join("input").join(x), "event"))
+                    .map(|x| format!("/dev/input/{}", x))
+                    .collect();
+                let hidraw_nodes = child_names(&hid_path.join("hidraw"), "hidraw")
+                    .into_iter()
+                    .map(|x| format!("/dev/{}", x))
+                    .collect();
+                hid_interfaces.push(Self {
+                    interface: interface.clone(),
+                    driver: link_name(&hid_path.join("driver")),
+                    hid_id,
+                    event_nodes,
+                    hidraw_nodes,
+                });
+            }
+        }
+        hid_interfaces
+    }
+
+    pub fn read_report_summary(&self) -> Result<HidReportSummary, io::Error> {
+        Ok(HidReportSummary::parse(&fs::read(
+            self.sysfs_path().join("report_descriptor"),
+        )?))
+    }
+}
+
+// Sizes of one report, in bits. Devices with a single report have no report
+// ids and show up as id 0.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HidReport {
+    pub id: u8,
+    pub input_bits: u32,
+    pub output_bits: u32,
+    pub feature_bits: u32,
+}
+
+// What a report descriptor declares, short of the individual fields
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HidReportSummary {
+    pub length: usize,
+    // The application collections, like "Generic Desktop / Keyboard"
+    pub applications: Vec<String>,
+    pub reports: Vec<HidReport>,
+}
+
+fn usage_page_name(page: u16) -> String {
+    match page {
+        0x01 => "Generic Desktop".to_owned(),
+        0x02 => "Simulation Controls".to_owned(),
+        0x05 => "Game Controls".to_owned(),
+        0x07 => "Keyboard".to_owned(),
+        0x08 => "LED".to_owned(),
+        0x09 => "Button".to_owned(),
+        0x0c => "Consumer".to_owned(),
+        0x0d => "Digitizers".to_owned(),
+        0x0f => "Physical Interface".to_owned(),
+        0x20 => "Sensors".to_owned(),
+        0x59 => "Lighting And Illumination".to_owned(),
+        0x84 => "Power Device".to_owned(),
+        0x85 => "Battery System".to_owned(),
+        0xff00..=0xffff => format!("Vendor Defined {:04X}", page),
+        t => format!("Usage Page {:04X}", t),
+    }
+}
+
+fn usage_name(page: u16, usage: u16) -> String {
+    let name = match (page, usage) {
+        (0x01, 0x01) => "Pointer",
+        (0x01, 0x02) => "Mouse",
+        (0x01, 0x04) => "Joystick",
+        (0x01, 0x05) => "Gamepad",
+        (0x01, 0x06) => "Keyboard",
+        (0x01, 0x07) => "Keypad",
+        (0x01, 0x08) => "Multi-axis Controller",
+        (0x01, 0x80) => "System Control",
+        (0x0c, 0x01) => "Consumer Control",
+        (0x0d, 0x01) => "Digitizer",
+        (0x0d, 0x02) => "Pen",
+        (0x0d, 0x04) => "Touch Screen",
+        (0x0d, 0x05) => "Touch Pad",
+        _ => return format!("{:04X}", usage),
+    };
+    name.to_owned()
+}
+
+impl HidReportSummary {
+    // Walks the items of the descriptor, see section 6.2.2 of the HID spec.
+    // Push and pop of the global state are not followed, descriptors using
+    // them may get report sizes attributed to the wrong report.
+    pub fn parse(descriptor: &[u8]) -> Self {
+        let mut summary = Self {
+            length: descriptor.len(),
+            ..Default::default()
+        };
+        let (mut usage_page, mut report_size, mut report_count) = (0u16, 0u32, 0u32);
+        let mut report_id = 0u8;
+        let mut usages: Vec<(u16, u16)> = vec![];
+        let mut depth = 0usize;
+        let mut i = 0;
+        while i < descriptor.len() {
+            let prefix = descriptor[i];
+            // Long items carry their size in the next byte and are never
+            // standard ones
+            if prefix == 0xfe {
+                i += 3 + *descriptor.get(i + 1).unwrap_or(&0) as usize;
+                continue;
+            }
+            let size = match prefix & 0x03 {
+                3 => 4,
+                t => t as usize,
+            };
+            let data = descriptor
+                .get(i + 1..i + 1 + size)
+                .unwrap_or_default()
+                .iter()
+                .rev()
+                .fold(0u32, |acc, x| (acc << 8) | *x as u32);
+            i += 1 + size;
+            match (prefix >> 2) & 0x03 {
+                // Main
+                0 => {
+                    let bits = report_size.saturating_mul(report_count);
+                    let field = match prefix >> 4 {
+                        0x8 => Some(0),
+                        0x9 => Some(1),
+                        0xb => Some(2),
+                        0xa => {
+                            // Application collections at the top level
+                            if data == 0x01 && depth == 0 {
+                                let (page, usage) =
+                                    usages.first().copied().unwrap_or((usage_page, 0));
+                                summary.applications.push(format!(
+                                    "{} / {}",
+                                    usage_page_name(page),
+                                    usage_name(page, usage)
+                                ));
+                            }
+                            depth += 1;
+                            None
+                        }
+                        0xc => {
+                            depth = depth.saturating_sub(1);
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let Some(field) = field {
+                        let report = match summary.reports.iter_mut().find(|x| x.id == report_id) {
+                            Some(t) => t,
+                            None => {
+                                summary.reports.push(HidReport {
+                                    id: report_id,
+                                    ..Default::default()
+                                });
+                                summary.reports.last_mut().unwrap()
+                            }
+                        };
+                        match field {
+                            0 => report.input_bits = report.input_bits.saturating_add(bits),
+                            1 => report.output_bits = report.output_bits.saturating_add(bits),
+                            _ => report.feature_bits = report.feature_bits.saturating_add(bits),
+                        }
+                    }
+                    // Local items only last until the next main item
+                    usages.clear();
+                }
+                // Global
+                1 => match prefix >> 4 {
+                    0x0 => usage_page = data as u16,
+                    0x7 => report_size = data,
+                    0x8 => report_id = data as u8,
+                    0x9 => report_count = data,
+                    _ => {}
+                },
+                // Local, a four byte usage brings its own usage page
+                2 if prefix >> 4 == 0x0 => match size {
+                    4 => usages.push(((data >> 16) as u16, data as u16)),
+                    _ => usages.push((usage_page, data as u16)),
+                },
+                _ => {}
+            }
+        }
+        summary.reports.sort_by_key(|x| x.id);
+        summary
+    }
+}
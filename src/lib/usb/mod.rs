@@ -1,15 +1,35 @@
-use regex::Regex;
-use serde::{Serialize, Serializer};
+mod classes;
+pub mod descriptors;
+pub mod hid;
+pub mod usbguard;
+
+use crate::{
+    blacklist,
+    cache::{sysfs_fingerprint, DeviceCache, DEFAULT_DEVICE_CACHE_TTL},
+    cancel::CancellationToken,
+    device::{group_by_class, CfhdbDevice},
+    error::CfhdbError,
+    ids::{SysfsBusId, UsbStableId, UsbTarget},
+    matching::{match_profiles, MatchField},
+    modules::{self, ModuleOption},
+    privilege,
+    profile::CfhdbProfile,
+    progress::{report, ProgressEvent, ProgressSink, ProgressStage},
+};
+use hid::CfhdbUsbHidInterface;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::{self, BufRead, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, ErrorKind},
+    str::FromStr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
-use users::get_current_username;
+use tracing::{debug, instrument, warn};
 
-// Implement Serialize for Rc<RefCell<Option<Vec<Rc<CfhdbUsbProfile>>
+// Implement Serialize for Arc<Mutex<Option<Vec<Arc<CfhdbUsbProfile>>>>>
 
 #[derive(Debug, Clone)]
 pub struct ProfileWrapper(pub Arc<Mutex<Option<Vec<Arc<CfhdbUsbProfile>>>>>);
@@ -33,106 +53,159 @@ impl Serialize for ProfileWrapper {
     }
 }
 
+// Profiles are serialized as bare codenames, which can't be turned back into
+// profiles, so a loaded snapshot starts out without any. Re-run
+// set_available_profiles against a profile database to fill them in.
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let _codenames: Option<Vec<String>> = Option::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
 fn from_hex(hex_number: u32, fill: usize) -> String {
     format!("{:01$x}", hex_number, fill)
 }
 
-fn parse_from_lsusb_output() -> Vec<LsUsbEntry> {
-    let output = std::process::Command::new("lsusb")
-        .arg("-v")
-        .output()
-        .expect("Failed to execute lsusb");
-    let output = std::str::from_utf8(&output.stdout).expect("Invalid UTF-8 in lsusb output");
-
-    let mut did_first_header = false;
-    let mut lsusb_entries = vec![];
-
-    let mut current_vendor_id = None;
-    let mut current_product_id = None;
-    let mut current_vendor_name = None;
-    let mut current_product_name = None;
-    let mut current_interface_class = None;
-
-    for line in output.lines() {
-        let line = line.trim();
-        if !did_first_header {
-            if line.starts_with("Device Descriptor:") {
-                did_first_header = true
-            }
-        } else if !line.starts_with("Device Descriptor:") {
-            if line.starts_with("idVendor") {
-                let parts: Vec<&str> = line.trim().split_whitespace().collect();
-                current_vendor_id = parts[1].strip_prefix("0x");
-                current_vendor_name = Some(parts[2..].join(" "));
-            }
-            if line.starts_with("idProduct") {
-                let parts: Vec<&str> = line.trim().split_whitespace().collect();
-                current_product_id = parts[1].strip_prefix("0x");
-                current_product_name = Some(parts[2..].join(" "));
-            }
-            if line.starts_with("bInterfaceClass") {
-                let parts: Vec<&str> = line.trim().split_whitespace().collect();
-                current_interface_class = Some(
-                    from_hex(parts[1].to_string().parse::<u32>().unwrap_or(00) as _, 2)
-                        .to_uppercase(),
-                );
+// The speed attribute of sysfs is in Mbps, named like the rusb speeds here
+fn speed_from_mbps(mbps: &str) -> &'static str {
+    match mbps {
+        "1.5" => "1.0",
+        "12" => "1.1",
+        "480" => "2.0",
+        "5000" => "3.0",
+        "10000" | "20000" => "3.1",
+        _ => "Unknown",
+    }
+}
+
+const STRING_DESCRIPTOR_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Where distributions install the usb.ids list from hwdata or usbutils
+const USB_IDS_PATHS: [&str; 3] = [
+    "/usr/share/hwdata/usb.ids",
+    "/usr/share/misc/usb.ids",
+    "/usr/share/usb.ids",
+];
+
+// Vendor id -> (vendor name, product id -> product name)
+type UsbIds = HashMap<String, (String, HashMap<String, String>)>;
+
+// Vendors are the unindented "<id>  <name>" lines and their products the ones
+// indented by one tab. The class, language and HID lists after them start
+// with a keyword instead of an id and are skipped.
+fn parse_usb_ids(data: &str) -> UsbIds {
+    let mut ids = UsbIds::new();
+    let mut current_vendor = None;
+    for line in data.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let (indented, line) = match line.strip_prefix('\t') {
+            Some(t) => (true, t),
+            None => (false, line),
+        };
+        let parsed = line
+            .split_once("  ")
+            .filter(|(id, _)| id.len() == 4 && id.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|(id, name)| (id.to_lowercase(), name.trim().to_owned()));
+        match (indented, parsed) {
+            (false, Some((id, name))) => {
+                ids.entry(id.clone()).or_default().0 = name;
+                current_vendor = Some(id);
             }
-        } else {
-            match (current_vendor_id, current_product_id) {
-                (Some(a), Some(b)) => {
-                    let entry = LsUsbEntry {
-                        vendor_id: a.to_string(),
-                        product_id: b.to_string(),
-                        vendor_name: current_vendor_name,
-                        product_name: current_product_name,
-                        interface_class: current_interface_class.unwrap_or("00".to_string()),
-                    };
-                    lsusb_entries.push(entry);
+            (false, None) => current_vendor = None,
+            (true, Some((id, name))) => {
+                if let Some(vendor) = current_vendor.as_ref().and_then(|x| ids.get_mut(x)) {
+                    vendor.1.insert(id, name);
                 }
-                (_, _) => {}
             }
-            current_vendor_id = None;
-            current_product_id = None;
-            current_vendor_name = None;
-            current_product_name = None;
-            current_interface_class = None;
+            _ => {}
         }
     }
-    match (current_vendor_id, current_product_id) {
-        (Some(a), Some(b)) => {
-            let entry = LsUsbEntry {
-                vendor_id: a.to_string(),
-                product_id: b.to_string(),
-                vendor_name: current_vendor_name,
-                product_name: current_product_name,
-                interface_class: current_interface_class.unwrap_or("00".to_string()),
-            };
-            lsusb_entries.push(entry);
+    ids
+}
+
+fn load_usb_ids() -> UsbIds {
+    for path in USB_IDS_PATHS {
+        if let Ok(data) = fs::read_to_string(path) {
+            debug!(path, "loaded usb.ids");
+            return parse_usb_ids(&data);
         }
-        (_, _) => {}
     }
+    debug!("no usb.ids found, vendor and product names stay unknown");
+    UsbIds::new()
+}
 
-    lsusb_entries
+// power/control of a device. With auto the kernel suspends it once it has
+// been idle for autosuspend_delay_ms, with on it is kept awake.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UsbPowerControl {
+    Auto,
+    On,
 }
 
-#[derive(Debug, Clone)]
-struct LsUsbEntry {
-    vendor_id: String,
-    product_id: String,
-    vendor_name: Option<String>,
-    product_name: Option<String>,
-    interface_class: String,
+impl UsbPowerControl {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsbPowerControl::Auto => "auto",
+            UsbPowerControl::On => "on",
+        }
+    }
 }
 
-#[derive(Serialize, Debug, Clone)]
+impl FromStr for UsbPowerControl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(UsbPowerControl::Auto),
+            "on" => Ok(UsbPowerControl::On),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' is not a power control, expected auto or on", s),
+            )),
+        }
+    }
+}
+
+lazy_static! {
+    static ref USB_IDS: UsbIds = load_usb_ids();
+    static ref USB_DEVICE_CACHE: DeviceCache<CfhdbUsbDevice> = DeviceCache::with_fingerprint(
+        DEFAULT_DEVICE_CACHE_TTL,
+        || sysfs_fingerprint("/sys/bus/usb/devices")
+    );
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbUsbDevice {
     // String identification
     pub manufacturer_string_index: String,
     pub product_string_index: String,
     pub serial_number_string_index: String,
+    // Names from usb.ids, for devices without string descriptors
+    #[serde(default)]
+    pub vendor_name: String,
+    #[serde(default)]
+    pub product_name: String,
     // Vendor IDs
     pub protocol_code: String,
     pub class_code: String,
+    // What the class, subclass and protocol of the interface make it, like
+    // "HID Keyboard, boot protocol"
+    #[serde(default)]
+    pub class_name: String,
+    // Only filled in for mass storage devices
+    #[serde(default)]
+    pub block_devices: Vec<CfhdbUsbBlockDevice>,
+    // Only filled in for hid devices, the input nodes and hid driver per
+    // interface
+    #[serde(default)]
+    pub hid_interfaces: Vec<CfhdbUsbHidInterface>,
     pub vendor_id: String,
     pub product_id: String,
     // System Info
@@ -141,13 +214,39 @@ pub struct CfhdbUsbDevice {
     pub port_number: u8,
     pub address: u8,
     pub sysfs_busid: String,
+    // See UsbStableId, None without a serial number
+    #[serde(default)]
+    pub stable_id: Option<String>,
     pub kernel_driver: String,
     pub started: Option<bool>,
     pub enabled: bool,
+    // The kernel authorized attribute, None where it can't be read. Ports can
+    // be locked down by deauthorizing what gets plugged into them.
+    #[serde(default)]
+    pub authorized: Option<bool>,
+    // Runtime power management, None where the attributes can't be read
+    #[serde(default)]
+    pub power_control: Option<UsbPowerControl>,
+    #[serde(default)]
+    pub autosuspend_delay_ms: Option<i64>,
+    // power/wakeup, None for devices that can't wake the system
+    #[serde(default)]
+    pub wakeup: Option<bool>,
+    // Negotiated with the port, and the most the device is rated for
     pub speed: String,
+    #[serde(default)]
+    pub max_speed: String,
     // Cfhdb Extras
     pub available_profiles: ProfileWrapper,
 }
+
+// GUI frontends and parallel workers share device lists between threads,
+// this fails to build if a field stops allowing that
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<CfhdbUsbDevice>;
+};
+
 impl CfhdbUsbDevice {
     fn get_sysfs_id(bus_number: u8, device_address: u8) -> Option<String> {
         // Base sysfs path
@@ -188,53 +287,151 @@ impl CfhdbUsbDevice {
         }
     }
 
-    fn get_serial(busid: &str) -> Result<String, io::Error> {
-        let device_manufacturer_path = std::path::Path::new("/sys/bus/usb/devices")
-            .join(busid)
-            .join("serial");
-        if device_manufacturer_path.exists() {
-            match fs::read_to_string(device_manufacturer_path) {
-                Ok(t) => Ok(t.trim().to_string()),
-                Err(e) => Err(io::Error::new(ErrorKind::NotFound, e)),
-            }
-        } else {
-            Err(io::Error::new(
-                ErrorKind::NotFound,
-                "serial file could not be found!",
-            ))
+    fn get_usb_ids_names(vendor_id: &str, product_id: &str) -> (String, String) {
+        match USB_IDS.get(vendor_id) {
+            Some((vendor, products)) => (
+                vendor.clone(),
+                products
+                    .get(product_id)
+                    .cloned()
+                    .unwrap_or("???".to_owned()),
+            ),
+            None => ("???".to_owned(), "???".to_owned()),
         }
     }
 
-    pub fn set_available_profiles(profile_data: &[CfhdbUsbProfile], device: &Self) {
-        let mut available_profiles: Vec<Arc<CfhdbUsbProfile>> = vec![];
-        for profile in profile_data.iter() {
-            let matching = {
-                if (profile.blacklisted_class_codes.contains(&"*".to_owned())
-                    || profile.blacklisted_class_codes.contains(&device.class_code))
-                    || (profile.blacklisted_vendor_ids.contains(&"*".to_owned())
-                        || profile.blacklisted_vendor_ids.contains(&device.vendor_id))
-                    || (profile.blacklisted_product_ids.contains(&"*".to_owned())
-                        || profile.blacklisted_product_ids.contains(&device.product_id))
-                {
-                    false
-                } else {
-                    (profile.class_codes.contains(&"*".to_owned())
-                        || profile.class_codes.contains(&device.class_code))
-                        && (profile.vendor_ids.contains(&"*".to_owned())
-                            || profile.vendor_ids.contains(&device.vendor_id))
-                        && (profile.product_ids.contains(&"*".to_owned())
-                            || profile.product_ids.contains(&device.product_id))
-                }
-            };
+    // The string descriptor when the device has one, the usb.ids name otherwise
+    pub fn get_manufacturer_name(&self) -> &str {
+        match self.manufacturer_string_index.as_str() {
+            "???" if !self.vendor_name.is_empty() => &self.vendor_name,
+            t => t,
+        }
+    }
+
+    pub fn get_product_name(&self) -> &str {
+        match self.product_string_index.as_str() {
+            "???" if !self.product_name.is_empty() => &self.product_name,
+            t => t,
+        }
+    }
 
-            if matching {
-                available_profiles.push(Arc::new(profile.clone()));
-            };
+    // Low and full speed devices often claim bcdUSB 2.0 as well, only a USB 3
+    // device on a USB 2 or older link can be told apart
+    pub fn is_below_max_speed(&self) -> bool {
+        self.max_speed.starts_with('3') && matches!(self.speed.as_str(), "1.0" | "1.1" | "2.0")
+    }
 
-            if !available_profiles.is_empty() {
-                *device.available_profiles.0.lock().unwrap() = Some(available_profiles.clone());
-            };
+    // The disks whose sysfs path runs through the device, a card reader has
+    // one per slot. Only looked up for mass storage devices.
+    fn get_block_devices(busid: &str) -> Vec<CfhdbUsbBlockDevice> {
+        let entries = match fs::read_dir("/sys/block") {
+            Ok(t) => t,
+            Err(_) => return vec![],
+        };
+        let mut block_devices: Vec<CfhdbUsbBlockDevice> = entries
+            .flatten()
+            .map(|x| x.path())
+            .filter(|x| {
+                fs::canonicalize(x).is_ok_and(|x| {
+                    x.components()
+                        .any(|x| x.as_os_str().to_str() == Some(busid))
+                })
+            })
+            .filter_map(|x| CfhdbUsbBlockDevice::from_sysfs(&x))
+            .collect();
+        block_devices.sort_by(|a, b| a.path.cmp(&b.path));
+        block_devices
+    }
+
+    fn get_sysfs_string(busid: &str, attribute: &str) -> Option<String> {
+        fs::read_to_string(
+            std::path::Path::new("/sys/bus/usb/devices")
+                .join(busid)
+                .join(attribute),
+        )
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+    }
+
+    // Manufacturer, product and serial, in that order. Opening the device
+    // needs write access to its node, without that the strings come from the
+    // copies the kernel keeps in sysfs.
+    fn get_string_descriptors(
+        iter: &rusb::Device<rusb::GlobalContext>,
+        device_descriptor: &rusb::DeviceDescriptor,
+        busid: &str,
+    ) -> [Option<String>; 3] {
+        let indexes = [
+            device_descriptor.manufacturer_string_index(),
+            device_descriptor.product_string_index(),
+            device_descriptor.serial_number_string_index(),
+        ];
+        let mut strings = [None, None, None];
+        if let Ok(handle) = iter.open() {
+            let language = handle
+                .read_languages(STRING_DESCRIPTOR_TIMEOUT)
+                .ok()
+                .and_then(|x| x.first().copied());
+            if let Some(language) = language {
+                for (string, index) in strings.iter_mut().zip(indexes) {
+                    *string = index
+                        .and_then(|i| {
+                            handle
+                                .read_string_descriptor(language, i, STRING_DESCRIPTOR_TIMEOUT)
+                                .ok()
+                        })
+                        .map(|x| x.trim().to_owned())
+                        .filter(|x| !x.is_empty());
+                }
+            }
         }
+        for (string, attribute) in strings
+            .iter_mut()
+            .zip(["manufacturer", "product", "serial"])
+        {
+            if string.is_none() {
+                *string = Self::get_sysfs_string(busid, attribute);
+            }
+        }
+        strings
+    }
+
+    // (class, subclass, protocol) of the last interface of the active
+    // configuration, composite devices leave the class in the device
+    // descriptor at 00
+    fn get_interface_class(iter: &rusb::Device<rusb::GlobalContext>) -> Option<(u8, u8, u8)> {
+        iter.active_config_descriptor().ok().and_then(|config| {
+            config
+                .interfaces()
+                .last()
+                .and_then(|x| x.descriptors().last())
+                .map(|x| (x.class_code(), x.sub_class_code(), x.protocol_code()))
+        })
+    }
+
+    #[instrument(
+        level = "trace",
+        skip_all,
+        fields(device = %device.sysfs_busid, profiles = profile_data.len())
+    )]
+    pub fn get_available_profiles(
+        profile_data: &[Arc<CfhdbUsbProfile>],
+        device: &Self,
+    ) -> Vec<Arc<CfhdbUsbProfile>> {
+        match_profiles(profile_data, device)
+    }
+
+    pub fn attach_available_profiles(&self, profiles: Vec<Arc<CfhdbUsbProfile>>) {
+        *self.available_profiles.0.lock().unwrap() = if profiles.is_empty() {
+            None
+        } else {
+            Some(profiles)
+        };
+    }
+
+    pub fn set_available_profiles(profile_data: &[Arc<CfhdbUsbProfile>], device: &Self) {
+        device.attach_available_profiles(Self::get_available_profiles(profile_data, device));
     }
 
     fn get_started(busid: &str) -> bool {
@@ -243,250 +440,587 @@ impl CfhdbUsbDevice {
         device_driver_path.exists()
     }
 
-    fn get_enabled(busid: &str) -> bool {
-        let usb_busid_blacklist_path = "/etc/cfhdb/usb_blacklist";
-        match File::open(&usb_busid_blacklist_path) {
-            Ok(file) => {
-                let reader = io::BufReader::new(file);
-                for line in reader.lines() {
-                    match line {
-                        Ok(t) => {
-                            if t.trim() == busid {
-                                return false;
-                            }
-                        }
-                        Err(_) => {}
-                    };
-                }
-            }
-            Err(_) => {}
-        }
-        return true;
+    // Blacklisted by busid, or by stable id to follow it from port to port
+    fn get_enabled(busid: &str, stable_id: Option<&str>) -> bool {
+        !blacklist::is_blacklisted("usb", busid)
+            && !stable_id.is_some_and(|x| blacklist::is_blacklisted("usb", x))
     }
 
-    fn get_modinfo_name(busid: &str) -> Result<String, io::Error> {
+    // The first module the interface modalias resolves to
+    fn get_module_name(busid: &str) -> Result<String, io::Error> {
         let modalias = fs::read_to_string(format!("/sys/bus/usb/devices/{}:1.0/modalias", busid))?;
-        let modinfo_cmd = duct::cmd!("modinfo", modalias);
-        let stdout = modinfo_cmd.read()?;
-        let re = Regex::new(r"name:\s+(\w+)").unwrap();
-        for line in stdout.lines() {
-            if let Some(captures) = re.captures(line) {
-                // Extract the module name from the capture group
-                if let Some(module_name) = captures.get(1) {
-                    return Ok(module_name.as_str().to_string());
-                }
-            }
-        }
-        Err(io::Error::new(ErrorKind::NotFound, "not found"))
+        modules::resolve_modalias(modalias.trim())
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "not found"))
     }
 
-    pub fn stop_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
+    pub fn stop_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&["stop_device", "usb", &self.sysfs_busid])
+            .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
         Ok(())
     }
 
-    pub fn start_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "usb",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "usb",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        };
-        cmd.run()?;
+    pub fn start_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&[
+            "start_device",
+            "usb",
+            &self.sysfs_busid,
+            &Self::get_module_name(&self.sysfs_busid).unwrap_or_default(),
+        ])
+        .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
         Ok(())
     }
 
-    pub fn enable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
+    // Re-enumerates the device as if it had been unplugged and plugged back in,
+    // which recovers most wedged devices
+    pub fn reset_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&["reset_device", "usb", &self.sysfs_busid])
+            .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
         Ok(())
     }
 
-    pub fn disable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
+    pub fn authorize_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&["authorize_device", "usb", &self.sysfs_busid])
+            .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
         Ok(())
     }
 
-    pub fn get_device_from_busid(busid: &str) -> Result<CfhdbUsbDevice, io::Error> {
-        let devices = match CfhdbUsbDevice::get_devices() {
-            Some(t) => t,
-            None => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Could not get usb devices",
-                ));
-            }
-        };
-        match devices.iter().find(|x| x.sysfs_busid == busid) {
-            Some(device) => Ok(device.clone()),
-            None => Err(io::Error::new(
-                ErrorKind::NotFound,
-                "no usb device with matching busid",
-            )),
+    pub fn deauthorize_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&["deauthorize_device", "usb", &self.sysfs_busid])
+            .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    pub fn set_power_control(&self, control: UsbPowerControl) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&[
+            "set_power_control",
+            "usb",
+            &self.sysfs_busid,
+            control.as_str(),
+        ])
+        .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    // How long the device has to be idle before it is suspended, only used
+    // while the power control is auto
+    pub fn set_autosuspend_delay(&self, delay_ms: i64) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&[
+            "set_autosuspend_delay",
+            "usb",
+            &self.sysfs_busid,
+            &delay_ms.to_string(),
+        ])
+        .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    // Whether the device may wake the system from suspend, like a keyboard
+    // key press or a mouse being moved
+    pub fn set_wakeup(&self, enabled: bool) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&[
+            "set_wakeup",
+            "usb",
+            &self.sysfs_busid,
+            if enabled { "enabled" } else { "disabled" },
+        ])
+        .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    pub fn enable_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&["enable_device", "usb", &self.sysfs_busid])
+            .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    pub fn disable_device(&self) -> Result<(), CfhdbError> {
+        privilege::run_sysfs_helper(&["disable_device", "usb", &self.sysfs_busid])
+            .map_err(CfhdbError::Sysfs)?;
+        Self::invalidate_cache();
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), fields(busid = %self.sysfs_busid))]
+    fn matches_target(&self, target: &UsbTarget) -> bool {
+        match target {
+            UsbTarget::BusId(x) => self.sysfs_busid == x.as_str(),
+            UsbTarget::Serial(x) => self.serial_number_string_index == *x,
+            UsbTarget::StableId(x) => self.stable_id.as_deref() == Some(x.as_str()),
         }
     }
 
-    pub fn get_devices() -> Option<Vec<Self>> {
-        let lsusb_entries = parse_from_lsusb_output();
+    // Takes a busid, serial:<serial> or a stable id. Two devices of the same
+    // model sharing a serial number can only be told apart by busid, the
+    // first one is taken.
+    pub fn get_device_from_busid(target: &str) -> Result<CfhdbUsbDevice, CfhdbError> {
+        let target: UsbTarget = target.parse()?;
+        let mut devices = Self::try_iter_devices(None)?;
+        match devices.find(|x| x.matches_target(&target)) {
+            Some(device) => Ok(device),
+            None => Err(CfhdbError::DeviceNotFound("usb", target.to_string())),
+        }
+    }
+
+    // The busid a target currently points at, only serials and stable ids
+    // need the devices enumerated for that
+    pub fn resolve_busid(target: &str) -> Result<SysfsBusId, CfhdbError> {
+        match target.parse()? {
+            UsbTarget::BusId(busid) => Ok(busid),
+            _ => Ok(Self::get_device_from_busid(target)?.sysfs_busid.parse()?),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn get_devices_async() -> Option<Vec<Self>> {
+        tokio::task::spawn_blocking(Self::get_devices)
+            .await
+            .unwrap_or(None)
+    }
+
+    fn from_rusb_device(iter: &rusb::Device<rusb::GlobalContext>) -> Option<Self> {
+        let device_descriptor = iter.device_descriptor().ok()?;
+
+        let item_bus_number = iter.bus_number();
+        let item_address = iter.address();
+        let item_sysfs_busid =
+            Self::get_sysfs_id(item_bus_number, item_address).unwrap_or("???".to_owned()); //format!("{}-{}-{}", iter.bus_number(), iter.port_number(), iter.address());
+        let item_vendor_id = from_hex(device_descriptor.vendor_id() as _, 4);
+        let item_product_id = from_hex(device_descriptor.product_id() as _, 4);
+        let [manufacturer, product, serial] =
+            Self::get_string_descriptors(iter, &device_descriptor, &item_sysfs_busid);
+        let item_manufacturer_string_index = manufacturer.unwrap_or("???".to_owned());
+        let item_product_string_index = product.unwrap_or("???".to_owned());
+        let item_stable_id: Option<String> = serial
+            .as_deref()
+            .map(|x| UsbStableId::compute(&item_vendor_id, &item_product_id, x.trim()).into());
+        let item_serial_number_string_index = serial.unwrap_or("Unknown".to_string());
+        let (item_vendor_name, item_product_name) =
+            Self::get_usb_ids_names(&item_vendor_id, &item_product_id);
+        let item_class = Self::get_interface_class(iter);
+        let item_class_code = item_class
+            .map(|(class, ..)| from_hex(class as _, 2).to_uppercase())
+            .unwrap_or("00".to_owned());
+        let item_class_name = item_class
+            .and_then(|(class, subclass, protocol)| classes::class_name(class, subclass, protocol))
+            .unwrap_or("Unknown");
+        let item_block_devices = match item_class_code.as_str() {
+            "08" => Self::get_block_devices(&item_sysfs_busid),
+            _ => vec![],
+        };
+        let item_hid_interfaces = match item_class_code.as_str() {
+            "03" => CfhdbUsbHidInterface::from_sysfs(&item_sysfs_busid),
+            _ => vec![],
+        };
+        let item_started = Self::get_started(&item_sysfs_busid);
+        let item_enabled = Self::get_enabled(&item_sysfs_busid, item_stable_id.as_deref());
+        let item_authorized =
+            Self::get_sysfs_string(&item_sysfs_busid, "authorized").map(|x| x != "0");
+        let item_power_control =
+            Self::get_sysfs_string(&item_sysfs_busid, "power/control").and_then(|x| x.parse().ok());
+        let item_autosuspend_delay_ms =
+            Self::get_sysfs_string(&item_sysfs_busid, "power/autosuspend_delay_ms")
+                .and_then(|x| x.parse().ok());
+        let item_wakeup = match Self::get_sysfs_string(&item_sysfs_busid, "power/wakeup").as_deref()
+        {
+            Some("enabled") => Some(true),
+            Some("disabled") => Some(false),
+            _ => None,
+        };
+        let item_protocol_code = from_hex(device_descriptor.protocol_code() as _, 4);
+        //let item_class_code = (from_hex(device_descriptor.class_code() as _, 2) + &from_hex(device_descriptor.sub_class_code() as _, 2)).to_uppercase();
+        //let item_class_code = from_hex(device_descriptor.class_code() as _, 2).to_uppercase();
+        let item_usb_version = device_descriptor.usb_version().to_string();
+        let item_port_number = iter.port_number();
+        let item_kernel_driver =
+            Self::get_kernel_driver(&item_sysfs_busid).unwrap_or("Unknown".to_string());
+        let item_speed = match iter.speed() {
+            rusb::Speed::Low => "1.0",
+            rusb::Speed::Full => "1.1",
+            rusb::Speed::High => "2.0",
+            rusb::Speed::Super => "3.0",
+            rusb::Speed::SuperPlus => "3.1",
+            _ => Self::get_sysfs_string(&item_sysfs_busid, "speed")
+                .map_or("Unknown", |x| speed_from_mbps(&x)),
+        };
+        // The fastest link bcdUSB allows, 3.1 and 3.2 devices can do 10Gbps
+        let usb_version = device_descriptor.usb_version();
+        let item_max_speed = match (usb_version.major(), usb_version.minor()) {
+            (3, 0) => "3.0",
+            (3, _) => "3.1",
+            (2, _) => "2.0",
+            (1, 0) => "1.0",
+            (1, _) => "1.1",
+            _ => "Unknown",
+        };
+        Some(Self {
+            manufacturer_string_index: item_manufacturer_string_index,
+            product_string_index: item_product_string_index,
+            serial_number_string_index: item_serial_number_string_index,
+            vendor_name: item_vendor_name,
+            product_name: item_product_name,
+            protocol_code: item_protocol_code,
+            class_code: item_class_code,
+            class_name: item_class_name.to_owned(),
+            block_devices: item_block_devices,
+            hid_interfaces: item_hid_interfaces,
+            vendor_id: item_vendor_id,
+            product_id: item_product_id,
+            usb_version: item_usb_version,
+            sysfs_busid: item_sysfs_busid,
+            stable_id: item_stable_id,
+            bus_number: item_bus_number,
+            port_number: item_port_number,
+            address: item_address,
+            kernel_driver: item_kernel_driver.clone(),
+            started: if item_kernel_driver != "Unknown" {
+                Some(item_started)
+            } else {
+                None
+            },
+            enabled: item_enabled,
+            authorized: item_authorized,
+            power_control: item_power_control,
+            autosuspend_delay_ms: item_autosuspend_delay_ms,
+            wakeup: item_wakeup,
+            speed: item_speed.to_string(),
+            max_speed: item_max_speed.to_string(),
+            available_profiles: ProfileWrapper(Arc::default()),
+        })
+    }
+
+    fn try_iter_devices<'a>(
+        progress: Option<&'a dyn ProgressSink>,
+    ) -> Result<impl Iterator<Item = Self> + 'a, CfhdbError> {
+        report(progress, ProgressEvent::stage(ProgressStage::Enumerating));
         // Get hardware devices
-        let usb_devices = rusb::devices().unwrap();
-        let mut devices = vec![];
-
-        for iter in usb_devices.iter() {
-            let device_descriptor = iter.device_descriptor().unwrap();
-
-            let item_bus_number = iter.bus_number();
-            let item_address = iter.address();
-            let item_sysfs_busid =
-                Self::get_sysfs_id(item_bus_number, item_address).unwrap_or("???".to_owned()); //format!("{}-{}-{}", iter.bus_number(), iter.port_number(), iter.address());
-            let item_vendor_id = from_hex(device_descriptor.vendor_id() as _, 4);
-            let item_product_id = from_hex(device_descriptor.product_id() as _, 4);
-            let item_lsusb_entry = lsusb_entries
-                .iter()
-                .cloned()
-                .find(|x| x.vendor_id == item_vendor_id && x.product_id == item_product_id);
-            let (item_manufacturer_string_index, item_product_string_index, item_class_code) =
-                match item_lsusb_entry {
-                    Some(t) => match (t.vendor_name, t.product_name) {
-                        (Some(a), Some(b)) => (a, b, t.interface_class),
-                        (_, _) => ("???".to_owned(), "???".to_owned(), t.interface_class),
-                    },
-                    None => ("???".to_owned(), "???".to_owned(), "00".to_owned()),
-                };
-            let item_started = Self::get_started(&item_sysfs_busid);
-            let item_enabled = Self::get_enabled(&item_sysfs_busid);
-            let item_serial_number_string_index =
-                Self::get_serial(&item_sysfs_busid).unwrap_or("Unknown".to_string());
-            let item_protocol_code = from_hex(device_descriptor.protocol_code() as _, 4);
-            //let item_class_code = (from_hex(device_descriptor.class_code() as _, 2) + &from_hex(device_descriptor.sub_class_code() as _, 2)).to_uppercase();
-            //let item_class_code = from_hex(device_descriptor.class_code() as _, 2).to_uppercase();
-            let item_usb_version = device_descriptor.usb_version().to_string();
-            let item_port_number = iter.port_number();
-            let item_kernel_driver =
-                Self::get_kernel_driver(&item_sysfs_busid).unwrap_or("Unknown".to_string());
-            let item_speed = match iter.speed() {
-                rusb::Speed::Low => "1.0",
-                rusb::Speed::Full => "1.1",
-                rusb::Speed::High => "2.0",
-                rusb::Speed::Super => "3.0",
-                rusb::Speed::SuperPlus => "3.1",
-                _ => "Unknown",
-            };
-            devices.push(Self {
-                manufacturer_string_index: item_manufacturer_string_index,
-                product_string_index: item_product_string_index,
-                serial_number_string_index: item_serial_number_string_index,
-                protocol_code: item_protocol_code,
-                class_code: item_class_code,
-                vendor_id: item_vendor_id,
-                product_id: item_product_id,
-                usb_version: item_usb_version,
-                sysfs_busid: item_sysfs_busid,
-                bus_number: item_bus_number,
-                port_number: item_port_number,
-                address: item_address,
-                kernel_driver: item_kernel_driver.clone(),
-                started: if item_kernel_driver != "Unknown" {
-                    Some(item_started)
-                } else {
-                    None
-                },
-                enabled: item_enabled,
-                speed: item_speed.to_string(),
-                available_profiles: ProfileWrapper(Arc::default()),
-            });
+        let usb_devices: Vec<rusb::Device<rusb::GlobalContext>> = rusb::devices()?.iter().collect();
+        let total = usb_devices.len();
+        let mut seen_busids = HashSet::new();
+
+        Ok(usb_devices
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(index, iter)| {
+                report(
+                    progress,
+                    ProgressEvent::percent(ProgressStage::Enumerating, index + 1, total),
+                );
+                let device = Self::from_rusb_device(&iter)?;
+                //Check if already in list
+                if device.sysfs_busid == "???" || !seen_busids.insert(device.sysfs_busid.clone()) {
+                    return None;
+                }
+                Some(device)
+            }))
+    }
+
+    fn iter_devices_reporting<'a>(
+        progress: Option<&'a dyn ProgressSink>,
+    ) -> Option<impl Iterator<Item = Self> + 'a> {
+        match Self::try_iter_devices(progress) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                warn!(error = %e, "could not list usb devices");
+                None
+            }
         }
+    }
+
+    // Devices are probed one at a time as the iterator advances, so callers that
+    // only need the first match can stop early.
+    pub fn iter_devices() -> Option<impl Iterator<Item = Self>> {
+        Self::iter_devices_reporting(None)
+    }
 
-        let mut uniq_devices = vec![];
-        for device in devices.iter() {
-            //Check if already in list
-            let found = uniq_devices
-                .iter()
-                .any(|x: &Self| device.sysfs_busid == x.sysfs_busid);
+    #[instrument(level = "debug", skip_all)]
+    pub fn get_devices_with_progress(progress: &dyn ProgressSink) -> Option<Vec<Self>> {
+        let devices = Self::iter_devices_reporting(Some(progress))?.collect();
+        progress.report(ProgressEvent::stage(ProgressStage::Finished));
+        Some(devices)
+    }
 
-            if !found && device.sysfs_busid != "???" {
-                uniq_devices.push(device.clone());
+    #[instrument(level = "debug", skip_all)]
+    pub fn get_devices_cancellable(
+        progress: Option<&dyn ProgressSink>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Self>, CfhdbError> {
+        let mut result = vec![];
+        for device in Self::try_iter_devices(progress)? {
+            if cancel.is_cancelled() {
+                return Err(CfhdbError::Cancelled);
             }
+            result.push(device);
         }
-        Some(uniq_devices)
+        Ok(result)
+    }
+
+    #[instrument(level = "debug")]
+    pub fn get_devices() -> Option<Vec<Self>> {
+        let devices: Vec<Self> = Self::iter_devices()?.collect();
+        debug!(count = devices.len(), "enumerated usb devices");
+        Some(devices)
+    }
+
+    pub fn get_devices_cached() -> Option<Vec<Self>> {
+        USB_DEVICE_CACHE.get_or_refresh(Self::get_devices)
+    }
+
+    pub fn set_cache_ttl(ttl: Duration) {
+        USB_DEVICE_CACHE.set_ttl(ttl)
     }
+
+    pub fn invalidate_cache() {
+        USB_DEVICE_CACHE.invalidate()
+    }
+
     pub fn create_class_hashmap(devices: Vec<Self>) -> HashMap<String, Vec<Self>> {
-        let mut map: HashMap<String, Vec<Self>> = HashMap::new();
+        group_by_class(devices)
+    }
+}
+
+// A disk of a usb storage device, or one of its partitions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbUsbBlockDevice {
+    // /dev/sdb, /dev/sdb1
+    pub path: String,
+    pub size_bytes: u64,
+    // As udev probed it, None when it found no filesystem or udev is not
+    // running
+    pub filesystem: Option<String>,
+    pub partitions: Vec<CfhdbUsbBlockDevice>,
+}
+
+impl CfhdbUsbBlockDevice {
+    fn from_sysfs(path: &std::path::Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_owned();
+        let read = |attribute: &str| {
+            fs::read_to_string(path.join(attribute))
+                .ok()
+                .map(|x| x.trim().to_owned())
+        };
+        // In 512 byte sectors whatever the logical block size is
+        let size_bytes = read("size")?.parse::<u64>().ok()? * 512;
+        let filesystem = read("dev")
+            .and_then(|x| fs::read_to_string(format!("/run/udev/data/b{}", x)).ok())
+            .and_then(|x| {
+                x.lines()
+                    .find_map(|x| x.strip_prefix("E:ID_FS_TYPE="))
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_owned())
+            });
+        // Partitions are the subdirectories with a partition attribute
+        let mut partitions: Vec<Self> = fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|x| x.path())
+                    .filter(|x| x.join("partition").exists())
+                    .filter_map(|x| Self::from_sysfs(&x))
+                    .collect()
+            })
+            .unwrap_or_default();
+        partitions.sort_by(|a, b| a.path.cmp(&b.path));
+        Some(Self {
+            path: format!("/dev/{}", name),
+            size_bytes,
+            filesystem,
+            partitions,
+        })
+    }
+}
 
+// A hub or device in the port hierarchy. Root hubs never make it into the
+// device list, their nodes have no device and take the driver of the host
+// controller and the speed of the bus from the usbN directory.
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbUsbTreeNode {
+    pub sysfs_busid: String,
+    pub kernel_driver: String,
+    pub speed: String,
+    pub device: Option<CfhdbUsbDevice>,
+    pub children: Vec<CfhdbUsbTreeNode>,
+}
+
+impl CfhdbUsbTreeNode {
+    fn root_hub(bus_number: u8) -> Self {
+        let busid = format!("usb{}", bus_number);
+        let kernel_driver = fs::read_link(format!("/sys/bus/usb/devices/{}/../driver", busid))
+            .ok()
+            .and_then(|link| link.file_name().map(|s| s.to_string_lossy().into_owned()))
+            .unwrap_or("Unknown".to_owned());
+        let speed = CfhdbUsbDevice::get_sysfs_string(&busid, "speed")
+            .map_or("Unknown", |x| speed_from_mbps(&x));
+        Self {
+            sysfs_busid: busid,
+            kernel_driver,
+            speed: speed.to_owned(),
+            device: None,
+            children: vec![],
+        }
+    }
+
+    fn from_device(device: CfhdbUsbDevice) -> Self {
+        Self {
+            sysfs_busid: device.sysfs_busid.clone(),
+            kernel_driver: device.kernel_driver.clone(),
+            speed: device.speed.clone(),
+            device: Some(device),
+            children: vec![],
+        }
+    }
+
+    // "1-1.4" hangs off port 4 of the hub at "1-1", which hangs off port 1 of
+    // the root hub of bus 1
+    fn parent_busid(busid: &str) -> Option<String> {
+        match busid.rsplit_once('.') {
+            Some((parent, _)) => Some(parent.to_owned()),
+            None => busid.split_once('-').map(|(bus, _)| format!("usb{}", bus)),
+        }
+    }
+
+    fn attach_children(&mut self, children: &mut HashMap<String, Vec<CfhdbUsbDevice>>) {
+        let mut devices = children.remove(&self.sysfs_busid).unwrap_or_default();
+        devices.sort_by_key(|x| x.port_number);
         for device in devices {
-            // Use the entry API to get or create a Vec for the key
-            map.entry(device.class_code.clone())
-                .or_insert_with(Vec::new)
-                .push(device);
+            let mut node = Self::from_device(device);
+            node.attach_children(children);
+            self.children.push(node);
         }
+    }
+
+    // One node per bus, buses without any devices plugged in included. A
+    // device whose hub is missing from the list goes straight under the root
+    // hub instead of being dropped.
+    pub fn build_tree(devices: Vec<CfhdbUsbDevice>) -> Vec<Self> {
+        let mut bus_numbers: Vec<u8> = devices.iter().map(|x| x.bus_number).collect();
+        if let Ok(entries) = fs::read_dir("/sys/bus/usb/devices") {
+            bus_numbers.extend(entries.flatten().filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|x| x.strip_prefix("usb"))
+                    .and_then(|x| x.parse::<u8>().ok())
+            }));
+        }
+        bus_numbers.sort();
+        bus_numbers.dedup();
 
-        map
+        let busids: HashSet<String> = devices.iter().map(|x| x.sysfs_busid.clone()).collect();
+        let mut children: HashMap<String, Vec<CfhdbUsbDevice>> = HashMap::new();
+        for device in devices {
+            let root = format!("usb{}", device.bus_number);
+            let parent = Self::parent_busid(&device.sysfs_busid)
+                .filter(|x| busids.contains(x))
+                .unwrap_or(root);
+            children.entry(parent).or_default().push(device);
+        }
+
+        bus_numbers
+            .into_iter()
+            .map(|bus_number| {
+                let mut root = Self::root_hub(bus_number);
+                root.attach_children(&mut children);
+                root
+            })
+            .collect()
     }
 }
 
-#[derive(Debug, Clone)]
+impl CfhdbDevice for CfhdbUsbDevice {
+    type Profile = CfhdbUsbProfile;
+    const CATEGORY: &'static str = "usb";
+
+    fn get_match_fields<'a>(&'a self, profile: &'a CfhdbUsbProfile) -> Vec<MatchField<'a>> {
+        vec![
+            MatchField::new(
+                &profile.class_codes,
+                &profile.blacklisted_class_codes,
+                &self.class_code,
+            ),
+            MatchField::new(
+                &profile.vendor_ids,
+                &profile.blacklisted_vendor_ids,
+                &self.vendor_id,
+            ),
+            MatchField::new(
+                &profile.product_ids,
+                &profile.blacklisted_product_ids,
+                &self.product_id,
+            ),
+        ]
+    }
+
+    fn get_id(&self) -> String {
+        self.sysfs_busid.clone()
+    }
+
+    fn get_class(&self) -> String {
+        self.class_code.clone()
+    }
+
+    fn get_display_fields(&self) -> (String, String) {
+        (
+            self.get_manufacturer_name().to_owned(),
+            self.get_product_name().to_owned(),
+        )
+    }
+
+    fn get_matched_profiles(&self) -> Option<Vec<Arc<CfhdbUsbProfile>>> {
+        self.available_profiles.0.lock().unwrap().clone()
+    }
+
+    fn attach_profiles(&self, profiles: Vec<Arc<CfhdbUsbProfile>>) {
+        self.attach_available_profiles(profiles)
+    }
+
+    fn iter_all() -> Option<Box<dyn Iterator<Item = Self>>> {
+        Some(Box::new(Self::iter_devices()?))
+    }
+
+    // Stable ids are taken as they are for the blacklist, which applies them
+    // to whichever port the device turns up on
+    fn normalize_id(id: &str) -> Result<String, io::Error> {
+        if id.trim().starts_with("id:") {
+            Ok(id.parse::<UsbStableId>()?.into())
+        } else {
+            Ok(id.parse::<SysfsBusId>()?.as_str().to_owned())
+        }
+    }
+
+    fn enable_device(&self) -> Result<(), io::Error> {
+        Ok(CfhdbUsbDevice::enable_device(self)?)
+    }
+
+    fn disable_device(&self) -> Result<(), io::Error> {
+        Ok(CfhdbUsbDevice::disable_device(self)?)
+    }
+
+    fn start_device(&self) -> Result<(), io::Error> {
+        Ok(CfhdbUsbDevice::start_device(self)?)
+    }
+
+    fn stop_device(&self) -> Result<(), io::Error> {
+        Ok(CfhdbUsbDevice::stop_device(self)?)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CfhdbUsbProfile {
     pub codename: String,
     pub i18n_desc: String,
+    #[serde(default = "crate::profile::default_icon_name")]
     pub icon_name: String,
     pub license: String,
     pub class_codes: Vec<String>,
@@ -495,53 +1029,129 @@ pub struct CfhdbUsbProfile {
     pub blacklisted_class_codes: Vec<String>,
     pub blacklisted_vendor_ids: Vec<String>,
     pub blacklisted_product_ids: Vec<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_packages")]
     pub packages: Option<Vec<String>>,
+    #[serde(default = "crate::profile::default_check_script")]
     pub check_script: String,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub install_script: Option<String>,
+    #[serde(deserialize_with = "crate::profile::deserialize_script")]
     pub remove_script: Option<String>,
     pub experimental: bool,
     pub removable: bool,
     pub veiled: bool,
+    pub unsandboxed_check: bool,
+    pub check_is_pure: bool,
+    pub flatpaks: Option<Vec<String>>,
+    pub snaps: Option<Vec<String>>,
+    pub signature: Option<String>,
+    pub superseded_by: Option<String>,
+    pub regenerate_initramfs: bool,
+    #[serde(with = "crate::modules::options_map")]
+    pub module_options: Vec<ModuleOption>,
+    pub blacklisted_modules: Vec<String>,
+    pub kernel_parameters: Vec<String>,
     pub priority: i32,
 }
 
 impl CfhdbUsbProfile {
     pub fn get_profile_from_codename(
         codename: &str,
-        profiles: Vec<CfhdbUsbProfile>,
-    ) -> Result<Self, io::Error> {
+        profiles: &[Arc<CfhdbUsbProfile>],
+    ) -> Result<Self, CfhdbError> {
         match profiles.iter().find(|x| x.codename == codename) {
-            Some(profile) => Ok(profile.clone()),
-            None => Err(io::Error::new(
-                ErrorKind::NotFound,
-                "no usb profile with matching codename",
-            )),
+            Some(profile) => Ok(profile.as_ref().clone()),
+            None => Err(CfhdbError::ProfileNotFound("usb", codename.to_owned())),
         }
     }
+}
 
-    pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+impl CfhdbProfile for CfhdbUsbProfile {
+    fn get_codename(&self) -> &str {
+        &self.codename
+    }
+
+    fn get_i18n_desc(&self) -> &str {
+        &self.i18n_desc
+    }
+
+    fn get_icon_name(&self) -> &str {
+        &self.icon_name
+    }
+
+    fn get_license(&self) -> &str {
+        &self.license
+    }
+
+    fn get_packages(&self) -> Option<&[String]> {
+        self.packages.as_deref()
+    }
+
+    fn get_check_script(&self) -> &str {
+        &self.check_script
+    }
+
+    fn get_install_script(&self) -> Option<&str> {
+        self.install_script.as_deref()
+    }
+
+    fn get_remove_script(&self) -> Option<&str> {
+        self.remove_script.as_deref()
+    }
+
+    fn is_experimental(&self) -> bool {
+        self.experimental
+    }
+
+    fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    fn is_veiled(&self) -> bool {
+        self.veiled
+    }
+
+    fn get_priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn is_check_unsandboxed(&self) -> bool {
+        self.unsandboxed_check
+    }
+
+    fn get_signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    fn is_check_pure(&self) -> bool {
+        self.check_is_pure
+    }
+
+    fn get_flatpaks(&self) -> Option<&[String]> {
+        self.flatpaks.as_deref()
+    }
+
+    fn get_snaps(&self) -> Option<&[String]> {
+        self.snaps.as_deref()
+    }
+
+    fn get_superseded_by(&self) -> Option<&str> {
+        self.superseded_by.as_deref()
+    }
+
+    fn regenerates_initramfs(&self) -> bool {
+        self.regenerate_initramfs
+    }
+
+    fn get_module_options(&self) -> &[ModuleOption] {
+        &self.module_options
+    }
+
+    fn get_blacklisted_modules(&self) -> &[String] {
+        &self.blacklisted_modules
+    }
+
+    fn get_kernel_parameters(&self) -> &[String] {
+        &self.kernel_parameters
     }
 }
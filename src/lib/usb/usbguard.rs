@@ -0,0 +1,87 @@
+use super::CfhdbUsbDevice;
+use crate::{blacklist, ids::UsbTarget, udev};
+use std::io;
+
+// usbguard strings are double quoted with C style escapes
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn device_rule(target: &str, device: &CfhdbUsbDevice, via_port: bool) -> String {
+    let mut rule = format!(
+        "{} id {}:{}",
+        target,
+        device.vendor_id.to_lowercase(),
+        device.product_id.to_lowercase()
+    );
+    if device.serial_number_string_index != "Unknown" {
+        rule.push_str(&format!(
+            " serial {}",
+            quote(&device.serial_number_string_index)
+        ));
+    }
+    if via_port {
+        rule.push_str(&format!(" via-port {}", quote(&device.sysfs_busid)));
+    }
+    rule
+}
+
+// The usb policy cfhdb enforces as a usbguard rules file. Connected devices
+// get an allow or block rule with their ids, devices blacklisted by stable
+// id are blocked wherever they are plugged in, and blacklisted or
+// persistently disabled ports with nothing on them are blocked by port.
+// A stable id that is not connected cannot be turned back into a serial
+// number, it is left as a comment. The implicit policy is up to
+// usbguard-daemon.conf, nothing is said about it here.
+pub fn export_rules(devices: &[CfhdbUsbDevice]) -> Result<String, io::Error> {
+    let blacklisted = blacklist::read_blacklist("usb")?;
+    let persistent = udev::read_persistent_disables()?;
+    let mut rules = vec!["# Exported from the cfhdb usb blacklist and udev rules".to_owned()];
+    let mut devices: Vec<&CfhdbUsbDevice> = devices.iter().collect();
+    devices.sort_by(|a, b| a.sysfs_busid.cmp(&b.sysfs_busid));
+    for device in &devices {
+        let by_port =
+            blacklisted.contains(&device.sysfs_busid) || persistent.contains(&device.sysfs_busid);
+        let by_id = device
+            .stable_id
+            .as_ref()
+            .is_some_and(|x| blacklisted.contains(x));
+        rules.push(if by_port {
+            device_rule("block", device, true)
+        } else if by_id {
+            device_rule("block", device, false)
+        } else {
+            device_rule("allow", device, true)
+        });
+    }
+    let mut unplugged: Vec<&String> = blacklisted.iter().chain(&persistent).collect();
+    unplugged.sort();
+    unplugged.dedup();
+    for entry in unplugged {
+        if devices
+            .iter()
+            .any(|x| x.sysfs_busid == *entry || x.stable_id.as_ref() == Some(entry))
+        {
+            continue;
+        }
+        match entry.parse::<UsbTarget>() {
+            Ok(UsbTarget::StableId(_)) => {
+                rules.push(format!("# {} is blacklisted but not connected", entry))
+            }
+            _ => rules.push(format!("block via-port {}", quote(entry))),
+        }
+    }
+    Ok(rules.into_iter().map(|x| x + "\n").collect())
+}
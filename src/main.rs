@@ -1,30 +1,87 @@
-use std::{fs, io::Write, os::unix::fs::PermissionsExt, path::Path, process::exit};
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use cli_table::{format::Justify, Cell, Color, Style, Table};
+use cli_table::{format::Justify, Cell, CellStruct, Color, Style, Table};
 use colored::Colorize;
+use libcfhdb::{
+    audit::{self, AuditAction, AuditRecord},
+    autoinstall::{AutoInstallMode, AutoInstallRules},
+    commands::{self, CommandError},
+    database::{self, DatabaseSource},
+    device::CfhdbDevice,
+    doctor::{self, DoctorStatus},
+    error::CfhdbError,
+    facts,
+    hotplug::{self, HotplugAction, HotplugEvent, HotplugPolicy},
+    hwdb::{self, HwdbMatch},
+    installed::{self, InstalledProfile, ProfileChange},
+    journal, kparams, lock,
+    metrics::{self, MetricsWriter},
+    modules::{self, DeviceModules, ModuleOption},
+    packages::{self, ConffilePolicy, PackageBackend, PackagePlan},
+    paths,
+    privilege::{self, EscalationBackend},
+    probe,
+    profile::{
+        self, CfhdbProfile, PartialInstallError, ProfileOperationOptions, ProfileOperationOutcome,
+    },
+    script,
+    signing::{self, ScriptVerifier, SignaturePolicy},
+    snapshot::{self, Snapshot, SnapshotTool},
+    state::{self, DisabledDevice, SystemState},
+    status::{self, DeviceStatus},
+    systemd::{self, TimerSettings},
+    transaction,
+};
 use serde::Deserialize;
-use users::get_current_username;
+use tracing_subscriber::EnvFilter;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const PROFILE_CONFIG_PATH: &str = "/etc/cfhdb/profile-config.json";
 
-mod config;
+// Set by --user, keeps caches and state in the home directory and refuses
+// operations that would need root
+static USER_MODE: AtomicBool = AtomicBool::new(false);
+// Set by --dry-run, profile operations print what they would do as JSON and
+// stop there
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+// Set by --assume-yes, skips the confirmation before profile installs
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+// Set by --atomic, failed installs remove the packages they added
+static ATOMIC: AtomicBool = AtomicBool::new(false);
+// Set when stdout carries a document for other programs, progress messages
+// then go to stderr
+static STDOUT_DOCUMENT: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "bt")]
 mod bt_func;
+mod config;
+#[cfg(feature = "daemon")]
+mod daemon;
+#[cfg(feature = "dmi")]
 mod dmi_func;
+#[cfg(feature = "daemon")]
+mod monitor;
+#[cfg(feature = "daemon")]
+mod notify;
+#[cfg(feature = "pci")]
 mod pci_func;
+#[cfg(feature = "daemon")]
+mod rpc;
+#[cfg(feature = "usb")]
 mod usb_func;
 
-
-const PERM_FIX_PROG: &str = r###"
-#! /bin/bash
-
-USER=$(whoami)
-
-chown $USER:$USER -R /var/cache/cfhdb || pkexec chown $USER:$USER -R /var/cache/cfhdb 
-chmod 777 -R /var/cache/cfhdb || pkexec chmod 777 -R /var/cache/cfhdb
-rm -rf /var/cache/cfhdb/check_cmd.sh || pkexec rm -rf /var/cache/cfhdb/check_cmd.sh
-
-"###;
-
 // Init translations for current crate.
 #[macro_use]
 extern crate rust_i18n;
@@ -36,10 +93,73 @@ pub struct ProfileUrlConfig {
     usb_json_url: String,
     dmi_json_url: String,
     bt_json_url: String,
+    #[serde(default)]
+    signature_policy: SignaturePolicy,
+    // Base64 ed25519 public keys the profile database is signed with
+    #[serde(default)]
+    trusted_keys: Vec<String>,
+    // Also send audit records to the systemd journal
+    #[serde(default)]
+    audit_journal: bool,
+    // pkexec, run0, sudo or doas, autodetected when left out
+    #[serde(default)]
+    escalation: Option<String>,
+    // keep or replace locally changed config files on package upgrades
+    #[serde(default)]
+    conffile_policy: ConffilePolicy,
+    // seconds to wait for other package operations, 0 fails right away
+    #[serde(default)]
+    lock_timeout: Option<u64>,
+    // codenames `cfhdb upgrade` leaves alone
+    #[serde(default)]
+    pinned_profiles: Vec<String>,
+    // snapper, timeshift or btrfs, takes a snapshot before profile scripts run
+    #[serde(default)]
+    snapshot_tool: Option<SnapshotTool>,
+    // OnCalendar expression of the timer `cfhdb systemd install` writes
+    #[serde(default)]
+    timer_schedule: Option<String>,
+    // have the timer run `cfhdb auto`, optionally limited to some categories
+    #[serde(default)]
+    timer_auto_apply: bool,
+    #[serde(default)]
+    timer_categories: Vec<String>,
+    // dry-run, offer or apply, what `cfhdbd --monitor` does for new hardware
+    #[serde(default)]
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    hotplug_policy: HotplugPolicy,
+    // seconds between two profile matching runs of one category
+    #[serde(default)]
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    hotplug_min_interval: Option<u64>,
+    // off, notify or install, for usb devices exactly one profile fits.
+    // Overrides hotplug_policy for those.
+    #[serde(default)]
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    usb_auto_install: AutoInstallMode,
+    // codenames usb_auto_install never picks
+    #[serde(default)]
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    usb_auto_install_exclude: Vec<String>,
+    // address `cfhdb exporter` listens on when none is given
+    #[serde(default)]
+    exporter_listen: Option<String>,
+    // categories `cfhdbd --notify` shows desktop notifications for, all when
+    // left out
+    #[serde(default)]
+    #[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+    notify_categories: Option<Vec<String>>,
+    // seconds between two metric collections, 300 by default
+    #[serde(default)]
+    exporter_interval: Option<u64>,
+    // battery percentages `cfhdb bt monitor --battery` reports, 20, 10 and 5
+    // when left out
+    #[serde(default)]
+    bt_battery_thresholds: Option<Vec<u8>>,
 }
 
 fn print_help_msg() {
-    let table = vec![
+    let mut rows = vec![
         // Secondary titles
         vec![
             t!("help_msg_title1")
@@ -92,6 +212,144 @@ fn print_help_msg() {
             "--json".cell(),
             "-j".cell(),
         ],
+        vec![
+            t!("help_msg_action_trace").cell(),
+            "--trace".cell(),
+            "-t".cell(),
+        ],
+        vec![
+            t!("help_msg_action_user").cell(),
+            "--user".cell(),
+            "-u".cell(),
+        ],
+        vec![
+            t!("help_msg_action_wait").cell(),
+            "--wait".cell(),
+            "-w".cell(),
+        ],
+        vec![
+            t!("help_msg_action_lock_timeout").cell(),
+            "--lock-timeout=<seconds>".cell(),
+            "".cell(),
+        ],
+        vec![
+            t!("help_msg_action_dry_run").cell(),
+            "--dry-run".cell(),
+            "-n".cell(),
+        ],
+        vec![
+            t!("help_msg_action_assume_yes").cell(),
+            "--assume-yes".cell(),
+            "-y".cell(),
+        ],
+        vec![
+            t!("help_msg_action_atomic").cell(),
+            "--atomic".cell(),
+            "".cell(),
+        ],
+        vec![
+            t!("help_msg_action_escalation").cell(),
+            "--escalation=<pkexec|run0|sudo|doas>".cell(),
+            "".cell(),
+        ],
+        vec![
+            t!("help_msg_action_root").cell(),
+            "--root <path>".cell(),
+            "".cell(),
+        ],
+        vec![
+            t!("help_msg_action_history").cell(),
+            "history [entry]".cell(),
+            "--history [entry]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_doctor").cell(),
+            "doctor".cell(),
+            "--doctor".cell(),
+        ],
+        vec![
+            t!("help_msg_action_status").cell(),
+            "status".cell(),
+            "--status".cell(),
+        ],
+        vec![
+            t!("help_msg_action_upgrade").cell(),
+            "upgrade".cell(),
+            "--upgrade".cell(),
+        ],
+        vec![
+            t!("help_msg_action_rollback").cell(),
+            "rollback".cell(),
+            "--rollback".cell(),
+        ],
+        vec![
+            t!("help_msg_action_autoremove").cell(),
+            "autoremove".cell(),
+            "--autoremove".cell(),
+        ],
+        vec![
+            t!("help_msg_action_auto").cell(),
+            "auto [--category=<dmi|pci|usb|bt>]".cell(),
+            "--auto [--category=<dmi|pci|usb|bt>]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_refresh").cell(),
+            "refresh".cell(),
+            "--refresh".cell(),
+        ],
+        vec![
+            t!("help_msg_action_systemd").cell(),
+            "systemd <install|remove>".cell(),
+            "--systemd <install|remove>".cell(),
+        ],
+        vec![
+            t!("help_msg_action_state").cell(),
+            "state <export [file]|import <file>>".cell(),
+            "--state <export [file]|import <file>>".cell(),
+        ],
+        vec![
+            t!("help_msg_action_exporter").cell(),
+            "exporter [address]".cell(),
+            "--exporter [address]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_probe").cell(),
+            "probe [upload]".cell(),
+            "--probe [upload]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_facts").cell(),
+            "facts".cell(),
+            "--facts".cell(),
+        ],
+        vec![
+            t!("help_msg_action_monitor").cell(),
+            "[pci|usb|bt] monitor [--output <text|ndjson>] [--category=<pci|usb|bt>]".cell(),
+            "--monitor [--output <text|ndjson>] [--category=<pci|usb|bt>]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_bt_battery").cell(),
+            "bt monitor --battery [--thresholds=<20,10,5>]".cell(),
+            "--monitor --battery [--thresholds=<20,10,5>]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_hwdb").cell(),
+            "hwdb <export [file]|install|remove>".cell(),
+            "--hwdb <export [file]|install|remove>".cell(),
+        ],
+        vec![
+            t!("help_msg_action_modules").cell(),
+            "modules [list|blacklist|unblacklist|options] <module> [options]".cell(),
+            "--modules [list|blacklist|unblacklist|options] <module> [options]".cell(),
+        ],
+        vec![
+            t!("help_msg_action_kparams").cell(),
+            "kparams [list|sync]".cell(),
+            "--kparams [list|sync]".cell(),
+        ],
+    ];
+    #[cfg(feature = "pci")]
+    rows.extend(vec![
         // PCI arguments title
         vec![
             t!("")
@@ -151,6 +409,9 @@ fn print_help_msg() {
             "--stop-pci-device {sysfs_id}".cell(),
             "-srpd".cell(),
         ],
+    ]);
+    #[cfg(feature = "usb")]
+    rows.extend(vec![
         // USB arguments title
         vec![
             t!("")
@@ -192,12 +453,12 @@ fn print_help_msg() {
         ],
         vec![
             t!("help_msg_action_enable_usb_device").cell(),
-            "--enable-usb-device {sysfs_id}".cell(),
+            "--enable-usb-device [--persistent] {sysfs_id}".cell(),
             "-eud".cell(),
         ],
         vec![
             t!("help_msg_action_disable_usb_device").cell(),
-            "--disable-usb-device {sysfs_id}".cell(),
+            "--disable-usb-device [--persistent] {sysfs_id}".cell(),
             "-dud".cell(),
         ],
         vec![
@@ -210,6 +471,59 @@ fn print_help_msg() {
             "--stop-usb-device {sysfs_id}".cell(),
             "-srud".cell(),
         ],
+        vec![
+            t!("help_msg_action_reset_usb_device").cell(),
+            "--reset-usb-device {sysfs_id}".cell(),
+            "-rud".cell(),
+        ],
+        vec![
+            t!("help_msg_action_authorize_usb_device").cell(),
+            "--authorize-usb-device {sysfs_id}".cell(),
+            "-aud".cell(),
+        ],
+        vec![
+            t!("help_msg_action_deauthorize_usb_device").cell(),
+            "--deauthorize-usb-device {sysfs_id}".cell(),
+            "-daud".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_describe").cell(),
+            "--usb-describe {sysfs_id}".cell(),
+            "-udsc".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_hid").cell(),
+            "--usb-hid {sysfs_id}".cell(),
+            "-uhid".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_export_usbguard").cell(),
+            "--usb-export-usbguard [file]".cell(),
+            "-uexp".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_tree").cell(),
+            "--usb-tree".cell(),
+            "-utr".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_blacklist").cell(),
+            "--usb-blacklist <list|add {sysfs_id}|remove {sysfs_id}>".cell(),
+            "-ubl".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_device_power").cell(),
+            "--usb-device-power {sysfs_id} [auto|on|{autosuspend delay ms}]".cell(),
+            "-pud".cell(),
+        ],
+        vec![
+            t!("help_msg_action_usb_wakeup").cell(),
+            "--usb-wakeup <enable|disable> {sysfs_id}".cell(),
+            "-uwk".cell(),
+        ],
+    ]);
+    #[cfg(feature = "dmi")]
+    rows.extend(vec![
         // DMI arguments title
         vec![
             t!("")
@@ -249,6 +563,9 @@ fn print_help_msg() {
             "--uninstall-dmi-profile {profile codename}".cell(),
             "-udp".cell(),
         ],
+    ]);
+    #[cfg(feature = "bt")]
+    rows.extend(vec![
         // BT arguments title
         vec![
             t!("")
@@ -323,34 +640,531 @@ fn print_help_msg() {
             "--unblock-bt-device {address}".cell(),
             "-ubbd".cell(),
         ],
-    ]
+        vec![
+            t!("help_msg_action_remove_bt_device").cell(),
+            "--remove-bt-device {address}".cell(),
+            "-rbd".cell(),
+        ],
+        vec![
+            t!("help_msg_action_bt_services").cell(),
+            "--bt-services {address}".cell(),
+            "-bsv".cell(),
+        ],
+        vec![
+            t!("help_msg_action_bt_adapter").cell(),
+            "--bt-adapter [hciN] <list|power|discoverable|pairable <on|off>|alias {name}>".cell(),
+            "-bad".cell(),
+        ],
+        vec![
+            t!("help_msg_action_bt_scan").cell(),
+            "--bt-scan [--timeout {seconds}]".cell(),
+            "-bsc".cell(),
+        ],
+    ]);
+    let table = rows
+        .table()
+        .title(vec![
+            t!("help_msg_title0")
+                .cell()
+                .bold(true)
+                .justify(Justify::Center),
+            VERSION.cell().bold(true).justify(Justify::Center),
+            "".cell().bold(true).justify(Justify::Center),
+        ])
+        .bold(true);
+
+    let table_display = table.display().unwrap();
+
+    println!("{}", table_display);
+}
+// Traces go to stderr so they never mix with --json output. CFHDB_LOG takes
+// an env-filter directive, --trace alone enables everything down to trace level.
+fn init_tracing(trace_mode: bool) {
+    let filter = match std::env::var("CFHDB_LOG") {
+        Ok(t) => EnvFilter::new(t),
+        Err(_) if trace_mode => EnvFilter::new("libcfhdb=trace,cfhdb=trace"),
+        Err(_) => EnvFilter::new("warn"),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+}
+
+// A missing config leaves the permissive default in place, the profile
+// commands report that themselves. A broken key list must not silently
+// downgrade a strict repository though.
+fn parse_escalation_backend(value: &str) -> Result<EscalationBackend, CliError> {
+    value
+        .parse()
+        .map_err(|e: io::Error| CliError::Failed(e.to_string()))
+}
+
+fn parse_lock_timeout(value: &str) -> Result<Duration, CliError> {
+    match value.parse() {
+        Ok(t) => Ok(Duration::from_secs(t)),
+        Err(_) => Err(CliError::Failed(
+            t!("invalid_lock_timeout", value = value).to_string(),
+        )),
+    }
+}
+
+// None for "/", which is the live system anyway
+fn parse_target_root(value: Option<&str>) -> Result<Option<PathBuf>, CliError> {
+    let value = value.unwrap_or_default();
+    match fs::canonicalize(value) {
+        Ok(t) if t.is_dir() => Ok(Some(t).filter(|x| x.as_path() != Path::new("/"))),
+        _ => Err(CliError::Failed(
+            t!("invalid_target_root", path = value).to_string(),
+        )),
+    }
+}
+
+// Only a missing file means the defaults. A file that does not parse would
+// otherwise drop the signature policy along with everything else.
+fn apply_profile_config() -> Result<(), CliError> {
+    let invalid_config =
+        |error: String| CliError::Failed(t!("invalid_profile_config", error = error).to_string());
+    let data = match fs::read_to_string(PROFILE_CONFIG_PATH) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(invalid_config(e.to_string())),
+    };
+    let config: ProfileUrlConfig =
+        serde_json::from_str(&data).map_err(|e| invalid_config(e.to_string()))?;
+    audit::set_audit_journal(config.audit_journal);
+    packages::set_conffile_policy(config.conffile_policy);
+    snapshot::set_snapshot_tool(config.snapshot_tool);
+    if let Some(seconds) = config.lock_timeout {
+        lock::set_lock_timeout(Some(Duration::from_secs(seconds)));
+    }
+    if let Some(backend) = &config.escalation {
+        privilege::set_escalation_backend(Some(parse_escalation_backend(backend)?));
+    }
+    match ScriptVerifier::new(config.signature_policy, &config.trusted_keys) {
+        Ok(t) => signing::set_script_verifier(t),
+        Err(e) => {
+            return Err(CliError::Failed(
+                t!("invalid_signature_config", error = e.to_string()).to_string(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn history_target(record: &AuditRecord) -> String {
+    match (&record.device, &record.profile) {
+        (Some(device), _) => device.clone(),
+        (None, Some(profile)) => profile.clone(),
+        (None, None) => "???".to_owned(),
+    }
+}
+
+fn history_result_cell(record: &AuditRecord) -> CellStruct {
+    match &record.error {
+        None => t!("history_result_success")
+            .cell()
+            .foreground_color(Some(Color::Green)),
+        Some(e) => e.cell().foreground_color(Some(Color::Red)),
+    }
+}
+
+fn display_history(json_mode: bool, entry: Option<&String>) -> Result<(), CliError> {
+    let records = audit::get_history();
+    if let Some(entry) = entry {
+        return display_history_entry(json_mode, &records, entry);
+    }
+    if json_mode {
+        let json_pretty = serde_json::to_string_pretty(&records).unwrap();
+        println!("{}", json_pretty);
+        return Ok(());
+    }
+    let mut table_struct = vec![];
+    for (index, record) in records.iter().enumerate() {
+        table_struct.push(vec![
+            (index + 1).cell(),
+            audit::format_timestamp(record.timestamp).cell(),
+            record.user.as_str().cell(),
+            record.action.as_str().cell(),
+            history_target(record).cell(),
+            history_result_cell(record),
+        ]);
+    }
+    let table = table_struct
+        .table()
+        .title(vec![
+            t!("history_table_index").cell().bold(true),
+            t!("history_table_time").cell().bold(true),
+            t!("history_table_user").cell().bold(true),
+            t!("history_table_action").cell().bold(true),
+            t!("history_table_target").cell().bold(true),
+            t!("history_table_result").cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+    Ok(())
+}
+
+// Entries are numbered from 1, oldest first, like the table shows them
+fn display_history_entry(
+    json_mode: bool,
+    records: &[AuditRecord],
+    entry: &str,
+) -> Result<(), CliError> {
+    let record = match entry
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_sub(1))
+        .and_then(|x| records.get(x))
+    {
+        Some(t) => t,
+        None => {
+            return Err(CliError::Failed(
+                t!("history_entry_not_found", entry = entry).to_string(),
+            ))
+        }
+    };
+    if json_mode {
+        let json_pretty = serde_json::to_string_pretty(record).unwrap();
+        println!("{}", json_pretty);
+        return Ok(());
+    }
+    let table = vec![vec![
+        audit::format_timestamp(record.timestamp).cell(),
+        record.user.as_str().cell(),
+        record.action.as_str().cell(),
+        history_target(record).cell(),
+        history_result_cell(record),
+    ]]
     .table()
     .title(vec![
-        t!("help_msg_title0")
-            .cell()
-            .bold(true)
-            .justify(Justify::Center),
-        VERSION.cell().bold(true).justify(Justify::Center),
-        "".cell().bold(true).justify(Justify::Center),
+        t!("history_table_time").cell().bold(true),
+        t!("history_table_user").cell().bold(true),
+        t!("history_table_action").cell().bold(true),
+        t!("history_table_target").cell().bold(true),
+        t!("history_table_result").cell().bold(true),
     ])
     .bold(true);
+    println!("{}", table.display().unwrap());
+    if record.scripts.is_empty() {
+        println!("{}", t!("history_no_scripts"));
+        return Ok(());
+    }
+    for (index, script) in record.scripts.iter().enumerate() {
+        println!(
+            "[{}] {}\n{}",
+            t!("info").bright_green(),
+            t!(
+                "history_script",
+                index = index + 1,
+                count = record.scripts.len()
+            ),
+            script.dimmed()
+        );
+    }
+    Ok(())
+}
 
+// Failed checks are in the report already
+fn display_doctor(json_mode: bool) -> Result<(), CliError> {
+    let checks = doctor::run_checks();
+    let failed = checks.iter().any(|x| x.status == DoctorStatus::Error);
+    if json_mode {
+        let json_pretty = serde_json::to_string_pretty(&checks).unwrap();
+        println!("{}", json_pretty);
+    } else {
+        let mut table_struct = vec![];
+        for check in checks {
+            table_struct.push(vec![
+                check.name.cell(),
+                match check.status {
+                    DoctorStatus::Ok => t!("doctor_status_ok")
+                        .cell()
+                        .foreground_color(Some(Color::Green)),
+                    DoctorStatus::Warning => t!("doctor_status_warning")
+                        .cell()
+                        .foreground_color(Some(Color::Yellow)),
+                    DoctorStatus::Error => t!("doctor_status_error")
+                        .cell()
+                        .foreground_color(Some(Color::Red)),
+                },
+                check.detail.cell(),
+            ]);
+        }
+        let table = table_struct
+            .table()
+            .title(vec![
+                t!("doctor_table_check").cell().bold(true),
+                t!("doctor_table_status").cell().bold(true),
+                t!("doctor_table_detail").cell().bold(true),
+            ])
+            .bold(true);
+        println!("{}", table.display().unwrap());
+    }
+    if failed {
+        return Err(CliError::Reported);
+    }
+    Ok(())
+}
 
-    let table_display = table.display().unwrap();
+fn collect_device_status() -> Vec<DeviceStatus> {
+    // A category that cannot be read (bluetooth off, no network for the
+    // database) should not hide the others
+    let results: Vec<(&str, Result<Vec<DeviceStatus>, String>)> = vec![
+        #[cfg(feature = "dmi")]
+        (
+            "dmi",
+            dmi_func::collect_dmi_status().map_err(|e| e.to_string()),
+        ),
+        #[cfg(feature = "pci")]
+        (
+            "pci",
+            pci_func::collect_pci_status().map_err(|e| e.to_string()),
+        ),
+        #[cfg(feature = "usb")]
+        (
+            "usb",
+            usb_func::collect_usb_status().map_err(|e| e.to_string()),
+        ),
+        #[cfg(feature = "bt")]
+        (
+            "bt",
+            bt_func::collect_bt_status().map_err(|e| e.to_string()),
+        ),
+    ];
+    let mut devices = vec![];
+    for (category, result) in results {
+        match result {
+            Ok(t) => devices.extend(t),
+            Err(e) => eprintln!(
+                "[{}] {} {}",
+                t!("warn").bright_yellow(),
+                t!("status_category_failed", category = category),
+                e
+            ),
+        }
+    }
+    devices
+}
 
-    println!("{}", table_display);
+fn status_name_cell(device: &DeviceStatus) -> CellStruct {
+    let name = format!("{} {}", device.vendor, device.name)
+        .trim()
+        .to_owned();
+    match name.char_indices().nth(48) {
+        None => name,
+        Some((idx, _)) => name[..idx].to_string() + "...",
+    }
+    .cell()
+}
+
+fn display_status(json_mode: bool) {
+    let devices = collect_device_status();
+    let reboot = status::pending_reboot();
+    if json_mode {
+        let json = serde_json::json!({
+            "devices": devices,
+            "reboot": reboot,
+        });
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        return;
+    }
+    let (covered, uncovered): (Vec<_>, Vec<_>) = devices.iter().partition(|x| x.is_covered());
+    if !covered.is_empty() {
+        let mut table_struct = vec![];
+        for device in &covered {
+            table_struct.push(vec![
+                device.category.cell(),
+                device.id.clone().cell(),
+                status_name_cell(device),
+                device.profiles.join(", ").cell(),
+                if device.installed.is_empty() {
+                    t!("status_installed_none")
+                        .cell()
+                        .foreground_color(Some(Color::Yellow))
+                } else {
+                    device
+                        .installed
+                        .join(", ")
+                        .cell()
+                        .foreground_color(Some(Color::Green))
+                },
+            ]);
+        }
+        let table = table_struct
+            .table()
+            .title(vec![
+                t!("status_table_category").cell().bold(true),
+                t!("status_table_device").cell().bold(true),
+                t!("status_table_name").cell().bold(true),
+                t!("status_table_profiles").cell().bold(true),
+                t!("status_table_installed").cell().bold(true),
+            ])
+            .bold(true);
+        println!(
+            "{}\n{}",
+            t!("status_title_covered").bright_green(),
+            table.display().unwrap()
+        );
+    }
+    if !uncovered.is_empty() {
+        let mut table_struct = vec![];
+        for device in &uncovered {
+            table_struct.push(vec![
+                device.category.cell(),
+                device.id.clone().cell(),
+                status_name_cell(device),
+            ]);
+        }
+        let table = table_struct
+            .table()
+            .title(vec![
+                t!("status_table_category").cell().bold(true),
+                t!("status_table_device").cell().bold(true),
+                t!("status_table_name").cell().bold(true),
+            ])
+            .bold(true);
+        println!(
+            "{}\n{}",
+            t!("status_title_uncovered").bright_green(),
+            table.display().unwrap()
+        );
+    }
+    if reboot.required {
+        for reason in reboot.reasons {
+            println!(
+                "[{}] {}",
+                t!("warn").bright_yellow(),
+                t!("status_reboot_pending", reason = reason)
+            );
+        }
+    } else {
+        println!("{}", t!("status_reboot_none").bright_green());
+    }
+}
+
+fn subsystem_not_built(category: &str) -> CliError {
+    CliError::Failed(t!("subsystem_not_built", category = category).to_string())
+}
+
+// The words of `cfhdb usb <command>`, only taken as one right after usb so
+// `cfhdb modules blacklist` and the like keep their meaning
+fn usb_subcommand_action(command: &str) -> Option<&'static str> {
+    Some(match command {
+        "enable" => "eud",
+        "disable" => "dud",
+        "reset" => "rud",
+        "authorize" => "aud",
+        "deauthorize" => "daud",
+        "power" => "pud",
+        "blacklist" => "ubl",
+        "tree" => "utr",
+        "hid" => "uhid",
+        "export-usbguard" => "uexp",
+        "wakeup" => "uwk",
+        "describe" => "udsc",
+        "monitor" => "monitor",
+        _ => return None,
+    })
+}
+
+// Same as usb_subcommand_action for `cfhdb bt <command>`
+fn bt_subcommand_action(command: &str) -> Option<&'static str> {
+    Some(match command {
+        "adapter" => "bad",
+        "scan" => "bsc",
+        "remove" => "rbd",
+        "services" => "bsv",
+        "monitor" => "monitor",
+        _ => return None,
+    })
+}
+
+// The action of `cfhdb <command>`, the same as its --<command> flag
+fn command_action(command: &str) -> Option<&'static str> {
+    Some(match command {
+        "history" => "history",
+        "doctor" => "doctor",
+        "status" => "status",
+        "upgrade" => "upgrade",
+        "rollback" => "rollback",
+        "autoremove" => "autoremove",
+        "auto" => "auto",
+        "refresh" => "refresh",
+        "systemd" => "systemd",
+        "state" => "state",
+        "exporter" => "exporter",
+        "probe" => "probe",
+        "facts" => "facts",
+        "monitor" => "monitor",
+        "hwdb" => "hwdb",
+        "modules" => "modules",
+        "kparams" => "kparams",
+        _ => return None,
+    })
 }
-fn parse_args(args: Vec<String>) {
+
+fn parse_args(args: Vec<String>) -> Result<(), CliError> {
     let mut json_mode = false;
+    let mut trace_mode = false;
+    let mut user_mode = false;
+    let mut persistent = false;
+    let mut usb_command = false;
+    let mut bt_command = false;
+    let mut escalation = None;
+    let mut lock_timeout = None;
+    let mut target_root = None;
+    let mut categories = vec![];
     let mut action = "-h";
     let mut additional_arguments = vec![];
-    for arg in args {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
         match arg.as_str() {
             // Global modes
             "-j" | "--json" => json_mode = true,
+            "-t" | "--trace" => trace_mode = true,
+            "-u" | "--user" => user_mode = true,
+            "-w" | "--wait" => lock_timeout = Some(None),
+            t if t.starts_with("--lock-timeout=") => {
+                lock_timeout = Some(Some(parse_lock_timeout(&t["--lock-timeout=".len()..])?))
+            }
+            "-n" | "--dry-run" => DRY_RUN.store(true, Ordering::Relaxed),
+            "-y" | "--assume-yes" => ASSUME_YES.store(true, Ordering::Relaxed),
+            "--atomic" => ATOMIC.store(true, Ordering::Relaxed),
+            "--persistent" => persistent = true,
+            t if t.starts_with("--escalation=") => {
+                escalation = Some(parse_escalation_backend(&t["--escalation=".len()..])?)
+            }
+            "--root" => target_root = Some(parse_target_root(args.next().as_deref())?),
+            t if t.starts_with("--root=") => {
+                target_root = Some(parse_target_root(Some(&t["--root=".len()..]))?)
+            }
+            t if t.starts_with("--category=") => categories.extend(
+                t["--category=".len()..]
+                    .split(',')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_owned()),
+            ),
             // Program arguments
             "-h" | "--help" => action = "h",
             "-v" | "--version" => action = "v",
+            "--history" => action = "history",
+            "--doctor" => action = "doctor",
+            "--status" => action = "status",
+            "--upgrade" => action = "upgrade",
+            "--rollback" => action = "rollback",
+            "--autoremove" => action = "autoremove",
+            "--auto" => action = "auto",
+            "--refresh" => action = "refresh",
+            "--systemd" => action = "systemd",
+            "--state" => action = "state",
+            "--exporter" => action = "exporter",
+            "--probe" => action = "probe",
+            "--facts" => action = "facts",
+            "--monitor" => action = "monitor",
+            "--hwdb" => action = "hwdb",
+            "--modules" => action = "modules",
+            "--kparams" => action = "kparams",
             // PCI arguments
             "-lpd" | "--list-pci-devices" => action = "lpd",
             "-lpp" | "--list-pci-profiles" => action = "lpp",
@@ -369,6 +1183,16 @@ fn parse_args(args: Vec<String>) {
             "-dud" | "--disable-usb-device" => action = "dud",
             "-ssud" | "--start-usb-device" => action = "ssud",
             "-srud" | "--stop-usb-device" => action = "srud",
+            "-rud" | "--reset-usb-device" => action = "rud",
+            "-aud" | "--authorize-usb-device" => action = "aud",
+            "-daud" | "--deauthorize-usb-device" => action = "daud",
+            "-pud" | "--usb-device-power" => action = "pud",
+            "-ubl" | "--usb-blacklist" => action = "ubl",
+            "-utr" | "--usb-tree" => action = "utr",
+            "-uhid" | "--usb-hid" => action = "uhid",
+            "-uexp" | "--usb-export-usbguard" => action = "uexp",
+            "-uwk" | "--usb-wakeup" => action = "uwk",
+            "-udsc" | "--usb-describe" => action = "udsc",
             // DMI arguments
             "-ldi" | "--list-dmi-info" => action = "ldi",
             "-ldp" | "--list-dmi-profiles" => action = "ldp",
@@ -386,322 +1210,2721 @@ fn parse_args(args: Vec<String>) {
             "-utbd" | "--untrust-bt-device" => action = "utbd",
             "-bbd" | "--block-bt-device" => action = "bbd",
             "-ubbd" | "--unblock-bt-device" => action = "ubbd",
+            "-bad" | "--bt-adapter" => action = "bad",
+            "-bsc" | "--bt-scan" => action = "bsc",
+            "-rbd" | "--remove-bt-device" => action = "rbd",
+            "-bsv" | "--bt-services" => action = "bsv",
+            "usb" if action == "-h" => {
+                usb_command = true;
+                additional_arguments.push(arg);
+            }
+            t if usb_command && action == "-h" => match usb_subcommand_action(t) {
+                Some(t) => action = t,
+                None => additional_arguments.push(arg),
+            },
+            "bt" if action == "-h" => {
+                bt_command = true;
+                additional_arguments.push(arg);
+            }
+            t if bt_command && action == "-h" => match bt_subcommand_action(t) {
+                Some(t) => action = t,
+                None => additional_arguments.push(arg),
+            },
+            // Only the first positional argument can be a command, anywhere
+            // else these words are codenames, aliases or paths
+            t if action == "-h" && additional_arguments.len() == 1 => match command_action(t) {
+                Some(t) => action = t,
+                None => additional_arguments.push(arg),
+            },
             _ => {
                 additional_arguments.push(arg);
             }
         }
     }
+    init_tracing(trace_mode);
+    if user_mode && target_root.is_some() {
+        return Err(CliError::Failed(t!("target_root_user_mode").to_string()));
+    }
+    USER_MODE.store(user_mode, Ordering::Relaxed);
+    paths::set_target_root(target_root.flatten());
+    apply_profile_config()?;
+    // The command line wins over the config file
+    if escalation.is_some() {
+        privilege::set_escalation_backend(escalation);
+    }
+    if let Some(timeout) = lock_timeout {
+        lock::set_lock_timeout(timeout);
+    }
+    let argument = |index: usize| additional_arguments.get(index).map(|x| x.as_str());
+    let device = || required_argument(&additional_arguments, "no_device_specified");
+    let profile = || required_argument(&additional_arguments, "no_profile_specified");
     match action {
         // Program arguments
-        "h" => print_help_msg(),
-        "v" => {
-            println!("{}", VERSION)
-        }
-        "j" => print_help_msg(),
-        // PCI arguments
-        "lpd" => {
-            pci_func::display_pci_devices(json_mode);
-        }
-        "lpp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::display_pci_profiles(json_mode, &additional_arguments[1]);
-            }
+        "h" | "j" => {
+            print_help_msg();
+            Ok(())
         }
-        "ipp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::install_pci_profile(&additional_arguments[1]);
-            }
+        "v" => {
+            println!("{}", VERSION);
+            Ok(())
         }
-        "upp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::uninstall_pci_profile(&additional_arguments[1]);
-            }
+        "history" => display_history(json_mode, additional_arguments.get(1)),
+        "doctor" => display_doctor(json_mode),
+        "status" => {
+            display_status(json_mode);
+            Ok(())
         }
-        "epd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::enable_pci_device(&additional_arguments[1]);
-            }
+        "upgrade" => upgrade_profiles(),
+        "rollback" => rollback_last_transaction(),
+        "autoremove" => autoremove_profiles(),
+        "auto" => auto_apply_profiles(categories),
+        "refresh" => refresh_profile_databases(),
+        "systemd" => manage_systemd_units(argument(1)),
+        "state" => manage_state(argument(1), argument(2)),
+        "exporter" => run_exporter(argument(1)),
+        "probe" => manage_probe(argument(1)),
+        "facts" => {
+            print_facts();
+            Ok(())
         }
-        "dpd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::disable_pci_device(&additional_arguments[1]);
+        "hwdb" => manage_hwdb(argument(1), argument(2)),
+        "modules" => manage_modules(json_mode, &additional_arguments),
+        "kparams" => manage_kparams(json_mode, argument(1)),
+        // `cfhdb usb monitor` is the same as `cfhdb monitor --category=usb`,
+        // bluetooth alone gets the bluez events with connections and battery
+        // levels instead, --battery only the levels passing a threshold
+        "monitor" => {
+            let ndjson = json_mode || monitor_output(&additional_arguments) == Some("ndjson");
+            let categories: Vec<String> = categories
+                .into_iter()
+                .chain(
+                    additional_arguments
+                        .iter()
+                        .skip(1)
+                        .filter(|x| ["pci", "usb", "bt"].contains(&x.as_str()))
+                        .cloned(),
+                )
+                .collect();
+            #[cfg(feature = "bt")]
+            if additional_arguments.iter().any(|x| x == "--battery") {
+                return monitor_bt_battery(ndjson, &additional_arguments);
             }
-        }
-        "sspd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::start_pci_device(&additional_arguments[1]);
+            #[cfg(feature = "bt")]
+            if !categories.is_empty() && categories.iter().all(|x| x == "bt") {
+                return monitor_bt_events(ndjson);
             }
+            monitor_devices(ndjson, categories)
         }
-        "srpd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::stop_pci_device(&additional_arguments[1]);
-            }
+        // PCI arguments
+        #[cfg(feature = "pci")]
+        "lpd" => Ok(pci_func::display_pci_devices(json_mode)?),
+        #[cfg(feature = "pci")]
+        "lpp" => Ok(pci_func::display_pci_profiles(json_mode, device()?)?),
+        #[cfg(feature = "pci")]
+        "ipp" => Ok(pci_func::install_pci_profile(profile()?)?),
+        #[cfg(feature = "pci")]
+        "upp" => Ok(pci_func::uninstall_pci_profile(profile()?)?),
+        #[cfg(feature = "pci")]
+        "epd" => Ok(pci_func::enable_pci_device(device()?)?),
+        #[cfg(feature = "pci")]
+        "dpd" => Ok(pci_func::disable_pci_device(device()?)?),
+        #[cfg(feature = "pci")]
+        "sspd" => Ok(pci_func::start_pci_device(device()?)?),
+        #[cfg(feature = "pci")]
+        "srpd" => Ok(pci_func::stop_pci_device(device()?)?),
+        #[cfg(not(feature = "pci"))]
+        "lpd" | "lpp" | "ipp" | "upp" | "epd" | "dpd" | "sspd" | "srpd" => {
+            Err(subsystem_not_built("pci"))
         }
         // USB arguments
-        "lud" => {
-            usb_func::display_usb_devices(json_mode);
-        }
-        "lup" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::display_usb_profiles(json_mode, &additional_arguments[1]);
-            }
+        #[cfg(feature = "usb")]
+        "lud" => Ok(usb_func::display_usb_devices(json_mode)?),
+        #[cfg(feature = "usb")]
+        "lup" => Ok(usb_func::display_usb_profiles(json_mode, device()?)?),
+        #[cfg(feature = "usb")]
+        "iup" => Ok(usb_func::install_usb_profile(profile()?)?),
+        #[cfg(feature = "usb")]
+        "uup" => Ok(usb_func::uninstall_usb_profile(profile()?)?),
+        #[cfg(feature = "usb")]
+        "eud" | "dud" if persistent => Ok(usb_func::set_usb_persistently_disabled(
+            usb_command_target(&additional_arguments)?,
+            action == "dud",
+        )?),
+        #[cfg(feature = "usb")]
+        "eud" => Ok(usb_func::enable_usb_device(usb_command_target(
+            &additional_arguments,
+        )?)?),
+        #[cfg(feature = "usb")]
+        "dud" => Ok(usb_func::disable_usb_device(usb_command_target(
+            &additional_arguments,
+        )?)?),
+        #[cfg(feature = "usb")]
+        "ssud" => Ok(usb_func::start_usb_device(device()?)?),
+        #[cfg(feature = "usb")]
+        "srud" => Ok(usb_func::stop_usb_device(device()?)?),
+        #[cfg(feature = "usb")]
+        "rud" => Ok(usb_func::reset_usb_device(usb_command_target(
+            &additional_arguments,
+        )?)?),
+        #[cfg(feature = "usb")]
+        "aud" => Ok(usb_func::authorize_usb_device(usb_command_target(
+            &additional_arguments,
+        )?)?),
+        #[cfg(feature = "usb")]
+        "daud" => Ok(usb_func::deauthorize_usb_device(usb_command_target(
+            &additional_arguments,
+        )?)?),
+        #[cfg(feature = "usb")]
+        "pud" => manage_usb_power(json_mode, &additional_arguments),
+        #[cfg(feature = "usb")]
+        "uwk" => manage_usb_wakeup(&additional_arguments),
+        #[cfg(feature = "usb")]
+        "udsc" => Ok(usb_func::display_usb_descriptors(
+            json_mode,
+            usb_command_target(&additional_arguments)?,
+        )?),
+        #[cfg(feature = "usb")]
+        "ubl" => manage_usb_blacklist(json_mode, &additional_arguments),
+        #[cfg(feature = "usb")]
+        "utr" => Ok(usb_func::display_usb_tree(json_mode)?),
+        #[cfg(feature = "usb")]
+        "uexp" => Ok(usb_func::export_usbguard_rules(
+            usb_command_arguments(&additional_arguments)
+                .first()
+                .copied(),
+        )?),
+        #[cfg(feature = "usb")]
+        "uhid" => Ok(usb_func::display_usb_hid(
+            json_mode,
+            usb_command_target(&additional_arguments)?,
+        )?),
+        #[cfg(not(feature = "usb"))]
+        "lud" | "lup" | "iup" | "uup" | "eud" | "dud" | "ssud" | "srud" | "rud" | "aud"
+        | "daud" | "pud" | "ubl" | "utr" | "uhid" | "uexp" | "uwk" | "udsc" => {
+            Err(subsystem_not_built("usb"))
         }
-        "iup" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::install_usb_profile(&additional_arguments[1]);
-            }
+        // DMI arguments
+        #[cfg(feature = "dmi")]
+        "ldi" => Ok(dmi_func::display_dmi_info(json_mode)?),
+        #[cfg(feature = "dmi")]
+        "ldp" => Ok(dmi_func::display_dmi_profiles(json_mode)?),
+        #[cfg(feature = "dmi")]
+        "idp" => Ok(dmi_func::install_dmi_profile(profile()?)?),
+        #[cfg(feature = "dmi")]
+        "udp" => Ok(dmi_func::uninstall_dmi_profile(profile()?)?),
+        #[cfg(not(feature = "dmi"))]
+        "ldi" | "ldp" | "idp" | "udp" => Err(subsystem_not_built("dmi")),
+        // BT arguments
+        #[cfg(feature = "bt")]
+        "lbd" => Ok(bt_func::display_bt_devices(json_mode)?),
+        #[cfg(feature = "bt")]
+        "lbp" => Ok(bt_func::display_bt_profiles(json_mode, device()?)?),
+        #[cfg(feature = "bt")]
+        "ibp" => Ok(bt_func::install_bt_profile(profile()?)?),
+        #[cfg(feature = "bt")]
+        "ubp" => Ok(bt_func::uninstall_bt_profile(profile()?)?),
+        #[cfg(feature = "bt")]
+        "pbd" => Ok(bt_func::pair_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "cbd" => Ok(bt_func::connect_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "dbd" => Ok(bt_func::disconnect_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "tbd" => Ok(bt_func::trust_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "utbd" => Ok(bt_func::untrust_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "bbd" => Ok(bt_func::block_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "ubbd" => Ok(bt_func::unblock_bt_device(device()?)?),
+        #[cfg(feature = "bt")]
+        "rbd" => Ok(bt_func::remove_bt_device(bt_command_target(
+            &additional_arguments,
+        )?)?),
+        #[cfg(feature = "bt")]
+        "bsv" => Ok(bt_func::display_bt_services(
+            json_mode,
+            bt_command_target(&additional_arguments)?,
+        )?),
+        #[cfg(feature = "bt")]
+        "bad" => manage_bt_adapter(json_mode, &additional_arguments),
+        #[cfg(feature = "bt")]
+        "bsc" => Ok(bt_func::scan_bt_devices(
+            json_mode,
+            bt_scan_timeout(&additional_arguments)?,
+        )?),
+        #[cfg(not(feature = "bt"))]
+        "lbd" | "lbp" | "ibp" | "ubp" | "pbd" | "cbd" | "dbd" | "tbd" | "utbd" | "bbd" | "ubbd"
+        | "rbd" | "bsv" | "bad" | "bsc" => Err(subsystem_not_built("bt")),
+        // Unknown argument
+        _ => Err(usage_error("unknown_argument")),
+    }
+}
+
+fn main() {
+    // Installed as a cfhdbd symlink the binary is the D-Bus service instead,
+    // with --socket the JSON-RPC one and with --notify the desktop notifier
+    #[cfg(feature = "daemon")]
+    if std::env::args_os()
+        .next()
+        .is_some_and(|x| Path::new(&x).file_name().is_some_and(|x| x == "cfhdbd"))
+    {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let result = if args.iter().any(|x| x == "--socket") {
+            rpc::run()
+        } else if args.iter().any(|x| x == "--notify") {
+            notify::run()
+        } else {
+            daemon::run(args.iter().any(|x| x == "--monitor"))
+        };
+        if let Err(e) = result {
+            eprintln!("[{}] {}", t!("error").red(), e);
+            exit(1);
         }
-        "uup" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::uninstall_usb_profile(&additional_arguments[1]);
-            }
+        return;
+    }
+    // Setup locales
+    let current_locale = match std::env::var_os("LANG") {
+        Some(v) => v.into_string().unwrap(),
+        None => panic!("$LANG is not set"),
+    };
+    rust_i18n::set_locale(current_locale.strip_suffix(".UTF-8").unwrap());
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.len() {
+        0 | 1 => {
+            print_help_msg();
+            Ok(())
         }
-        "eud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::enable_usb_device(&additional_arguments[1]);
-            }
+        _ => parse_args(args),
+    };
+    // The one place a failed command turns into a message and an exit code
+    if let Err(e) = result {
+        match e {
+            CliError::Usage(message) => eprintln!("{}", message),
+            CliError::Reported => {}
+            e => eprintln!("[{}] {}", t!("error").red(), e),
         }
-        "dud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::disable_usb_device(&additional_arguments[1]);
-            }
+        exit(1);
+    }
+}
+
+// The arguments of `cfhdb usb <command> <busid> ...` and of the flags doing
+// the same, starting with the busid
+#[cfg(feature = "usb")]
+fn usb_command_arguments(arguments: &[String]) -> Vec<&str> {
+    arguments
+        .iter()
+        .skip(1)
+        .map(|x| x.as_str())
+        .filter(|x| *x != "usb")
+        .collect()
+}
+
+#[cfg(feature = "usb")]
+fn usb_command_target(arguments: &[String]) -> Result<&str, CliError> {
+    match usb_command_arguments(arguments).first().copied() {
+        Some(t) => Ok(t),
+        None => Err(usage_error("no_device_specified")),
+    }
+}
+
+// `cfhdb usb blacklist <list|add <busid>|remove <busid>>`, lists without an
+// operation
+#[cfg(feature = "usb")]
+fn manage_usb_blacklist(json: bool, arguments: &[String]) -> Result<(), CliError> {
+    let arguments = usb_command_arguments(arguments);
+    match (arguments.first().copied(), arguments.get(1).copied()) {
+        (None | Some("list"), _) => Ok(usb_func::display_usb_blacklist(json)?),
+        (Some("add"), Some(busid)) => {
+            require_privileges(AuditAction::Disable)?;
+            Ok(usb_func::set_usb_blacklisted(busid, true)?)
         }
-        "ssud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::start_usb_device(&additional_arguments[1]);
-            }
+        (Some("remove"), Some(busid)) => {
+            require_privileges(AuditAction::Enable)?;
+            Ok(usb_func::set_usb_blacklisted(busid, false)?)
         }
-        "srud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::stop_usb_device(&additional_arguments[1]);
+        (Some("add" | "remove"), None) => Err(usage_error("no_device_specified")),
+        _ => Err(usage_error("usb_blacklist_no_operation")),
+    }
+}
+
+// `cfhdb usb power <busid> [auto|on|<autosuspend delay in ms>]`, shows the
+// current settings without a value
+#[cfg(feature = "usb")]
+fn manage_usb_power(json: bool, arguments: &[String]) -> Result<(), CliError> {
+    let busid = usb_command_target(arguments)?;
+    let result = match usb_command_arguments(arguments).get(1) {
+        None => usb_func::display_usb_power(json, busid),
+        Some(value) => match value.parse::<i64>() {
+            Ok(delay) => usb_func::set_usb_autosuspend_delay(busid, delay),
+            Err(_) => {
+                usb_func::set_usb_power_control(busid, value.parse().map_err(CfhdbError::Io)?)
             }
+        },
+    };
+    Ok(result?)
+}
+
+// `cfhdb usb wakeup <enable|disable> <busid>`, the current setting is shown
+// by `cfhdb usb power <busid>`
+#[cfg(feature = "usb")]
+fn manage_usb_wakeup(arguments: &[String]) -> Result<(), CliError> {
+    let arguments = usb_command_arguments(arguments);
+    match (arguments.first().copied(), arguments.get(1).copied()) {
+        (Some("enable"), Some(busid)) => Ok(usb_func::set_usb_wakeup(busid, true)?),
+        (Some("disable"), Some(busid)) => Ok(usb_func::set_usb_wakeup(busid, false)?),
+        (Some("enable" | "disable"), None) => Err(usage_error("no_device_specified")),
+        _ => Err(usage_error("usb_wakeup_no_operation")),
+    }
+}
+
+// The arguments after `cfhdb bt <command>` or its flag
+#[cfg(feature = "bt")]
+fn bt_command_arguments(arguments: &[String]) -> Vec<&str> {
+    arguments
+        .iter()
+        .skip(1)
+        .map(|x| x.as_str())
+        .filter(|x| *x != "bt")
+        .collect()
+}
+
+#[cfg(feature = "bt")]
+fn bt_command_target(arguments: &[String]) -> Result<&str, CliError> {
+    match bt_command_arguments(arguments).first().copied() {
+        Some(t) => Ok(t),
+        None => Err(usage_error("no_device_specified")),
+    }
+}
+
+// `cfhdb bt adapter [hciN] <list|power|discoverable|pairable <on|off>|alias
+// <name>>`, lists without an operation and uses the default adapter without
+// a name
+#[cfg(feature = "bt")]
+fn manage_bt_adapter(json: bool, arguments: &[String]) -> Result<(), CliError> {
+    let mut arguments = bt_command_arguments(arguments);
+    let adapter = match arguments.first() {
+        Some(t) if t.starts_with("hci") => Some(arguments.remove(0)),
+        _ => None,
+    };
+    let result = match (arguments.first().copied(), arguments.get(1).copied()) {
+        (None | Some("list"), _) => bt_func::display_bt_adapters(json, adapter),
+        (Some("alias"), Some(_)) => {
+            bt_func::set_bt_adapter_alias(adapter, &arguments[1..].join(" "))
         }
-        // DMI arguments
-        "ldi" => {
-            dmi_func::display_dmi_info(json_mode);
+        (Some(setting @ ("power" | "discoverable" | "pairable")), Some(value @ ("on" | "off"))) => {
+            bt_func::set_bt_adapter_setting(adapter, setting, value == "on")
         }
-        "ldp" => {
-            dmi_func::display_dmi_profiles(json_mode);
+        _ => return Err(usage_error("bt_adapter_no_operation")),
+    };
+    Ok(result?)
+}
+
+// `--timeout <seconds>` of `cfhdb bt scan`, 10 seconds without it
+#[cfg(feature = "bt")]
+fn bt_scan_timeout(arguments: &[String]) -> Result<Duration, CliError> {
+    let value = match arguments.iter().position(|x| x == "--timeout") {
+        Some(index) => arguments.get(index + 1),
+        None => return Ok(Duration::from_secs(10)),
+    };
+    match value.and_then(|x| x.parse::<u64>().ok()) {
+        Some(t) if t > 0 => Ok(Duration::from_secs(t)),
+        _ => Err(usage_error("bt_scan_invalid_timeout")),
+    }
+}
+
+// How a command failed. The actions hand it up and main is the one place
+// that prints it and exits, the func modules only return a CfhdbError.
+#[derive(Debug)]
+pub enum CliError {
+    // Missing or unknown arguments, printed as is
+    Usage(String),
+    // A failure only the command itself can phrase
+    Failed(String),
+    // The command printed what went wrong already, like the failed checks of
+    // doctor or the profiles auto could not apply
+    Reported,
+    Cfhdb(CfhdbError),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) | CliError::Failed(message) => write!(f, "{}", message),
+            CliError::Reported => write!(f, "command failed"),
+            CliError::Cfhdb(e) => write!(f, "{}", cfhdb_error_message(e)),
         }
-        "idp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                dmi_func::install_dmi_profile(&additional_arguments[1]);
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<CfhdbError> for CliError {
+    fn from(e: CfhdbError) -> Self {
+        CliError::Cfhdb(e)
+    }
+}
+
+fn usage_error(key: &str) -> CliError {
+    CliError::Usage(t!(key).to_string())
+}
+
+// The device or profile an action is for, right after the action
+fn required_argument<'a>(arguments: &'a [String], missing: &str) -> Result<&'a str, CliError> {
+    match arguments.get(1) {
+        Some(t) => Ok(t),
+        None => Err(usage_error(missing)),
+    }
+}
+
+fn cfhdb_error_message(e: &CfhdbError) -> String {
+    let message = match e {
+        CfhdbError::EnumerationFailed(category) => {
+            t!(format!("failed_to_get_{}_devices", category))
+        }
+        CfhdbError::DeviceNotFound(category, _) => {
+            t!(format!("no_matching_{}_device", category))
+        }
+        CfhdbError::NoProfilesAvailable("dmi") => t!("no_profiles_available_for_info"),
+        CfhdbError::NoProfilesAvailable(_) => t!("no_profiles_available_for_device"),
+        CfhdbError::ProfileNotFound(..) => t!("no_matching_profile_codename"),
+        CfhdbError::ProfileOperation(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            t!("script_lock_busy", reason = e.to_string())
+        }
+        CfhdbError::ProfileOperation(e) => format!("{}\n{}", t!("install_script_failed"), e).into(),
+        e => e.to_string().into(),
+    };
+    message.into_owned()
+}
+
+fn report_profile_operation(
+    result: Result<ProfileOperationOutcome, CommandError>,
+    codename: &str,
+) -> Result<(), CfhdbError> {
+    match result {
+        Ok(ProfileOperationOutcome::Completed) => {
+            println!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!("install_script_successful")
+            );
+        }
+        Ok(ProfileOperationOutcome::AlreadyInstalled) => {
+            println!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!("profile_already_installed")
+            );
+        }
+        Ok(ProfileOperationOutcome::NotInstalled) => {
+            println!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!("profile_not_installed")
+            );
+        }
+        Ok(ProfileOperationOutcome::NothingToDo) => {}
+        Err(CommandError::Io(e)) => return Err(CfhdbError::ProfileOperation(e)),
+        Err(e) => return Err(e.into_cfhdb_error("profile", codename)),
+    }
+    Ok(())
+}
+
+// Impure check scripts may look at more than the filesystem, so their answer
+// is flagged as less reliable
+pub fn profile_status_cell(installed: bool, check_is_pure: bool) -> CellStruct {
+    let text = match (installed, check_is_pure) {
+        (true, true) => t!("enabled_yes").to_string(),
+        (false, true) => t!("enabled_no").to_string(),
+        (true, false) => format!("{} {}", t!("enabled_yes"), t!("check_impure_marker")),
+        (false, false) => format!("{} {}", t!("enabled_no"), t!("check_impure_marker")),
+    };
+    text.cell()
+        .foreground_color(Some(if installed { Color::Green } else { Color::Red }))
+}
+
+// Shows exactly what is about to run as root, package manager commands included
+fn print_script_preview(script: Option<String>) {
+    if let Some(script) = script {
+        println!(
+            "[{}] {}\n{}",
+            t!("info").bright_green(),
+            t!("script_preview"),
+            script.dimmed()
+        );
+    }
+}
+
+// A CfhdbError since the profile and device helpers the func modules share
+// check it too
+fn require_privileges(action: AuditAction) -> Result<(), CfhdbError> {
+    if USER_MODE.load(Ordering::Relaxed) && commands::is_privileged(action) {
+        return Err(CfhdbError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            t!("operation_requires_admin", action = action.as_str()).to_string(),
+        )));
+    }
+    Ok(())
+}
+
+fn profile_cache_dir() -> Result<PathBuf, io::Error> {
+    if !USER_MODE.load(Ordering::Relaxed) {
+        return Ok(paths::in_target_root(paths::SYSTEM_CACHE_DIR));
+    }
+    paths::user_cache_dir().ok_or(io::Error::new(
+        io::ErrorKind::NotFound,
+        "neither XDG_CACHE_HOME nor HOME is set",
+    ))
+}
+
+pub fn format_size(size: Option<u64>) -> String {
+    let size = match size {
+        Some(t) => t as f64,
+        None => return t!("size_unknown").to_string(),
+    };
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut scaled = size;
+    let mut unit = 0;
+    while scaled >= 1000.0 && unit < units.len() - 1 {
+        scaled /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", scaled, units[unit])
+}
+
+fn print_install_plan(plans: &[PackagePlan]) {
+    for plan in plans {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!(
+                "install_plan_packages",
+                backend = plan.backend,
+                packages = plan.to_install.join(", ")
+            )
+        );
+    }
+    println!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!(
+            "install_plan_sizes",
+            download = format_size(plans.iter().map(|x| x.download_size).sum()),
+            installed = format_size(plans.iter().map(|x| x.installed_size).sum())
+        )
+    );
+}
+
+pub fn ask_yes_no(question: &str) -> bool {
+    print!("{} ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// Only asks on a terminal, so scripted runs keep working unattended
+fn confirm_operation() -> bool {
+    ASSUME_YES.load(Ordering::Relaxed)
+        || !io::stdin().is_terminal()
+        || ask_yes_no(&t!("confirm_operation"))
+}
+
+// Without --atomic the packages a failed install added stay, unless the user
+// asks for them to go. Unattended runs never remove anything on their own.
+// The install failure itself is reported by the caller afterwards.
+fn offer_rollback(partial: &PartialInstallError, backend: &dyn PackageBackend) {
+    let packages = partial.packages().join(", ");
+    let rollback = ASSUME_YES.load(Ordering::Relaxed)
+        || (io::stdin().is_terminal() && ask_yes_no(&t!("rollback_offer", packages = packages)));
+    if !rollback {
+        eprintln!(
+            "[{}] {}",
+            t!("warn").bright_yellow(),
+            t!("rollback_skipped", packages = packages)
+        );
+        return;
+    }
+    match commands::rollback_partial_install(partial, backend) {
+        Ok(()) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("rollback_successful", packages = packages)
+        ),
+        Err(e) => eprintln!("[{}] {}\n{}", t!("error").red(), t!("rollback_failed"), e),
+    }
+}
+
+fn print_dry_run(codename: &str, script: Option<String>, plans: Option<Vec<PackagePlan>>) {
+    let json = serde_json::json!({
+        "codename": codename,
+        "script": script,
+        "packages": plans,
+    });
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+// Installs cut short by a kill or a power loss are found by the next profile
+// operation. Only a terminal user gets to decide, unattended runs just warn.
+// Filesystem level recovery is left to the admin, cfhdb only points at it
+fn print_snapshot_hint(snapshot: &Option<Snapshot>) {
+    if let Some(snapshot) = snapshot {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!(
+                "snapshot_available",
+                tool = snapshot.tool.as_str(),
+                id = snapshot.id
+            )
+        );
+    }
+}
+
+fn recover_interrupted_installs(backend: &dyn PackageBackend) {
+    for entry in journal::interrupted() {
+        eprintln!(
+            "[{}] {}",
+            t!("warn").bright_yellow(),
+            t!(
+                "journal_interrupted",
+                codename = entry.codename,
+                stage = entry.stage.as_str(),
+                time = audit::format_timestamp(entry.timestamp)
+            )
+        );
+        print_snapshot_hint(&entry.snapshot);
+        if !io::stdin().is_terminal() {
+            continue;
+        }
+        print!("{} ", t!("journal_recover_prompt"));
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() {
+            continue;
+        }
+        let result = match answer.trim().to_lowercase().as_str() {
+            "r" | "resume" => commands::resume_interrupted_install(&entry, backend),
+            "b" | "rollback" => commands::rollback_interrupted_install(&entry, backend),
+            "d" | "discard" => journal::discard(&entry.id).map_err(CommandError::Io),
+            _ => continue,
+        };
+        match result {
+            Ok(()) => println!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!("journal_recovered", codename = entry.codename)
+            ),
+            Err(e) => eprintln!(
+                "[{}] {}\n{}",
+                t!("error").red(),
+                t!("journal_recover_failed", codename = entry.codename),
+                e
+            ),
+        }
+    }
+}
+
+pub fn install_profile<P: CfhdbProfile>(profile: P) -> Result<(), CfhdbError> {
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    if !dry_run {
+        require_privileges(AuditAction::Install)?;
+    }
+    let backend = config::distro_package_backend();
+    if !dry_run {
+        recover_interrupted_installs(&*backend);
+    }
+    let script = profile::compose_install_script(&profile, &*backend)
+        .ok()
+        .flatten();
+    let plans = profile::plan_install(&profile, &*backend);
+    if dry_run {
+        match plans {
+            Ok(plans) => print_dry_run(profile.get_codename(), script, Some(plans)),
+            Err(e) => {
+                return Err(CfhdbError::Io(io::Error::new(
+                    e.kind(),
+                    format!("{}\n{}", t!("install_plan_failed"), e),
+                )))
             }
         }
-        "udp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                dmi_func::uninstall_dmi_profile(&additional_arguments[1]);
+        return Ok(());
+    }
+    print_script_preview(script);
+    match &plans {
+        Ok(plans) if !plans.is_empty() => print_install_plan(plans),
+        Ok(_) => {}
+        Err(e) => eprintln!(
+            "[{}] {}\n{}",
+            t!("warn").bright_yellow(),
+            t!("install_plan_failed"),
+            e
+        ),
+    }
+    if !confirm_operation() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("operation_cancelled")
+        );
+        return Ok(());
+    }
+    let options = ProfileOperationOptions {
+        atomic: ATOMIC.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+    let codename = profile.get_codename().to_owned();
+    let result = commands::install_profile(Ok::<_, CfhdbError>(profile), &*backend, &options);
+    if let Err(CommandError::Io(e)) = &result {
+        if let Some(partial) = PartialInstallError::from_io_error(e) {
+            offer_rollback(partial, &*backend);
+        }
+    }
+    report_profile_operation(result, &codename)
+}
+
+pub fn uninstall_profile<P: CfhdbProfile>(profile: P) -> Result<(), CfhdbError> {
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    if !dry_run {
+        require_privileges(AuditAction::Uninstall)?;
+    }
+    let backend = config::distro_package_backend();
+    if !dry_run {
+        recover_interrupted_installs(&*backend);
+    }
+    let script = profile::compose_uninstall_script(&profile, &*backend)
+        .ok()
+        .flatten();
+    if dry_run {
+        print_dry_run(profile.get_codename(), script, None);
+        return Ok(());
+    }
+    print_script_preview(script);
+    let codename = profile.get_codename().to_owned();
+    report_profile_operation(
+        commands::uninstall_profile(Ok::<_, CfhdbError>(profile), &*backend),
+        &codename,
+    )
+}
+
+// Installed profiles are matched against the freshly fetched database of
+// every category in turn, records left over at the end are no longer in any
+// database
+pub struct ProfileUpgrade {
+    records: Vec<InstalledProfile>,
+    pinned: Vec<String>,
+    backend: Box<dyn PackageBackend>,
+    dry_run: bool,
+    planned: Vec<serde_json::Value>,
+    upgraded: usize,
+    failed: bool,
+}
+
+impl ProfileUpgrade {
+    fn print_changes(codename: &str, changes: &[ProfileChange]) {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("upgrade_profile_changed", codename = codename)
+        );
+        for change in changes {
+            match change {
+                ProfileChange::Packages { added, removed } => {
+                    if !added.is_empty() {
+                        println!(
+                            "  {}",
+                            t!("upgrade_change_added", packages = added.join(", "))
+                        );
+                    }
+                    if !removed.is_empty() {
+                        println!(
+                            "  {}",
+                            t!("upgrade_change_removed", packages = removed.join(", "))
+                        );
+                    }
+                }
+                ProfileChange::InstallScript => println!("  {}", t!("upgrade_change_script")),
+                ProfileChange::SupersededBy { codename } => {
+                    println!("  {}", t!("upgrade_change_superseded", codename = codename))
+                }
             }
         }
-        // BT arguments
-        "lbd" => {
-            bt_func::display_bt_devices(json_mode);
+    }
+
+    fn report(&mut self, codename: &str, result: Result<ProfileOperationOutcome, CommandError>) {
+        match result {
+            Ok(_) => {
+                self.upgraded += 1;
+                println!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!("upgrade_successful", codename = codename)
+                );
+            }
+            Err(e) => {
+                self.failed = true;
+                eprintln!(
+                    "[{}] {}\n{}",
+                    t!("error").red(),
+                    t!("upgrade_failed", codename = codename),
+                    e
+                );
+            }
         }
-        "lbp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::display_bt_profiles(json_mode, &additional_arguments[1]);
+    }
+
+    fn replace<P: CfhdbProfile + Clone>(&mut self, profile: &P, replacement: Option<&Arc<P>>) {
+        let codename = profile.get_codename();
+        let replacement = match replacement {
+            Some(t) => t.as_ref().clone(),
+            None => {
+                self.failed = true;
+                eprintln!(
+                    "[{}] {}",
+                    t!("error").red(),
+                    t!(
+                        "upgrade_replacement_missing",
+                        codename = codename,
+                        replacement = profile.get_superseded_by().unwrap_or_default()
+                    )
+                );
+                return;
+            }
+        };
+        // The replacement usually conflicts with what it replaces, so the old
+        // profile goes first where it can be removed at all
+        if profile.is_removable() {
+            if let Err(e) =
+                commands::uninstall_profile(Ok::<_, CfhdbError>(profile.clone()), &*self.backend)
+            {
+                self.report(codename, Err(e));
+                return;
             }
+        } else {
+            installed::forget_installed(codename);
         }
-        "ibp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::install_bt_profile(&additional_arguments[1]);
+        let options = ProfileOperationOptions {
+            atomic: ATOMIC.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        let result =
+            commands::install_profile(Ok::<_, CfhdbError>(replacement), &*self.backend, &options);
+        self.report(codename, result);
+    }
+
+    pub fn apply<P: CfhdbProfile + Clone>(&mut self, profiles: &[Arc<P>]) {
+        let (records, rest): (Vec<_>, Vec<_>) = self
+            .records
+            .drain(..)
+            .partition(|x| profiles.iter().any(|y| y.get_codename() == x.codename));
+        self.records = rest;
+        for record in records {
+            let profile = match profiles
+                .iter()
+                .find(|x| x.get_codename() == record.codename)
+            {
+                Some(t) => t.as_ref(),
+                None => continue,
+            };
+            let changes = installed::changes(&record, profile);
+            if changes.is_empty() {
+                continue;
+            }
+            let pinned = self.pinned.contains(&record.codename);
+            if self.dry_run {
+                self.planned.push(serde_json::json!({
+                    "codename": record.codename,
+                    "pinned": pinned,
+                    "changes": changes,
+                }));
+                continue;
+            }
+            if pinned {
+                println!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!("upgrade_pinned", codename = record.codename)
+                );
+                continue;
+            }
+            // Profiles removed by hand are not brought back
+            if !profile.get_status() {
+                println!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!("upgrade_not_installed", codename = record.codename)
+                );
+                installed::forget_installed(&record.codename);
+                continue;
+            }
+            Self::print_changes(&record.codename, &changes);
+            if !confirm_operation() {
+                println!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!("upgrade_skipped", codename = record.codename)
+                );
+                continue;
             }
+            if let Some(replacement) = profile.get_superseded_by() {
+                let replacement = profiles.iter().find(|x| x.get_codename() == replacement);
+                self.replace(profile, replacement);
+                continue;
+            }
+            let options = ProfileOperationOptions {
+                atomic: ATOMIC.load(Ordering::Relaxed),
+                reapply: true,
+                ..Default::default()
+            };
+            let result = commands::install_profile(
+                Ok::<_, CfhdbError>(profile.clone()),
+                &*self.backend,
+                &options,
+            );
+            self.report(&record.codename, result);
         }
-        "ubp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::uninstall_bt_profile(&additional_arguments[1]);
+    }
+}
+
+fn rollback_last_transaction() -> Result<(), CliError> {
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    if !dry_run {
+        require_privileges(AuditAction::Uninstall)?;
+        recover_interrupted_installs(&*config::distro_package_backend());
+    }
+    let last = match transaction::last_reversible() {
+        Some(t) => t,
+        None => {
+            println!("[{}] {}", t!("info").bright_green(), t!("rollback_nothing"));
+            return Ok(());
+        }
+    };
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&last).unwrap());
+        return Ok(());
+    }
+    println!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!(
+            "rollback_last_transaction",
+            kind = last.kind.as_str(),
+            codename = last.codename,
+            time = audit::format_timestamp(last.timestamp)
+        )
+    );
+    print_snapshot_hint(&last.snapshot);
+    let packages = |sets: &[(String, Vec<String>)]| {
+        sets.iter()
+            .flat_map(|(_, x)| x.iter().map(|x| x.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !last.added.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("rollback_will_remove", packages = packages(&last.added))
+        );
+    }
+    if !last.removed.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!(
+                "rollback_will_reinstall",
+                packages = packages(&last.removed)
+            )
+        );
+    }
+    print_script_preview(last.reverse_script.clone());
+    if !confirm_operation() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("operation_cancelled")
+        );
+        return Ok(());
+    }
+    match commands::rollback_transaction(&last) {
+        Ok(()) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("rollback_transaction_successful", codename = last.codename)
+        ),
+        Err(CommandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+            return Err(CliError::Failed(
+                t!("script_lock_busy", reason = e.to_string()).to_string(),
+            ))
+        }
+        Err(e) => {
+            return Err(CliError::Failed(format!(
+                "{}\n{}",
+                t!("rollback_transaction_failed"),
+                e
+            )))
+        }
+    }
+    Ok(())
+}
+
+// Installed profiles that no present device matches any more, found category
+// by category. A category that could not be enumerated never reaches apply(),
+// so its profiles are left alone.
+pub struct ProfileCleanup {
+    records: Vec<InstalledProfile>,
+    backend: Box<dyn PackageBackend>,
+    dry_run: bool,
+    orphaned: Vec<String>,
+    removed: usize,
+    failed: bool,
+}
+
+impl ProfileCleanup {
+    pub fn apply<'a, D>(
+        &mut self,
+        profiles: &[Arc<D::Profile>],
+        devices: impl Iterator<Item = &'a D>,
+    ) where
+        D: CfhdbDevice + 'a,
+        D::Profile: CfhdbProfile + Clone,
+    {
+        let matched: Vec<String> = devices
+            .flat_map(|x| x.get_matched_profiles().unwrap_or_default())
+            .map(|x| x.get_codename().to_owned())
+            .collect();
+        let (records, rest): (Vec<_>, Vec<_>) = self
+            .records
+            .drain(..)
+            .partition(|x| profiles.iter().any(|y| y.get_codename() == x.codename));
+        self.records = rest;
+        for record in records {
+            if matched.contains(&record.codename) {
+                continue;
+            }
+            let profile = match profiles
+                .iter()
+                .find(|x| x.get_codename() == record.codename)
+            {
+                Some(t) => t.as_ref(),
+                None => continue,
+            };
+            if !profile.get_status() {
+                installed::forget_installed(&record.codename);
+                continue;
+            }
+            self.orphaned.push(record.codename.clone());
+            if self.dry_run {
+                continue;
+            }
+            if !profile.is_removable() {
+                println!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!("autoremove_not_removable", codename = record.codename)
+                );
+                continue;
+            }
+            // Removing is never done unattended without --assume-yes
+            let remove = ASSUME_YES.load(Ordering::Relaxed)
+                || (io::stdin().is_terminal()
+                    && ask_yes_no(&t!("autoremove_offer", codename = record.codename)));
+            if !remove {
+                continue;
+            }
+            match commands::uninstall_profile(Ok::<_, CfhdbError>(profile.clone()), &*self.backend)
+            {
+                Ok(_) => {
+                    self.removed += 1;
+                    println!(
+                        "[{}] {}",
+                        t!("info").bright_green(),
+                        t!("autoremove_successful", codename = record.codename)
+                    );
+                }
+                Err(e) => {
+                    self.failed = true;
+                    eprintln!(
+                        "[{}] {}\n{}",
+                        t!("error").red(),
+                        t!("autoremove_failed", codename = record.codename),
+                        e
+                    );
+                }
             }
         }
-        "pbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::pair_bt_device(&additional_arguments[1]);
+    }
+}
+
+fn autoremove_profiles() -> Result<(), CliError> {
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    if !dry_run {
+        require_privileges(AuditAction::Uninstall)?;
+        recover_interrupted_installs(&*config::distro_package_backend());
+    }
+    let mut cleanup = ProfileCleanup {
+        records: installed::get_installed(),
+        backend: config::distro_package_backend(),
+        dry_run,
+        orphaned: vec![],
+        removed: 0,
+        failed: false,
+    };
+    let results: Vec<(&str, Result<(), CommandError>)> = vec![
+        #[cfg(feature = "dmi")]
+        ("dmi", dmi_func::autoremove_dmi_profiles(&mut cleanup)),
+        #[cfg(feature = "pci")]
+        ("pci", pci_func::autoremove_pci_profiles(&mut cleanup)),
+        #[cfg(feature = "usb")]
+        ("usb", usb_func::autoremove_usb_profiles(&mut cleanup)),
+        #[cfg(feature = "bt")]
+        ("bt", bt_func::autoremove_bt_profiles(&mut cleanup)),
+    ];
+    for (category, result) in results {
+        if let Err(e) = result {
+            eprintln!(
+                "[{}] {} {}",
+                t!("warn").bright_yellow(),
+                t!("status_category_failed", category = category),
+                e
+            );
+        }
+    }
+    if dry_run {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&cleanup.orphaned).unwrap()
+        );
+        return Ok(());
+    }
+    if cleanup.orphaned.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("autoremove_nothing")
+        );
+    }
+    if cleanup.failed {
+        return Err(CliError::Reported);
+    }
+    Ok(())
+}
+
+type PendingInstall = Box<
+    dyn Fn(
+        &dyn PackageBackend,
+        &ProfileOperationOptions,
+    ) -> Result<ProfileOperationOutcome, CommandError>,
+>;
+
+struct AutoPick {
+    category: &'static str,
+    device: String,
+    codename: String,
+    install: PendingInstall,
+}
+
+// The recommended profile of every uncovered device, gathered in the order
+// auto_apply_profiles() walks the categories. That order is the install order:
+// platform profiles first and bluetooth last, since bluetooth adapters sit on
+// the pci and usb buses and may need the drivers picked there.
+pub struct ProfileAutoApply {
+    categories: Vec<String>,
+    picks: Vec<AutoPick>,
+    // Pick only where the rules single out a profile, instead of taking the
+    // recommended one
+    rules: Option<AutoInstallRules>,
+}
+
+impl ProfileAutoApply {
+    fn wants(&self, category: &str) -> bool {
+        self.categories.is_empty() || self.categories.iter().any(|x| x == category)
+    }
+
+    pub fn apply<'a, D>(&mut self, category: &'static str, devices: impl Iterator<Item = &'a D>)
+    where
+        D: CfhdbDevice + 'a,
+        D::Profile: CfhdbProfile + Clone + 'static,
+    {
+        for device in devices {
+            let profiles = match commands::get_sorted_profiles(device) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            // A device someone already set up is left as it is
+            if profiles.iter().any(|x| x.get_status()) {
+                continue;
+            }
+            let profile = match &self.rules {
+                Some(rules) => rules.pick(&profiles).cloned(),
+                None => profiles.into_iter().find(|x| {
+                    !x.is_veiled() && !x.is_experimental() && x.get_superseded_by().is_none()
+                }),
+            };
+            let profile = match profile {
+                Some(t) => t,
+                None => continue,
+            };
+            let codename = profile.get_codename().to_owned();
+            if self.picks.iter().any(|x| x.codename == codename) {
+                continue;
             }
+            self.picks.push(AutoPick {
+                category,
+                device: device.get_id(),
+                codename,
+                install: Box::new(
+                    move |backend: &dyn PackageBackend, options: &ProfileOperationOptions| {
+                        commands::install_profile(
+                            Ok::<_, CfhdbError>(profile.as_ref().clone()),
+                            backend,
+                            options,
+                        )
+                    },
+                ),
+            });
+        }
+    }
+}
+
+const AUTO_CATEGORIES: [&str; 4] = ["dmi", "pci", "usb", "bt"];
+
+fn auto_apply_profiles(categories: Vec<String>) -> Result<(), CliError> {
+    for category in &categories {
+        if !AUTO_CATEGORIES.contains(&category.as_str()) {
+            return Err(CliError::Failed(
+                t!("auto_unknown_category", category = category).to_string(),
+            ));
+        }
+    }
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    let backend = config::distro_package_backend();
+    if !dry_run {
+        require_privileges(AuditAction::Install)?;
+        recover_interrupted_installs(&*backend);
+    }
+    let mut auto = ProfileAutoApply {
+        categories,
+        picks: vec![],
+        rules: None,
+    };
+    let mut results: Vec<(&str, Result<(), CommandError>)> = vec![];
+    #[cfg(feature = "dmi")]
+    if auto.wants("dmi") {
+        results.push(("dmi", dmi_func::auto_dmi_profiles(&mut auto)));
+    }
+    #[cfg(feature = "pci")]
+    if auto.wants("pci") {
+        results.push(("pci", pci_func::auto_pci_profiles(&mut auto)));
+    }
+    #[cfg(feature = "usb")]
+    if auto.wants("usb") {
+        results.push(("usb", usb_func::auto_usb_profiles(&mut auto)));
+    }
+    #[cfg(feature = "bt")]
+    if auto.wants("bt") {
+        results.push(("bt", bt_func::auto_bt_profiles(&mut auto)));
+    }
+    for category in &auto.categories {
+        if !results.iter().any(|(x, _)| *x == category.as_str()) {
+            return Err(subsystem_not_built(category));
         }
-        "cbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
+    }
+    // Only categories asked for by name fail the run, a machine without
+    // bluetooth still gets its other profiles at first boot
+    let mut failed = false;
+    for (category, result) in results {
+        if let Err(e) = result {
+            if auto.categories.is_empty() {
+                eprintln!(
+                    "[{}] {} {}",
+                    t!("warn").bright_yellow(),
+                    t!("status_category_failed", category = category),
+                    e
+                );
             } else {
-                bt_func::connect_bt_device(&additional_arguments[1]);
+                failed = true;
+                eprintln!(
+                    "[{}] {} {}",
+                    t!("error").red(),
+                    t!("status_category_failed", category = category),
+                    e
+                );
             }
         }
-        "dbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::disconnect_bt_device(&additional_arguments[1]);
+    }
+    if dry_run {
+        let planned: Vec<serde_json::Value> = auto
+            .picks
+            .iter()
+            .map(|x| {
+                serde_json::json!({
+                    "category": x.category,
+                    "device": x.device,
+                    "codename": x.codename,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&planned).unwrap());
+        if failed {
+            return Err(CliError::Reported);
+        }
+        return Ok(());
+    }
+    if auto.picks.is_empty() {
+        println!("[{}] {}", t!("info").bright_green(), t!("auto_nothing"));
+        if failed {
+            return Err(CliError::Reported);
+        }
+        return Ok(());
+    }
+    let table = auto
+        .picks
+        .iter()
+        .map(|x| {
+            vec![
+                x.category.cell(),
+                x.device.as_str().cell(),
+                x.codename.as_str().cell(),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            t!("status_table_category").cell().bold(true),
+            t!("auto_table_device").cell().bold(true),
+            t!("auto_table_profile").cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+    if !confirm_operation() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("operation_cancelled")
+        );
+        return Ok(());
+    }
+    let options = ProfileOperationOptions {
+        atomic: ATOMIC.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+    // One failed profile does not keep the remaining devices from working
+    for pick in &auto.picks {
+        match (pick.install)(&*backend, &options) {
+            Ok(_) => println!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!("auto_successful", codename = pick.codename)
+            ),
+            Err(e) => {
+                failed = true;
+                eprintln!(
+                    "[{}] {}\n{}",
+                    t!("error").red(),
+                    t!("auto_failed", codename = pick.codename),
+                    e
+                );
             }
         }
-        "tbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::trust_bt_device(&additional_arguments[1]);
+    }
+    if failed {
+        return Err(CliError::Reported);
+    }
+    Ok(())
+}
+
+// What the timer runs when auto-apply is off
+fn refresh_profile_databases() -> Result<(), CliError> {
+    let config = get_profile_url_config();
+    let databases: Vec<(&str, &str)> = vec![
+        #[cfg(feature = "dmi")]
+        ("dmi", config.dmi_json_url.as_str()),
+        #[cfg(feature = "pci")]
+        ("pci", config.pci_json_url.as_str()),
+        #[cfg(feature = "usb")]
+        ("usb", config.usb_json_url.as_str()),
+        #[cfg(feature = "bt")]
+        ("bt", config.bt_json_url.as_str()),
+    ];
+    let mut failed = false;
+    for (category, url) in databases {
+        if download_profile_database(category, url).is_err() {
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(CliError::Reported);
+    }
+    Ok(())
+}
+
+fn manage_systemd_units(operation: Option<&str>) -> Result<(), CliError> {
+    let script = match operation {
+        Some("install") => {
+            let config = get_profile_url_config();
+            let settings = TimerSettings {
+                schedule: config.timer_schedule,
+                auto_apply: config.timer_auto_apply,
+                categories: config.timer_categories,
+            };
+            match env::current_exe().and_then(|x| systemd::compose_install_script(&x, &settings)) {
+                Ok(t) => t,
+                Err(e) => {
+                    return Err(CliError::Failed(format!(
+                        "{}\n{}",
+                        t!("systemd_invalid_config"),
+                        e
+                    )))
+                }
             }
         }
-        "utbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::untrust_bt_device(&additional_arguments[1]);
+        Some("remove") => systemd::compose_remove_script(),
+        _ => return Err(usage_error("systemd_no_operation")),
+    };
+    if DRY_RUN.load(Ordering::Relaxed) {
+        println!("{}", script);
+        return Ok(());
+    }
+    require_privileges(AuditAction::Install)?;
+    print_script_preview(Some(script.clone()));
+    if !confirm_operation() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("operation_cancelled")
+        );
+        return Ok(());
+    }
+    match script::run_in_lock_script(&script) {
+        Ok(_) if operation == Some("install") => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("systemd_install_successful", timer = systemd::REFRESH_TIMER)
+        ),
+        Ok(_) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("systemd_remove_successful", timer = systemd::REFRESH_TIMER)
+        ),
+        Err(e) => return Err(CliError::Failed(format!("{}\n{}", t!("systemd_failed"), e))),
+    }
+    Ok(())
+}
+
+// Profiles named in an imported state, looked up in the database of every
+// category in turn. Codenames still wanted at the end are in none of them.
+pub struct ProfileImport {
+    wanted: Vec<String>,
+    pending: Vec<(String, PendingInstall)>,
+}
+
+impl ProfileImport {
+    pub fn apply<P: CfhdbProfile + Clone + 'static>(&mut self, profiles: &[Arc<P>]) {
+        for profile in profiles {
+            let codename = profile.get_codename().to_owned();
+            if !self.wanted.contains(&codename) {
+                continue;
+            }
+            self.wanted.retain(|x| *x != codename);
+            if profile.get_status() {
+                continue;
             }
+            let profile = profile.clone();
+            self.pending.push((
+                codename,
+                Box::new(
+                    move |backend: &dyn PackageBackend, options: &ProfileOperationOptions| {
+                        commands::install_profile(
+                            Ok::<_, CfhdbError>(profile.as_ref().clone()),
+                            backend,
+                            options,
+                        )
+                    },
+                ),
+            ));
         }
-        "bbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::block_bt_device(&additional_arguments[1]);
+    }
+}
+
+const EXPORTER_INTERVAL: Duration = Duration::from_secs(300);
+
+// Only written when a download succeeded, a fallback to the cache leaves it
+fn database_refresh_timestamp(category: &str) -> Option<f64> {
+    let path = profile_cache_dir().ok()?.join(format!("{}.json", category));
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs_f64())
+}
+
+type ClassCounts = Result<Vec<(String, usize)>, CommandError>;
+
+fn collect_metrics() -> String {
+    let mut writer = MetricsWriter::new();
+    let classes: Vec<(&str, ClassCounts)> = vec![
+        #[cfg(feature = "pci")]
+        ("pci", pci_func::pci_class_counts()),
+        #[cfg(feature = "usb")]
+        ("usb", usb_func::usb_class_counts()),
+        #[cfg(feature = "bt")]
+        ("bt", bt_func::bt_class_counts()),
+    ];
+    writer.family(
+        "cfhdb_devices",
+        "Devices present by category and class",
+        "gauge",
+    );
+    for (category, result) in classes {
+        match result {
+            Ok(counts) => {
+                for (class, count) in counts {
+                    writer.sample(
+                        "cfhdb_devices",
+                        &[("category", category), ("class", class.as_str())],
+                        count as f64,
+                    );
+                }
             }
+            Err(e) => eprintln!(
+                "[{}] {} {}",
+                t!("warn").bright_yellow(),
+                t!("exporter_category_failed", category = category),
+                e
+            ),
         }
-        "ubbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::unblock_bt_device(&additional_arguments[1]);
+    }
+    #[cfg(feature = "bt")]
+    {
+        writer.family(
+            "cfhdb_bt_battery_percent",
+            "Battery level of connected bluetooth devices that report one",
+            "gauge",
+        );
+        match bt_func::bt_battery_levels() {
+            Ok(levels) => {
+                for (address, name, level) in levels {
+                    writer.sample(
+                        "cfhdb_bt_battery_percent",
+                        &[("address", address.as_str()), ("name", name.as_str())],
+                        level as f64,
+                    );
+                }
             }
+            Err(e) => eprintln!(
+                "[{}] {} {}",
+                t!("warn").bright_yellow(),
+                t!("exporter_category_failed", category = "bt"),
+                e
+            ),
         }
-        // Unknown argument
-        _ => {
-            eprintln!("{}", t!("unknown_argument"));
-            std::process::exit(1);
+    }
+    // installed, missing (a profile matches but none is installed), uncovered
+    let mut states: Vec<(&str, [usize; 3])> = vec![];
+    for device in collect_device_status() {
+        let state = if !device.installed.is_empty() {
+            0
+        } else if !device.profiles.is_empty() {
+            1
+        } else {
+            2
+        };
+        match states.iter_mut().find(|(x, _)| *x == device.category) {
+            Some((_, counts)) => counts[state] += 1,
+            None => {
+                let mut counts = [0; 3];
+                counts[state] = 1;
+                states.push((device.category, counts));
+            }
+        }
+    }
+    writer.family(
+        "cfhdb_device_profiles",
+        "Devices with an installed profile, with a matching one missing, or with none in the database",
+        "gauge",
+    );
+    for (category, counts) in states {
+        for (state, count) in ["installed", "missing", "uncovered"]
+            .into_iter()
+            .zip(counts)
+        {
+            writer.sample(
+                "cfhdb_device_profiles",
+                &[("category", category), ("state", state)],
+                count as f64,
+            );
+        }
+    }
+    writer.family(
+        "cfhdb_installed_profiles",
+        "Profiles recorded as installed",
+        "gauge",
+    );
+    writer.sample(
+        "cfhdb_installed_profiles",
+        &[],
+        installed::get_installed().len() as f64,
+    );
+    writer.family(
+        "cfhdb_database_refresh_timestamp_seconds",
+        "Time the profile database was last downloaded successfully",
+        "gauge",
+    );
+    for category in AUTO_CATEGORIES {
+        if let Some(timestamp) = database_refresh_timestamp(category) {
+            writer.sample(
+                "cfhdb_database_refresh_timestamp_seconds",
+                &[("category", category)],
+                timestamp,
+            );
         }
     }
+    writer.finish()
 }
 
-fn main() {
-    // fix perms
-    duct::cmd!("bash", "-c", PERM_FIX_PROG).run().unwrap();
-    // Setup locales
-    let current_locale = match std::env::var_os("LANG") {
-        Some(v) => v.into_string().unwrap(),
-        None => panic!("$LANG is not set"),
+// Collects on a timer of its own, the databases would otherwise be
+// downloaded on every scrape
+fn run_exporter(listen: Option<&str>) -> Result<(), CliError> {
+    let config = get_profile_url_config();
+    let listen = listen
+        .map(|x| x.to_owned())
+        .or(config.exporter_listen)
+        .unwrap_or_else(|| metrics::DEFAULT_LISTEN.to_owned());
+    let interval = config
+        .exporter_interval
+        .map(Duration::from_secs)
+        .unwrap_or(EXPORTER_INTERVAL);
+    let rendered = Arc::new(Mutex::new(String::new()));
+    let collected = rendered.clone();
+    thread::spawn(move || loop {
+        let text = collect_metrics();
+        *collected.lock().unwrap() = text;
+        thread::sleep(interval);
+    });
+    println!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("exporter_listening", listen = listen)
+    );
+    if let Err(e) = metrics::serve_metrics(&listen, rendered) {
+        return Err(CliError::Failed(format!(
+            "{} {}",
+            t!("exporter_failed", listen = listen),
+            e
+        )));
+    }
+    Ok(())
+}
+
+fn collect_hwdb() -> Result<String, CliError> {
+    let results: Vec<(&str, Result<Vec<HwdbMatch>, io::Error>)> = vec![
+        #[cfg(feature = "pci")]
+        ("pci", pci_func::pci_hwdb_matches()),
+        #[cfg(feature = "usb")]
+        ("usb", usb_func::usb_hwdb_matches()),
+    ];
+    let mut matches = vec![];
+    for (category, result) in results {
+        match result {
+            Ok(t) => matches.extend(t),
+            Err(e) => {
+                return Err(CliError::Failed(format!(
+                    "{} {}",
+                    t!("hwdb_category_failed", category = category),
+                    e
+                )))
+            }
+        }
+    }
+    Ok(hwdb::render_hwdb(&matches))
+}
+
+// Only pci and usb, the categories udev has modaliases for
+fn manage_hwdb(operation: Option<&str>, path: Option<&str>) -> Result<(), CliError> {
+    let script = match operation {
+        Some("export") => {
+            STDOUT_DOCUMENT.store(true, Ordering::Relaxed);
+            let content = collect_hwdb()?;
+            match path {
+                Some(path) => {
+                    if let Err(e) = fs::write(path, content) {
+                        return Err(CliError::Failed(format!(
+                            "{} {}",
+                            t!("hwdb_write_failed", path = path),
+                            e
+                        )));
+                    }
+                }
+                None => print!("{}", content),
+            }
+            return Ok(());
+        }
+        Some("install") => hwdb::compose_install_script(&collect_hwdb()?),
+        Some("remove") => hwdb::compose_remove_script(),
+        _ => return Err(usage_error("hwdb_no_operation")),
     };
-    rust_i18n::set_locale(current_locale.strip_suffix(".UTF-8").unwrap());
-    let args: Vec<String> = std::env::args().collect();
-    let arg_num = args.len();
-    match arg_num {
-        0 | 1 => {
-            print_help_msg();
+    if DRY_RUN.load(Ordering::Relaxed) {
+        println!("{}", script);
+        return Ok(());
+    }
+    require_privileges(AuditAction::Install)?;
+    match script::run_in_lock_script(&script) {
+        Ok(_) if operation == Some("install") => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("hwdb_installed", path = hwdb::HWDB_FILE)
+        ),
+        Ok(_) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("hwdb_removed", path = hwdb::HWDB_FILE)
+        ),
+        Err(e) => return Err(CliError::Failed(format!("{}\n{}", t!("hwdb_failed"), e))),
+    }
+    Ok(())
+}
+
+fn collect_device_modules() -> Vec<DeviceModules> {
+    let results: Vec<(&str, Result<Vec<DeviceModules>, String>)> = vec![
+        #[cfg(feature = "pci")]
+        (
+            "pci",
+            pci_func::pci_device_modules().map_err(|e| e.to_string()),
+        ),
+        #[cfg(feature = "usb")]
+        (
+            "usb",
+            usb_func::usb_device_modules().map_err(|e| e.to_string()),
+        ),
+    ];
+    let mut devices = vec![];
+    for (category, result) in results {
+        match result {
+            Ok(t) => devices.extend(t),
+            Err(e) => eprintln!(
+                "[{}] {} {}",
+                t!("warn").bright_yellow(),
+                t!("status_category_failed", category = category),
+                e
+            ),
         }
-        _ => {
-            parse_args(args);
+    }
+    devices
+}
+
+fn display_modules(json_mode: bool) {
+    let devices = collect_device_modules();
+    if json_mode {
+        let local = modules::read_local_dropin();
+        let json = serde_json::json!({
+            "devices": devices,
+            "blacklist": local.blacklist,
+            "options": local.options,
+        });
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        return;
+    }
+    let mut table_struct = vec![];
+    for device in devices.iter().filter(|x| !x.modules.is_empty()) {
+        let modules = device
+            .modules
+            .iter()
+            .map(|x| match (x.loaded, x.blacklisted) {
+                (_, true) => format!("{} ({})", x.name, t!("modules_blacklisted")),
+                (true, false) => format!("{} ({})", x.name, t!("modules_loaded")),
+                (false, false) => x.name.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        table_struct.push(vec![
+            device.category.cell(),
+            device.id.clone().cell(),
+            format!("{} {}", device.vendor, device.name).trim().cell(),
+            match &device.driver {
+                Some(t) => t.clone().cell().foreground_color(Some(Color::Green)),
+                None => t!("modules_no_driver")
+                    .cell()
+                    .foreground_color(Some(Color::Yellow)),
+            },
+            modules.cell(),
+        ]);
+    }
+    let table = table_struct
+        .table()
+        .title(vec![
+            t!("status_table_category").cell().bold(true),
+            t!("status_table_device").cell().bold(true),
+            t!("status_table_name").cell().bold(true),
+            t!("modules_table_driver").cell().bold(true),
+            t!("modules_table_modules").cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+}
+
+// Only cfhdb-local.conf is changed here, the files of profiles come and go
+// with their profile
+fn manage_modules(json_mode: bool, arguments: &[String]) -> Result<(), CliError> {
+    let operation = arguments.get(1).map(|x| x.as_str());
+    if matches!(operation, None | Some("list")) {
+        display_modules(json_mode);
+        return Ok(());
+    }
+    let module = match arguments.get(2) {
+        Some(t) => t.as_str(),
+        None => return Err(usage_error("modules_no_module")),
+    };
+    if let Err(e) = modules::validate_module_name(module) {
+        return Err(CliError::Failed(e.to_string()));
+    }
+    let mut dropin = modules::read_local_dropin();
+    let normalized = modules::normalize_module_name(module);
+    let same_module = |x: &str| modules::normalize_module_name(x) == normalized;
+    match operation {
+        Some("blacklist") => {
+            if !dropin.blacklist.iter().any(|x| same_module(x)) {
+                dropin.blacklist.push(module.to_owned());
+            }
         }
+        Some("unblacklist") => dropin.blacklist.retain(|x| !same_module(x)),
+        // Without options the ones set before are cleared
+        Some("options") => {
+            dropin.options.retain(|x| !same_module(&x.module));
+            let options = arguments[3..].join(" ");
+            if !options.trim().is_empty() {
+                dropin.options.push(ModuleOption {
+                    module: module.to_owned(),
+                    options,
+                });
+            }
+        }
+        _ => return Err(usage_error("modules_no_operation")),
+    }
+    let script = match modules::compose_write_script(
+        modules::LOCAL_DROPIN,
+        &dropin,
+        "Generated by cfhdb modules, changes are overwritten",
+    ) {
+        Ok(t) => t,
+        Err(e) => return Err(CliError::Failed(e.to_string())),
+    };
+    if DRY_RUN.load(Ordering::Relaxed) {
+        println!("{}", script);
+        return Ok(());
+    }
+    require_privileges(AuditAction::Install)?;
+    match script::run_in_lock_script(&script) {
+        Ok(_) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!(
+                "modules_updated",
+                path = format!("{}/{}", modules::MODPROBE_DIR, modules::LOCAL_DROPIN)
+            )
+        ),
+        Err(e) => return Err(CliError::Failed(format!("{}\n{}", t!("modules_failed"), e))),
     }
+    Ok(())
 }
 
-pub fn run_in_lock_script(script: &str) {
-    let file_path = "/var/cache/cfhdb/script_lock.sh";
-    let file_fs_path = Path::new(file_path);
-    if file_fs_path.exists() {
-        fs::remove_file(file_fs_path).unwrap();
+// Parameters only show up as managed once a profile declaring them is
+// installed, sync rewrites the bootloader configuration from those files
+fn manage_kparams(json_mode: bool, operation: Option<&str>) -> Result<(), CliError> {
+    match operation {
+        None | Some("list") => {}
+        Some("sync") => {
+            let script = kparams::compose_sync_script();
+            if DRY_RUN.load(Ordering::Relaxed) {
+                println!("{}", script);
+                return Ok(());
+            }
+            require_privileges(AuditAction::Install)?;
+            match script::run_in_lock_script(&script) {
+                Ok(_) => println!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!(
+                        "kparams_synced",
+                        bootloader = kparams::detect_bootloader().as_str()
+                    )
+                ),
+                Err(e) => return Err(CliError::Failed(format!("{}\n{}", t!("kparams_failed"), e))),
+            }
+            return Ok(());
+        }
+        _ => return Err(usage_error("kparams_no_operation")),
     }
-    {
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(file_path)
-            .expect(&(file_path.to_string() + "cannot be read"));
-        file.write_all(script.as_bytes())
-            .expect(&(file_path.to_string() + "cannot be written to"));
-        let mut perms = file
-            .metadata()
-            .expect(&(file_path.to_string() + "cannot be read"))
-            .permissions();
-        perms.set_mode(0o777);
-        fs::set_permissions(file_path, perms)
-            .expect(&(file_path.to_string() + "cannot be written to"));
-    }
-    let final_cmd = if get_current_username().unwrap() == "root" {
-        duct::cmd!(file_path)
+    let bootloader = kparams::detect_bootloader();
+    let parameters = kparams::managed_parameters();
+    if json_mode {
+        let json = serde_json::json!({
+            "bootloader": bootloader,
+            "parameters": parameters,
+        });
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        return Ok(());
+    }
+    println!(
+        "{}",
+        t!("kparams_bootloader", bootloader = bootloader.as_str())
+    );
+    if parameters.is_empty() {
+        println!("{}", t!("kparams_none"));
+        return Ok(());
+    }
+    let mut table_struct = vec![];
+    for parameter in &parameters {
+        table_struct.push(vec![
+            parameter.parameter.clone().cell(),
+            parameter.profiles.join(", ").cell(),
+            if parameter.active {
+                t!("kparams_active")
+                    .cell()
+                    .foreground_color(Some(Color::Green))
+            } else {
+                t!("kparams_pending")
+                    .cell()
+                    .foreground_color(Some(Color::Yellow))
+            },
+        ]);
+    }
+    let table = table_struct
+        .table()
+        .title(vec![
+            t!("kparams_table_parameter").cell().bold(true),
+            t!("kparams_table_profiles").cell().bold(true),
+            t!("kparams_table_state").cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+    Ok(())
+}
+
+// --output ndjson or --output=ndjson
+fn monitor_output(arguments: &[String]) -> Option<&str> {
+    arguments
+        .iter()
+        .enumerate()
+        .find_map(|(i, x)| match x.as_str() {
+            "--output" => arguments.get(i + 1).map(|x| x.as_str()),
+            t => t.strip_prefix("--output="),
+        })
+}
+
+fn device_snapshot(event: &HotplugEvent) -> Option<serde_json::Value> {
+    match event.category {
+        #[cfg(feature = "pci")]
+        "pci" => pci_func::pci_device_snapshot(&event.id),
+        #[cfg(feature = "usb")]
+        "usb" => usb_func::usb_device_snapshot(&event.id),
+        #[cfg(feature = "bt")]
+        "bt" => bt_func::bt_device_snapshot(&event.id),
+        _ => None,
+    }
+}
+
+// Merges udev and bluetooth events into one stream, as text or one JSON
+// object per line. A removed device is described as it was last seen.
+// Codenames from the available_profiles of a device snapshot
+fn snapshot_profiles(device: Option<&serde_json::Value>) -> Vec<&str> {
+    device
+        .and_then(|x| x.get("available_profiles"))
+        .and_then(|x| x.as_array())
+        .map(|x| x.iter().filter_map(|x| x.as_str()).collect())
+        .unwrap_or_default()
+}
+
+fn monitor_devices(ndjson: bool, categories: Vec<String>) -> Result<(), CliError> {
+    if ndjson {
+        STDOUT_DOCUMENT.store(true, Ordering::Relaxed);
     } else {
-        duct::cmd!("pkexec", file_path)
-    };
-    match final_cmd.run() {
-        Ok(_) => {
+        println!(
+            "{}",
+            format!(
+                "{:<8} {:<8} {:<20} {}",
+                t!("monitor_table_action"),
+                t!("monitor_table_category"),
+                t!("monitor_table_id"),
+                t!("monitor_table_profiles")
+            )
+            .bold()
+        );
+    }
+    let (sender, receiver) = mpsc::channel();
+    if let Err(e) = hotplug::watch_udev(sender.clone()) {
+        return Err(CliError::Failed(format!("{}\n{}", t!("monitor_failed"), e)));
+    }
+    #[cfg(feature = "bt")]
+    if let Err(e) = hotplug::watch_bluetooth(sender.clone()) {
+        eprintln!(
+            "[{}] {} {}",
+            t!("warn").bright_yellow(),
+            t!("monitor_bt_failed"),
+            e
+        );
+    }
+    drop(sender);
+    let mut last_seen: HashMap<(&str, String), serde_json::Value> = HashMap::new();
+    for event in receiver {
+        if !categories.is_empty() && !categories.iter().any(|x| x == event.category) {
+            continue;
+        }
+        let key = (event.category, event.id.clone());
+        let device = match event.action {
+            HotplugAction::Remove => last_seen.remove(&key),
+            _ => device_snapshot(&event).inspect(|x| {
+                last_seen.insert(key, x.clone());
+            }),
+        };
+        if ndjson {
+            let record = serde_json::json!({
+                "timestamp": SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|t| t.as_secs_f64())
+                    .unwrap_or_default(),
+                "category": event.category,
+                "action": event.action,
+                "id": event.id,
+                "device": device,
+            });
+            println!("{}", record);
+        } else {
+            let profiles = snapshot_profiles(device.as_ref());
+            println!(
+                "{} {:<8} {:<20} {}",
+                format!("{:<8}", event.action.as_str()).bright_green(),
+                event.category,
+                event.id,
+                if profiles.is_empty() {
+                    "-".to_owned()
+                } else {
+                    profiles.join(", ")
+                }
+            );
+        }
+        let _ = io::stdout().flush();
+    }
+    Ok(())
+}
+
+#[cfg(feature = "bt")]
+fn monitor_bt_events(ndjson: bool) -> Result<(), CliError> {
+    use libcfhdb::bt::events::{self, CfhdbBtEvent};
+    if ndjson {
+        STDOUT_DOCUMENT.store(true, Ordering::Relaxed);
+    } else {
+        println!(
+            "{}",
+            format!(
+                "{:<16} {:<20} {}",
+                t!("monitor_table_action"),
+                t!("monitor_table_id"),
+                t!("bt_monitor_table_details")
+            )
+            .bold()
+        );
+    }
+    let (sender, receiver) = mpsc::channel();
+    if let Err(e) = events::watch_events(sender) {
+        return Err(CliError::Failed(format!("{}\n{}", t!("monitor_failed"), e)));
+    }
+    for event in receiver {
+        let mut record = serde_json::to_value(&event).unwrap();
+        if ndjson {
+            record["timestamp"] = serde_json::json!(SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs_f64())
+                .unwrap_or_default());
+            println!("{}", record);
+        } else {
+            let details = match &event {
+                CfhdbBtEvent::BatteryChanged { battery_level, .. } => {
+                    format!("{}%", battery_level)
+                }
+                CfhdbBtEvent::AdapterChanged {
+                    property, value, ..
+                }
+                | CfhdbBtEvent::DeviceChanged {
+                    property, value, ..
+                } => format!("{} = {}", property, value),
+                _ => String::new(),
+            };
+            println!(
+                "{} {:<20} {}",
+                format!("{:<16}", record["event"].as_str().unwrap_or_default()).bright_green(),
+                record
+                    .get("address")
+                    .or(record.get("adapter"))
+                    .and_then(|x| x.as_str())
+                    .unwrap_or("-"),
+                details
+            );
+        }
+        let _ = io::stdout().flush();
+    }
+    Ok(())
+}
+
+// --thresholds=20,10 replaces the ones from the config for one run
+#[cfg(feature = "bt")]
+fn bt_battery_thresholds(arguments: &[String]) -> Result<Vec<u8>, CliError> {
+    let given = arguments
+        .iter()
+        .enumerate()
+        .find_map(|(i, x)| match x.as_str() {
+            "--thresholds" => arguments.get(i + 1).map(|x| x.as_str()),
+            t => t.strip_prefix("--thresholds="),
+        });
+    match given {
+        Some(list) => match list
+            .split(',')
+            .map(|x| x.trim().parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>()
+        {
+            Ok(t) if !t.is_empty() && t.iter().all(|&x| x <= 100) => Ok(t),
+            _ => Err(CliError::Failed(
+                t!("bt_battery_invalid_thresholds", thresholds = list).to_string(),
+            )),
+        },
+        None => Ok(get_profile_url_config()
+            .bt_battery_thresholds
+            .unwrap_or(libcfhdb::bt::events::DEFAULT_BATTERY_THRESHOLDS.to_vec())),
+    }
+}
+
+// Only reports levels passing a threshold, the rest of the bluez events are
+// left to monitor_bt_events
+#[cfg(feature = "bt")]
+fn monitor_bt_battery(ndjson: bool, arguments: &[String]) -> Result<(), CliError> {
+    use libcfhdb::bt::events::{self, BatteryCrossing, BatteryWatch, CfhdbBtEvent};
+    let thresholds = bt_battery_thresholds(arguments)?;
+    let mut watch = BatteryWatch::new(&thresholds);
+    // Without them devices that are already low get reported on their first
+    // change
+    if let Ok(levels) = bt_func::bt_battery_levels() {
+        for (address, _, battery_level) in levels {
+            watch.seed(&address, battery_level);
+        }
+    }
+    if ndjson {
+        STDOUT_DOCUMENT.store(true, Ordering::Relaxed);
+    } else {
+        println!(
+            "{}",
+            format!(
+                "{:<16} {:<20} {}",
+                t!("monitor_table_action"),
+                t!("monitor_table_id"),
+                t!("bt_monitor_table_details")
+            )
+            .bold()
+        );
+    }
+    let (sender, receiver) = mpsc::channel();
+    if let Err(e) = events::watch_events(sender) {
+        return Err(CliError::Failed(format!("{}\n{}", t!("monitor_failed"), e)));
+    }
+    for event in receiver {
+        let (adapter, address, battery_level) = match event {
+            CfhdbBtEvent::BatteryChanged {
+                adapter,
+                address,
+                battery_level,
+            } => (adapter, address, battery_level),
+            // bluez drops the level on disconnect, the next connection
+            // starts over
+            CfhdbBtEvent::Disconnected { address, .. }
+            | CfhdbBtEvent::DeviceRemoved { address, .. } => {
+                watch.forget(&address);
+                continue;
+            }
+            _ => continue,
+        };
+        let Some((crossing, threshold)) = watch.update(&address, battery_level) else {
+            continue;
+        };
+        let event_name = match crossing {
+            BatteryCrossing::Below => "battery-low",
+            BatteryCrossing::Above => "battery-recovered",
+        };
+        if ndjson {
             println!(
+                "{}",
+                serde_json::json!({
+                    "event": event_name,
+                    "adapter": adapter,
+                    "address": address,
+                    "battery_level": battery_level,
+                    "threshold": threshold,
+                    "crossing": crossing,
+                    "timestamp": SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|t| t.as_secs_f64())
+                        .unwrap_or_default(),
+                })
+            );
+        } else {
+            let event_cell = format!("{:<16}", event_name);
+            println!(
+                "{} {:<20} {}",
+                match crossing {
+                    BatteryCrossing::Below => event_cell.bright_red(),
+                    BatteryCrossing::Above => event_cell.bright_green(),
+                },
+                address,
+                match crossing {
+                    BatteryCrossing::Below => t!(
+                        "bt_battery_below",
+                        level = battery_level,
+                        threshold = threshold
+                    ),
+                    BatteryCrossing::Above => t!(
+                        "bt_battery_above",
+                        level = battery_level,
+                        threshold = threshold
+                    ),
+                }
+            );
+        }
+        let _ = io::stdout().flush();
+    }
+    Ok(())
+}
+
+// Always JSON, Ansible runs it as /etc/ansible/facts.d/cfhdb.fact
+fn print_facts() {
+    STDOUT_DOCUMENT.store(true, Ordering::Relaxed);
+    let facts = facts::collect_facts(
+        VERSION,
+        &collect_device_status(),
+        &installed::get_installed(),
+        &status::pending_reboot(),
+    );
+    println!("{}", serde_json::to_string_pretty(&facts).unwrap());
+}
+
+fn manage_probe(operation: Option<&str>) -> Result<(), CliError> {
+    match operation {
+        None | Some("show") => {
+            let probe = probe::build_probe(VERSION, &collect_device_status());
+            println!("{}", serde_json::to_string_pretty(&probe).unwrap());
+        }
+        Some("upload") => upload_hardware_probe()?,
+        _ => return Err(usage_error("probe_no_operation")),
+    }
+    Ok(())
+}
+
+// Uploads are public, so unlike installs they are never done without an
+// explicit yes, a pipe on stdin is not consent
+fn upload_hardware_probe() -> Result<(), CliError> {
+    let probe = probe::build_probe(VERSION, &collect_device_status());
+    let uncovered = probe
+        .devices
+        .iter()
+        .filter(|x| x.profiles.is_empty() && x.installed.is_empty())
+        .count();
+    println!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!(
+            "probe_summary",
+            devices = probe.devices.len(),
+            uncovered = uncovered
+        )
+    );
+    println!("{}", t!("probe_upload_notice", tool = probe::HW_PROBE));
+    let consent = ASSUME_YES.load(Ordering::Relaxed)
+        || (io::stdin().is_terminal() && ask_yes_no(&t!("probe_upload_confirm")));
+    if !consent {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("operation_cancelled")
+        );
+        return Ok(());
+    }
+    match probe::upload_probe() {
+        Ok(url) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("probe_uploaded", url = url)
+        ),
+        Err(e) => {
+            return Err(CliError::Failed(format!(
+                "{}\n{}",
+                t!("probe_upload_failed"),
+                e
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn manage_state(operation: Option<&str>, path: Option<&str>) -> Result<(), CliError> {
+    match (operation, path) {
+        (Some("export"), path) => export_state(path),
+        (Some("import"), Some(path)) => import_state(path),
+        (Some("import"), None) => Err(usage_error("state_no_file")),
+        _ => Err(usage_error("state_no_operation")),
+    }
+}
+
+// Without a file the state goes to stdout, ready to be piped to another host
+fn export_state(path: Option<&str>) -> Result<(), CliError> {
+    let json_pretty = serde_json::to_string_pretty(&state::export_state()).unwrap();
+    let path = match path {
+        Some(t) => t,
+        None => {
+            println!("{}", json_pretty);
+            return Ok(());
+        }
+    };
+    match fs::write(path, json_pretty) {
+        Ok(_) => println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("state_exported", path = path)
+        ),
+        Err(e) => {
+            return Err(CliError::Failed(format!(
+                "{}\n{}",
+                t!("state_export_failed", path = path),
+                e
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn disable_imported_device(device: &DisabledDevice) -> Result<(), CommandError> {
+    match device.category.as_str() {
+        #[cfg(feature = "pci")]
+        "pci" => pci_func::disable_pci_device_from_state(&device.id),
+        #[cfg(feature = "usb")]
+        "usb" => usb_func::disable_usb_device_from_state(&device.id),
+        _ => Err(CommandError::DeviceNotFound),
+    }
+}
+
+fn import_state(path: &str) -> Result<(), CliError> {
+    let imported: SystemState = match state::read_state(Path::new(path)) {
+        Ok(t) => t,
+        Err(e) => {
+            return Err(CliError::Failed(format!(
+                "{}\n{}",
+                t!("state_import_failed", path = path),
+                e
+            )))
+        }
+    };
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    let backend = config::distro_package_backend();
+    if !dry_run {
+        require_privileges(AuditAction::Install)?;
+        recover_interrupted_installs(&*backend);
+    }
+    let current = state::export_state().disabled_devices;
+    let to_disable: Vec<&DisabledDevice> = imported
+        .disabled_devices
+        .iter()
+        .filter(|x| {
+            !current
+                .iter()
+                .any(|y| y.category == x.category && y.id == x.id)
+        })
+        .collect();
+    let mut import = ProfileImport {
+        wanted: imported
+            .profiles
+            .iter()
+            .map(|x| x.codename.clone())
+            .collect(),
+        pending: vec![],
+    };
+    let results: Vec<(&str, Result<(), io::Error>)> = vec![
+        #[cfg(feature = "dmi")]
+        ("dmi", dmi_func::import_dmi_profiles(&mut import)),
+        #[cfg(feature = "pci")]
+        ("pci", pci_func::import_pci_profiles(&mut import)),
+        #[cfg(feature = "usb")]
+        ("usb", usb_func::import_usb_profiles(&mut import)),
+        #[cfg(feature = "bt")]
+        ("bt", bt_func::import_bt_profiles(&mut import)),
+    ];
+    let mut failed = false;
+    for (category, result) in results {
+        if let Err(e) = result {
+            failed = true;
+            eprintln!(
+                "[{}] {} {}",
+                t!("error").red(),
+                t!("status_category_failed", category = category),
+                e
+            );
+        }
+    }
+    for codename in &import.wanted {
+        eprintln!(
+            "[{}] {}",
+            t!("warn").bright_yellow(),
+            t!("state_profile_missing", codename = codename)
+        );
+    }
+    if dry_run {
+        let planned = serde_json::json!({
+            "install": import.pending.iter().map(|(x, _)| x).collect::<Vec<_>>(),
+            "disable": to_disable,
+        });
+        println!("{}", serde_json::to_string_pretty(&planned).unwrap());
+        return Ok(());
+    }
+    if import.pending.is_empty() && to_disable.is_empty() {
+        println!("[{}] {}", t!("info").bright_green(), t!("state_nothing"));
+        return Ok(());
+    }
+    for (codename, _) in &import.pending {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("state_will_install", codename = codename)
+        );
+    }
+    for device in &to_disable {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!(
+                "state_will_disable",
+                category = device.category,
+                id = device.id
+            )
+        );
+    }
+    if !confirm_operation() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("operation_cancelled")
+        );
+        return Ok(());
+    }
+    let options = ProfileOperationOptions {
+        atomic: ATOMIC.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+    for (codename, install) in &import.pending {
+        match install(&*backend, &options) {
+            Ok(_) => println!(
                 "[{}] {}",
                 t!("info").bright_green(),
-                t!("install_script_successful")
+                t!("auto_successful", codename = codename)
+            ),
+            Err(e) => {
+                failed = true;
+                eprintln!(
+                    "[{}] {}\n{}",
+                    t!("error").red(),
+                    t!("auto_failed", codename = codename),
+                    e
+                );
+            }
+        }
+    }
+    // Bus addresses often differ on other hardware, so a missing device is
+    // only worth a warning. Another device at the address is never disabled
+    // in place of the exported one.
+    for device in &to_disable {
+        if let Some(present) = device.present_ids() {
+            if device.ids.as_ref() != Some(&present) {
+                failed = true;
+                eprintln!(
+                    "[{}] {}",
+                    t!("error").red(),
+                    t!(
+                        "state_disable_mismatch",
+                        category = device.category,
+                        id = device.id,
+                        expected = device
+                            .ids
+                            .as_ref()
+                            .map_or(t!("unknown").to_string(), |x| x.to_string()),
+                        present = present
+                    )
+                );
+                continue;
+            }
+        }
+        if let Err(e) = disable_imported_device(device) {
+            eprintln!(
+                "[{}] {} {}",
+                t!("warn").bright_yellow(),
+                t!(
+                    "state_disable_failed",
+                    category = device.category,
+                    id = device.id
+                ),
+                e
+            );
+        }
+    }
+    if failed {
+        return Err(CliError::Reported);
+    }
+    Ok(())
+}
+
+fn upgrade_profiles() -> Result<(), CliError> {
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    if !dry_run {
+        require_privileges(AuditAction::Install)?;
+    }
+    if !dry_run {
+        recover_interrupted_installs(&*config::distro_package_backend());
+    }
+    let records = installed::get_installed();
+    if records.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("upgrade_nothing_recorded")
+        );
+        return Ok(());
+    }
+    let mut upgrade = ProfileUpgrade {
+        records,
+        pinned: get_profile_url_config().pinned_profiles,
+        backend: config::distro_package_backend(),
+        dry_run,
+        planned: vec![],
+        upgraded: 0,
+        failed: false,
+    };
+    let results: Vec<(&str, Result<(), io::Error>)> = vec![
+        #[cfg(feature = "dmi")]
+        ("dmi", dmi_func::upgrade_dmi_profiles(&mut upgrade)),
+        #[cfg(feature = "pci")]
+        ("pci", pci_func::upgrade_pci_profiles(&mut upgrade)),
+        #[cfg(feature = "usb")]
+        ("usb", usb_func::upgrade_usb_profiles(&mut upgrade)),
+        #[cfg(feature = "bt")]
+        ("bt", bt_func::upgrade_bt_profiles(&mut upgrade)),
+    ];
+    for (category, result) in results {
+        if let Err(e) = result {
+            upgrade.failed = true;
+            eprintln!(
+                "[{}] {} {}",
+                t!("error").red(),
+                t!("upgrade_category_failed", category = category),
+                e
             );
-            fs::remove_file(file_fs_path).unwrap();
+        }
+    }
+    if dry_run {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&upgrade.planned).unwrap()
+        );
+        return Ok(());
+    }
+    for record in &upgrade.records {
+        eprintln!(
+            "[{}] {}",
+            t!("warn").bright_yellow(),
+            t!("upgrade_missing", codename = record.codename)
+        );
+    }
+    if upgrade.upgraded == 0 && !upgrade.failed {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("upgrade_up_to_date")
+        );
+    }
+    if upgrade.failed {
+        return Err(CliError::Reported);
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "bt", feature = "pci", feature = "usb"))]
+pub fn run_device_action<D, E, F>(
+    target: Result<D, E>,
+    audit_action: AuditAction,
+    action: impl FnOnce(&D) -> Result<(), F>,
+    category: &'static str,
+    id: &str,
+) -> Result<(), CfhdbError>
+where
+    D: CfhdbDevice,
+    E: Into<io::Error>,
+    F: Into<io::Error>,
+{
+    require_privileges(audit_action)?;
+    commands::run_device_action(target, audit_action, action)
+        .map_err(|e| e.into_cfhdb_error(category, id))
+}
+
+// Keeps stdout clean for `cfhdb facts` and the like
+fn print_download_message(message: String) {
+    if STDOUT_DOCUMENT.load(Ordering::Relaxed) {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+pub fn download_profile_database(category: &str, url: &str) -> Result<String, io::Error> {
+    let cached_db_path = profile_cache_dir()?.join(format!("{}.json", category));
+    print_download_message(format!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!(format!("{}_download_starting", category))
+    ));
+    match database::fetch_profile_database(url, &cached_db_path, None, None) {
+        Ok((data, DatabaseSource::Remote)) => {
+            print_download_message(format!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!(format!("{}_download_successful", category))
+            ));
+            Ok(data)
+        }
+        Ok((data, DatabaseSource::Cache)) => {
+            print_download_message(format!(
+                "[{}] {}",
+                t!("warn").bright_yellow(),
+                t!(format!("{}_download_failed", category))
+            ));
+            print_download_message(format!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!(format!("{}_download_cache_found", category))
+            ));
+            Ok(data)
         }
         Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("install_script_failed"));
-            fs::remove_file(file_fs_path).unwrap();
-            exit(1);
+            print_download_message(format!(
+                "[{}] {}",
+                t!("warn").bright_yellow(),
+                t!(format!("{}_download_failed", category))
+            ));
+            eprintln!(
+                "[{}] {}",
+                t!("error").red(),
+                t!(format!("{}_download_cache_not_found", category))
+            );
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                t!(format!("{}_download_cache_not_found", category)),
+            ))
         }
     }
 }
 
 pub fn get_profile_url_config() -> ProfileUrlConfig {
-    let file_path = "/etc/cfhdb/profile-config.json";
-    let json_content = fs::read_to_string(file_path).unwrap();
+    let json_content = fs::read_to_string(PROFILE_CONFIG_PATH).unwrap();
     let config: ProfileUrlConfig = serde_json::from_str(&json_content).unwrap();
     config
 }
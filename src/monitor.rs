@@ -0,0 +1,259 @@
+#[cfg(feature = "bt")]
+use crate::bt_func;
+#[cfg(feature = "pci")]
+use crate::pci_func;
+#[cfg(feature = "usb")]
+use crate::usb_func;
+use crate::{
+    config,
+    daemon::{send_replies, INTERFACE, OBJECT_PATH},
+    get_profile_url_config, ProfileAutoApply,
+};
+use dbus::{
+    blocking::Connection,
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    strings::{Interface, Member},
+    Message, Path,
+};
+use dbus_crossroads::{Context, Crossroads};
+use libcfhdb::{
+    autoinstall::{AutoInstallMode, AutoInstallRules},
+    hotplug::{self, HotplugAction, HotplugEvent, HotplugPolicy},
+    profile::ProfileOperationOptions,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const PROCESS_INTERVAL: Duration = Duration::from_millis(500);
+// A dock or hub brings many devices at once, they are matched in one go
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+// A profile that was offered, or failed to install, is left alone for a while
+// instead of coming back with every replug
+const HANDLED_COOLDOWN: Duration = Duration::from_secs(3600);
+
+struct HotplugMonitor {
+    policy: HotplugPolicy,
+    usb_rules: AutoInstallRules,
+    // Shortest time between two matching runs of the same category
+    min_interval: Duration,
+    // Categories with new hardware, and when the first of it showed up
+    pending: HashMap<&'static str, Instant>,
+    last_run: HashMap<&'static str, Instant>,
+    handled: HashMap<String, Instant>,
+}
+
+impl HotplugMonitor {
+    fn queue(&mut self, event: HotplugEvent) {
+        if event.action != HotplugAction::Add {
+            return;
+        }
+        info!(category = event.category, id = %event.id, "new hardware");
+        self.pending
+            .entry(event.category)
+            .or_insert_with(Instant::now);
+    }
+
+    fn due(&mut self) -> Vec<&'static str> {
+        let due: Vec<&'static str> = self
+            .pending
+            .iter()
+            .filter(|(category, first_seen)| {
+                first_seen.elapsed() >= SETTLE_TIME
+                    && self
+                        .last_run
+                        .get(*category)
+                        .is_none_or(|x| x.elapsed() >= self.min_interval)
+            })
+            .map(|(category, _)| *category)
+            .collect();
+        for category in &due {
+            self.pending.remove(category);
+            self.last_run.insert(category, Instant::now());
+        }
+        due
+    }
+
+    fn run_category(&mut self, category: &'static str, connection: &Connection) {
+        let rules = (category == "usb" && self.usb_rules.enabled()).then(|| self.usb_rules.clone());
+        let policy = match rules.as_ref().map(|x| x.mode) {
+            Some(AutoInstallMode::Notify) => HotplugPolicy::Offer,
+            Some(AutoInstallMode::Install) => HotplugPolicy::Apply,
+            _ => self.policy,
+        };
+        let mut auto = ProfileAutoApply {
+            categories: vec![category.to_owned()],
+            picks: vec![],
+            rules,
+        };
+        let result = match category {
+            #[cfg(feature = "pci")]
+            "pci" => pci_func::auto_pci_profiles(&mut auto),
+            #[cfg(feature = "usb")]
+            "usb" => usb_func::auto_usb_profiles(&mut auto),
+            #[cfg(feature = "bt")]
+            "bt" => bt_func::auto_bt_profiles(&mut auto),
+            _ => return,
+        };
+        if let Err(e) = result {
+            warn!(category, error = %e, "could not match profiles for new hardware");
+            return;
+        }
+        let backend = config::distro_package_backend();
+        for pick in &auto.picks {
+            if self
+                .handled
+                .get(&pick.codename)
+                .is_some_and(|x| x.elapsed() < HANDLED_COOLDOWN)
+            {
+                continue;
+            }
+            self.handled.insert(pick.codename.clone(), Instant::now());
+            match policy {
+                HotplugPolicy::DryRun => info!(
+                    category,
+                    device = %pick.device,
+                    codename = %pick.codename,
+                    "would install the recommended profile"
+                ),
+                HotplugPolicy::Offer => {
+                    info!(
+                        category,
+                        device = %pick.device,
+                        codename = %pick.codename,
+                        "offering the recommended profile"
+                    );
+                    emit(
+                        connection,
+                        "ProfileAvailable",
+                        (category, pick.device.as_str(), pick.codename.as_str()),
+                    );
+                }
+                HotplugPolicy::Apply => {
+                    let result = (pick.install)(&*backend, &ProfileOperationOptions::default());
+                    match &result {
+                        Ok(_) => {
+                            info!(codename = %pick.codename, "installed the recommended profile")
+                        }
+                        Err(e) => warn!(
+                            codename = %pick.codename,
+                            error = %e,
+                            "could not install the recommended profile"
+                        ),
+                    }
+                    emit(
+                        connection,
+                        "ProfileApplied",
+                        (
+                            category,
+                            pick.device.as_str(),
+                            pick.codename.as_str(),
+                            result.is_ok(),
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn emit<A: dbus::arg::AppendAll>(connection: &Connection, member: &'static str, args: A) {
+    let mut signal = Message::signal(
+        &Path::from(OBJECT_PATH),
+        &Interface::from(INTERFACE),
+        &Member::from(member),
+    );
+    args.append(&mut dbus::arg::IterAppend::new(&mut signal));
+    if connection.send(signal).is_err() {
+        warn!(member, "could not emit signal");
+    }
+}
+
+// BlueZ announces new devices as objects under /org/bluez
+#[cfg(feature = "bt")]
+fn watch_bluez(
+    connection: &Connection,
+    sender: mpsc::Sender<HotplugEvent>,
+) -> Result<(), dbus::Error> {
+    let rule = MatchRule::new_signal("org.freedesktop.DBus.ObjectManager", "InterfacesAdded");
+    connection.add_match(
+        rule,
+        move |(path, interfaces): (Path<'static>, HashMap<String, dbus::arg::PropMap>), _, _| {
+            if !path.starts_with("/org/bluez/") {
+                return true;
+            }
+            let address = interfaces
+                .get("org.bluez.Device1")
+                .and_then(|x| x.get("Address"))
+                .and_then(|x| x.0.as_str());
+            if let Some(address) = address {
+                let _ = sender.send(HotplugEvent {
+                    category: "bt",
+                    action: HotplugAction::Add,
+                    id: address.to_owned(),
+                });
+            }
+            true
+        },
+    )?;
+    Ok(())
+}
+
+// Serves the D-Bus interface like the plain daemon, and in between matches
+// profiles against hardware that udev or BlueZ reported
+pub fn run(
+    crossroads: Crossroads,
+    connection: &Connection,
+    replies: mpsc::Receiver<Context>,
+) -> Result<(), Box<dyn Error>> {
+    let config = get_profile_url_config();
+    let (sender, receiver) = mpsc::channel();
+    #[cfg(feature = "bt")]
+    watch_bluez(connection, sender.clone())?;
+    hotplug::watch_udev(sender)?;
+    let mut crossroads = crossroads;
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, connection| {
+            if crossroads.handle_message(message, connection).is_err() {
+                warn!("could not handle a D-Bus method call");
+            }
+            true
+        }),
+    );
+    let mut monitor = HotplugMonitor {
+        policy: config.hotplug_policy,
+        usb_rules: AutoInstallRules {
+            mode: config.usb_auto_install,
+            exclude: config.usb_auto_install_exclude,
+        },
+        min_interval: config
+            .hotplug_min_interval
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MIN_INTERVAL),
+        pending: HashMap::new(),
+        last_run: HashMap::new(),
+        handled: HashMap::new(),
+    };
+    info!(
+        policy = monitor.policy.as_str(),
+        usb_auto_install = monitor.usb_rules.mode.as_str(),
+        "watching for new hardware"
+    );
+    loop {
+        connection.process(PROCESS_INTERVAL)?;
+        send_replies(&replies, connection);
+        while let Ok(event) = receiver.try_recv() {
+            monitor.queue(event);
+        }
+        for category in monitor.due() {
+            monitor.run_category(category, connection);
+        }
+    }
+}
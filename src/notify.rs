@@ -0,0 +1,279 @@
+use crate::{
+    daemon::{init_service, BUS_NAME, INTERFACE, OBJECT_PATH},
+    get_profile_url_config,
+};
+use dbus::{arg::PropMap, blocking::Connection, message::MatchRule};
+use std::{collections::HashMap, error::Error, sync::mpsc, thread, time::Duration};
+use tracing::{info, warn};
+
+const PROCESS_INTERVAL: Duration = Duration::from_millis(500);
+// The install waits for polkit and the package download
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(3600);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(120);
+const INSTALL_ACTION: &str = "install";
+
+enum AgentEvent {
+    Available {
+        category: String,
+        device: String,
+        codename: String,
+    },
+    Applied {
+        category: String,
+        device: String,
+        codename: String,
+        success: bool,
+    },
+    ActionInvoked {
+        id: u32,
+        action: String,
+    },
+    Closed {
+        id: u32,
+    },
+    Installed {
+        device: String,
+        codename: String,
+        result: Result<String, String>,
+    },
+}
+
+struct Offer {
+    category: String,
+    device: String,
+    codename: String,
+}
+
+struct NotifyAgent {
+    session: Connection,
+    // None shows notifications for every category
+    categories: Option<Vec<String>>,
+    // Notifications with an install button that is still on screen
+    offers: HashMap<u32, Offer>,
+    sender: mpsc::Sender<AgentEvent>,
+}
+
+// "Vendor Name" of the device as `cfhdb status` knows it, the bare id when
+// the daemon cannot tell
+fn device_name(category: &str, device: &str) -> String {
+    let name = Connection::new_system().ok().and_then(|connection| {
+        let (status,): (String,) = connection
+            .with_proxy(BUS_NAME, OBJECT_PATH, QUERY_TIMEOUT)
+            .method_call(INTERFACE, "Status", ())
+            .ok()?;
+        let devices: Vec<serde_json::Value> = serde_json::from_str(&status).ok()?;
+        let found = devices
+            .iter()
+            .find(|x| x["category"] == category && x["id"] == device)?;
+        Some(format!(
+            "{} {}",
+            found["vendor"].as_str().unwrap_or_default(),
+            found["name"].as_str().unwrap_or_default()
+        ))
+    });
+    match name {
+        Some(t) if !t.trim().is_empty() => t.trim().to_owned(),
+        _ => device.to_owned(),
+    }
+}
+
+impl NotifyAgent {
+    fn wants(&self, category: &str) -> bool {
+        self.categories
+            .as_ref()
+            .is_none_or(|x| x.iter().any(|x| x == category))
+    }
+
+    fn notify(&self, summary: &str, body: &str, actions: Vec<&str>) -> Option<u32> {
+        let proxy = self.session.with_proxy(
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            QUERY_TIMEOUT,
+        );
+        let result: Result<(u32,), dbus::Error> = proxy.method_call(
+            "org.freedesktop.Notifications",
+            "Notify",
+            (
+                "cfhdb",
+                0u32,
+                "drive-harddisk",
+                summary,
+                body,
+                actions,
+                PropMap::new(),
+                -1i32,
+            ),
+        );
+        match result {
+            Ok((id,)) => Some(id),
+            Err(e) => {
+                warn!(error = %e, "could not show a notification");
+                None
+            }
+        }
+    }
+
+    // The install runs on a connection of its own, so notifications keep
+    // coming while polkit asks for the password
+    fn install(&self, offer: Offer) {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let result = Connection::new_system()
+                .and_then(|connection| {
+                    let (outcome,): (String,) = connection
+                        .with_proxy(BUS_NAME, OBJECT_PATH, INSTALL_TIMEOUT)
+                        .method_call(
+                            INTERFACE,
+                            "InstallProfile",
+                            (offer.category.as_str(), offer.codename.as_str()),
+                        )?;
+                    Ok(outcome)
+                })
+                .map_err(|e| e.message().unwrap_or("unknown error").to_owned());
+            let _ = sender.send(AgentEvent::Installed {
+                device: offer.device,
+                codename: offer.codename,
+                result,
+            });
+        });
+    }
+
+    fn handle(&mut self, event: AgentEvent) {
+        match event {
+            AgentEvent::Available {
+                category,
+                device,
+                codename,
+            } => {
+                if !self.wants(&category) {
+                    return;
+                }
+                let device = device_name(&category, &device);
+                let summary = t!("notify_device_detected", device = device);
+                let body = t!("notify_profile_available", codename = codename);
+                let label = t!("notify_install");
+                if let Some(id) = self.notify(&summary, &body, vec![INSTALL_ACTION, &label]) {
+                    self.offers.insert(
+                        id,
+                        Offer {
+                            category,
+                            device,
+                            codename,
+                        },
+                    );
+                }
+            }
+            AgentEvent::Applied {
+                category,
+                device,
+                codename,
+                success,
+            } => {
+                if !self.wants(&category) {
+                    return;
+                }
+                let device = device_name(&category, &device);
+                let summary = t!("notify_device_detected", device = device);
+                let body = if success {
+                    t!("notify_profile_installed", codename = codename)
+                } else {
+                    t!("notify_profile_failed", codename = codename)
+                };
+                self.notify(&summary, &body, vec![]);
+            }
+            AgentEvent::ActionInvoked { id, action } => {
+                if action != INSTALL_ACTION {
+                    return;
+                }
+                if let Some(offer) = self.offers.remove(&id) {
+                    info!(codename = %offer.codename, "installing the offered profile");
+                    self.install(offer);
+                }
+            }
+            AgentEvent::Closed { id } => {
+                self.offers.remove(&id);
+            }
+            AgentEvent::Installed {
+                device,
+                codename,
+                result,
+            } => {
+                let summary = t!("notify_device_detected", device = device);
+                let body = match result {
+                    Ok(_) => t!("notify_profile_installed", codename = codename).into_owned(),
+                    Err(e) => format!(
+                        "{}\n{}",
+                        t!("notify_profile_failed", codename = codename),
+                        e
+                    ),
+                };
+                self.notify(&summary, &body, vec![]);
+            }
+        }
+    }
+}
+
+// Runs in the desktop session of every user, the system daemon cannot reach
+// their notification servers. Follows the signals of `cfhdbd --monitor`.
+pub fn run() -> Result<(), Box<dyn Error>> {
+    init_service()?;
+    let config = get_profile_url_config();
+    let system = Connection::new_system()?;
+    let session = Connection::new_session()?;
+    let (sender, receiver) = mpsc::channel();
+    let available = sender.clone();
+    system.add_match(
+        MatchRule::new_signal(INTERFACE, "ProfileAvailable"),
+        move |(category, device, codename): (String, String, String), _, _| {
+            let _ = available.send(AgentEvent::Available {
+                category,
+                device,
+                codename,
+            });
+            true
+        },
+    )?;
+    let applied = sender.clone();
+    system.add_match(
+        MatchRule::new_signal(INTERFACE, "ProfileApplied"),
+        move |(category, device, codename, success): (String, String, String, bool), _, _| {
+            let _ = applied.send(AgentEvent::Applied {
+                category,
+                device,
+                codename,
+                success,
+            });
+            true
+        },
+    )?;
+    let invoked = sender.clone();
+    session.add_match(
+        MatchRule::new_signal("org.freedesktop.Notifications", "ActionInvoked"),
+        move |(id, action): (u32, String), _, _| {
+            let _ = invoked.send(AgentEvent::ActionInvoked { id, action });
+            true
+        },
+    )?;
+    let closed = sender.clone();
+    session.add_match(
+        MatchRule::new_signal("org.freedesktop.Notifications", "NotificationClosed"),
+        move |(id, _reason): (u32, u32), _, _| {
+            let _ = closed.send(AgentEvent::Closed { id });
+            true
+        },
+    )?;
+    let mut agent = NotifyAgent {
+        session,
+        categories: config.notify_categories,
+        offers: HashMap::new(),
+        sender,
+    };
+    info!("waiting for hardware suggestions");
+    loop {
+        system.process(PROCESS_INTERVAL)?;
+        agent.session.process(Duration::ZERO)?;
+        while let Ok(event) = receiver.try_recv() {
+            agent.handle(event);
+        }
+    }
+}
@@ -1,12 +1,36 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    download_profile_database, get_profile_url_config, install_profile, profile_status_cell,
+    run_device_action, uninstall_profile, ProfileAutoApply, ProfileCleanup, ProfileImport,
+    ProfileUpgrade,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::pci::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path, process::exit};
+use libcfhdb::{
+    audit::AuditAction,
+    commands::{self, CommandError},
+    database,
+    device::CfhdbDevice,
+    error::CfhdbError,
+    hwdb::{self, HwdbMatch},
+    ids::PciAddress,
+    modules::{self, DeviceModules},
+    pci::*,
+    profile::CfhdbProfile,
+    status::{self, DeviceStatus},
+};
+#[cfg(feature = "daemon")]
+use libcfhdb::{
+    packages::PackageBackend,
+    profile::{ProfileOperationOptions, ProfileOperationOutcome},
+};
+use std::{collections::HashMap, ops::Deref, path::Path, sync::Arc};
 
 lazy_static! {
     static ref PCI_PROFILE_JSON_URL: String = get_profile_url_config().pci_json_url;
+    // Fetched for the first device `cfhdb monitor` sees, not at startup
+    static ref PCI_MONITOR_PROFILES: Vec<Arc<CfhdbPciProfile>> =
+        get_pci_profiles_from_url().unwrap_or_default();
 }
 
 fn display_pci_devices_print_json(hashmap: HashMap<String, Vec<CfhdbPciDevice>>) {
@@ -80,20 +104,11 @@ fn display_pci_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbPciDevi
     }
 }
 
-fn display_pci_profiles_print_cli_table(target: &CfhdbPciDevice) {
+fn display_pci_profiles_print_cli_table(
+    target: &CfhdbPciDevice,
+    profiles: Vec<Arc<CfhdbPciProfile>>,
+) {
     let mut table_struct = vec![];
-    let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
-        Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_device")
-            );
-            exit(1);
-        }
-    };
-    profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
         let profile = profile.deref().clone();
         let profile_status = profile.get_status();
@@ -111,13 +126,7 @@ fn display_pci_profiles_print_cli_table(target: &CfhdbPciDevice) {
             } else {
                 t!("enabled_no").cell().foreground_color(Some(Color::Green))
             },
-            if profile_status {
-                t!("enabled_yes")
-                    .cell()
-                    .foreground_color(Some(Color::Green))
-            } else {
-                t!("enabled_no").cell().foreground_color(Some(Color::Red))
-            },
+            profile_status_cell(profile_status, profile.check_is_pure),
         ];
         table_struct.push(cell_table);
     }
@@ -138,441 +147,222 @@ fn display_pci_profiles_print_cli_table(target: &CfhdbPciDevice) {
     println!("{}\n{}", target.sysfs_busid.bright_green(), table_display);
 }
 
-pub fn display_pci_devices(json: bool) {
-    match CfhdbPciDevice::get_devices() {
-        Some(devices) => {
-            let profiles = match get_pci_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-            for i in &devices {
-                CfhdbPciDevice::set_available_profiles(&profiles, &i);
-            }
-            let hashmap = CfhdbPciDevice::create_class_hashmap(devices);
-            if json {
-                display_pci_devices_print_json(hashmap)
-            } else {
-                display_pci_devices_print_cli_table(hashmap)
-            }
-        }
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("failed_to_get_pci_devices")
-            );
-            exit(1);
-        }
+pub fn display_pci_devices(json: bool) -> Result<(), CfhdbError> {
+    let profiles = get_pci_profiles_from_url()?;
+    let hashmap =
+        commands::list_pci_devices(&profiles).map_err(|e| e.into_cfhdb_error("pci", ""))?;
+    if json {
+        display_pci_devices_print_json(hashmap)
+    } else {
+        display_pci_devices_print_cli_table(hashmap)
     }
+    Ok(())
 }
 
-pub fn display_pci_profiles(json: bool, target: &str) {
-    match CfhdbPciDevice::get_device_from_busid(target) {
-        Ok(target_device) => {
-            let profiles = match get_pci_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-            CfhdbPciDevice::set_available_profiles(&profiles, &target_device);
-            if json {
-                let mut profile_arc =
-                    match target_device.available_profiles.0.lock().unwrap().clone() {
-                        Some(t) => t,
-                        None => {
-                            eprintln!(
-                                "[{}] {}",
-                                t!("error").red(),
-                                t!("no_profiles_available_for_device")
-                            );
-                            exit(1);
-                        }
-                    };
-                profile_arc.sort_by_key(|k| k.priority);
-                let profiles = profile_arc
-                    .iter()
-                    .map(|s| s.codename.clone())
-                    .collect::<Vec<_>>();
-                let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-                println!("{}", json_pretty);
-            } else {
-                display_pci_profiles_print_cli_table(&target_device);
-            }
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
-    }
+pub fn collect_pci_status() -> Result<Vec<DeviceStatus>, CommandError> {
+    let profiles = get_pci_profiles_from_url()?;
+    let hashmap = commands::list_pci_devices(&profiles)?;
+    Ok(hashmap
+        .values()
+        .flatten()
+        .map(|x| status::device_status("pci", x))
+        .collect())
 }
 
-pub fn install_pci_profile(profile_codename: &str) {
-    let profiles = match get_pci_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbPciProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+// Veiled profiles stay out, like they do in listings
+pub fn pci_hwdb_matches() -> Result<Vec<HwdbMatch>, std::io::Error> {
+    let profiles = get_pci_profiles_from_url()?;
+    Ok(profiles
+        .iter()
+        .filter(|x| !x.veiled)
+        .flat_map(|x| hwdb::pci_matches(x))
+        .collect())
 }
-pub fn uninstall_pci_profile(profile_codename: &str) {
-    let profiles = match get_pci_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbPciProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+
+// Bound and candidate kernel modules per device for `cfhdb modules`
+pub fn pci_device_modules() -> Result<Vec<DeviceModules>, CommandError> {
+    let hashmap = commands::list_pci_devices(&[])?;
+    let loaded = modules::loaded_modules();
+    let blacklisted = modules::blacklisted_modules();
+    Ok(hashmap
+        .values()
+        .flatten()
+        .map(|x| {
+            let sysfs_path = Path::new("/sys/bus/pci/devices").join(&x.sysfs_busid);
+            modules::device_modules("pci", x, &sysfs_path, &loaded, &blacklisted)
+        })
+        .collect())
 }
 
-pub fn enable_pci_device(target_sysfs_id: &str) {
-    match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.enable_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
-    }
+// What `cfhdb monitor` prints with an event, None once the device is gone.
+// The codenames of the matching profiles end up in available_profiles.
+pub fn pci_device_snapshot(id: &str) -> Option<serde_json::Value> {
+    let device = CfhdbPciDevice::get_device_from_busid(id).ok()?;
+    CfhdbPciDevice::set_available_profiles(&PCI_MONITOR_PROFILES, &device);
+    serde_json::to_value(device).ok()
 }
-pub fn disable_pci_device(target_sysfs_id: &str) {
-    match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.disable_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
-    }
+
+// Devices per class for `cfhdb exporter`, profiles are not needed for that
+pub fn pci_class_counts() -> Result<Vec<(String, usize)>, CommandError> {
+    let hashmap = commands::list_pci_devices(&[])?;
+    Ok(hashmap
+        .iter()
+        .map(|(class, devices)| (class.clone(), devices.len()))
+        .collect())
 }
 
-pub fn start_pci_device(target_sysfs_id: &str) {
-    match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.start_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
+pub fn display_pci_profiles(json: bool, target: &str) -> Result<(), CfhdbError> {
+    let address: PciAddress = target.parse()?;
+    let profiles = get_pci_profiles_from_url()?;
+    let target_device = commands::get_pci_device_with_profiles(&address, &profiles)
+        .map_err(|e| e.into_cfhdb_error("pci", target))?;
+    let matched_profiles = commands::get_sorted_profiles(&target_device)
+        .map_err(|e| e.into_cfhdb_error("pci", target))?;
+    if json {
+        let codenames = matched_profiles
+            .iter()
+            .map(|s| s.codename.clone())
+            .collect::<Vec<_>>();
+        let json_pretty = serde_json::to_string_pretty(&codenames).unwrap();
+        println!("{}", json_pretty);
+    } else {
+        display_pci_profiles_print_cli_table(&target_device, matched_profiles);
     }
+    Ok(())
 }
-pub fn stop_pci_device(target_sysfs_id: &str) {
-    match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.stop_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
+
+pub fn install_pci_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_pci_profiles_from_url()?;
+    install_profile(
+        CfhdbPciProfile::get_profile_from_codename(profile_codename, &profiles)
+            .map_err(|_| CfhdbError::ProfileNotFound("pci", profile_codename.to_owned()))?,
+    )
+}
+
+pub fn upgrade_pci_profiles(upgrade: &mut ProfileUpgrade) -> Result<(), std::io::Error> {
+    upgrade.apply(&get_pci_profiles_from_url()?);
+    Ok(())
+}
+
+pub fn autoremove_pci_profiles(cleanup: &mut ProfileCleanup) -> Result<(), CommandError> {
+    let profiles = get_pci_profiles_from_url()?;
+    let hashmap = commands::list_pci_devices(&profiles)?;
+    cleanup.apply(&profiles, hashmap.values().flatten());
+    Ok(())
+}
+
+pub fn auto_pci_profiles(auto: &mut ProfileAutoApply) -> Result<(), CommandError> {
+    let profiles = get_pci_profiles_from_url()?;
+    let hashmap = commands::list_pci_devices(&profiles)?;
+    auto.apply("pci", hashmap.values().flatten());
+    Ok(())
+}
+
+pub fn import_pci_profiles(import: &mut ProfileImport) -> Result<(), std::io::Error> {
+    import.apply(&get_pci_profiles_from_url()?);
+    Ok(())
+}
+
+#[cfg(feature = "daemon")]
+pub fn pci_devices_json() -> Result<String, CommandError> {
+    let profiles = get_pci_profiles_from_url()?;
+    let hashmap = commands::list_pci_devices(&profiles)?;
+    Ok(serde_json::to_string(&hashmap).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn pci_profiles_json(target: &str) -> Result<String, CommandError> {
+    let target: PciAddress = target.parse()?;
+    let profiles = get_pci_profiles_from_url()?;
+    let target_device = commands::get_pci_device_with_profiles(&target, &profiles)?;
+    let matched_profiles = commands::get_sorted_profiles(&target_device)?;
+    let matched_profiles: Vec<&CfhdbPciProfile> =
+        matched_profiles.iter().map(|x| x.deref()).collect();
+    Ok(serde_json::to_string(&matched_profiles).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn run_pci_profile_operation(
+    profile_codename: &str,
+    install: bool,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, CommandError> {
+    let profiles = get_pci_profiles_from_url()?;
+    let target = CfhdbPciProfile::get_profile_from_codename(profile_codename, &profiles);
+    if install {
+        commands::install_profile(target, backend, &ProfileOperationOptions::default())
+    } else {
+        commands::uninstall_profile(target, backend)
     }
 }
 
-fn get_pci_profiles_from_url() -> Result<Vec<CfhdbPciProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/pci.json");
-    println!(
-        "[{}] {}",
-        t!("info").bright_green(),
-        t!("pci_download_starting")
-    );
-    let client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(5))
-        .build()
-        .unwrap();
-    let data = match client.get(PCI_PROFILE_JSON_URL.clone()).send() {
-        Ok(t) => {
-            println!(
-                "[{}] {}",
-                t!("info").bright_green(),
-                t!("pci_download_successful")
-            );
-            let cache = t.text().unwrap();
-            let _ = fs::File::create(cached_db_path);
-            let _ = fs::write(cached_db_path, &cache);
-            cache
-        }
-        Err(_) => {
-            println!(
-                "[{}] {}",
-                t!("warn").bright_yellow(),
-                t!("pci_download_failed")
-            );
-            if cached_db_path.exists() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("pci_download_cache_found")
-                );
-                fs::read_to_string(cached_db_path).unwrap()
-            } else {
-                eprintln!(
-                    "[{}] {}",
-                    t!("error").red(),
-                    t!("pci_download_cache_not_found")
-                );
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    t!("pci_download_cache_not_found"),
-                ));
+pub fn disable_pci_device_from_state(busid: &str) -> Result<(), CommandError> {
+    commands::run_device_action(
+        CfhdbPciDevice::get_device_from_busid(busid),
+        AuditAction::Disable,
+        CfhdbDevice::disable_device,
+    )
+}
+
+pub fn uninstall_pci_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_pci_profiles_from_url()?;
+    uninstall_profile(
+        CfhdbPciProfile::get_profile_from_codename(profile_codename, &profiles)
+            .map_err(|_| CfhdbError::ProfileNotFound("pci", profile_codename.to_owned()))?,
+    )
+}
+
+pub fn enable_pci_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbPciDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Enable,
+        CfhdbDevice::enable_device,
+        "pci",
+        target_sysfs_id,
+    )
+}
+pub fn disable_pci_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbPciDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Disable,
+        CfhdbDevice::disable_device,
+        "pci",
+        target_sysfs_id,
+    )
+}
+
+pub fn start_pci_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbPciDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Start,
+        CfhdbDevice::start_device,
+        "pci",
+        target_sysfs_id,
+    )
+}
+pub fn stop_pci_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbPciDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Stop,
+        CfhdbDevice::stop_device,
+        "pci",
+        target_sysfs_id,
+    )
+}
+
+fn get_pci_profiles_from_url() -> Result<Vec<Arc<CfhdbPciProfile>>, std::io::Error> {
+    let data = download_profile_database("pci", &PCI_PROFILE_JSON_URL)?;
+    let profiles: Vec<CfhdbPciProfile> =
+        database::parse_profile_database(&data, &rust_i18n::locale())?;
+    let mut profiles_array: Vec<Arc<CfhdbPciProfile>> = profiles
+        .into_iter()
+        .map(|mut profile| {
+            if profile.license.is_empty() {
+                profile.license = t!("unknown").to_string();
             }
-        }
-    };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-            let class_ids: Vec<String> = match profile["class_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let vendor_ids: Vec<String> = match profile["vendor_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let device_ids: Vec<String> = match profile["device_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_class_ids: Vec<String> =
-                match profile["blacklisted_class_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_vendor_ids: Vec<String> =
-                match profile["blacklisted_vendor_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_device_ids: Vec<String> =
-                match profile["blacklisted_device_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_pci_profile_json")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbPciProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                class_ids,
-                vendor_ids,
-                device_ids,
-                blacklisted_class_ids,
-                blacklisted_vendor_ids,
-                blacklisted_device_ids,
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
-    }
+            Arc::new(profile)
+        })
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }
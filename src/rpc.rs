@@ -0,0 +1,209 @@
+use crate::{
+    collect_device_status,
+    daemon::{init_service, list_devices, list_profiles, run_profile_operation},
+};
+use libcfhdb::commands::CommandError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    error::Error,
+    fs,
+    io::{self, BufRead, ErrorKind, Write},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    thread,
+};
+use tracing::{info, warn};
+
+pub const SOCKET_PATH: &str = "/run/cfhdb/cfhdb.sock";
+
+// JSON-RPC 2.0 reserves -32768 to -32000, cfhdb errors count down from -32001
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const IO_ERROR: i64 = -32000;
+const ENUMERATION_FAILED: i64 = -32001;
+const DEVICE_NOT_FOUND: i64 = -32002;
+const NO_PROFILES_AVAILABLE: i64 = -32003;
+const PROFILE_NOT_FOUND: i64 = -32004;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    // Left out for notifications, which get no response
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+    // The CommandError variant, so clients need not match on messages
+    data: Value,
+}
+
+impl RpcError {
+    fn new(code: i64, kind: &str, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: json!({ "kind": kind }),
+        }
+    }
+}
+
+impl From<CommandError> for RpcError {
+    fn from(e: CommandError) -> Self {
+        let (code, kind) = match &e {
+            CommandError::EnumerationFailed => (ENUMERATION_FAILED, "enumeration_failed"),
+            CommandError::DeviceNotFound => (DEVICE_NOT_FOUND, "device_not_found"),
+            CommandError::NoProfilesAvailable => (NO_PROFILES_AVAILABLE, "no_profiles_available"),
+            CommandError::ProfileNotFound => (PROFILE_NOT_FOUND, "profile_not_found"),
+            CommandError::Io(e) if e.kind() == ErrorKind::InvalidInput => {
+                (INVALID_PARAMS, "invalid_params")
+            }
+            CommandError::Io(_) => (IO_ERROR, "io"),
+        };
+        RpcError::new(code, kind, e)
+    }
+}
+
+#[derive(Deserialize)]
+struct CategoryParams {
+    category: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceParams {
+    category: String,
+    device: String,
+}
+
+#[derive(Deserialize)]
+struct ProfileParams {
+    category: String,
+    codename: String,
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, "invalid_params", e))
+}
+
+// The query methods already produce JSON, it is embedded as is
+fn embed(output: String) -> Result<Value, RpcError> {
+    serde_json::from_str(&output).map_err(|e| RpcError::new(IO_ERROR, "io", e))
+}
+
+fn call(method: &str, args: Value) -> Result<Value, RpcError> {
+    match method {
+        "version" => Ok(json!(crate::VERSION)),
+        "list_devices" => {
+            let p: CategoryParams = params(args)?;
+            embed(list_devices(&p.category)?)
+        }
+        "list_profiles" => {
+            let p: DeviceParams = params(args)?;
+            embed(list_profiles(&p.category, &p.device)?)
+        }
+        "status" => Ok(serde_json::to_value(collect_device_status()).unwrap()),
+        "install_profile" | "uninstall_profile" => {
+            let p: ProfileParams = params(args)?;
+            let install = method == "install_profile";
+            info!(category = %p.category, codename = %p.codename, method, "profile operation over the socket");
+            Ok(json!(run_profile_operation(
+                &p.category,
+                &p.codename,
+                install
+            )?))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            "method_not_found",
+            format!("unknown method {}", method),
+        )),
+    }
+}
+
+fn respond(line: &str) -> Option<Value> {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(t) => t,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, "parse_error", e);
+            return Some(json!({ "jsonrpc": "2.0", "id": null, "error": error }));
+        }
+    };
+    let result = if request.jsonrpc != "2.0" {
+        Err(RpcError::new(
+            INVALID_REQUEST,
+            "invalid_request",
+            "only JSON-RPC 2.0 is supported",
+        ))
+    } else {
+        call(&request.method, request.params)
+    };
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    })
+}
+
+// One request per line, answered with one response per line in order
+fn serve_client(stream: UnixStream) -> Result<(), io::Error> {
+    let mut writer = stream.try_clone()?;
+    for line in io::BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = respond(&line) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+    Ok(())
+}
+
+// The socket is only open to root, who may install profiles anyway, so there
+// is no per call authorization like the D-Bus service has
+pub fn run() -> Result<(), Box<dyn Error>> {
+    init_service()?;
+    let path = Path::new(SOCKET_PATH);
+    // The socket itself only gets its mode after bind, the directory keeps
+    // everyone else out before that. One left by an older version is
+    // tightened as well.
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+    }
+    // Left behind by a previous run that did not exit cleanly
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!(path = SOCKET_PATH, "cfhdbd listening for JSON-RPC");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = serve_client(stream) {
+                        warn!(error = %e, "JSON-RPC client connection failed");
+                    }
+                });
+            }
+            Err(e) => warn!(error = %e, "could not accept a JSON-RPC client"),
+        }
+    }
+    Ok(())
+}
@@ -1,41 +1,138 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
-use cli_table::{Cell, Color, Style, Table};
+use crate::{
+    download_profile_database, format_size, get_profile_url_config, install_profile,
+    profile_status_cell, run_device_action, uninstall_profile, ProfileAutoApply, ProfileCleanup,
+    ProfileImport, ProfileUpgrade,
+};
+use cli_table::{Cell, CellStruct, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::usb::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path, process::exit};
+use libcfhdb::{
+    audit::AuditAction,
+    blacklist,
+    commands::{self, CommandError},
+    database,
+    device::CfhdbDevice,
+    error::CfhdbError,
+    hwdb::{self, HwdbMatch},
+    ids::UsbTarget,
+    modules::{self, DeviceModules},
+    profile::CfhdbProfile,
+    status::{self, DeviceStatus},
+    usb::{descriptors::CfhdbUsbDescriptors, hid::CfhdbUsbHidInterface, *},
+};
+#[cfg(feature = "daemon")]
+use libcfhdb::{
+    packages::PackageBackend,
+    profile::{ProfileOperationOptions, ProfileOperationOutcome},
+};
+use std::{collections::HashMap, ops::Deref, path::Path, sync::Arc};
 
 lazy_static! {
     static ref USB_PROFILE_JSON_URL: String = get_profile_url_config().usb_json_url;
+    // Fetched for the first device `cfhdb monitor` sees, not at startup
+    static ref USB_MONITOR_PROFILES: Vec<Arc<CfhdbUsbProfile>> =
+        get_usb_profiles_from_url().unwrap_or_default();
 }
 
 fn display_usb_devices_print_json(hashmap: HashMap<String, Vec<CfhdbUsbDevice>>) {
     let json_pretty = serde_json::to_string_pretty(&hashmap).unwrap();
     println!("{}", json_pretty);
 }
+fn usb_power_cell(device: &CfhdbUsbDevice) -> CellStruct {
+    match (device.power_control, device.autosuspend_delay_ms) {
+        (Some(UsbPowerControl::Auto), Some(delay)) => {
+            t!("usb_power_auto_delay", delay = delay).cell()
+        }
+        (Some(UsbPowerControl::Auto), None) => t!("usb_power_auto").cell(),
+        (Some(UsbPowerControl::On), _) => t!("usb_power_on").cell(),
+        (None, _) => t!("unknown")
+            .to_string()
+            .cell()
+            .foreground_color(Some(Color::Yellow)),
+    }
+}
+
+fn usb_wakeup_cell(device: &CfhdbUsbDevice) -> CellStruct {
+    match device.wakeup {
+        Some(true) => t!("usb_wakeup_enabled").cell(),
+        Some(false) => t!("usb_wakeup_disabled").cell(),
+        None => t!("usb_wakeup_unsupported").cell(),
+    }
+}
+
+fn usb_speed_cell(device: &CfhdbUsbDevice) -> CellStruct {
+    if device.is_below_max_speed() {
+        t!(
+            "usb_speed_below_max",
+            speed = device.speed,
+            max_speed = device.max_speed
+        )
+        .cell()
+        .foreground_color(Some(Color::Yellow))
+    } else {
+        device.speed.as_str().cell()
+    }
+}
+
+// One line per disk, "/dev/sdb 16.0 GB: sdb1 vfat"
+fn usb_block_devices_cell(device: &CfhdbUsbDevice) -> CellStruct {
+    if device.block_devices.is_empty() {
+        return t!("usb_block_devices_none").cell();
+    }
+    device
+        .block_devices
+        .iter()
+        .map(|disk| {
+            let mut line = format!("{} {}", disk.path, format_size(Some(disk.size_bytes)));
+            let filesystems: Vec<String> = if disk.partitions.is_empty() {
+                disk.filesystem.iter().cloned().collect()
+            } else {
+                disk.partitions
+                    .iter()
+                    .map(|x| {
+                        format!(
+                            "{} {}",
+                            x.path.trim_start_matches("/dev/"),
+                            x.filesystem.as_deref().unwrap_or("?")
+                        )
+                    })
+                    .collect()
+            };
+            if !filesystems.is_empty() {
+                line += &format!(": {}", filesystems.join(", "));
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .cell()
+}
+
 fn display_usb_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbUsbDevice>>) {
     for (class, devices) in hashmap {
+        // Mass storage devices get the disks they show up as
+        let storage = class == "08";
         let mut table_struct = vec![];
         for device in devices {
-            let cell_table = vec![
-                match device.manufacturer_string_index.char_indices().nth(18) {
-                    None => device.manufacturer_string_index,
-                    Some((idx, _)) => device.manufacturer_string_index[..idx].to_string() + "...",
+            let mut cell_table = vec![
+                match device.get_manufacturer_name().char_indices().nth(18) {
+                    None => device.get_manufacturer_name().to_string(),
+                    Some((idx, _)) => device.get_manufacturer_name()[..idx].to_string() + "...",
                 }
                 .cell(),
-                match device.product_string_index.char_indices().nth(36) {
-                    None => device.product_string_index,
-                    Some((idx, _)) => device.product_string_index[..idx].to_string() + "...",
+                match device.get_product_name().char_indices().nth(36) {
+                    None => device.get_product_name().to_string(),
+                    Some((idx, _)) => device.get_product_name()[..idx].to_string() + "...",
                 }
                 .cell(),
-                device.sysfs_busid.cell(),
-                device.speed.cell(),
+                device.sysfs_busid.as_str().cell(),
+                usb_speed_cell(&device),
                 match device.kernel_driver.as_str() {
                     "Unknown" => t!("unknown")
                         .to_string()
                         .cell()
                         .foreground_color(Some(Color::Yellow)),
-                    _ => device.kernel_driver.cell(),
+                    _ => device.kernel_driver.as_str().cell(),
                 },
                 match device.started {
                     Some(t) => {
@@ -56,21 +153,27 @@ fn display_usb_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbUsbDevi
                 } else {
                     t!("enabled_no").cell().foreground_color(Some(Color::Red))
                 },
+                usb_power_cell(&device),
             ];
+            if storage {
+                cell_table.push(usb_block_devices_cell(&device));
+            }
             table_struct.push(cell_table);
         }
-        let table = table_struct
-            .table()
-            .title(vec![
-                t!("usb_table_manufacturer_string_index").cell().bold(true),
-                t!("usb_table_product_string_index").cell().bold(true),
-                t!("usb_table_sysfs_bus_id").cell().bold(true),
-                t!("usb_table_speed").cell().bold(true),
-                t!("usb_table_driver").cell().bold(true),
-                t!("usb_table_started").cell().bold(true),
-                t!("usb_table_enabled").cell().bold(true),
-            ])
-            .bold(true);
+        let mut title = vec![
+            t!("usb_table_manufacturer_string_index").cell().bold(true),
+            t!("usb_table_product_string_index").cell().bold(true),
+            t!("usb_table_sysfs_bus_id").cell().bold(true),
+            t!("usb_table_speed").cell().bold(true),
+            t!("usb_table_driver").cell().bold(true),
+            t!("usb_table_started").cell().bold(true),
+            t!("usb_table_enabled").cell().bold(true),
+            t!("usb_table_power").cell().bold(true),
+        ];
+        if storage {
+            title.push(t!("usb_table_block_devices").cell().bold(true));
+        }
+        let table = table_struct.table().title(title).bold(true);
 
         let table_display = table.display().unwrap();
 
@@ -82,20 +185,11 @@ fn display_usb_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbUsbDevi
     }
 }
 
-fn display_usb_profiles_print_cli_table(target: &CfhdbUsbDevice) {
+fn display_usb_profiles_print_cli_table(
+    target: &CfhdbUsbDevice,
+    profiles: Vec<Arc<CfhdbUsbProfile>>,
+) {
     let mut table_struct = vec![];
-    let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
-        Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_device")
-            );
-            exit(1);
-        }
-    };
-    profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
         let profile = profile.deref().clone();
         let profile_status = profile.get_status();
@@ -113,13 +207,7 @@ fn display_usb_profiles_print_cli_table(target: &CfhdbUsbDevice) {
             } else {
                 t!("enabled_no").cell().foreground_color(Some(Color::Green))
             },
-            if profile_status {
-                t!("enabled_yes")
-                    .cell()
-                    .foreground_color(Some(Color::Green))
-            } else {
-                t!("enabled_no").cell().foreground_color(Some(Color::Red))
-            },
+            profile_status_cell(profile_status, profile.check_is_pure),
         ];
         table_struct.push(cell_table);
     }
@@ -140,441 +228,590 @@ fn display_usb_profiles_print_cli_table(target: &CfhdbUsbDevice) {
     println!("{}\n{}", target.sysfs_busid.bright_green(), table_display);
 }
 
-pub fn display_usb_devices(json: bool) {
-    match CfhdbUsbDevice::get_devices() {
-        Some(devices) => {
-            let profiles = match get_usb_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-            for i in &devices {
-                CfhdbUsbDevice::set_available_profiles(&profiles, &i);
-            }
-            let hashmap = CfhdbUsbDevice::create_class_hashmap(devices);
-            if json {
-                display_usb_devices_print_json(hashmap)
-            } else {
-                display_usb_devices_print_cli_table(hashmap)
-            }
-        }
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("failed_to_get_usb_devices")
-            );
-            exit(1);
-        }
+pub fn display_usb_devices(json: bool) -> Result<(), CfhdbError> {
+    let profiles = get_usb_profiles_from_url()?;
+    let hashmap =
+        commands::list_usb_devices(&profiles).map_err(|e| e.into_cfhdb_error("usb", ""))?;
+    if json {
+        display_usb_devices_print_json(hashmap)
+    } else {
+        display_usb_devices_print_cli_table(hashmap)
     }
+    Ok(())
 }
 
-pub fn display_usb_profiles(json: bool, target: &str) {
-    match CfhdbUsbDevice::get_device_from_busid(target) {
-        Ok(target_device) => {
-            let profiles = match get_usb_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-            CfhdbUsbDevice::set_available_profiles(&profiles, &target_device);
-            if json {
-                let mut profile_arc =
-                    match target_device.available_profiles.0.lock().unwrap().clone() {
-                        Some(t) => t,
-                        None => {
-                            eprintln!(
-                                "[{}] {}",
-                                t!("error").red(),
-                                t!("no_profiles_available_for_device")
-                            );
-                            exit(1);
-                        }
-                    };
-                profile_arc.sort_by_key(|k| k.priority);
-                let profiles = profile_arc
-                    .iter()
-                    .map(|s| s.codename.clone())
-                    .collect::<Vec<_>>();
-                let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-                println!("{}", json_pretty);
-            } else {
-                display_usb_profiles_print_cli_table(&target_device);
-            }
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+fn usb_tree_node_label(node: &CfhdbUsbTreeNode) -> String {
+    let name = match &node.device {
+        Some(device) => format!(
+            "{} {}",
+            device.get_manufacturer_name(),
+            device.get_product_name()
+        ),
+        None => t!("usb_tree_root_hub").to_string(),
+    };
+    let unknown = |x: &str| match x {
+        "Unknown" => t!("unknown").to_string(),
+        t => t.to_owned(),
+    };
+    format!(
+        "{} {} ({})",
+        node.sysfs_busid.bright_green(),
+        name,
+        t!(
+            "usb_tree_node_details",
+            driver = unknown(&node.kernel_driver),
+            speed = unknown(&node.speed)
+        )
+    )
+}
+
+fn display_usb_tree_print_children(nodes: &[CfhdbUsbTreeNode], prefix: &str) {
+    for (index, node) in nodes.iter().enumerate() {
+        let (branch, indent) = if index + 1 == nodes.len() {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        println!("{}{}{}", prefix, branch, usb_tree_node_label(node));
+        display_usb_tree_print_children(&node.children, &format!("{}{}", prefix, indent));
     }
 }
 
-pub fn install_usb_profile(profile_codename: &str) {
-    let profiles = match get_usb_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbUsbProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
+pub fn display_usb_tree(json: bool) -> Result<(), CfhdbError> {
+    let devices = commands::list_usb_devices(&[])
+        .map_err(|e| e.into_cfhdb_error("usb", ""))?
+        .into_values()
+        .flatten()
+        .collect();
+    let tree = CfhdbUsbTreeNode::build_tree(devices);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&tree).unwrap());
+    } else {
+        for root in tree {
+            println!("{}", usb_tree_node_label(&root));
+            display_usb_tree_print_children(&root.children, "");
         }
     }
+    Ok(())
 }
-pub fn uninstall_usb_profile(profile_codename: &str) {
-    let profiles = match get_usb_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
+
+pub fn collect_usb_status() -> Result<Vec<DeviceStatus>, CommandError> {
+    let profiles = get_usb_profiles_from_url()?;
+    let hashmap = commands::list_usb_devices(&profiles)?;
+    Ok(hashmap
+        .values()
+        .flatten()
+        .map(|x| status::device_status("usb", x))
+        .collect())
+}
+
+// Veiled profiles stay out, like they do in listings
+pub fn usb_hwdb_matches() -> Result<Vec<HwdbMatch>, std::io::Error> {
+    let profiles = get_usb_profiles_from_url()?;
+    Ok(profiles
+        .iter()
+        .filter(|x| !x.veiled)
+        .flat_map(|x| hwdb::usb_matches(x))
+        .collect())
+}
+
+// Bound and candidate kernel modules per device for `cfhdb modules`, usb
+// drivers bind to the interfaces below the device
+pub fn usb_device_modules() -> Result<Vec<DeviceModules>, CommandError> {
+    let hashmap = commands::list_usb_devices(&[])?;
+    let loaded = modules::loaded_modules();
+    let blacklisted = modules::blacklisted_modules();
+    Ok(hashmap
+        .values()
+        .flatten()
+        .map(|x| {
+            let sysfs_path = Path::new("/sys/bus/usb/devices").join(&x.sysfs_busid);
+            modules::device_modules("usb", x, &sysfs_path, &loaded, &blacklisted)
+        })
+        .collect())
+}
+
+// What `cfhdb monitor` prints with an event, None once the device is gone.
+// The codenames of the matching profiles end up in available_profiles.
+pub fn usb_device_snapshot(id: &str) -> Option<serde_json::Value> {
+    let device = CfhdbUsbDevice::get_device_from_busid(id).ok()?;
+    CfhdbUsbDevice::set_available_profiles(&USB_MONITOR_PROFILES, &device);
+    serde_json::to_value(device).ok()
+}
+
+// Devices per class for `cfhdb exporter`, profiles are not needed for that
+pub fn usb_class_counts() -> Result<Vec<(String, usize)>, CommandError> {
+    let hashmap = commands::list_usb_devices(&[])?;
+    Ok(hashmap
+        .iter()
+        .map(|(class, devices)| (class.clone(), devices.len()))
+        .collect())
+}
+
+pub fn display_usb_profiles(json: bool, target: &str) -> Result<(), CfhdbError> {
+    let address = CfhdbUsbDevice::resolve_busid(target)?;
+    let profiles = get_usb_profiles_from_url()?;
+    let target_device = commands::get_usb_device_with_profiles(&address, &profiles)
+        .map_err(|e| e.into_cfhdb_error("usb", target))?;
+    let matched_profiles = commands::get_sorted_profiles(&target_device)
+        .map_err(|e| e.into_cfhdb_error("usb", target))?;
+    if json {
+        let codenames = matched_profiles
+            .iter()
+            .map(|s| s.codename.clone())
+            .collect::<Vec<_>>();
+        let json_pretty = serde_json::to_string_pretty(&codenames).unwrap();
+        println!("{}", json_pretty);
+    } else {
+        display_usb_profiles_print_cli_table(&target_device, matched_profiles);
+    }
+    Ok(())
+}
+
+pub fn install_usb_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_usb_profiles_from_url()?;
+    install_profile(CfhdbUsbProfile::get_profile_from_codename(
+        profile_codename,
+        &profiles,
+    )?)
+}
+
+pub fn upgrade_usb_profiles(upgrade: &mut ProfileUpgrade) -> Result<(), std::io::Error> {
+    upgrade.apply(&get_usb_profiles_from_url()?);
+    Ok(())
+}
+
+pub fn autoremove_usb_profiles(cleanup: &mut ProfileCleanup) -> Result<(), CommandError> {
+    let profiles = get_usb_profiles_from_url()?;
+    let hashmap = commands::list_usb_devices(&profiles)?;
+    cleanup.apply(&profiles, hashmap.values().flatten());
+    Ok(())
+}
+
+pub fn auto_usb_profiles(auto: &mut ProfileAutoApply) -> Result<(), CommandError> {
+    let profiles = get_usb_profiles_from_url()?;
+    let hashmap = commands::list_usb_devices(&profiles)?;
+    auto.apply("usb", hashmap.values().flatten());
+    Ok(())
+}
+
+pub fn import_usb_profiles(import: &mut ProfileImport) -> Result<(), std::io::Error> {
+    import.apply(&get_usb_profiles_from_url()?);
+    Ok(())
+}
+
+#[cfg(feature = "daemon")]
+pub fn usb_devices_json() -> Result<String, CommandError> {
+    let profiles = get_usb_profiles_from_url()?;
+    let hashmap = commands::list_usb_devices(&profiles)?;
+    Ok(serde_json::to_string(&hashmap).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn usb_profiles_json(target: &str) -> Result<String, CommandError> {
+    let target = CfhdbUsbDevice::resolve_busid(target).map_err(std::io::Error::from)?;
+    let profiles = get_usb_profiles_from_url()?;
+    let target_device = commands::get_usb_device_with_profiles(&target, &profiles)?;
+    let matched_profiles = commands::get_sorted_profiles(&target_device)?;
+    let matched_profiles: Vec<&CfhdbUsbProfile> =
+        matched_profiles.iter().map(|x| x.deref()).collect();
+    Ok(serde_json::to_string(&matched_profiles).unwrap())
+}
+
+#[cfg(feature = "daemon")]
+pub fn run_usb_profile_operation(
+    profile_codename: &str,
+    install: bool,
+    backend: &dyn PackageBackend,
+) -> Result<ProfileOperationOutcome, CommandError> {
+    let profiles = get_usb_profiles_from_url()?;
+    let target = CfhdbUsbProfile::get_profile_from_codename(profile_codename, &profiles);
+    if install {
+        commands::install_profile(target, backend, &ProfileOperationOptions::default())
+    } else {
+        commands::uninstall_profile(target, backend)
+    }
+}
+
+pub fn disable_usb_device_from_state(busid: &str) -> Result<(), CommandError> {
+    commands::run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(busid),
+        AuditAction::Disable,
+        CfhdbDevice::disable_device,
+    )
+}
+
+pub fn uninstall_usb_profile(profile_codename: &str) -> Result<(), CfhdbError> {
+    let profiles = get_usb_profiles_from_url()?;
+    uninstall_profile(CfhdbUsbProfile::get_profile_from_codename(
+        profile_codename,
+        &profiles,
+    )?)
+}
+
+pub fn enable_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Enable,
+        CfhdbDevice::enable_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+pub fn disable_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Disable,
+        CfhdbDevice::disable_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+pub fn set_usb_persistently_disabled(target: &str, disabled: bool) -> Result<(), CfhdbError> {
+    let busid = CfhdbUsbDevice::resolve_busid(target)?;
+    let changed = commands::set_persistently_disabled(&busid, disabled)
+        .map_err(|e| e.into_cfhdb_error("usb", target))?;
+    let message = match (changed, disabled) {
+        (true, true) => t!("usb_persistent_disabled", busid = busid),
+        (true, false) => t!("usb_persistent_enabled", busid = busid),
+        (false, _) => t!("usb_persistent_unchanged", busid = busid),
     };
-    match CfhdbUsbProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
+    println!("[{}] {}", t!("info").bright_green(), message);
+    Ok(())
+}
+
+pub fn start_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Start,
+        CfhdbDevice::start_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+pub fn stop_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Stop,
+        CfhdbDevice::stop_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+pub fn authorize_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Authorize,
+        CfhdbUsbDevice::authorize_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+pub fn deauthorize_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Deauthorize,
+        CfhdbUsbDevice::deauthorize_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+// Indented like `lsusb -v` but only down to the fields, --json has the rest
+pub fn display_usb_descriptors(json: bool, target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    let descriptors = CfhdbUsbDescriptors::from_target(target_sysfs_id)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&descriptors).unwrap());
+        return Ok(());
+    }
+    let device = &descriptors.device;
+    println!(
+        "{} {}:{} USB {} {}",
+        descriptors.sysfs_busid.bright_green(),
+        device.vendor_id,
+        device.product_id,
+        device.usb_version,
+        [&device.manufacturer, &device.product]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    for config in &descriptors.configurations {
+        println!(
+            "  {}",
+            t!(
+                "usb_describe_configuration",
+                number = config.number,
+                max_power = config.max_power_ma
+            )
+        );
+        for interface in &config.interfaces {
+            println!(
+                "    {}",
+                t!(
+                    "usb_describe_interface",
+                    number = interface.number,
+                    alternate_setting = interface.alternate_setting,
+                    class = format!(
+                        "{}:{}:{}",
+                        interface.class_code, interface.sub_class_code, interface.protocol_code
+                    ),
+                    class_name = interface.class_name
+                )
+            );
+            for endpoint in &interface.endpoints {
                 println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
+                    "      {}",
+                    t!(
+                        "usb_describe_endpoint",
+                        address = endpoint.address,
+                        direction = endpoint.direction,
+                        transfer_type = endpoint.transfer_type,
+                        max_packet_size = endpoint.max_packet_size
+                    )
                 );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
             }
         }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
     }
+    Ok(())
 }
 
-pub fn enable_usb_device(target_sysfs_id: &str) {
-    match CfhdbUsbDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.enable_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+// Looked up for any device here, not only the ones whose class is hid, so
+// the hid interface of a headset or a gamepad with audio shows up too
+pub fn display_usb_hid(json: bool, target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    let device = CfhdbUsbDevice::get_device_from_busid(target_sysfs_id)?;
+    let hid_interfaces = CfhdbUsbHidInterface::from_sysfs(&device.sysfs_busid);
+    let summaries = hid_interfaces
+        .iter()
+        .map(|x| x.read_report_summary())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CfhdbError::Sysfs)?;
+    if json {
+        let interfaces: Vec<serde_json::Value> = hid_interfaces
+            .iter()
+            .zip(&summaries)
+            .map(|(interface, summary)| {
+                serde_json::json!({
+                    "interface": interface,
+                    "report_summary": summary,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&interfaces).unwrap());
+        return Ok(());
     }
-}
-pub fn disable_usb_device(target_sysfs_id: &str) {
-    match CfhdbUsbDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.disable_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+    if hid_interfaces.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("usb_hid_none", busid = device.sysfs_busid)
+        );
+        return Ok(());
     }
+    for (interface, summary) in hid_interfaces.iter().zip(&summaries) {
+        let details = vec![
+            vec![
+                t!("usb_hid_driver").cell(),
+                interface
+                    .driver
+                    .clone()
+                    .unwrap_or(t!("unknown").to_string())
+                    .cell(),
+            ],
+            vec![
+                t!("usb_hid_event_nodes").cell(),
+                interface.event_nodes.join("\n").cell(),
+            ],
+            vec![
+                t!("usb_hid_hidraw_nodes").cell(),
+                interface.hidraw_nodes.join("\n").cell(),
+            ],
+            vec![
+                t!("usb_hid_descriptor_length").cell(),
+                summary.length.cell(),
+            ],
+            vec![
+                t!("usb_hid_applications").cell(),
+                summary.applications.join("\n").cell(),
+            ],
+        ]
+        .table()
+        .bold(true);
+        let reports = summary
+            .reports
+            .iter()
+            .map(|x| {
+                vec![
+                    x.id.cell(),
+                    x.input_bits.cell(),
+                    x.output_bits.cell(),
+                    x.feature_bits.cell(),
+                ]
+            })
+            .collect::<Vec<_>>()
+            .table()
+            .title(vec![
+                t!("usb_hid_table_report_id").cell().bold(true),
+                t!("usb_hid_table_input_bits").cell().bold(true),
+                t!("usb_hid_table_output_bits").cell().bold(true),
+                t!("usb_hid_table_feature_bits").cell().bold(true),
+            ])
+            .bold(true);
+        println!(
+            "{}\n{}\n{}",
+            format!("{} ({})", interface.interface, interface.hid_id).bright_green(),
+            details.display().unwrap(),
+            reports.display().unwrap()
+        );
+    }
+    Ok(())
 }
 
-pub fn start_usb_device(target_sysfs_id: &str) {
-    match CfhdbUsbDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.start_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+pub fn display_usb_power(json: bool, target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    let device = CfhdbUsbDevice::get_device_from_busid(target_sysfs_id)?;
+    if json {
+        let power = serde_json::json!({
+            "power_control": device.power_control,
+            "autosuspend_delay_ms": device.autosuspend_delay_ms,
+            "wakeup": device.wakeup,
+        });
+        println!("{}", serde_json::to_string_pretty(&power).unwrap());
+    } else {
+        let table = vec![vec![
+            device.sysfs_busid.as_str().cell(),
+            usb_power_cell(&device),
+            usb_wakeup_cell(&device),
+        ]]
+        .table()
+        .title(vec![
+            t!("usb_table_sysfs_bus_id").cell().bold(true),
+            t!("usb_table_power").cell().bold(true),
+            t!("usb_table_wakeup").cell().bold(true),
+        ])
+        .bold(true);
+        println!("{}", table.display().unwrap());
     }
+    Ok(())
 }
-pub fn stop_usb_device(target_sysfs_id: &str) {
-    match CfhdbUsbDevice::get_device_from_busid(target_sysfs_id) {
-        Ok(target_device) => {
-            match target_device.stop_device() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
-        }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
+
+pub fn set_usb_power_control(
+    target_sysfs_id: &str,
+    control: UsbPowerControl,
+) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Power,
+        |device: &CfhdbUsbDevice| device.set_power_control(control),
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+pub fn set_usb_autosuspend_delay(target_sysfs_id: &str, delay_ms: i64) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Power,
+        |device: &CfhdbUsbDevice| device.set_autosuspend_delay(delay_ms),
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+pub fn set_usb_wakeup(target_sysfs_id: &str, enabled: bool) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Power,
+        |device: &CfhdbUsbDevice| device.set_wakeup(enabled),
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+pub fn display_usb_blacklist(json: bool) -> Result<(), CfhdbError> {
+    let entries = blacklist::read_blacklist("usb")?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+    } else if entries.is_empty() {
+        println!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("usb_blacklist_empty")
+        );
+    } else {
+        for entry in entries {
+            println!("{}", entry);
         }
     }
+    Ok(())
 }
 
-fn get_usb_profiles_from_url() -> Result<Vec<CfhdbUsbProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/usb.json");
-    println!(
-        "[{}] {}",
-        t!("info").bright_green(),
-        t!("usb_download_starting")
-    );
-    let client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(5))
-        .build()
-        .unwrap();
-    let data = match client.get(USB_PROFILE_JSON_URL.clone()).send() {
-        Ok(t) => {
+pub fn set_usb_blacklisted(target: &str, blacklisted: bool) -> Result<(), CfhdbError> {
+    // A serial number goes on the list as the stable id of the device, which
+    // unbind_blacklist.sh can find again at boot
+    let entry = match target.parse::<UsbTarget>()? {
+        UsbTarget::Serial(_) => CfhdbUsbDevice::get_device_from_busid(target)?
+            .stable_id
+            .ok_or(CfhdbError::DeviceNotFound("usb", target.to_owned()))?,
+        t => t.to_string(),
+    };
+    let changed = commands::set_blacklisted::<CfhdbUsbDevice>(&entry, blacklisted)
+        .map_err(|e| e.into_cfhdb_error("usb", &entry))?;
+    let message = match (changed, blacklisted) {
+        (true, true) => t!("usb_blacklist_added", busid = entry),
+        (true, false) => t!("usb_blacklist_removed", busid = entry),
+        (false, _) => t!("usb_blacklist_unchanged", busid = entry),
+    };
+    println!("[{}] {}", t!("info").bright_green(), message);
+    Ok(())
+}
+
+// Prints the rules without a path, so they can be piped into
+// `usbguard append-rule` or reviewed first
+pub fn export_usbguard_rules(path: Option<&str>) -> Result<(), CfhdbError> {
+    let devices = CfhdbUsbDevice::get_devices().ok_or(CfhdbError::EnumerationFailed("usb"))?;
+    let rules = usbguard::export_rules(&devices)?;
+    match path {
+        None => print!("{}", rules),
+        Some(path) => {
+            std::fs::write(path, rules)?;
             println!(
                 "[{}] {}",
                 t!("info").bright_green(),
-                t!("usb_download_successful")
-            );
-            let cache = t.text().unwrap();
-            let _ = fs::File::create(cached_db_path);
-            let _ = fs::write(cached_db_path, &cache);
-            cache
-        }
-        Err(_) => {
-            println!(
-                "[{}] {}",
-                t!("warn").bright_yellow(),
-                t!("usb_download_failed")
+                t!("usb_usbguard_exported", path = path)
             );
-            if cached_db_path.exists() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("usb_download_cache_found")
-                );
-                fs::read_to_string(cached_db_path).unwrap()
-            } else {
-                eprintln!(
-                    "[{}] {}",
-                    t!("error").red(),
-                    t!("usb_download_cache_not_found")
-                );
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    t!("usb_download_cache_not_found"),
-                ));
-            }
-        }
-    };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-            let class_codes: Vec<String> = match profile["class_codes"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let vendor_ids: Vec<String> = match profile["vendor_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let product_ids: Vec<String> = match profile["product_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_class_codes: Vec<String> =
-                match profile["blacklisted_class_codes"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_vendor_ids: Vec<String> =
-                match profile["blacklisted_vendor_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_product_ids: Vec<String> =
-                match profile["blacklisted_product_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_usb_profile_class_ids")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbUsbProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                class_codes,
-                vendor_ids,
-                product_ids,
-                blacklisted_class_codes,
-                blacklisted_vendor_ids,
-                blacklisted_product_ids,
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
         }
     }
+    Ok(())
+}
+
+pub fn reset_usb_device(target_sysfs_id: &str) -> Result<(), CfhdbError> {
+    run_device_action(
+        CfhdbUsbDevice::get_device_from_busid(target_sysfs_id),
+        AuditAction::Reset,
+        CfhdbUsbDevice::reset_device,
+        "usb",
+        target_sysfs_id,
+    )
+}
+
+fn get_usb_profiles_from_url() -> Result<Vec<Arc<CfhdbUsbProfile>>, std::io::Error> {
+    let data = download_profile_database("usb", &USB_PROFILE_JSON_URL)?;
+    let profiles: Vec<CfhdbUsbProfile> =
+        database::parse_profile_database(&data, &rust_i18n::locale())?;
+    let mut profiles_array: Vec<Arc<CfhdbUsbProfile>> = profiles
+        .into_iter()
+        .map(|mut profile| {
+            if profile.license.is_empty() {
+                profile.license = t!("unknown").to_string();
+            }
+            Arc::new(profile)
+        })
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }